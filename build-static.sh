@@ -0,0 +1,68 @@
+#!/bin/bash
+#
+# SMTP Tunnel Proxy - Static Release Builder
+#
+# Builds fully static, size-optimized musl binaries suitable for shipping
+# inside client packages (smtp-tunnel-adduser --client-bin ...).
+#
+# Usage:
+#   ./build-static.sh [target...]
+#
+# Environment:
+#   TLS_BACKEND   tls-ring (default) or tls-aws-lc
+#   FEATURES      extra cargo features (e.g. "minimal")
+#   OUT_DIR       output directory (default: dist)
+
+set -e
+
+# Colors
+RED='\033[0;31m'
+GREEN='\033[0;32m'
+NC='\033[0m'
+
+TLS_BACKEND="${TLS_BACKEND:-tls-ring}"
+OUT_DIR="${OUT_DIR:-dist}"
+TARGETS=("$@")
+if [ ${#TARGETS[@]} -eq 0 ]; then
+    TARGETS=("x86_64-unknown-linux-musl" "aarch64-unknown-linux-musl")
+fi
+
+print_info() {
+    echo -e "${GREEN}[INFO]${NC} $1"
+}
+
+print_error() {
+    echo -e "${RED}[ERROR]${NC} $1"
+}
+
+FEATURE_LIST="client,server,$TLS_BACKEND"
+if [ -n "$FEATURES" ]; then
+    FEATURE_LIST="$FEATURE_LIST,$FEATURES"
+fi
+
+mkdir -p "$OUT_DIR"
+
+for TARGET in "${TARGETS[@]}"; do
+    print_info "Building $TARGET ($FEATURE_LIST)"
+
+    if ! rustup target list --installed | grep -q "^$TARGET\$"; then
+        print_error "Target $TARGET not installed (rustup target add $TARGET)"
+        exit 1
+    fi
+
+    RUSTFLAGS="-C target-feature=+crt-static" cargo build \
+        --profile release-static \
+        --target "$TARGET" \
+        --no-default-features \
+        --features "$FEATURE_LIST" \
+        --bin smtp-tunnel-client \
+        --bin smtp-tunnel-server
+
+    ARCH="${TARGET%%-*}"
+    for BIN in smtp-tunnel-client smtp-tunnel-server; do
+        cp "target/$TARGET/release-static/$BIN" "$OUT_DIR/$BIN-$ARCH"
+    done
+done
+
+print_info "Binaries written to $OUT_DIR/"
+ls -lh "$OUT_DIR"
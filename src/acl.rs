@@ -0,0 +1,230 @@
+//! Destination access control for tunneled connections
+//!
+//! The server checks every CONNECT against a deny list before dialing out.
+//! Rejections can optionally be recorded to a separate honeypot log, which
+//! makes a leaked client secret being used for scanning easy to spot.
+//! Clients use the same rules to pick destinations they reach directly.
+
+use crate::proto::ConnectMeta;
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// A single deny rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Rule {
+    /// IP address or CIDR range (also matched against resolved addresses)
+    Network(IpNet),
+    /// Exact hostname
+    Host(String),
+    /// `*.example.com`: any subdomain of the suffix
+    Suffix(String),
+}
+
+impl Rule {
+    fn parse(entry: &str) -> anyhow::Result<Self> {
+        let entry = entry.trim();
+        if let Ok(net) = entry.parse::<IpNet>() {
+            return Ok(Rule::Network(net));
+        }
+        if let Ok(ip) = entry.parse::<IpAddr>() {
+            return Ok(Rule::Network(IpNet::from(ip)));
+        }
+        let host = entry.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(suffix) = host.strip_prefix("*.") {
+            if suffix.is_empty() {
+                anyhow::bail!("Invalid destination rule: {entry}");
+            }
+            return Ok(Rule::Suffix(format!(".{suffix}")));
+        }
+        if host.is_empty() || host.contains(['/', ' ', '*']) {
+            anyhow::bail!("Invalid destination rule: {entry}");
+        }
+        Ok(Rule::Host(host))
+    }
+}
+
+/// Destination rules: the server's deny list, or the client's direct list
+#[derive(Debug, Default)]
+pub struct DestinationAcl {
+    /// Rules alongside their original text for logging
+    rules: Vec<(String, Rule)>,
+}
+
+impl DestinationAcl {
+    /// Build from config entries (IPs, CIDRs, hostnames or `*.domain`)
+    pub fn new(entries: &[String]) -> anyhow::Result<Self> {
+        let rules = entries
+            .iter()
+            .map(|entry| Ok((entry.trim().to_string(), Rule::parse(entry)?)))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Whether any rules are configured
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Rules in order, with their original text
+    #[cfg_attr(not(feature = "client"), allow(dead_code))]
+    pub(crate) fn rules(&self) -> impl Iterator<Item = (&str, &Rule)> {
+        self.rules.iter().map(|(text, rule)| (text.as_str(), rule))
+    }
+
+    /// Rule matching the requested host (name or IP literal), if any
+    pub fn check_host(&self, host: &str) -> Option<&str> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return self.check_addr(ip);
+        }
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.rules
+            .iter()
+            .find(|(_, rule)| match rule {
+                Rule::Host(name) => *name == host,
+                Rule::Suffix(suffix) => host.ends_with(suffix.as_str()),
+                Rule::Network(_) => false,
+            })
+            .map(|(text, _)| text.as_str())
+    }
+
+    /// Rule matching a resolved address, if any
+    pub fn check_addr(&self, ip: IpAddr) -> Option<&str> {
+        let ip = ip.to_canonical();
+        self.rules
+            .iter()
+            .find(|(_, rule)| matches!(rule, Rule::Network(net) if net.contains(&ip)))
+            .map(|(text, _)| text.as_str())
+    }
+}
+
+/// A rejected CONNECT, as recorded in the honeypot log
+#[derive(Debug)]
+pub struct HoneypotEntry<'a> {
+    pub username: &'a str,
+    pub client_addr: SocketAddr,
+    pub channel_id: u16,
+    pub host: &'a str,
+    pub port: u16,
+    pub resolved: Option<IpAddr>,
+    pub rule: &'a str,
+    pub meta: &'a ConnectMeta,
+}
+
+/// Append-only log of ACL rejections, one logfmt line per request
+#[derive(Debug)]
+pub struct HoneypotLog {
+    file: Mutex<tokio::fs::File>,
+}
+
+impl HoneypotLog {
+    /// Open (or create) the log file for appending
+    pub async fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let mut options = tokio::fs::OpenOptions::new();
+        options.create(true).append(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let file = options
+            .open(path)
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to open honeypot log {}: {e}", path.display()))?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Record a rejected request
+    pub async fn record(&self, entry: &HoneypotEntry<'_>) -> std::io::Result<()> {
+        let line = format_entry(OffsetDateTime::now_utc(), entry);
+        let mut file = self.file.lock().await;
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await
+    }
+}
+
+/// Format one honeypot log line
+fn format_entry(now: OffsetDateTime, entry: &HoneypotEntry<'_>) -> String {
+    let mut line = format!(
+        "ts={} user={:?} client={} channel={} host={:?} port={} rule={:?}",
+        now.format(&Rfc3339).unwrap_or_default(),
+        entry.username,
+        entry.client_addr,
+        entry.channel_id,
+        entry.host,
+        entry.port,
+        entry.rule,
+    );
+    if let Some(ip) = entry.resolved {
+        line.push_str(&format!(" resolved={ip}"));
+    }
+    if let Some(source) = entry.meta.source {
+        line.push_str(&format!(" source={source}"));
+    }
+    if let Some(protocol) = entry.meta.protocol {
+        line.push_str(&format!(" protocol={}", protocol.as_str()));
+    }
+    line.push('\n');
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(entries: &[&str]) -> DestinationAcl {
+        let entries: Vec<String> = entries.iter().map(|e| e.to_string()).collect();
+        DestinationAcl::new(&entries).unwrap()
+    }
+
+    #[test]
+    fn test_acl_rules() {
+        let acl = acl(&["10.0.0.0/8", "169.254.169.254", "localhost", "*.internal"]);
+
+        assert_eq!(acl.check_host("10.1.2.3"), Some("10.0.0.0/8"));
+        assert_eq!(acl.check_host("169.254.169.254"), Some("169.254.169.254"));
+        assert_eq!(acl.check_host("LOCALHOST."), Some("localhost"));
+        assert_eq!(acl.check_host("db.corp.internal"), Some("*.internal"));
+        assert_eq!(acl.check_host("internal"), None);
+        assert_eq!(acl.check_host("example.com"), None);
+
+        // Resolved and IPv4-mapped addresses hit network rules too
+        assert_eq!(
+            acl.check_addr("::ffff:10.0.0.1".parse().unwrap()),
+            Some("10.0.0.0/8")
+        );
+        assert_eq!(acl.check_addr("8.8.8.8".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn test_acl_invalid_rule() {
+        assert!(DestinationAcl::new(&["*.".to_string()]).is_err());
+        assert!(DestinationAcl::new(&["10.0.0.0/33".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_honeypot_entry_format() {
+        let meta = ConnectMeta::default();
+        let entry = HoneypotEntry {
+            username: "alice",
+            client_addr: "203.0.113.5:40000".parse().unwrap(),
+            channel_id: 3,
+            host: "db.internal",
+            port: 5432,
+            resolved: Some("10.0.0.7".parse().unwrap()),
+            rule: "10.0.0.0/8",
+            meta: &meta,
+        };
+        let line = format_entry(OffsetDateTime::UNIX_EPOCH, &entry);
+
+        assert_eq!(
+            line,
+            "ts=1970-01-01T00:00:00Z user=\"alice\" client=203.0.113.5:40000 channel=3 \
+             host=\"db.internal\" port=5432 rule=\"10.0.0.0/8\" resolved=10.0.0.7\n"
+        );
+    }
+}
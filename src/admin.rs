@@ -0,0 +1,113 @@
+//! Admin socket for the user-management tools
+//!
+//! With `admin` configured the server listens on a Unix socket, and
+//! smtp-tunnel-listusers, -adduser and -deluser given `--admin-socket` ask
+//! it instead of editing the users file themselves. The server changes its
+//! users and rewrites the file under one lock, so an edit can't race a
+//! SIGHUP reload, and the tools don't need to know where the file is.
+//! `smtp-tunnel-server metrics` asks it for the server's metrics.
+//!
+//! Each connection carries one request and one response, both YAML; the
+//! client shuts down its write half to end the request.
+
+use crate::config::{UserEntry, UsersConfig};
+use crate::metrics::FrameStatsSnapshot;
+use serde::{Deserialize, Serialize};
+
+/// Largest request accepted
+pub const MAX_REQUEST: u64 = 64 * 1024;
+
+/// A request to the server
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    ListUsers,
+    AddUser { username: String, entry: UserEntry },
+    RemoveUser { username: String },
+    Metrics,
+}
+
+/// The server's answer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Response {
+    Users {
+        users: UsersConfig,
+    },
+    Metrics {
+        summary: String,
+        frames: FrameStatsSnapshot,
+    },
+    Done,
+    Error {
+        message: String,
+    },
+}
+
+impl Response {
+    pub fn error(message: impl Into<String>) -> Self {
+        Self::Error {
+            message: message.into(),
+        }
+    }
+}
+
+impl Request {
+    /// Whether the request changes anything
+    pub fn is_write(&self) -> bool {
+        !matches!(self, Self::ListUsers | Self::Metrics)
+    }
+}
+
+/// Send `request` to the server at `path`. `Ok(None)` means nothing is
+/// listening there, i.e. the server isn't running.
+#[cfg(unix)]
+pub fn call(path: &std::path::Path, request: &Request) -> anyhow::Result<Option<Response>> {
+    let request = serde_yaml::to_string(request)?;
+    let response = crate::platform::unix_request(path, request.as_bytes())
+        .map_err(|e| anyhow::anyhow!("Can't reach {}: {}", path.display(), e))?;
+    response
+        .map(|response| serde_yaml::from_str(&response))
+        .transpose()
+        .map_err(Into::into)
+}
+
+/// Send `request` to the server at `path`. `Ok(None)` means nothing is
+/// listening there, i.e. the server isn't running.
+#[cfg(not(unix))]
+pub fn call(_path: &std::path::Path, _request: &Request) -> anyhow::Result<Option<Response>> {
+    anyhow::bail!("The admin socket is only available on Unix")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_roundtrip() {
+        let request = Request::AddUser {
+            username: "alice".to_string(),
+            entry: UserEntry {
+                secret: "s3cret".to_string(),
+                whitelist: vec!["10.0.0.0/8".to_string()],
+                logging: false,
+            },
+        };
+        let yaml = serde_yaml::to_string(&request).unwrap();
+        assert!(yaml.starts_with("command: add_user\n"));
+        let Request::AddUser { username, entry } = serde_yaml::from_str(&yaml).unwrap() else {
+            panic!("wrong request");
+        };
+        assert_eq!(username, "alice");
+        assert_eq!(entry.whitelist, ["10.0.0.0/8"]);
+        assert!(request.is_write());
+        assert!(!Request::ListUsers.is_write());
+        assert!(!Request::Metrics.is_write());
+
+        let yaml = serde_yaml::to_string(&Response::error("nope")).unwrap();
+        assert!(matches!(
+            serde_yaml::from_str(&yaml).unwrap(),
+            Response::Error { message } if message == "nope"
+        ));
+    }
+}
@@ -0,0 +1,560 @@
+//! Probe-resistance self-audit
+//!
+//! `smtp-tunnel-server audit` connects to the server's public endpoint the
+//! way an active prober would and lists whatever sets it apart from an
+//! ordinary Postfix submission service: a banner or EHLO that doesn't look
+//! like Postfix, a certificate that names the tunnel or that public CAs
+//! don't vouch for, replies to odd command sequences that Postfix wouldn't
+//! give, and timing too quick and regular for a real MTA. Each anomaly is
+//! something for the operator to fix in the config, DNS or certificate.
+
+use crate::config::ServerConfig;
+use crate::proto::read_line;
+use crate::tls::CertInfo;
+use bytes::BytesMut;
+use std::fmt;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+
+/// How long a connect or an ordinary reply may take
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a probe waits for its reply; Postfix answers every line
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Words that give away what the server really is
+const TELLS: &[&str] = &["tunnel", "proxy", "vpn", "socks"];
+
+/// What Postfix advertises in EHLO on a submission port before STARTTLS
+const EXPECTED_EXTENSIONS: &[&str] = &[
+    "PIPELINING",
+    "SIZE",
+    "STARTTLS",
+    "ENHANCEDSTATUSCODES",
+    "8BITMIME",
+];
+
+/// EHLO keywords real MTAs advertise
+const KNOWN_EXTENSIONS: &[&str] = &[
+    "PIPELINING",
+    "SIZE",
+    "VRFY",
+    "ETRN",
+    "STARTTLS",
+    "AUTH",
+    "ENHANCEDSTATUSCODES",
+    "8BITMIME",
+    "DSN",
+    "SMTPUTF8",
+    "CHUNKING",
+    "BINARYMIME",
+    "DELIVERBY",
+    "HELP",
+];
+
+/// Replies slower than the round trip by less than this, on average and
+/// in spread, look machine-made
+const MIN_REPLY_DELAY_MS: f64 = 2.0;
+const MIN_REPLY_SPREAD_MS: f64 = 1.0;
+
+/// A command sequence and the reply codes Postfix gives it
+struct Probe {
+    name: &'static str,
+    /// Send EHLO first
+    ehlo: bool,
+    command: &'static str,
+    expect: &'static [u16],
+}
+
+const PROBES: &[Probe] = &[
+    Probe {
+        name: "unknown command",
+        ehlo: true,
+        command: "XYZZY",
+        expect: &[500, 502],
+    },
+    Probe {
+        name: "empty line",
+        ehlo: true,
+        command: "",
+        expect: &[500],
+    },
+    Probe {
+        name: "NOOP",
+        ehlo: true,
+        command: "NOOP",
+        expect: &[250],
+    },
+    Probe {
+        name: "RSET",
+        ehlo: true,
+        command: "RSET",
+        expect: &[250],
+    },
+    Probe {
+        name: "VRFY",
+        ehlo: true,
+        command: "VRFY postmaster",
+        expect: &[252, 502],
+    },
+    Probe {
+        name: "MAIL before EHLO",
+        ehlo: false,
+        command: "MAIL FROM:<probe@example.com>",
+        expect: &[503],
+    },
+    Probe {
+        name: "DATA before MAIL",
+        ehlo: true,
+        command: "DATA",
+        expect: &[503, 554],
+    },
+    Probe {
+        name: "AUTH before STARTTLS",
+        ehlo: true,
+        command: "AUTH PLAIN AHByb2JlAHByb2Jl",
+        expect: &[503, 530, 538],
+    },
+    Probe {
+        name: "HTTP request",
+        ehlo: false,
+        command: "GET / HTTP/1.0",
+        expect: &[221, 500, 502],
+    },
+];
+
+/// One check's result
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub check: String,
+    /// Something a prober could tell apart from a real MTA
+    pub anomaly: bool,
+    pub detail: String,
+}
+
+/// Everything the audit found
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub target: String,
+    pub findings: Vec<Finding>,
+}
+
+impl Report {
+    fn ok(&mut self, check: impl Into<String>, detail: impl Into<String>) {
+        self.push(check, false, detail);
+    }
+
+    fn anomaly(&mut self, check: impl Into<String>, detail: impl Into<String>) {
+        self.push(check, true, detail);
+    }
+
+    fn push(&mut self, check: impl Into<String>, anomaly: bool, detail: impl Into<String>) {
+        self.findings.push(Finding {
+            check: check.into(),
+            anomaly,
+            detail: detail.into(),
+        });
+    }
+
+    /// Number of anomalies found
+    pub fn anomalies(&self) -> usize {
+        self.findings.iter().filter(|f| f.anomaly).count()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Audit of {}", self.target)?;
+        for finding in &self.findings {
+            let mark = if finding.anomaly { "ANOMALY" } else { "ok" };
+            writeln!(f, "  {:8} {}: {}", mark, finding.check, finding.detail)?;
+        }
+        match self.anomalies() {
+            0 => writeln!(f, "No anomalies found"),
+            n => writeln!(f, "{n} anomalies found"),
+        }
+    }
+}
+
+/// An SMTP reply: its code and the text of each line
+#[derive(Debug)]
+struct Reply {
+    code: u16,
+    lines: Vec<String>,
+}
+
+impl Reply {
+    fn first_line(&self) -> String {
+        format!(
+            "{} {}",
+            self.code,
+            self.lines.first().map(String::as_str).unwrap_or_default()
+        )
+    }
+}
+
+/// Read a possibly multi-line reply, or None if the server hung up first
+async fn read_reply<S>(stream: &mut S, buf: &mut BytesMut) -> anyhow::Result<Option<Reply>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut lines = Vec::new();
+    loop {
+        let Some(line) = read_line(stream, buf).await? else {
+            return Ok(None);
+        };
+        let code = line
+            .get(..3)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| anyhow::anyhow!("not an SMTP reply: {line:?}"))?;
+        lines.push(line.get(4..).unwrap_or_default().to_string());
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(Some(Reply { code, lines }));
+        }
+    }
+}
+
+/// Send `command` and read the reply, which must come
+async fn command<S>(stream: &mut S, buf: &mut BytesMut, command: &str) -> anyhow::Result<Reply>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream
+        .write_all(format!("{command}\r\n").as_bytes())
+        .await?;
+    tokio::time::timeout(IO_TIMEOUT, read_reply(stream, buf))
+        .await
+        .map_err(|_| anyhow::anyhow!("no reply to {command}"))??
+        .ok_or_else(|| anyhow::anyhow!("connection closed after {command}"))
+}
+
+/// A fresh connection, past the greeting
+struct Connection {
+    stream: TcpStream,
+    buf: BytesMut,
+    greeting: Reply,
+    /// Time to establish the TCP connection: one round trip
+    rtt: Duration,
+    /// Time from connecting to the greeting's arrival
+    greeting_delay: Duration,
+}
+
+async fn connect(target: &str) -> anyhow::Result<Connection> {
+    let start = Instant::now();
+    let mut stream = tokio::time::timeout(IO_TIMEOUT, TcpStream::connect(target))
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out connecting to {target}"))??;
+    let rtt = start.elapsed();
+    let mut buf = BytesMut::with_capacity(1024);
+    let greeting = tokio::time::timeout(IO_TIMEOUT, read_reply(&mut stream, &mut buf))
+        .await
+        .map_err(|_| anyhow::anyhow!("no greeting from {target}"))??
+        .ok_or_else(|| anyhow::anyhow!("{target} closed the connection before greeting"))?;
+    Ok(Connection {
+        stream,
+        buf,
+        greeting,
+        rtt,
+        greeting_delay: start.elapsed() - rtt,
+    })
+}
+
+/// Run every check against `target` (host:port), sampling timing over
+/// `samples` connections
+pub async fn run(config: &ServerConfig, target: &str, samples: usize) -> anyhow::Result<Report> {
+    let mut report = Report {
+        target: target.to_string(),
+        findings: Vec::new(),
+    };
+    let first = connect(target).await.map_err(|e| {
+        if config.knock.is_some() {
+            anyhow::anyhow!("{e} (knocking is enabled, so unknocked addresses see nothing)")
+        } else {
+            e
+        }
+    })?;
+
+    if let Err(e) = check_banner(config, first, &mut report).await {
+        report.anomaly("banner", format!("check failed: {e:#}"));
+    }
+    if let Err(e) = check_tls(config, target, &mut report).await {
+        report.anomaly("tls", format!("check failed: {e:#}"));
+    }
+    for probe in PROBES {
+        if let Err(e) = run_probe(target, probe, &mut report).await {
+            report.anomaly(probe.name, format!("check failed: {e:#}"));
+        }
+    }
+    if let Err(e) = check_timing(target, samples.max(2), &mut report).await {
+        report.anomaly("timing", format!("check failed: {e:#}"));
+    }
+    Ok(report)
+}
+
+/// Greeting and EHLO should read like Postfix's for the configured name
+async fn check_banner(
+    config: &ServerConfig,
+    mut conn: Connection,
+    report: &mut Report,
+) -> anyhow::Result<()> {
+    let greeting = conn.greeting.first_line();
+    let named = conn.greeting.lines[0].split_whitespace().next();
+    if conn.greeting.code != 220 {
+        report.anomaly("greeting", format!("{greeting}; expected 220"));
+    } else if named != Some(config.hostname.as_str()) {
+        report.anomaly(
+            "greeting",
+            format!("{greeting}; doesn't open with {}", config.hostname),
+        );
+    } else if let Some(tell) = find_tell(&greeting) {
+        report.anomaly("greeting", format!("{greeting}; mentions {tell:?}"));
+    } else {
+        report.ok("greeting", greeting);
+    }
+
+    let ehlo = command(&mut conn.stream, &mut conn.buf, "EHLO audit.example.com").await?;
+    if ehlo.code != 250 {
+        report.anomaly("ehlo", format!("{}; expected 250", ehlo.first_line()));
+        return Ok(());
+    }
+    let problems = ehlo_anomalies(&ehlo.lines[1..]);
+    if problems.is_empty() {
+        report.ok("ehlo", ehlo.lines[1..].join(", "));
+    }
+    for problem in problems {
+        report.anomaly("ehlo", problem);
+    }
+    let _ = command(&mut conn.stream, &mut conn.buf, "QUIT").await;
+    Ok(())
+}
+
+/// What's off about the extensions in an EHLO reply (its lines after the
+/// first)
+fn ehlo_anomalies(extensions: &[String]) -> Vec<String> {
+    let keywords: Vec<String> = extensions
+        .iter()
+        .filter_map(|line| line.split_whitespace().next())
+        .map(str::to_ascii_uppercase)
+        .collect();
+    let mut problems = Vec::new();
+    let missing: Vec<&str> = EXPECTED_EXTENSIONS
+        .iter()
+        .copied()
+        .filter(|expected| !keywords.iter().any(|k| k == expected))
+        .collect();
+    if !missing.is_empty() {
+        problems.push(format!(
+            "lacks {}, which Postfix advertises",
+            missing.join(", ")
+        ));
+    }
+    let unknown: Vec<&str> = keywords
+        .iter()
+        .map(String::as_str)
+        .filter(|k| !KNOWN_EXTENSIONS.contains(k) && !k.starts_with('X'))
+        .collect();
+    if !unknown.is_empty() {
+        problems.push(format!("advertises unusual {}", unknown.join(", ")));
+    }
+    if keywords.iter().any(|k| k == "AUTH") && keywords.iter().any(|k| k == "STARTTLS") {
+        problems.push("offers AUTH before STARTTLS, which Postfix doesn't by default".to_string());
+    }
+    problems
+}
+
+/// The certificate should be publicly trusted for the hostname and not
+/// name the tunnel
+async fn check_tls(config: &ServerConfig, target: &str, report: &mut Report) -> anyhow::Result<()> {
+    let server_name = ServerName::try_from(config.hostname.clone())
+        .map_err(|e| anyhow::anyhow!("invalid hostname {}: {e}", config.hostname))?;
+
+    // Accept whatever is presented to look at it
+    let stream = starttls(target).await?;
+    let connector = crate::tls::self_test_connector()?;
+    let tls = connector.connect(server_name.clone(), stream).await?;
+    let (_, conn) = tls.get_ref();
+    let version = conn
+        .protocol_version()
+        .map(|v| format!("{v:?}"))
+        .unwrap_or_default();
+    let chain = conn.peer_certificates().unwrap_or_default();
+    let Some(leaf) = chain.first() else {
+        report.anomaly("certificate", "none presented");
+        return Ok(());
+    };
+    let cert = CertInfo::from_der(leaf)?;
+    report.ok(
+        "certificate",
+        format!(
+            "{}, issued by {}, {} in chain, {version}",
+            cert.subject,
+            cert.issuer,
+            chain.len()
+        ),
+    );
+    if let Some(tell) = find_tell(&cert.subject).or_else(|| find_tell(&cert.issuer)) {
+        report.anomaly("certificate", format!("names mention {tell:?}"));
+    }
+    if cert.subject == cert.issuer {
+        report.anomaly("certificate", "self-signed");
+    }
+    if cert.days_remaining() < 0 {
+        report.anomaly("certificate", format!("expired on {}", cert.expiry_date()));
+    }
+
+    // What a client trusting the web PKI makes of it
+    let stream = starttls(target).await?;
+    let connector = tokio_rustls::TlsConnector::from(crate::tls::client_config(None)?);
+    match connector.connect(server_name, stream).await {
+        Ok(_) => report.ok(
+            "trust",
+            format!("valid for {} under public CAs", config.hostname),
+        ),
+        Err(e) => report.anomaly(
+            "trust",
+            format!(
+                "not valid for {} under public CAs ({e}); most mail hosts present one that is",
+                config.hostname
+            ),
+        ),
+    }
+    Ok(())
+}
+
+/// A connection ready for the TLS handshake
+async fn starttls(target: &str) -> anyhow::Result<TcpStream> {
+    let mut conn = connect(target).await?;
+    command(&mut conn.stream, &mut conn.buf, "EHLO audit.example.com").await?;
+    let reply = command(&mut conn.stream, &mut conn.buf, "STARTTLS").await?;
+    if reply.code != 220 {
+        anyhow::bail!("STARTTLS refused: {}", reply.first_line());
+    }
+    Ok(conn.stream)
+}
+
+/// Postfix answers each probe with one of a few codes
+async fn run_probe(target: &str, probe: &Probe, report: &mut Report) -> anyhow::Result<()> {
+    let mut conn = connect(target).await?;
+    if probe.ehlo {
+        command(&mut conn.stream, &mut conn.buf, "EHLO audit.example.com").await?;
+    }
+    conn.stream
+        .write_all(format!("{}\r\n", probe.command).as_bytes())
+        .await?;
+    let expected: Vec<String> = probe.expect.iter().map(u16::to_string).collect();
+    let expected = expected.join(" or ");
+    match tokio::time::timeout(PROBE_TIMEOUT, read_reply(&mut conn.stream, &mut conn.buf)).await {
+        Ok(Ok(Some(reply))) if probe.expect.contains(&reply.code) => {
+            report.ok(probe.name, reply.first_line());
+        }
+        Ok(Ok(Some(reply))) => report.anomaly(
+            probe.name,
+            format!("{}; Postfix answers {expected}", reply.first_line()),
+        ),
+        Ok(Ok(None)) | Ok(Err(_)) => report.anomaly(
+            probe.name,
+            format!("connection closed without a reply; Postfix answers {expected}"),
+        ),
+        Err(_) => report.anomaly(
+            probe.name,
+            format!(
+                "no reply within {}s; Postfix answers {expected}",
+                PROBE_TIMEOUT.as_secs()
+            ),
+        ),
+    }
+    let _ = conn.stream.write_all(b"QUIT\r\n").await;
+    Ok(())
+}
+
+/// Greeting and EHLO delays, beyond the network's, should vary like a
+/// busy MTA's rather than come back instantly every time
+async fn check_timing(target: &str, samples: usize, report: &mut Report) -> anyhow::Result<()> {
+    let mut greeting = Vec::with_capacity(samples);
+    let mut ehlo = Vec::with_capacity(samples);
+    for _ in 0..samples {
+        let mut conn = connect(target).await?;
+        // The greeting is sent as the connection completes, half a round
+        // trip before we see it complete
+        greeting.push(millis(conn.greeting_delay.saturating_sub(conn.rtt / 2)));
+        let start = Instant::now();
+        command(&mut conn.stream, &mut conn.buf, "EHLO audit.example.com").await?;
+        ehlo.push(millis(start.elapsed().saturating_sub(conn.rtt)));
+        let _ = conn.stream.write_all(b"QUIT\r\n").await;
+    }
+    for (check, delays) in [("greeting timing", greeting), ("ehlo timing", ehlo)] {
+        let (mean, spread) = mean_and_spread(&delays);
+        let detail = format!(
+            "{mean:.1} ms beyond the round trip, σ {spread:.1} ms over {samples} connections"
+        );
+        if mean < MIN_REPLY_DELAY_MS && spread < MIN_REPLY_SPREAD_MS {
+            report.anomaly(
+                check,
+                format!("{detail}; too quick and even for an MTA (see camouflage.response_delays)"),
+            );
+        } else {
+            report.ok(check, detail);
+        }
+    }
+    Ok(())
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Mean and standard deviation
+fn mean_and_spread(values: &[f64]) -> (f64, f64) {
+    let n = values.len().max(1) as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / n;
+    (mean, variance.sqrt())
+}
+
+fn find_tell(text: &str) -> Option<&'static str> {
+    let text = text.to_ascii_lowercase();
+    TELLS.iter().copied().find(|tell| text.contains(tell))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::profile::POSTFIX;
+
+    #[test]
+    fn test_ehlo_anomalies() {
+        let ehlo =
+            "250-mail.example.com\r\n250-STARTTLS\r\n250-AUTH PLAIN LOGIN\r\n250 8BITMIME\r\n";
+        let extensions: Vec<String> = ehlo
+            .lines()
+            .skip(1)
+            .map(|line| line[4..].to_string())
+            .collect();
+        let problems = ehlo_anomalies(&extensions);
+        assert_eq!(
+            problems,
+            [
+                "lacks PIPELINING, SIZE, ENHANCEDSTATUSCODES, which Postfix advertises",
+                "offers AUTH before STARTTLS, which Postfix doesn't by default",
+            ]
+        );
+
+        let postfix: Vec<String> = EXPECTED_EXTENSIONS
+            .iter()
+            .chain(&["DSN", "XFORWARD NAME"])
+            .map(|k| k.to_string())
+            .collect();
+        assert!(ehlo_anomalies(&postfix).is_empty());
+        assert_eq!(
+            ehlo_anomalies(&[postfix.clone(), vec!["BINARY".to_string()]].concat()),
+            ["advertises unusual BINARY"]
+        );
+
+        // What the server itself offers before STARTTLS passes its own audit
+        let ours = POSTFIX.ehlo("mail.example.com", "probe", [192, 0, 2, 1].into(), false);
+        let ours: Vec<String> = ours.lines().skip(1).map(|l| l[4..].to_string()).collect();
+        assert!(ehlo_anomalies(&ours).is_empty());
+    }
+}
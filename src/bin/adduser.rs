@@ -1,9 +1,11 @@
 //! Add User Tool - Creates users and generates client packages
 
 use anyhow::Result;
-use clap::Parser;
-use smtp_tunnel::config::{Config, UserEntry, UsersConfig};
+use clap::{Parser, Subcommand};
+use smtp_tunnel::admin::{self, Request, Response};
+use smtp_tunnel::config::{Config, DataChannel, UserEntry, UsersConfig};
 use smtp_tunnel::crypto::generate_secret;
+use smtp_tunnel::proto::smtp;
 use std::fs;
 use std::path::{Path, PathBuf};
 
@@ -12,9 +14,12 @@ use std::path::{Path, PathBuf};
 #[command(name = "smtp-tunnel-adduser")]
 #[command(about = "Add a new user and generate client package")]
 #[command(version)]
+#[command(args_conflicts_with_subcommands = true)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
     /// Username to add
-    username: String,
+    #[arg(required = true)]
+    username: Option<String>,
 
     /// Secret (auto-generated if not provided)
     #[arg(short, long)]
@@ -29,37 +34,90 @@ struct Args {
     no_logging: bool,
 
     /// Users file
-    #[arg(short, long, default_value = "/etc/smtp-tunnel/users.yaml")]
+    #[arg(
+        short,
+        long,
+        global = true,
+        default_value = "/etc/smtp-tunnel/users.yaml"
+    )]
     users_file: PathBuf,
 
+    /// Go through the running server's admin socket; the users file is
+    /// used only if the server isn't running
+    #[arg(long, global = true)]
+    admin_socket: Option<PathBuf>,
+
     /// Server config file
-    #[arg(short, long, default_value = "/etc/smtp-tunnel/config.yaml")]
+    #[arg(
+        short,
+        long,
+        global = true,
+        default_value = "/etc/smtp-tunnel/config.yaml"
+    )]
     config: PathBuf,
 
     /// Output directory for ZIP file
-    #[arg(short, long, default_value = ".")]
+    #[arg(short, long, global = true, default_value = ".")]
     output_dir: PathBuf,
 
     /// Do not generate client ZIP package
     #[arg(long)]
     no_package: bool,
+
+    /// Client binary to bundle in the package (e.g. a static musl build)
+    #[arg(long, global = true)]
+    client_bin: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn create_client_config(
-    server_host: &str,
-    server_port: u16,
-    username: &str,
-    secret: &str,
-) -> String {
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Rebuild client packages from the current server settings (e.g.
+    /// after moving the server), keeping each user's secret
+    Regenerate {
+        /// Users whose packages to rebuild
+        #[arg(required_unless_present = "all", conflicts_with = "all")]
+        usernames: Vec<String>,
+
+        /// Rebuild every user's package
+        #[arg(long)]
+        all: bool,
+    },
+}
+
+/// What a client needs to know about the server
+struct ServerInfo {
+    host: String,
+    port: u16,
+    binary_verb: String,
+    data_channel: DataChannel,
+}
+
+fn create_client_config(server: &ServerInfo, username: &str, secret: &str) -> String {
+    let mut mode = if server.binary_verb == smtp::BINARY_VERB {
+        String::new()
+    } else {
+        format!(
+            "\n  # Must match the server's\n  binary_verb: \"{}\"\n",
+            server.binary_verb
+        )
+    };
+    if server.data_channel == DataChannel::Bdat {
+        mode.push_str(
+            "\n  # Tunnel data goes in BDAT chunks, as on the server\n  data_channel: bdat\n",
+        );
+    }
     format!(
         r#"# SMTP Tunnel Client Configuration
 # Generated for user: {username}
 
 client:
   # Server connection
-  server_host: "{server_host}"
-  server_port: {server_port}
-
+  server_host: "{host}"
+  server_port: {port}
+{mode}
   # Authentication
   username: "{username}"
   secret: "{secret}"
@@ -70,7 +128,9 @@ client:
 
   # CA certificate for server verification
   ca_cert: "ca.crt"
-"#
+"#,
+        host = server.host,
+        port = server.port,
     )
 }
 
@@ -215,10 +275,10 @@ pause
 fn create_client_package(
     username: &str,
     secret: &str,
-    server_host: &str,
-    server_port: u16,
+    server: &ServerInfo,
     base_dir: &Path,
     output_dir: &Path,
+    client_bin: Option<&Path>,
 ) -> Result<PathBuf> {
     use std::io::Write;
 
@@ -237,7 +297,7 @@ fn create_client_package(
     }
 
     // Generate client config
-    let config_content = create_client_config(server_host, server_port, username, secret);
+    let config_content = create_client_config(server, username, secret);
     let config_path = pkg_dir.join("config.yaml");
     fs::write(&config_path, config_content)?;
 
@@ -250,18 +310,24 @@ fn create_client_package(
     let start_sh = create_start_sh(username);
     let start_sh_path = pkg_dir.join("start.sh");
     fs::write(&start_sh_path, start_sh)?;
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        let mut perms = fs::metadata(&start_sh_path)?.permissions();
-        perms.set_mode(0o755);
-        fs::set_permissions(&start_sh_path, perms)?;
-    }
+    smtp_tunnel::platform::set_executable(&start_sh_path)?;
 
     let start_bat = create_start_bat(username);
     let start_bat_path = pkg_dir.join("start.bat");
     fs::write(&start_bat_path, start_bat)?;
 
+    // Bundle client binary under the name the start scripts look for
+    if let Some(bin) = client_bin {
+        let name = if bin.extension().is_some_and(|e| e == "exe") {
+            "smtp-tunnel-client.exe"
+        } else {
+            "smtp-tunnel-client"
+        };
+        let bin_path = pkg_dir.join(name);
+        fs::copy(bin, &bin_path)?;
+        smtp_tunnel::platform::set_executable(&bin_path)?;
+    }
+
     // Create ZIP file
     let zip_filename = format!("{username}.zip");
     let zip_path = output_dir.join(&zip_filename);
@@ -272,13 +338,18 @@ fn create_client_package(
     let options = zip::write::FileOptions::default()
         .compression_method(zip::CompressionMethod::Deflated)
         .unix_permissions(0o644);
+    let exec_options = options.unix_permissions(0o755);
 
     for entry in walkdir::WalkDir::new(&pkg_dir) {
         let entry = entry?;
         let path = entry.path();
         if path.is_file() {
             let name = path.strip_prefix(&temp_dir)?;
-            zip.start_file(name.to_string_lossy(), options)?;
+            let is_exec = path.file_name().is_some_and(|n| {
+                n == "start.sh" || n == "smtp-tunnel-client" || n == "smtp-tunnel-client.exe"
+            });
+            let opts = if is_exec { exec_options } else { options };
+            zip.start_file(name.to_string_lossy(), opts)?;
             let content = fs::read(path)?;
             zip.write_all(&content)?;
         }
@@ -289,93 +360,194 @@ fn create_client_package(
     Ok(zip_path)
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+/// Add the user through the admin socket; false if the server isn't
+/// running there
+fn add_through_server(socket: &Path, username: &str, entry: UserEntry) -> Result<bool> {
+    let request = Request::AddUser {
+        username: username.to_string(),
+        entry,
+    };
+    match admin::call(socket, &request)? {
+        Some(Response::Error { message }) => {
+            eprintln!("Error: {message}");
+            std::process::exit(1);
+        }
+        Some(_) => {
+            println!("User '{username}' added by the server");
+            Ok(true)
+        }
+        None => {
+            println!("Server not running, editing the users file");
+            Ok(false)
+        }
+    }
+}
 
-    // Get base directory
-    let base_dir = std::env::current_dir()?;
+/// `path`, relative to `base_dir` unless absolute
+fn resolve(base_dir: &Path, path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        base_dir.join(path)
+    }
+}
 
-    // Load existing users
-    let users_file = if args.users_file.is_absolute() {
-        args.users_file.clone()
+/// Hostname and port clients connect to, from the server config
+fn server_info(config_file: &Path) -> Result<ServerInfo> {
+    if config_file.exists() {
+        let config = Config::from_file(config_file)?;
+        Ok(ServerInfo {
+            host: config.server.hostname,
+            port: config.server.port,
+            binary_verb: config.server.binary_verb,
+            data_channel: config.server.camouflage.data_channel,
+        })
     } else {
-        base_dir.join(&args.users_file)
-    };
+        println!(
+            "Warning: Config file {} not found, using defaults",
+            config_file.display()
+        );
+        Ok(ServerInfo {
+            host: "localhost".to_string(),
+            port: 587,
+            binary_verb: smtp::BINARY_VERB.to_string(),
+            data_channel: DataChannel::default(),
+        })
+    }
+}
+
+/// Current users, from the running server if it's listening on
+/// `admin_socket`, else from the users file
+fn load_users(users_file: &Path, admin_socket: Option<&Path>) -> Result<UsersConfig> {
+    if let Some(socket) = admin_socket {
+        match admin::call(socket, &Request::ListUsers)? {
+            Some(Response::Users { users }) => return Ok(users),
+            Some(Response::Error { message }) => anyhow::bail!("{}", message),
+            Some(_) => anyhow::bail!("Unexpected response from the server"),
+            None => println!("Server not running, reading the users file"),
+        }
+    }
+    if !users_file.exists() {
+        anyhow::bail!("Users file not found: {}", users_file.display());
+    }
+    UsersConfig::from_file(users_file)
+}
+
+/// Rebuild the packages of `usernames` (or of every user) with their
+/// stored secrets
+fn regenerate(args: &Args, base_dir: &Path, usernames: &[String], all: bool) -> Result<()> {
+    let users_file = resolve(base_dir, &args.users_file);
+    let users = load_users(&users_file, args.admin_socket.as_deref())?;
 
-    let mut users = if users_file.exists() {
-        UsersConfig::from_file(&users_file)?
+    let mut selected: Vec<&String> = if all {
+        users.users.keys().collect()
     } else {
-        UsersConfig::default()
+        usernames.iter().collect()
     };
-
-    // Check if user already exists
-    if users.users.contains_key(&args.username) {
-        eprintln!("Error: User '{}' already exists", args.username);
+    selected.sort();
+    if let Some(missing) = selected.iter().find(|u| users.get_user(u).is_none()) {
+        eprintln!("Error: User '{missing}' not found");
         std::process::exit(1);
     }
+    if selected.is_empty() {
+        println!("No users configured");
+        return Ok(());
+    }
+
+    let server = server_info(&resolve(base_dir, &args.config))?;
+    let output_dir = resolve(base_dir, &args.output_dir);
+    println!("Rebuilding packages for {}:{}", server.host, server.port);
+    for username in &selected {
+        let entry = &users.users[*username];
+        let zip_path = create_client_package(
+            username,
+            &entry.secret,
+            &server,
+            base_dir,
+            &output_dir,
+            args.client_bin.as_deref(),
+        )?;
+        println!("  {}", zip_path.display());
+    }
+    println!("{} client package(s) rebuilt", selected.len());
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    // Get base directory
+    let base_dir = std::env::current_dir()?;
+
+    if let Some(Command::Regenerate { usernames, all }) = &args.command {
+        return regenerate(&args, &base_dir, usernames, *all);
+    }
+    let username = args.username.clone().unwrap_or_default();
+
+    // Users file, edited when the server doesn't do it
+    let users_file = resolve(&base_dir, &args.users_file);
 
     // Generate secret if not provided
-    let secret = args.secret.unwrap_or_else(generate_secret);
+    let secret = args.secret.clone().unwrap_or_else(generate_secret);
 
     // Create user entry
     let entry = UserEntry {
         secret: secret.clone(),
-        whitelist: if args.whitelist.is_empty() {
-            vec![]
-        } else {
-            args.whitelist
-        },
+        whitelist: args.whitelist.clone(),
         logging: !args.no_logging,
     };
 
-    // Add user
-    users.users.insert(args.username.clone(), entry);
-
-    // Save users file
-    users.save_to_file(&users_file)?;
-    println!("User '{}' added to {}", args.username, users_file.display());
+    let added_by_server = match &args.admin_socket {
+        Some(socket) => add_through_server(socket, &username, entry.clone())?,
+        None => false,
+    };
 
-    // Generate client package
-    if !args.no_package {
-        // Load server config to get hostname and port
-        let config_file = if args.config.is_absolute() {
-            args.config.clone()
+    if !added_by_server {
+        let mut users = if users_file.exists() {
+            UsersConfig::from_file(&users_file)?
         } else {
-            base_dir.join(&args.config)
+            UsersConfig::default()
         };
 
-        let (server_host, server_port) = if config_file.exists() {
-            let config = Config::from_file(&config_file)?;
-            (config.server.hostname, config.server.port)
-        } else {
-            println!(
-                "Warning: Config file {} not found, using defaults",
-                config_file.display()
-            );
-            ("localhost".to_string(), 587)
-        };
+        // Check if user already exists
+        if users.users.contains_key(&username) {
+            eprintln!("Error: User '{}' already exists", username);
+            std::process::exit(1);
+        }
 
-        let output_dir = if args.output_dir.is_absolute() {
-            args.output_dir.clone()
-        } else {
-            std::env::current_dir()?.join(&args.output_dir)
-        };
+        // Add user
+        users.users.insert(username.clone(), entry);
+
+        // Save users file
+        users.save_to_file(&users_file)?;
+        println!("User '{}' added to {}", username, users_file.display());
+    }
+
+    // Generate client package
+    if !args.no_package {
+        // Load server config to get hostname and port
+        let server = server_info(&resolve(&base_dir, &args.config))?;
+        let output_dir = resolve(&base_dir, &args.output_dir);
 
         let zip_path = create_client_package(
-            &args.username,
+            &username,
             &secret,
-            &server_host,
-            server_port,
+            &server,
             &base_dir,
             &output_dir,
+            args.client_bin.as_deref(),
         )?;
 
         println!("Client package created: {}", zip_path.display());
         println!();
         println!("Send this ZIP file to the user. They need to:");
         println!("  1. Extract the ZIP");
-        println!("  2. Download smtp-tunnel-client binary for their platform");
-        println!("  3. Run ./start.sh (Linux/Mac) or start.bat (Windows)");
+        if args.client_bin.is_none() {
+            println!("  2. Download smtp-tunnel-client binary for their platform");
+            println!("  3. Run ./start.sh (Linux/Mac) or start.bat (Windows)");
+        } else {
+            println!("  2. Run ./start.sh (Linux/Mac) or start.bat (Windows)");
+        }
     }
 
     Ok(())
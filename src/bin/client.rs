@@ -1,11 +1,19 @@
 //! SMTP Tunnel Client Binary
 
 use anyhow::Result;
-use clap::Parser;
-use smtp_tunnel::config::{ClientConfig, Config};
+use clap::{Parser, Subcommand};
+use smtp_tunnel::client::{Client, ClientStatus, ServerLatency};
+use smtp_tunnel::config::{ClientConfig, Config, DnsMode, Route};
+use smtp_tunnel::leaktest;
+use smtp_tunnel::logging;
+#[cfg(not(feature = "minimal"))]
+use smtp_tunnel::metrics::FrameStats;
+use std::io::{IsTerminal, Write};
 use std::path::PathBuf;
-use tracing::{Level, info};
-use tracing_subscriber::FmtSubscriber;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Notify, watch};
+use tracing::{info, warn};
 
 /// SMTP Tunnel Client
 #[derive(Parser, Debug)]
@@ -25,6 +33,10 @@ struct Args {
     #[arg(long)]
     server_port: Option<u16>,
 
+    /// Connect with implicit TLS (SMTPS) instead of STARTTLS
+    #[arg(long)]
+    implicit_tls: bool,
+
     /// Local SOCKS port
     #[arg(short, long)]
     socks_port: Option<u16>,
@@ -34,62 +46,142 @@ struct Args {
     username: Option<String>,
 
     /// Secret
-    #[arg(short, long)]
+    // No short flag: `-s` is the SOCKS port
+    #[arg(long)]
     secret: Option<String>,
 
     /// CA certificate file
     #[arg(long)]
     ca_cert: Option<String>,
 
-    /// Enable debug logging
+    /// Log filter in RUST_LOG syntax (overrides the config file)
+    #[arg(long)]
+    log_level: Option<String>,
+
+    /// Enable debug logging (shorthand for --log-level debug)
     #[arg(short, long)]
     debug: bool,
+
+    /// Print concise status lines instead of log output
+    #[arg(long)]
+    pretty: bool,
+
+    /// Inject faults, e.g. `drop-frame=50,connect-delay=500,fail-dns`
+    #[cfg(feature = "testing")]
+    #[arg(long, value_name = "FAULTS")]
+    chaos: Option<smtp_tunnel::chaos::Faults>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check, through the running client, that traffic doesn't bypass the tunnel
+    Leaktest {
+        /// Plain-HTTP service answering with the caller's address
+        #[arg(long, default_value = leaktest::DEFAULT_IP_URL)]
+        ip_url: String,
+    },
+    /// Watch the running client's channels and throughput, refreshed each
+    /// second, through its control socket
+    #[cfg(not(feature = "minimal"))]
+    Status {
+        /// Control socket (default: `control.socket` from the config)
+        #[arg(long)]
+        socket: Option<PathBuf>,
+    },
+}
+
+impl Args {
+    /// Config-file log filter, muted to errors in pretty mode
+    fn config_log_level<'a>(&self, config: Option<&'a str>) -> Option<&'a str> {
+        if self.pretty { Some("error") } else { config }
+    }
+
+    /// Apply command line overrides to `config`
+    fn apply_overrides(&self, config: &mut ClientConfig) {
+        if let Some(server) = self.server.clone() {
+            config.server_host = server;
+            config.servers.clear();
+        }
+        if let Some(port) = self.server_port {
+            config.server_port = port;
+        }
+        if self.implicit_tls {
+            config.implicit_tls = true;
+        }
+        if let Some(port) = self.socks_port {
+            config.socks_port = port;
+        }
+        if let Some(username) = self.username.clone() {
+            config.username = username;
+        }
+        if let Some(secret) = self.secret.clone() {
+            config.secret = secret;
+        }
+        if let Some(ca_cert) = self.ca_cert.clone() {
+            config.ca_cert = Some(ca_cert);
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
-    // Initialize logging
-    let level = if args.debug {
-        Level::DEBUG
-    } else {
-        Level::INFO
-    };
-    let subscriber = FmtSubscriber::builder().with_max_level(level).finish();
-    tracing::subscriber::set_global_default(subscriber)?;
-
     // Load or create config
-    let mut config = if args.config.exists() {
+    let config_found = args.config.exists();
+    let mut config = if config_found {
         let cfg = Config::from_file(&args.config)?;
         cfg.client
     } else {
-        info!("No config file found, using defaults");
         ClientConfig::default()
     };
 
-    // Apply command line overrides
-    if let Some(server) = args.server {
-        config.server_host = server;
-    }
-    if let Some(port) = args.server_port {
-        config.server_port = port;
-    }
-    if let Some(port) = args.socks_port {
-        config.socks_port = port;
+    // Initialize logging
+    let filter = logging::resolve_filter(
+        args.log_level.as_deref(),
+        args.debug,
+        args.config_log_level(config.log_level.as_deref()),
+    );
+    let log = logging::init(&filter)?;
+    if !config_found {
+        info!("No config file found, using defaults");
     }
-    if let Some(username) = args.username {
-        config.username = username;
+
+    #[cfg(feature = "testing")]
+    if let Some(faults) = args.chaos {
+        warn!("Chaos: injecting faults {}", faults);
+        smtp_tunnel::chaos::install(faults);
     }
-    if let Some(secret) = args.secret {
-        config.secret = secret;
+
+    args.apply_overrides(&mut config);
+
+    if let Some(Command::Leaktest { ip_url }) = &args.command {
+        let report = leaktest::run(&config, ip_url).await?;
+        print!("{report}");
+        if report.leaks() > 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
     }
-    if let Some(ca_cert) = args.ca_cert {
-        config.ca_cert = Some(ca_cert);
+
+    #[cfg(not(feature = "minimal"))]
+    if let Some(Command::Status { socket }) = &args.command {
+        let socket = socket
+            .clone()
+            .or_else(|| config.control.as_ref().map(|c| PathBuf::from(&c.socket)))
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "No control socket: set control.socket in the config or use --socket"
+                )
+            })?;
+        return dashboard::run(&socket, Style::detect());
     }
 
     // Validate config
-    if config.server_host.is_empty() {
+    if config.servers().iter().any(|server| server.host.is_empty()) {
         eprintln!("Error: Server hostname is required");
         eprintln!("Use --server <hostname> or set in config file");
         std::process::exit(1);
@@ -108,12 +200,548 @@ async fn main() -> Result<()> {
     }
 
     info!("SMTP Tunnel Client {}", smtp_tunnel::VERSION);
-    info!("Server: {}:{}", config.server_host, config.server_port);
-    info!("SOCKS5: {}:{}", config.socks_host, config.socks_port);
+    smtp_tunnel::platform::Capabilities::detect().log_summary();
+    for (i, server) in config.servers().iter().enumerate() {
+        let label = if i == 0 { "Server" } else { "Fallback" };
+        match &config.port_rotation {
+            Some(rotation) => info!(
+                "{}: {} (rotating ports {}-{})",
+                label, server.host, rotation.min_port, rotation.max_port
+            ),
+            None => info!("{}: {}:{}", label, server.host, server.port),
+        }
+    }
+    for listener in config.socks_listeners() {
+        let mut policy = Vec::new();
+        if listener.route != Route::Rules {
+            policy.push(format!("route {}", listener.route));
+        }
+        if listener.auth.is_some() {
+            policy.push("password required".to_string());
+        }
+        if listener.port == 0 {
+            policy.push("any free port".to_string());
+        } else if !listener.fallback_ports.is_empty() {
+            let ports: Vec<String> = listener.fallback_ports.iter().map(u16::to_string).collect();
+            policy.push(format!("else {}", ports.join(", ")));
+        }
+        if policy.is_empty() {
+            info!("SOCKS5: {}:{}", listener.host, listener.port);
+        } else {
+            info!(
+                "SOCKS5: {}:{} ({})",
+                listener.host,
+                listener.port,
+                policy.join(", ")
+            );
+        }
+        if !listener.allowed_clients.is_empty() {
+            info!("SOCKS clients: {}", listener.allowed_clients.join(", "));
+        } else if listener.auth.is_none()
+            && listener
+                .bind_addr()
+                .is_ok_and(|addr| !addr.ip().is_loopback())
+        {
+            warn!(
+                "SOCKS proxy on {}:{} is open to anyone who can reach it; set allowed_clients",
+                listener.host, listener.port
+            );
+        }
+    }
     info!("Username: {}", config.username);
+    if !config.direct.is_empty() {
+        info!("Direct: {}", config.direct.join(", "));
+    }
+    if config.dns == DnsMode::Local {
+        info!("DNS: resolving hostnames locally, outside the tunnel");
+    }
+
+    let pretty = args.pretty;
+    let style = Style::detect();
+    if pretty {
+        println!(
+            "{} {} for {}",
+            style.bold("SMTP Tunnel Client"),
+            smtp_tunnel::VERSION,
+            config.username
+        );
+    }
 
     // Run client
-    smtp_tunnel::client::run_client(config).await?;
+    let control = config.control.clone();
+    let client = Arc::new(Client::new(config));
+    let stop = Arc::new(Notify::new());
+    #[cfg(unix)]
+    {
+        let running = Arc::new(Running {
+            client: Arc::clone(&client),
+            args,
+            log,
+            #[cfg(not(feature = "minimal"))]
+            stop: Arc::clone(&stop),
+        });
+        #[cfg(not(feature = "minimal"))]
+        if let Some(control) = control {
+            let listener = smtp_tunnel::control::bind(&control.socket)?;
+            info!("Control socket on {}", control.socket);
+            tokio::spawn(smtp_tunnel::control::serve(
+                listener,
+                Arc::clone(&client),
+                running.clone(),
+                control.read_only,
+            ));
+        }
+        #[cfg(feature = "minimal")]
+        if control.is_some() {
+            warn!("The control socket isn't available in minimal builds");
+        }
+        tokio::spawn(reload_on_sighup(running));
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (args, log);
+        if control.is_some() {
+            warn!("The control socket is only available on Unix");
+        }
+    }
+    if pretty {
+        tokio::spawn(print_status(client.status(), style));
+    }
+    tokio::spawn(report_latency(client.latency(), pretty.then_some(style)));
+    let traffic = client.traffic();
+    // Router builds don't count frames
+    #[cfg(not(feature = "minimal"))]
+    let frame_stats = client.frame_stats();
+    #[cfg(all(unix, not(feature = "minimal")))]
+    tokio::spawn(log_frames_on_sigusr1(frame_stats.clone()));
+
+    tokio::select! {
+        result = client.run() => return result,
+        _ = tokio::signal::ctrl_c() => {}
+        _ = stop.notified() => {}
+    }
+    let sent = format_bytes(traffic.sent());
+    let received = format_bytes(traffic.received());
+    if pretty {
+        println!();
+        println!(
+            "{} Stopped ({sent} sent, {received} received)",
+            style.yellow("■")
+        );
+    } else {
+        info!("Shutting down ({} sent, {} received)", sent, received);
+    }
+    #[cfg(not(feature = "minimal"))]
+    log_frames(&frame_stats);
+    client.shutdown().await;
 
     Ok(())
 }
+
+/// ANSI styling, disabled when stdout isn't a terminal or NO_COLOR is set
+#[derive(Clone, Copy)]
+struct Style {
+    enabled: bool,
+}
+
+impl Style {
+    fn detect() -> Self {
+        Self {
+            enabled: std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none(),
+        }
+    }
+
+    fn paint(&self, code: &str, text: &str) -> String {
+        if self.enabled {
+            format!("\x1b[{code}m{text}\x1b[0m")
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn bold(&self, text: &str) -> String {
+        self.paint("1", text)
+    }
+
+    fn green(&self, text: &str) -> String {
+        self.paint("32", text)
+    }
+
+    fn yellow(&self, text: &str) -> String {
+        self.paint("33", text)
+    }
+
+    fn red(&self, text: &str) -> String {
+        self.paint("31", text)
+    }
+}
+
+/// Print one status line per state change, with a reconnect countdown
+async fn print_status(mut status: watch::Receiver<ClientStatus>, style: Style) {
+    loop {
+        let current = status.borrow_and_update().clone();
+        match current {
+            ClientStatus::Idle => {}
+            ClientStatus::Connecting { server } => {
+                println!("{} Connecting to {server}...", style.yellow("●"));
+            }
+            ClientStatus::Connected {
+                server,
+                announcement,
+                handshake,
+            } => {
+                println!("{} Connected to {server}", style.green("✓"));
+                println!("  Handshake: {handshake}");
+                if let Some(announcement) = announcement {
+                    println!("{} {}", style.yellow("!"), style.bold(&announcement));
+                }
+            }
+            ClientStatus::Ready { socks_addrs } => {
+                let addrs: Vec<String> = socks_addrs.iter().map(ToString::to_string).collect();
+                println!(
+                    "{} Proxy ready at {}",
+                    style.green("✓"),
+                    style.bold(&addrs.join(", "))
+                );
+            }
+            ClientStatus::Standby { socks_addrs } => {
+                let addrs: Vec<String> = socks_addrs.iter().map(ToString::to_string).collect();
+                println!(
+                    "{} Proxy at {}, connecting when used",
+                    style.yellow("○"),
+                    style.bold(&addrs.join(", "))
+                );
+            }
+            ClientStatus::Reconnecting { delay, error } => {
+                println!("{} Connection lost: {error}", style.red("✗"));
+                let mut remaining = delay.as_secs();
+                let mut interrupted = false;
+                while remaining > 0 && !interrupted {
+                    print!("\r  Reconnecting in {remaining}s... ");
+                    let _ = std::io::stdout().flush();
+                    tokio::select! {
+                        _ = tokio::time::sleep(Duration::from_secs(1)) => remaining -= 1,
+                        changed = status.changed() => {
+                            if changed.is_err() {
+                                return;
+                            }
+                            interrupted = true;
+                        }
+                    }
+                }
+                print!("\r{:32}\r", "");
+                let _ = std::io::stdout().flush();
+                if interrupted {
+                    continue;
+                }
+            }
+        }
+        if status.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Print, or log, each round of server latency probes
+async fn report_latency(mut latency: watch::Receiver<Vec<ServerLatency>>, pretty: Option<Style>) {
+    while latency.changed().await.is_ok() {
+        let line = latency
+            .borrow_and_update()
+            .iter()
+            .map(|probe| match probe.rtt {
+                Some(rtt) => format!("{} {} ms", probe.server, rtt.as_millis()),
+                None => format!("{} unreachable", probe.server),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        match pretty {
+            Some(style) => println!("{} Latency: {line}", style.yellow("~")),
+            None => info!("Server latency: {}", line),
+        }
+    }
+}
+
+/// Human-readable byte count
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{value:.1} {}", UNITS[unit])
+    }
+}
+
+/// `status`: a live view of the running client, like iftop
+#[cfg(not(feature = "minimal"))]
+mod dashboard {
+    use super::{Style, format_bytes};
+    use anyhow::Result;
+    use smtp_tunnel::control::{self, Request, Response};
+    use std::collections::VecDeque;
+    use std::io::Write;
+    use std::time::{Duration, Instant};
+
+    /// Throughput samples the dashboard's sparkline covers, one per refresh
+    const SPARKLINE_SAMPLES: usize = 60;
+
+    /// Channels listed on the dashboard, busiest first
+    const DASHBOARD_ROWS: usize = 20;
+
+    /// Redraw the running client's status each second until interrupted
+    pub(super) fn run(socket: &std::path::Path, style: Style) -> Result<()> {
+        let mut throughput = VecDeque::with_capacity(SPARKLINE_SAMPLES);
+        let mut previous: Option<(Instant, u64)> = None;
+        loop {
+            let mut status = match query(socket, &Request::Status)? {
+                Response::Status(status) => status,
+                other => anyhow::bail!("Unexpected answer: {:?}", other),
+            };
+            let channels = match query(socket, &Request::Channels)? {
+                Response::Channels { channels } => channels,
+                other => anyhow::bail!("Unexpected answer: {:?}", other),
+            };
+
+            // The totals cover closed channels; add what the open ones carried
+            status.sent += channels.iter().map(|c| c.sent).sum::<u64>();
+            status.received += channels.iter().map(|c| c.received).sum::<u64>();
+            let now = Instant::now();
+            let total = status.sent + status.received;
+            let rate = previous.map_or(0, |(then, bytes): (Instant, u64)| {
+                let seconds = now.duration_since(then).as_secs_f64().max(0.001);
+                (total.saturating_sub(bytes) as f64 / seconds) as u64
+            });
+            previous = Some((now, total));
+            if throughput.len() == SPARKLINE_SAMPLES {
+                throughput.pop_front();
+            }
+            throughput.push_back(rate);
+
+            let mut screen = String::new();
+            draw(&mut screen, &status, &channels, &throughput, style)?;
+            if style.enabled {
+                // Home and clear, so the table redraws in place
+                print!("\x1b[H\x1b[2J{screen}");
+            } else {
+                println!("{screen}");
+            }
+            std::io::stdout().flush()?;
+            std::thread::sleep(Duration::from_secs(1));
+        }
+    }
+
+    /// Send `request` to the client, failing if it isn't running or refuses
+    fn query(socket: &std::path::Path, request: &Request) -> Result<Response> {
+        match control::call(socket, request)? {
+            None => anyhow::bail!("No client is listening on {}", socket.display()),
+            Some(Response::Error { message }) => anyhow::bail!("{}", message),
+            Some(response) => Ok(response),
+        }
+    }
+
+    /// Render one refresh of the dashboard
+    fn draw(
+        out: &mut String,
+        status: &control::Status,
+        channels: &[control::Channel],
+        throughput: &VecDeque<u64>,
+        style: Style,
+    ) -> std::fmt::Result {
+        use std::fmt::Write;
+
+        let state = match status.state.as_str() {
+            "ready" | "connected" => style.green(&status.state),
+            "reconnecting" => style.red(&status.state),
+            _ => style.yellow(&status.state),
+        };
+        writeln!(
+            out,
+            "{} {}  {}",
+            style.bold("smtp-tunnel"),
+            state,
+            status.server
+        )?;
+        if let Some(error) = &status.error {
+            writeln!(out, "  {}", style.red(error))?;
+        }
+        if let Some(announcement) = &status.announcement {
+            writeln!(out, "{} {}", style.yellow("!"), style.bold(announcement))?;
+        }
+        let rtt = status
+            .rtt_ms
+            .map_or_else(|| "-".to_string(), |ms| format!("{ms} ms"));
+        writeln!(
+            out,
+            "Server RTT {}   Reconnects {}   Channels {}",
+            rtt, status.reconnects, status.channels
+        )?;
+        writeln!(
+            out,
+            "Sent {}   Received {}",
+            format_bytes(status.sent),
+            format_bytes(status.received)
+        )?;
+        let current = throughput.back().copied().unwrap_or(0);
+        writeln!(
+            out,
+            "Throughput {} {}/s",
+            sparkline(throughput),
+            format_bytes(current)
+        )?;
+        writeln!(out)?;
+
+        writeln!(
+            out,
+            "{}",
+            style.bold(&format!(
+                "{:>7} {:>5}  {:<40} {:>6} {:>10} {:>10} {:>7}",
+                "SESSION", "ID", "DESTINATION", "AGE", "SENT", "RECEIVED", "RTT"
+            ))
+        )?;
+        let mut busiest: Vec<_> = channels.iter().collect();
+        busiest.sort_by_key(|c| std::cmp::Reverse(c.sent + c.received));
+        for channel in busiest.iter().take(DASHBOARD_ROWS) {
+            let destination: String = channel.destination.chars().take(40).collect();
+            let rtt = channel
+                .rtt_ms
+                .map_or_else(|| "-".to_string(), |ms| format!("{ms} ms"));
+            writeln!(
+                out,
+                "{:>7} {:>5}  {:<40} {:>6} {:>10} {:>10} {:>7}",
+                channel.session,
+                channel.id,
+                destination,
+                format_age(channel.age_ms),
+                format_bytes(channel.sent),
+                format_bytes(channel.received),
+                rtt
+            )?;
+        }
+        if busiest.len() > DASHBOARD_ROWS {
+            writeln!(out, "  ... and {} more", busiest.len() - DASHBOARD_ROWS)?;
+        }
+        Ok(())
+    }
+
+    /// One bar per sample, scaled to the largest
+    fn sparkline(samples: &VecDeque<u64>) -> String {
+        const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+        let max = samples.iter().copied().max().unwrap_or(0).max(1);
+        samples
+            .iter()
+            .map(|&sample| BARS[(sample as f64 / max as f64 * 7.0).round() as usize])
+            .collect()
+    }
+
+    /// Channel age in its largest whole unit
+    fn format_age(ms: u64) -> String {
+        let seconds = ms / 1000;
+        match seconds {
+            0..60 => format!("{seconds}s"),
+            60..3600 => format!("{}m", seconds / 60),
+            _ => format!("{}h", seconds / 3600),
+        }
+    }
+}
+
+/// Log the tunnel's frame counters
+#[cfg(not(feature = "minimal"))]
+fn log_frames(stats: &FrameStats) {
+    let frames = stats.snapshot();
+    info!("Frames sent: {}", frames.sent);
+    info!("Frames received: {}", frames.received);
+}
+
+/// Log the frame counters whenever SIGUSR1 is received
+#[cfg(all(unix, not(feature = "minimal")))]
+async fn log_frames_on_sigusr1(stats: Arc<FrameStats>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut usr1 = match signal(SignalKind::user_defined1()) {
+        Ok(usr1) => usr1,
+        Err(e) => {
+            warn!("SIGUSR1 frame statistics unavailable: {}", e);
+            return;
+        }
+    };
+    while usr1.recv().await.is_some() {
+        log_frames(&stats);
+    }
+}
+
+/// The running client, with what it was started from
+#[cfg(unix)]
+struct Running {
+    client: Arc<Client>,
+    args: Args,
+    log: logging::LogHandle,
+    /// Told to stop the client
+    #[cfg(not(feature = "minimal"))]
+    stop: Arc<Notify>,
+}
+
+#[cfg(unix)]
+impl Running {
+    /// Re-read the config file, applying the log filter and whatever the
+    /// running client can take
+    fn reload(&self) -> Result<()> {
+        let args = &self.args;
+        let mut config = if args.config.exists() {
+            Config::from_file(&args.config)
+                .map_err(|e| anyhow::anyhow!("Failed to reload {}: {}", args.config.display(), e))?
+                .client
+        } else {
+            ClientConfig::default()
+        };
+        args.apply_overrides(&mut config);
+        let filter = logging::resolve_filter(
+            args.log_level.as_deref(),
+            args.debug,
+            args.config_log_level(config.log_level.as_deref()),
+        );
+        match self.log.set_filter(&filter) {
+            Ok(()) => info!("Log filter set to '{}'", self.log.current()),
+            Err(e) => warn!("Invalid log filter '{}': {}", filter, e),
+        }
+        self.client.reload(&config)
+    }
+}
+
+#[cfg(all(unix, not(feature = "minimal")))]
+impl smtp_tunnel::control::Controller for Running {
+    fn reload(&self) -> Result<()> {
+        info!("Reloading configuration (control socket)");
+        Running::reload(self)
+    }
+
+    fn shutdown(&self) {
+        info!("Shutdown requested on the control socket");
+        self.stop.notify_one();
+    }
+}
+
+/// Re-read the config file whenever SIGHUP is received
+#[cfg(unix)]
+async fn reload_on_sighup(running: Arc<Running>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hup = match signal(SignalKind::hangup()) {
+        Ok(hup) => hup,
+        Err(e) => {
+            warn!("SIGHUP reload unavailable: {}", e);
+            return;
+        }
+    };
+
+    while hup.recv().await.is_some() {
+        info!("SIGHUP received, reloading configuration");
+        if let Err(e) = running.reload() {
+            warn!("Keeping the current configuration: {}", e);
+        }
+    }
+}
@@ -2,6 +2,7 @@
 
 use anyhow::Result;
 use clap::Parser;
+use smtp_tunnel::admin::{self, Request, Response};
 use smtp_tunnel::config::UsersConfig;
 use std::path::PathBuf;
 
@@ -18,14 +19,58 @@ struct Args {
     #[arg(short, long, default_value = "/etc/smtp-tunnel/users.yaml")]
     users_file: PathBuf,
 
+    /// Remove the user through the running server's admin socket; the
+    /// users file is edited only if the server isn't running
+    #[arg(long)]
+    admin_socket: Option<PathBuf>,
+
     /// Do not ask for confirmation
     #[arg(short, long)]
     force: bool,
 }
 
+/// Ask the user to confirm, unless forced
+fn confirm(args: &Args) -> Result<bool> {
+    if args.force {
+        return Ok(true);
+    }
+    print!("Delete user '{}'? [y/N]: ", args.username);
+    std::io::Write::flush(&mut std::io::stdout())?;
+    let mut response = String::new();
+    std::io::stdin().read_line(&mut response)?;
+    if response.trim().to_lowercase() != "y" {
+        println!("Cancelled");
+        return Ok(false);
+    }
+    Ok(true)
+}
+
 fn main() -> Result<()> {
     let args = Args::parse();
 
+    let mut confirmed = false;
+    if let Some(socket) = &args.admin_socket {
+        confirmed = confirm(&args)?;
+        if !confirmed {
+            return Ok(());
+        }
+        let request = Request::RemoveUser {
+            username: args.username.clone(),
+        };
+        match admin::call(socket, &request)? {
+            Some(Response::Error { message }) => {
+                eprintln!("Error: {message}");
+                std::process::exit(1);
+            }
+            Some(_) => {
+                println!("User '{}' removed by the server", args.username);
+                remind_zip(&args.username);
+                return Ok(());
+            }
+            None => println!("Server not running, editing the users file"),
+        }
+    }
+
     // Get base directory
     let base_dir = std::env::current_dir()?;
 
@@ -50,15 +95,8 @@ fn main() -> Result<()> {
     }
 
     // Confirm deletion
-    if !args.force {
-        print!("Delete user '{}'? [y/N]: ", args.username);
-        std::io::Write::flush(&mut std::io::stdout())?;
-        let mut response = String::new();
-        std::io::stdin().read_line(&mut response)?;
-        if response.trim().to_lowercase() != "y" {
-            println!("Cancelled");
-            return Ok(());
-        }
+    if !confirmed && !confirm(&args)? {
+        return Ok(());
     }
 
     // Remove user
@@ -67,12 +105,15 @@ fn main() -> Result<()> {
     // Save users file
     users.save_to_file(&users_file)?;
     println!("User '{}' removed", args.username);
+    remind_zip(&args.username);
+
+    Ok(())
+}
 
-    // Remind about ZIP files
-    let zip_file = format!("{}.zip", args.username);
+/// Remind about the user's client package, if it's here
+fn remind_zip(username: &str) {
+    let zip_file = format!("{username}.zip");
     if std::path::Path::new(&zip_file).exists() {
         println!("Note: Client package '{zip_file}' still exists - delete manually if needed");
     }
-
-    Ok(())
 }
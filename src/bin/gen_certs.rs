@@ -11,9 +11,10 @@ use std::time::Duration;
 #[command(name = "smtp-tunnel-gen-certs")]
 #[command(about = "Generate TLS certificates")]
 #[command(version)]
+#[command(disable_help_flag = true)]
 struct Args {
     /// Hostname for the certificate
-    #[arg(short, long, default_value = "mail.example.com")]
+    #[arg(short = 'h', long, default_value = "mail.example.com")]
     hostname: String,
 
     /// Output directory
@@ -23,6 +24,10 @@ struct Args {
     /// Validity in days
     #[arg(short, long, default_value = "365")]
     days: u64,
+
+    /// Print help (`-h` is the hostname)
+    #[arg(long, action = clap::ArgAction::Help)]
+    help: Option<bool>,
 }
 
 fn main() -> Result<()> {
@@ -34,11 +39,11 @@ fn main() -> Result<()> {
     // Create output directory
     std::fs::create_dir_all(&args.output)?;
 
-    // Use default algorithm (PKCS_RSA_SHA256)
-    let alg = &rcgen::PKCS_RSA_SHA256;
+    // ECDSA P-256 (rcgen cannot generate RSA keys)
+    let alg = &rcgen::PKCS_ECDSA_P256_SHA256;
 
     // Generate CA key pair (used for signing)
-    let _ca_key = KeyPair::generate(alg)?;
+    let ca_key = KeyPair::generate(alg)?;
 
     // Generate CA certificate
     let mut ca_params = CertificateParams::new(vec!["SMTP Tunnel CA".to_string()]);
@@ -54,6 +59,8 @@ fn main() -> Result<()> {
         rcgen::KeyUsagePurpose::KeyCertSign,
         rcgen::KeyUsagePurpose::CrlSign,
     ];
+    ca_params.alg = alg;
+    ca_params.key_pair = Some(ca_key);
 
     let ca_cert = Certificate::from_params(ca_params)?;
 
@@ -84,6 +91,8 @@ fn main() -> Result<()> {
         rcgen::KeyUsagePurpose::KeyEncipherment,
     ];
     server_params.extended_key_usages = vec![rcgen::ExtendedKeyUsagePurpose::ServerAuth];
+    server_params.alg = alg;
+    server_params.key_pair = Some(server_key);
 
     let server_cert = Certificate::from_params(server_params)?;
 
@@ -95,7 +104,7 @@ fn main() -> Result<()> {
     // Serialize PEM
     let ca_pem = ca_cert.serialize_pem_with_signer(&ca_cert)?;
     let server_pem = server_cert.serialize_pem_with_signer(&ca_cert)?;
-    let server_key_pem = server_key.serialize_pem();
+    let server_key_pem = server_cert.serialize_private_key_pem();
 
     std::fs::write(&ca_cert_path, ca_pem)?;
     std::fs::write(&server_cert_path, server_pem)?;
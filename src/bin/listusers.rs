@@ -2,6 +2,7 @@
 
 use anyhow::Result;
 use clap::Parser;
+use smtp_tunnel::admin::{self, Request, Response};
 use smtp_tunnel::config::UsersConfig;
 use std::path::PathBuf;
 
@@ -15,14 +16,37 @@ struct Args {
     #[arg(short, long, default_value = "/etc/smtp-tunnel/users.yaml")]
     users_file: PathBuf,
 
+    /// Ask the running server through its admin socket; the users file is
+    /// read only if the server isn't running
+    #[arg(long)]
+    admin_socket: Option<PathBuf>,
+
     /// Show detailed information
     #[arg(short, long)]
     verbose: bool,
 }
 
+/// Users known to the running server, if it's listening on `socket`
+fn users_from_server(socket: &std::path::Path) -> Result<Option<UsersConfig>> {
+    match admin::call(socket, &Request::ListUsers)? {
+        Some(Response::Users { users }) => Ok(Some(users)),
+        Some(Response::Error { message }) => anyhow::bail!("{}", message),
+        Some(_) => anyhow::bail!("Unexpected response from the server"),
+        None => {
+            println!("Server not running, reading the users file");
+            Ok(None)
+        }
+    }
+}
+
 fn main() -> Result<()> {
     let args = Args::parse();
 
+    let from_server = match &args.admin_socket {
+        Some(socket) => users_from_server(socket)?,
+        None => None,
+    };
+
     // Get base directory
     let base_dir = std::env::current_dir()?;
 
@@ -33,7 +57,9 @@ fn main() -> Result<()> {
         base_dir.join(&args.users_file)
     };
 
-    let users = if users_file.exists() {
+    let users = if let Some(users) = from_server {
+        users
+    } else if users_file.exists() {
         UsersConfig::from_file(&users_file)?
     } else {
         println!("No users configured");
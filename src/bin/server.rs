@@ -1,11 +1,20 @@
 //! SMTP Tunnel Server Binary
 
 use anyhow::Result;
-use clap::Parser;
-use smtp_tunnel::config::{Config, UsersConfig};
+use clap::{Parser, Subcommand};
+#[cfg(not(feature = "minimal"))]
+use smtp_tunnel::admin;
+use smtp_tunnel::audit;
+use smtp_tunnel::config::{Config, UsersBackend, UsersConfig};
+use smtp_tunnel::logging;
+use smtp_tunnel::records;
+use smtp_tunnel::server::Server;
+use smtp_tunnel::tls::CertInfo;
+use smtp_tunnel::users;
+use std::net::IpAddr;
 use std::path::PathBuf;
-use tracing::{Level, info};
-use tracing_subscriber::FmtSubscriber;
+use std::sync::Arc;
+use tracing::{info, warn};
 
 /// SMTP Tunnel Server
 #[derive(Parser, Debug)]
@@ -21,38 +30,139 @@ struct Args {
     #[arg(short, long)]
     users: Option<PathBuf>,
 
-    /// Enable debug logging
+    /// Log filter in RUST_LOG syntax (overrides the config file)
+    #[arg(long)]
+    log_level: Option<String>,
+
+    /// Enable debug logging (shorthand for --log-level debug)
     #[arg(short, long)]
     debug: bool,
+
+    /// Inject faults, e.g. `drop-frame=50,connect-delay=500,fail-dns`
+    #[cfg(feature = "testing")]
+    #[arg(long, value_name = "FAULTS")]
+    chaos: Option<smtp_tunnel::chaos::Faults>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the DNS records to publish for the configured hostname
+    DnsRecords {
+        /// Public address of the server (repeatable)
+        #[arg(long)]
+        ip: Vec<IpAddr>,
+
+        /// Mail domain (default: the hostname minus its first label)
+        #[arg(long)]
+        domain: Option<String>,
+    },
+    /// Probe the running server as a scanner would and report anomalies
+    Audit {
+        /// Endpoint to probe (default: the hostname and port from the config)
+        #[arg(long)]
+        target: Option<String>,
+
+        /// Connections to time replies over
+        #[arg(long, default_value_t = 10)]
+        samples: usize,
+    },
+    /// Ask the running server for its metrics over the admin socket
+    #[cfg(not(feature = "minimal"))]
+    Metrics,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
-    // Initialize logging
-    let level = if args.debug {
-        Level::DEBUG
-    } else {
-        Level::INFO
-    };
-    let subscriber = FmtSubscriber::builder().with_max_level(level).finish();
-    tracing::subscriber::set_global_default(subscriber)?;
-
     // Load config
-    let config = if args.config.exists() {
+    let config_found = args.config.exists();
+    let config = if config_found {
         Config::from_file(&args.config)?
     } else {
-        info!("No config file found, using defaults");
         Config::default()
     };
 
+    if let Some(Command::DnsRecords { ip, domain }) = &args.command {
+        let cert = match std::fs::read(&config.server.cert_file) {
+            Ok(pem) => Some(CertInfo::from_pem(&pem)?),
+            Err(e) => {
+                eprintln!("Warning: can't read {}: {}", config.server.cert_file, e);
+                None
+            }
+        };
+        print!(
+            "{}",
+            records::zone(&config.server, domain.as_deref(), ip, cert.as_ref())
+        );
+        return Ok(());
+    }
+
+    if let Some(Command::Audit { target, samples }) = &args.command {
+        let target = target
+            .clone()
+            .unwrap_or_else(|| format!("{}:{}", config.server.hostname, config.server.port));
+        let report = audit::run(&config.server, &target, *samples).await?;
+        print!("{report}");
+        if report.anomalies() > 0 {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    #[cfg(not(feature = "minimal"))]
+    if let Some(Command::Metrics) = &args.command {
+        let Some(admin) = &config.server.admin else {
+            anyhow::bail!("No admin socket configured; set admin.socket");
+        };
+        let socket = std::path::Path::new(&admin.socket);
+        match admin::call(socket, &admin::Request::Metrics)? {
+            Some(admin::Response::Metrics { summary, frames }) => {
+                println!("{summary}");
+                println!("frames sent: {}", frames.sent);
+                println!("frames received: {}", frames.received);
+            }
+            Some(admin::Response::Error { message }) => anyhow::bail!("{}", message),
+            Some(_) => anyhow::bail!("Unexpected response from the server"),
+            None => anyhow::bail!("Server not running (nothing on {})", socket.display()),
+        }
+        return Ok(());
+    }
+
+    // Initialize logging
+    let filter = logging::resolve_filter(
+        args.log_level.as_deref(),
+        args.debug,
+        config.server.log_level.as_deref(),
+    );
+    let log = logging::init(&filter)?;
+    if !config_found {
+        info!("No config file found, using defaults");
+    }
+
+    #[cfg(feature = "testing")]
+    if let Some(faults) = args.chaos {
+        warn!("Chaos: injecting faults {}", faults);
+        smtp_tunnel::chaos::install(faults);
+    }
+
     // Load users
     let users_file = args
         .users
+        .clone()
         .unwrap_or_else(|| PathBuf::from(&config.server.users_file));
 
-    let users = if users_file.exists() {
+    let mut server_config = config.server;
+    server_config.users_file = users_file.to_string_lossy().to_string();
+
+    let users = if !matches!(server_config.users_backend, UsersBackend::File) {
+        let store = users::open(&server_config)?;
+        info!("Loading users from {}", store.describe());
+        store.load()?
+    } else if users_file.exists() {
         UsersConfig::from_file(&users_file)?
     } else {
         eprintln!("Error: Users file not found: {}", users_file.display());
@@ -65,32 +175,94 @@ async fn main() -> Result<()> {
         std::process::exit(1);
     };
 
-    if users.users.is_empty() {
+    if users.users.is_empty() && matches!(server_config.users_backend, UsersBackend::File) {
         eprintln!("Error: No users configured in {}", users_file.display());
         std::process::exit(1);
     }
 
     // Check TLS certificates
-    if !std::path::Path::new(&config.server.cert_file).exists() {
+    if !std::path::Path::new(&server_config.cert_file).exists() {
         eprintln!(
             "Error: Certificate file not found: {}",
-            config.server.cert_file
+            server_config.cert_file
         );
         eprintln!("Generate certificates with: smtp-tunnel-gen-certs");
         std::process::exit(1);
     }
 
-    if !std::path::Path::new(&config.server.key_file).exists() {
-        eprintln!("Error: Key file not found: {}", config.server.key_file);
+    if !std::path::Path::new(&server_config.key_file).exists() {
+        eprintln!("Error: Key file not found: {}", server_config.key_file);
         eprintln!("Generate certificates with: smtp-tunnel-gen-certs");
         std::process::exit(1);
     }
 
     info!("SMTP Tunnel Server {}", smtp_tunnel::VERSION);
+    smtp_tunnel::platform::Capabilities::detect().log_summary();
     info!("Loaded {} users", users.users.len());
 
     // Run server
-    smtp_tunnel::server::run_server(config.server, users).await?;
+    let server = Arc::new(Server::new(server_config, users).await?);
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(Arc::clone(&server), args, log));
+    #[cfg(not(unix))]
+    let _ = (args, log);
+
+    tokio::select! {
+        result = server.run() => result?,
+        _ = tokio::signal::ctrl_c() => {
+            info!("Shutting down");
+            server.shutdown().await;
+        }
+    }
 
     Ok(())
 }
+
+/// Reload users and the log filter whenever SIGHUP is received
+#[cfg(unix)]
+async fn reload_on_sighup(server: Arc<Server>, args: Args, log: logging::LogHandle) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hup = match signal(SignalKind::hangup()) {
+        Ok(hup) => hup,
+        Err(e) => {
+            warn!("SIGHUP reload unavailable: {}", e);
+            return;
+        }
+    };
+
+    while hup.recv().await.is_some() {
+        info!("SIGHUP received, reloading configuration");
+
+        if let Err(e) = server.reload_users().await {
+            warn!("Failed to reload users: {}", e);
+        }
+
+        let config = if args.config.exists() {
+            match Config::from_file(&args.config) {
+                Ok(config) => Some(config.server),
+                Err(e) => {
+                    warn!("Failed to reload {}: {}", args.config.display(), e);
+                    continue;
+                }
+            }
+        } else {
+            None
+        };
+        let announcement = config.as_ref().and_then(|c| c.announcement.clone());
+        if let Err(e) = server.set_announcement(announcement) {
+            warn!("Keeping the current announcement: {}", e);
+        }
+        let config_level = config.and_then(|c| c.log_level);
+        let filter = logging::resolve_filter(
+            args.log_level.as_deref(),
+            args.debug,
+            config_level.as_deref(),
+        );
+        match log.set_filter(&filter) {
+            Ok(()) => info!("Log filter set to '{}'", log.current()),
+            Err(e) => warn!("Invalid log filter '{}': {}", filter, e),
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Diagnostic Tools - Utilities for debugging tunnel deployments
+
+use anyhow::{Context, Result};
+use clap::{Parser, Subcommand};
+use smtp_tunnel::pcap::{self, ScrubOptions};
+use std::path::PathBuf;
+
+/// SMTP Tunnel diagnostic tools
+#[derive(Parser, Debug)]
+#[command(name = "smtp-tunnel-tools")]
+#[command(about = "SMTP Tunnel diagnostic tools")]
+#[command(version)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Strip user data from a pcap of tunnel traffic, keeping SMTP lines
+    /// and TLS and frame headers, so it can be shared
+    ScrubPcap {
+        /// Capture to scrub (pcap, not pcapng)
+        input: PathBuf,
+
+        /// Where to write the scrubbed capture
+        output: PathBuf,
+
+        /// The tunnel server's port; traffic on other ports is zeroed
+        #[arg(short, long = "port", default_values_t = [587, 465])]
+        ports: Vec<u16>,
+
+        /// The client's binary_verb, if it isn't BINARY
+        #[arg(long, default_value = smtp_tunnel::proto::smtp::BINARY_VERB)]
+        binary_verb: String,
+
+        /// The session agreed on frame checksums
+        #[arg(long)]
+        checksum: bool,
+    },
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    match args.command {
+        Command::ScrubPcap {
+            input,
+            output,
+            ports,
+            binary_verb,
+            checksum,
+        } => {
+            let mut capture = std::fs::read(&input)
+                .with_context(|| format!("Failed to read {}", input.display()))?;
+            let options = ScrubOptions {
+                ports,
+                binary_verb,
+                checksum,
+            };
+            let stats = pcap::scrub(&mut capture, &options)
+                .with_context(|| format!("Failed to scrub {}", input.display()))?;
+            std::fs::write(&output, &capture)
+                .with_context(|| format!("Failed to write {}", output.display()))?;
+
+            println!(
+                "Scrubbed {} packets, {} tunnel connections",
+                stats.packets, stats.streams
+            );
+            if stats.lost > 0 {
+                println!(
+                    "{} connections lost their place (a gap or truncated packet) and were zeroed from there",
+                    stats.lost
+                );
+            }
+            println!("Written to {}", output.display());
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,59 @@
+//! Imitating a real mail server
+//!
+//! Real MTAs take a while to answer: reverse DNS before the greeting,
+//! policy lookups before accepting AUTH. A server replying within
+//! microseconds stands out to anyone timing the handshake, so each response
+//! can be held back for a random time from a configured range.
+
+use crate::config::ResponseDelays;
+use crate::proto::Command;
+use rand::Rng;
+use std::time::Duration;
+
+/// Wait before sending the greeting
+pub async fn before_greeting(delays: &ResponseDelays) {
+    wait(delays.greeting).await;
+}
+
+/// Wait before answering `command`
+pub async fn before_reply(delays: &ResponseDelays, command: Command) {
+    let range = match command {
+        Command::Ehlo | Command::Helo => delays.ehlo,
+        Command::StartTls => delays.starttls,
+        Command::Auth => delays.auth,
+        _ => delays.other,
+    };
+    wait(range).await;
+}
+
+async fn wait(range: [u64; 2]) {
+    let delay = pick(range);
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Random delay within `[min, max]` milliseconds
+fn pick([min, max]: [u64; 2]) -> Duration {
+    let millis = if max > min {
+        rand::thread_rng().gen_range(min..=max)
+    } else {
+        min
+    };
+    Duration::from_millis(millis)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick_within_range() {
+        assert_eq!(pick([0, 0]), Duration::ZERO);
+        assert_eq!(pick([30, 10]), Duration::from_millis(30));
+        for _ in 0..100 {
+            let delay = pick([50, 300]);
+            assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(300));
+        }
+    }
+}
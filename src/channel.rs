@@ -0,0 +1,196 @@
+//! Channel registry shared by the client and server frame loops
+//!
+//! Both ends multiplex channels over one tunnel session. The registry owns
+//! each channel's state keyed by channel ID; entries are opened, looked up
+//! and closed explicitly and never cloned out, so no task can end up holding
+//! a handle to a channel that has since been replaced.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Channel ID reserved for session-level frames (keepalives, ACKs)
+pub const CONTROL_CHANNEL: u16 = 0;
+
+/// Open channels of one tunnel session.
+///
+/// Cloning the registry clones the handle, not the channels. The lock is
+/// never held across an await; callers copy out what they need in
+/// [`with`](Self::with).
+#[derive(Debug)]
+pub struct ChannelRegistry<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+#[derive(Debug)]
+struct Inner<T> {
+    next_id: u16,
+    channels: HashMap<u16, T>,
+}
+
+impl<T> ChannelRegistry<T> {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Inner {
+                next_id: 1,
+                channels: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Register a channel under the next free ID (None if all are in use)
+    pub fn allocate(&self, state: T) -> Option<u16> {
+        let mut inner = self.inner.lock().unwrap();
+        for _ in 0..u16::MAX {
+            let id = inner.next_id;
+            inner.next_id = inner.next_id.checked_add(1).unwrap_or(1);
+            if let Entry::Vacant(entry) = inner.channels.entry(id) {
+                entry.insert(state);
+                return Some(id);
+            }
+        }
+        None
+    }
+
+    /// Register a channel under an ID chosen by the peer.
+    ///
+    /// Fails, handing the state back, if the ID is reserved or already open.
+    pub fn open(&self, id: u16, state: T) -> Result<(), T> {
+        if id == CONTROL_CHANNEL {
+            return Err(state);
+        }
+        match self.inner.lock().unwrap().channels.entry(id) {
+            Entry::Vacant(entry) => {
+                entry.insert(state);
+                Ok(())
+            }
+            Entry::Occupied(_) => Err(state),
+        }
+    }
+
+    /// Run `f` on an open channel's state
+    pub fn with<R>(&self, id: u16, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        self.inner.lock().unwrap().channels.get_mut(&id).map(f)
+    }
+
+    /// Remove a channel, returning its state
+    pub fn close(&self, id: u16) -> Option<T> {
+        self.inner.lock().unwrap().channels.remove(&id)
+    }
+
+    /// Number of open channels
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().channels.len()
+    }
+
+    /// Whether no channels are open
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Map every open channel's ID and state, in no particular order
+    pub fn collect<R>(&self, mut f: impl FnMut(u16, &T) -> R) -> Vec<R> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .channels
+            .iter()
+            .map(|(id, state)| f(*id, state))
+            .collect()
+    }
+
+    /// Remove every channel, e.g. when the session ends
+    pub fn drain(&self) -> Vec<T> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.channels.drain().map(|(_, state)| state).collect()
+    }
+}
+
+impl<T> Clone for ChannelRegistry<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T> Default for ChannelRegistry<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// When a connection last carried data, for idle timeouts
+#[derive(Debug, Clone)]
+#[cfg_attr(not(any(feature = "client", feature = "server")), allow(dead_code))]
+pub(crate) struct Activity {
+    start: tokio::time::Instant,
+    /// Milliseconds from `start` to the last transfer
+    last: Arc<AtomicU64>,
+}
+
+#[cfg_attr(not(any(feature = "client", feature = "server")), allow(dead_code))]
+impl Activity {
+    pub(crate) fn new() -> Self {
+        Self {
+            start: tokio::time::Instant::now(),
+            last: Arc::default(),
+        }
+    }
+
+    /// Note a transfer now
+    pub(crate) fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Resolves once there's been no transfer for `timeout`
+    pub(crate) async fn idle(&self, timeout: Duration) {
+        loop {
+            let last = self.start + Duration::from_millis(self.last.load(Ordering::Relaxed));
+            let deadline = last + timeout;
+            if tokio::time::Instant::now() >= deadline {
+                return;
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_skips_used_and_reserved_ids() {
+        let registry = ChannelRegistry::new();
+        assert_eq!(registry.allocate("a"), Some(1));
+        assert_eq!(registry.allocate("b"), Some(2));
+
+        registry.inner.lock().unwrap().next_id = u16::MAX;
+        assert_eq!(registry.allocate("c"), Some(u16::MAX));
+        // Wraps past the control channel and the IDs still in use
+        assert_eq!(registry.allocate("d"), Some(3));
+        assert_eq!(registry.len(), 4);
+    }
+
+    #[test]
+    fn test_open_lookup_close() {
+        let registry = ChannelRegistry::new();
+        assert_eq!(registry.open(CONTROL_CHANNEL, 1), Err(1));
+        assert_eq!(registry.open(7, 1), Ok(()));
+        assert_eq!(registry.open(7, 2), Err(2));
+
+        assert_eq!(registry.with(7, |n| std::mem::replace(n, 3)), Some(1));
+        assert_eq!(registry.with(8, |n| *n), None);
+        assert_eq!(registry.collect(|id, n| (id, *n)), [(7, 3)]);
+
+        // Clones share the same channels
+        let other = registry.clone();
+        assert_eq!(other.close(7), Some(3));
+        assert!(registry.is_empty());
+        assert_eq!(registry.close(7), None);
+    }
+}
@@ -0,0 +1,167 @@
+//! Fault injection for resilience testing
+//!
+//! With the `testing` feature the tunnel has hooks where faults can be
+//! injected: every Nth session frame written is dropped together with the
+//! connection carrying it, connects to the server and to destinations are
+//! delayed, and DNS lookups fail. The faults are deterministic, so
+//! reconnection, resume and flow control can be exercised the same way on
+//! every run. [`install`] sets them for the whole process, which is what an
+//! integration test (a process of its own) or the binaries' `--chaos` flag
+//! want; unit tests sharing a process with others shouldn't use it.
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Faults in effect
+static FAULTS: RwLock<Faults> = RwLock::new(Faults {
+    drop_every: 0,
+    connect_delay: Duration::ZERO,
+    fail_dns: false,
+});
+
+/// Session frames written since the faults were installed
+static FRAMES: AtomicU64 = AtomicU64::new(0);
+
+/// Faults to inject, from `drop-frame=N,connect-delay=MS,fail-dns`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Faults {
+    /// Drop every Nth session frame written, and its connection; 0 = never
+    pub drop_every: u64,
+    /// Added before each connect to the server or to a destination
+    pub connect_delay: Duration,
+    /// Fail every DNS lookup
+    pub fail_dns: bool,
+}
+
+impl Faults {
+    /// Whether the `n`th frame written (from 1) is dropped
+    pub fn drops(&self, n: u64) -> bool {
+        self.drop_every > 0 && n.is_multiple_of(self.drop_every)
+    }
+
+    /// Fail a lookup of `host` if DNS is failing
+    pub fn lookup(&self, host: &str) -> io::Result<()> {
+        if self.fail_dns {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("Chaos: lookup of {host} failed"),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Faults {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> anyhow::Result<Self> {
+        let mut faults = Faults::default();
+        for fault in spec.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+            let (name, value) = match fault.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (fault, None),
+            };
+            let number = || -> anyhow::Result<u64> {
+                value
+                    .and_then(|v| v.parse().ok())
+                    .ok_or_else(|| anyhow::anyhow!("Chaos fault {name} needs a number"))
+            };
+            match name {
+                "drop-frame" => faults.drop_every = number()?,
+                "connect-delay" => faults.connect_delay = Duration::from_millis(number()?),
+                "fail-dns" if value.is_none() => faults.fail_dns = true,
+                _ => anyhow::bail!("Unknown chaos fault {fault}"),
+            }
+        }
+        Ok(faults)
+    }
+}
+
+/// The faults in the form they're parsed from
+impl fmt::Display for Faults {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut faults = Vec::new();
+        if self.drop_every > 0 {
+            faults.push(format!("drop-frame={}", self.drop_every));
+        }
+        if !self.connect_delay.is_zero() {
+            faults.push(format!("connect-delay={}", self.connect_delay.as_millis()));
+        }
+        if self.fail_dns {
+            faults.push("fail-dns".to_string());
+        }
+        if faults.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", faults.join(","))
+        }
+    }
+}
+
+/// Inject `faults` from now on, replacing any before them
+pub fn install(faults: Faults) {
+    *FAULTS.write().unwrap() = faults;
+    FRAMES.store(0, Ordering::Relaxed);
+}
+
+fn current() -> Faults {
+    *FAULTS.read().unwrap()
+}
+
+/// Count a session frame about to be written; true if it's to be dropped
+pub fn drop_frame() -> bool {
+    let faults = current();
+    faults.drop_every > 0 && faults.drops(FRAMES.fetch_add(1, Ordering::Relaxed) + 1)
+}
+
+/// Wait out the connect delay
+pub async fn delay_connect() {
+    let delay = current().connect_delay;
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Fail a lookup of `host` if DNS is failing
+pub fn lookup(host: &str) -> io::Result<()> {
+    current().lookup(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_faults() {
+        let faults: Faults = "drop-frame=3, connect-delay=250,fail-dns".parse().unwrap();
+        assert_eq!(
+            faults,
+            Faults {
+                drop_every: 3,
+                connect_delay: Duration::from_millis(250),
+                fail_dns: true,
+            }
+        );
+        assert_eq!(
+            faults.to_string(),
+            "drop-frame=3,connect-delay=250,fail-dns"
+        );
+        assert_eq!(Faults::default().to_string(), "none");
+        let dropped: Vec<u64> = (1..=9).filter(|&n| faults.drops(n)).collect();
+        assert_eq!(dropped, [3, 6, 9]);
+        assert!(!Faults::default().drops(3));
+        assert_eq!(
+            faults.lookup("example.com").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert!(Faults::default().lookup("example.com").is_ok());
+
+        assert!("drop-frame".parse::<Faults>().is_err());
+        assert!("fail-dns=1".parse::<Faults>().is_err());
+        assert!("flood".parse::<Faults>().is_err());
+    }
+}
@@ -2,37 +2,624 @@
 //!
 //! Connects to SMTP tunnel server and provides SOCKS5 proxy interface.
 
-use crate::config::ClientConfig;
-use crate::crypto::AuthToken;
-use bytes::{Buf, BytesMut};
-use std::collections::HashMap;
+use crate::acl::DestinationAcl;
+use crate::channel::ChannelRegistry;
+use crate::config::{
+    ClientConfig, DaneConfig, DataChannel, DnsMode, LatencyProbeConfig, ListenerConfig, Route,
+    ServerEntry,
+};
+use crate::crypto::{AuthToken, KeyExchange, Role, SessionKeys};
+use crate::dnsstub::DnsStub;
+use crate::link::{Attachment, Batching, Heartbeat, Link, LinkOptions, SessionId};
+use crate::metrics::FrameStats;
+use crate::proto::chunking::Chunked;
+use crate::proto::compress::Compression;
+use crate::proto::flow::{RecvWindow, SendWindow};
+use crate::proto::hello::{Features, Hello, hello_client};
+use crate::proto::padding::Padding;
+use crate::proto::{
+    AddressFamily, ConnectMeta, Frame, FrameType, MAX_DATAGRAM_SIZE, MAX_RESOLVE_HOST_LEN,
+    Priority, read_line,
+};
+use crate::rotation::PortSchedule;
+use crate::socks5::{ConnectRequest, ProxyStream, TrafficStats, TunnelIo, TunnelStream};
+use crate::tasks::TaskGroup;
+use crate::transparent::Redirector;
+use crate::upstream::{UpstreamProxy, split_host_port};
+use bytes::{Bytes, BytesMut};
+use ipnet::IpNet;
+use std::convert::Infallible;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::RwLock;
-use tracing::{debug, info};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{RwLock, mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+use tokio_util::either::Either;
+use tracing::{debug, info, trace, warn};
+
+/// How long a SOCKS request waits for the server's CONNECT_OK / CONNECT_FAIL
+const CHANNEL_OPEN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Datagrams from the server queued per UDP association; more are dropped
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+const UDP_QUEUE: usize = 64;
+
+/// Longest wait between reconnect attempts, in seconds
+const MAX_RECONNECT_DELAY: u64 = 30;
+
+/// How often an on-demand tunnel checks whether it's still in use
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a server has to answer a latency probe
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a RESOLVE waits for its result
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Chunks of SOCKS data queued per channel while waiting for send window
+const CHANNEL_QUEUE_SIZE: usize = 64;
+
+/// How long shutdown waits for session tasks to stop
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Head start a knock gets over the TCP connect that follows it
+const KNOCK_LEAD: Duration = Duration::from_millis(100);
+
+/// Timeout for SOCKS connections made directly, outside the tunnel
+const DIRECT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// SMTP Tunnel Client
 pub struct Client {
     config: ClientConfig,
+    /// Servers in priority order, and which one is in use
+    servers: std::sync::RwLock<Vec<ServerEntry>>,
+    server: AtomicUsize,
+    /// Username and secret, as last loaded
+    login: std::sync::RwLock<Login>,
+    /// `direct` rules, as last loaded
+    direct: watch::Sender<Arc<DestinationAcl>>,
+    /// Bumped when a reload changes the servers or login, to end the
+    /// connection so the next one uses them, or to reconnect on request.
+    /// A connection watches from before its handshake, so a change made
+    /// while it's connecting isn't missed.
+    relogin: watch::Sender<u64>,
+    /// Where the current round of failover attempts began
+    round_start: AtomicUsize,
+    /// Latest probe of each server, with `latency_probe`
+    latency: watch::Sender<Vec<ServerLatency>>,
     state: Arc<RwLock<ClientState>>,
+    status: watch::Sender<ClientStatus>,
+    traffic: Arc<TrafficStats>,
+    frame_stats: Arc<FrameStats>,
+    /// Connections made to the server for the SOCKS listeners
+    connections: AtomicU64,
+    events: Arc<dyn ClientEvents>,
+    tasks: TaskGroup,
+    /// Transparent or tun mode, once started. Tun mode routes all of IPv4
+    /// into its device, so every server is pinned there before it's dialed.
+    redirector: std::sync::OnceLock<Arc<Redirector>>,
+}
+
+/// Credentials presented to the server
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Login {
+    username: String,
+    secret: String,
+}
+
+impl Login {
+    fn from_config(config: &ClientConfig) -> Self {
+        Self {
+            username: config.username.clone(),
+            secret: config.secret.clone(),
+        }
+    }
+}
+
+/// Callbacks for what happens to a [`Client`], registered with
+/// [`Client::with_events`] so GUIs and monitoring wrappers needn't scrape
+/// logs. Every method does nothing by default. They're called from the
+/// client's tasks, so they shouldn't block.
+pub trait ClientEvents: Send + Sync {
+    /// A session is connected and authenticated to `server`
+    fn on_connected(&self, server: SocketAddr) {
+        let _ = server;
+    }
+
+    /// The main or embedded session's connection ended, with the error
+    /// that ended it if it didn't close cleanly
+    fn on_disconnected(&self, error: Option<&str>) {
+        let _ = error;
+    }
+
+    /// The server opened a channel
+    fn on_channel_open(&self, channel: &ChannelStats) {
+        let _ = channel;
+    }
+
+    /// An open channel closed, with its final traffic
+    fn on_channel_close(&self, channel: &ChannelStats) {
+        let _ = channel;
+    }
+
+    /// The server refused our credentials, with its reply
+    fn on_auth_failure(&self, reply: &str) {
+        let _ = reply;
+    }
+}
+
+/// The events of a client nobody registered any for
+struct NoEvents;
+
+impl ClientEvents for NoEvents {}
+
+/// Connection status published for UIs and console output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientStatus {
+    /// Not connected yet
+    Idle,
+    /// Connecting to the tunnel server
+    Connecting { server: String },
+    /// Tunnel established, with the operator's announcement if the server
+    /// sent one
+    Connected {
+        server: SocketAddr,
+        announcement: Option<String>,
+        handshake: HandshakeTiming,
+    },
+    /// Local SOCKS5 proxies accepting connections
+    Ready { socks_addrs: Vec<SocketAddr> },
+    /// Proxies listening, with the tunnel connected once one is used
+    Standby { socks_addrs: Vec<SocketAddr> },
+    /// Connection lost, retrying after `delay`
+    Reconnecting { delay: Duration, error: String },
+}
+
+/// How long each step of connecting to the server took
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HandshakeTiming {
+    /// Steps in order, each with the time since the one before
+    pub phases: Vec<(&'static str, Duration)>,
+}
+
+impl HandshakeTiming {
+    /// All steps together
+    pub fn total(&self) -> Duration {
+        self.phases.iter().map(|(_, took)| *took).sum()
+    }
+}
+
+impl std::fmt::Display for HandshakeTiming {
+    /// `connect 12.0 ms, greeting 40.2 ms, ... (total 139.5 ms)`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ms = |took: Duration| took.as_secs_f64() * 1000.0;
+        for (i, (phase, took)) in self.phases.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{phase} {:.1} ms", ms(*took))?;
+        }
+        write!(f, " (total {:.1} ms)", ms(self.total()))
+    }
+}
+
+/// Times handshake steps as they complete
+struct Stopwatch {
+    last: Instant,
+    timing: HandshakeTiming,
+}
+
+impl Stopwatch {
+    fn start() -> Self {
+        Self {
+            last: Instant::now(),
+            timing: HandshakeTiming::default(),
+        }
+    }
+
+    /// `phase` just finished
+    fn lap(&mut self, phase: &'static str) {
+        let now = Instant::now();
+        self.timing.phases.push((phase, now - self.last));
+        self.last = now;
+    }
+
+    /// Leave the time since the last step out of the next
+    fn skip(&mut self) {
+        self.last = Instant::now();
+    }
+}
+
+/// One server's latest latency probe
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServerLatency {
+    /// `host:port` as configured
+    pub server: String,
+    /// Time to the SMTP greeting, or `None` if the server didn't answer
+    pub rtt: Option<Duration>,
+}
+
+/// One open channel, as [`Client::channels`] reports it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelStats {
+    /// Session carrying the channel: 0 for the main or embedded session,
+    /// then one for each further connection in the pool
+    pub session: usize,
+    /// Channel ID within its session
+    pub id: u16,
+    /// `host:port` the channel was opened to
+    pub destination: String,
+    /// Time since the channel was requested
+    pub age: Duration,
+    /// Bytes sent to the destination
+    pub sent: u64,
+    /// Bytes received from the destination
+    pub received: u64,
+    /// From CONNECT to the server's CONNECT_OK: a round trip to the server
+    /// plus its connect to the destination. `None` until it's open.
+    pub rtt: Option<Duration>,
+}
+
+/// Local sockets, bound once and served over each connection
+struct Listeners {
+    socks: Vec<(SocksListener, TcpListener)>,
+    dns_stub: Option<DnsStub>,
+}
+
+impl Listeners {
+    /// Wait until something wants the tunnel: a SOCKS connection, returned
+    /// with the index of its listener, or a DNS query left to be read
+    async fn demand(&self) -> io::Result<Option<(usize, TcpStream, SocketAddr)>> {
+        let socks = self.socks.iter().enumerate().map(|(i, (_, listener))| {
+            Box::pin(async move {
+                let (stream, addr) = listener.accept().await?;
+                io::Result::Ok((i, stream, addr))
+            })
+        });
+        let socks = futures_util::future::select_all(socks);
+        let dns = async {
+            match &self.dns_stub {
+                Some(stub) => stub.readable().await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            (accepted, _, _) = socks => accepted.map(Some),
+            result = dns => result.map(|()| None),
+        }
+    }
+}
+
+/// A SOCKS listener's checked settings
+#[derive(Debug, Clone)]
+struct SocksListener {
+    addr: SocketAddr,
+    fallback_ports: Vec<u16>,
+    route: Route,
+    credentials: Option<(String, String)>,
+    allowed_clients: Vec<IpNet>,
+}
+
+impl SocksListener {
+    fn new(config: &ListenerConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            addr: config.bind_addr()?,
+            fallback_ports: config.fallback_ports.clone(),
+            route: config.route,
+            credentials: config
+                .auth
+                .as_ref()
+                .map(|auth| (auth.username.clone(), auth.password.clone())),
+            allowed_clients: config.allowed_networks()?,
+        })
+    }
+
+    /// Bind the port, or else the first free fallback port. Bound once,
+    /// the port is kept across reconnects.
+    async fn bind(&self) -> anyhow::Result<TcpListener> {
+        let ports = std::iter::once(self.addr.port()).chain(self.fallback_ports.iter().copied());
+        for port in ports {
+            let addr = SocketAddr::new(self.addr.ip(), port);
+            match TcpListener::bind(addr).await {
+                Ok(listener) => {
+                    if port != self.addr.port() {
+                        warn!(
+                            "SOCKS port {} is in use; listening on {} instead",
+                            self.addr.port(),
+                            listener.local_addr()?
+                        );
+                    }
+                    return Ok(listener);
+                }
+                Err(e) if e.kind() == io::ErrorKind::AddrInUse => {}
+                Err(e) => anyhow::bail!("Can't listen on {}: {}", addr, e),
+            }
+        }
+        Err(crate::Error::SocksPortInUse {
+            host: self.addr.ip(),
+            port: self.addr.port(),
+            fallbacks: self.fallback_ports.clone(),
+        }
+        .into())
+    }
 }
 
 /// Client connection state
 #[derive(Debug)]
-#[allow(dead_code)]
 struct ClientState {
     connected: bool,
-    next_channel_id: u16,
-    channels: HashMap<u16, Channel>,
+    /// Node to try on the next connection, where the server said our
+    /// session is held
+    redirect: Option<String>,
+    /// Session from `open_session`, carried without SOCKS listeners
+    embedded: Option<TunnelHandle>,
+    /// Main session and pool serving the SOCKS listeners, while connected
+    serving: Option<(TunnelHandle, TunnelPool)>,
+    /// The main session's link, while connected
+    link: Option<Arc<Link>>,
 }
 
 /// A tunneled channel
 #[derive(Debug)]
-#[allow(dead_code)]
 struct Channel {
-    _tx: tokio::sync::mpsc::Sender<Vec<u8>>,
-    connected: bool,
+    /// Waiting for the server's CONNECT_OK / CONNECT_FAIL
+    pending: Option<oneshot::Sender<io::Result<TunnelStream>>>,
+    /// Data from the server towards the SOCKS client, once connected.
+    /// Unbounded, but the server can't queue more than its send window.
+    tx: Option<mpsc::UnboundedSender<Bytes>>,
+    send_window: SendWindow,
+    recv_window: RecvWindow,
+    /// SHUTDOWN sent: the SOCKS side has nothing more to send
+    shut_down: bool,
+    /// `host:port`, for [`ChannelStats`]
+    destination: String,
+    opened: Instant,
+    /// Time to CONNECT_OK
+    rtt: Option<Duration>,
+    sent: u64,
+    received: u64,
+}
+
+/// Connect to a destination matching a `direct` rule, bypassing the tunnel
+async fn connect_direct(req: &ConnectRequest) -> io::Result<ProxyStream> {
+    let connect = TcpStream::connect((req.host.as_str(), req.port));
+    let stream = tokio::time::timeout(DIRECT_CONNECT_TIMEOUT, connect)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    crate::platform::configure_stream(&stream);
+    Ok(ProxyStream::new(stream.local_addr()?, stream))
+}
+
+/// Replace the hostname in `req` with its first address from the local
+/// resolver, for `dns: local`
+async fn resolve_locally(req: ConnectRequest) -> io::Result<ConnectRequest> {
+    if req.host.parse::<IpAddr>().is_ok() {
+        return Ok(req);
+    }
+    #[cfg(feature = "testing")]
+    crate::chaos::lookup(&req.host)?;
+    let addr = tokio::net::lookup_host((req.host.as_str(), req.port))
+        .await
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::HostUnreachable,
+                format!("Can't resolve {}", req.host),
+            )
+        })?;
+    debug!("Resolved {} to {} locally", req.host, addr.ip());
+    Ok(ConnectRequest {
+        host: addr.ip().to_string(),
+        port: req.port,
+    })
+}
+
+/// Handle for opening channels over an established tunnel session
+#[derive(Clone)]
+pub(crate) struct TunnelHandle {
+    out: mpsc::Sender<Frame>,
+    channels: ChannelRegistry<Channel>,
+    /// RESOLVEs awaiting their result, keyed by request ID
+    resolves: ChannelRegistry<oneshot::Sender<io::Result<Vec<IpAddr>>>>,
+    /// Open UDP associations, keyed by association ID
+    associations: Associations,
+    /// Optional features the server agreed to
+    features: Features,
+    /// Tasks serving the session's channels, cancelled when it ends
+    tasks: TaskGroup,
+    events: SessionEvents,
+}
+
+/// Where a session reports its channels opening and closing
+#[derive(Clone)]
+pub(crate) struct SessionEvents {
+    /// The session's number in [`ChannelStats`]
+    index: usize,
+    events: Arc<dyn ClientEvents>,
+}
+
+impl SessionEvents {
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    pub(crate) fn none() -> Self {
+        Self {
+            index: 0,
+            events: Arc::new(NoEvents),
+        }
+    }
+}
+
+impl std::fmt::Debug for TunnelHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TunnelHandle")
+            .field("channels", &self.channels.len())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Where each UDP association's incoming datagrams go
+type Associations = ChannelRegistry<mpsc::Sender<(SocketAddr, Bytes)>>;
+
+/// A UDP association through the tunnel. Datagrams can go to any
+/// destination, and replies come back from wherever they were sent. The
+/// server drops an association after a minute without traffic.
+pub struct UdpAssociation {
+    id: u16,
+    out: mpsc::Sender<Frame>,
+    rx: mpsc::Receiver<(SocketAddr, Bytes)>,
+    associations: Associations,
+}
+
+impl UdpAssociation {
+    /// Send `data` to `host:port` from the server
+    pub async fn send_to(&self, host: &str, port: u16, data: &[u8]) -> io::Result<()> {
+        if host.is_empty() || host.len() > u8::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid hostname",
+            ));
+        }
+        if data.len() > MAX_DATAGRAM_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Datagram too large",
+            ));
+        }
+        self.out
+            .send(Frame::datagram(self.id, host, port, data))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::NotConnected, "Tunnel closed"))
+    }
+
+    /// Next datagram from any address this association sent to
+    pub async fn recv_from(&mut self) -> io::Result<(SocketAddr, Bytes)> {
+        self.rx
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "Tunnel closed"))
+    }
+}
+
+impl Drop for UdpAssociation {
+    fn drop(&mut self) {
+        self.associations.close(self.id);
+    }
+}
+
+/// A tunnel session, kept across reconnects so it can be resumed
+struct Resumable {
+    link: Arc<Link>,
+    tunnel: TunnelHandle,
+    session: JoinHandle<()>,
+    /// Affinity token from the server last carrying the session
+    affinity: Option<String>,
+    /// The server gave the session an ID to resume it by
+    resumes: bool,
+}
+
+/// Sessions a reconnect picks up again: the main one, and one for each
+/// further connection in the pool
+struct Sessions {
+    main: Option<Resumable>,
+    pool: Vec<Option<Resumable>>,
+}
+
+/// Pool sessions currently connected, which channels are spread across
+/// along with the main one
+#[derive(Debug, Clone, Default)]
+struct TunnelPool(Arc<std::sync::Mutex<Vec<(usize, TunnelHandle)>>>);
+
+impl TunnelPool {
+    /// The session with the fewest open channels, `main` on a tie
+    fn pick(&self, main: &TunnelHandle) -> TunnelHandle {
+        let members = self.0.lock().unwrap();
+        std::iter::once(main)
+            .chain(members.iter().map(|(_, tunnel)| tunnel))
+            .min_by_key(|tunnel| tunnel.channels.len())
+            .unwrap_or(main)
+            .clone()
+    }
+
+    fn join(&self, index: usize, tunnel: TunnelHandle) {
+        self.0.lock().unwrap().push((index, tunnel));
+    }
+
+    /// Stop giving session `index` new channels
+    fn leave(&self, index: usize) {
+        self.0.lock().unwrap().retain(|(i, _)| *i != index);
+    }
+
+    fn in_use(&self) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(_, tunnel)| tunnel.in_use())
+    }
+}
+
+/// Connection to the server once authenticated: in binary mode, or
+/// inside BDAT chunks
+type DataStream = Either<TlsStream<TcpStream>, Chunked<TlsStream<TcpStream>>>;
+
+/// A session connected to the server, ready to carry
+struct Connected {
+    current: Resumable,
+    attachment: Attachment,
+    stream: DataStream,
+    leftover: BytesMut,
+    /// Session frames the server already has from us
+    peer_received: u64,
+    peer_addr: SocketAddr,
+    binary: BinaryMode,
+    timing: HandshakeTiming,
+}
+
+/// The server's answer to BINARY
+#[derive(Debug)]
+struct BinaryMode {
+    /// None from a server that can't resume sessions
+    session: Option<SessionId>,
+    /// Session frames the server already has from us
+    received: u64,
+    /// The previous session was picked up
+    resumed: bool,
+    /// Optional features agreed in HELLO
+    features: Features,
+    /// Session affinity token given at auth
+    affinity: Option<String>,
+    /// Operator announcement sent in HELLO
+    announcement: Option<String>,
+    /// Keys for sealing frame payloads, if agreed in HELLO
+    keys: Option<SessionKeys>,
+}
+
+impl BinaryMode {
+    /// Parse "299 Binary mode activated session=<id> received=<n>"
+    fn parse(line: &str, resumed: bool) -> anyhow::Result<Self> {
+        let field = |name: &str| {
+            line.split_whitespace()
+                .find_map(|word| word.strip_prefix(name)?.strip_prefix('='))
+        };
+        let session = field("session").map(str::parse).transpose()?;
+        let received = field("received").unwrap_or("0").parse()?;
+        Ok(Self {
+            session,
+            received,
+            resumed,
+            features: Features::NONE,
+            affinity: None,
+            announcement: None,
+            keys: None,
+        })
+    }
 }
 
 impl Client {
@@ -40,105 +627,1287 @@ impl Client {
     pub fn new(config: ClientConfig) -> Self {
         let state = Arc::new(RwLock::new(ClientState {
             connected: false,
-            next_channel_id: 1,
-            channels: HashMap::new(),
+            redirect: None,
+            embedded: None,
+            serving: None,
+            link: None,
         }));
 
-        Self { config, state }
+        Self {
+            servers: std::sync::RwLock::new(config.servers()),
+            server: AtomicUsize::new(0),
+            login: std::sync::RwLock::new(Login::from_config(&config)),
+            direct: watch::Sender::new(Arc::default()),
+            relogin: watch::Sender::new(0),
+            round_start: AtomicUsize::new(0),
+            latency: watch::Sender::new(Vec::new()),
+            config,
+            state,
+            status: watch::Sender::new(ClientStatus::Idle),
+            traffic: Arc::default(),
+            frame_stats: Arc::default(),
+            connections: AtomicU64::new(0),
+            events: Arc::new(NoEvents),
+            tasks: TaskGroup::new(),
+            redirector: std::sync::OnceLock::new(),
+        }
+    }
+
+    /// Report what happens to the client to `events`
+    pub fn with_events(mut self, events: Arc<dyn ClientEvents>) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Subscribe to connection status changes
+    pub fn status(&self) -> watch::Receiver<ClientStatus> {
+        self.status.subscribe()
+    }
+
+    /// Subscribe to the servers' probed latencies, updated after each
+    /// round of probes
+    pub fn latency(&self) -> watch::Receiver<Vec<ServerLatency>> {
+        self.latency.subscribe()
+    }
+
+    /// Traffic counters for all proxied connections
+    pub fn traffic(&self) -> Arc<TrafficStats> {
+        Arc::clone(&self.traffic)
+    }
+
+    /// Frames sent and received over the tunnel, across reconnects
+    pub fn frame_stats(&self) -> Arc<FrameStats> {
+        Arc::clone(&self.frame_stats)
+    }
+
+    /// Open channels with their traffic so far, on the session from
+    /// [`open_session`](Self::open_session) and those serving the SOCKS
+    /// listeners. Poll it for as long as they're wanted.
+    pub async fn channels(&self) -> Vec<ChannelStats> {
+        let state = self.state.read().await;
+        let mut channels = Vec::new();
+        if let Some(tunnel) = &state.embedded {
+            channels.extend(tunnel.channel_stats());
+        }
+        if let Some((main, pool)) = &state.serving {
+            channels.extend(main.channel_stats());
+            for (_, tunnel) in pool.0.lock().unwrap().iter() {
+                channels.extend(tunnel.channel_stats());
+            }
+        }
+        channels.sort_by_key(|c| (c.session, c.id));
+        channels
+    }
+
+    /// Times the SOCKS listeners' session was connected again after the
+    /// first
+    pub fn reconnects(&self) -> u64 {
+        self.connections.load(Ordering::Relaxed).saturating_sub(1)
+    }
+
+    /// Round trip to the server, measured by the latest answered keepalive
+    /// on the SOCKS listeners' session
+    pub async fn server_rtt(&self) -> Option<Duration> {
+        self.state.read().await.link.as_ref()?.rtt()
+    }
+
+    /// Connect to the server and carry one session in the background, for
+    /// channels opened with [`open_stream`](Self::open_stream) rather than
+    /// through SOCKS listeners. The session isn't resumed if the connection
+    /// drops; open another. One already open is closed.
+    pub async fn open_session(&self) -> anyhow::Result<()> {
+        crate::config::check_binary_verb(&self.config.binary_verb)?;
+        let connector = crate::tls::client_connector(self.config.ca_cert.as_deref())?;
+        let Connected {
+            current,
+            attachment,
+            stream,
+            leftover,
+            peer_received,
+            peer_addr,
+            mut binary,
+            timing,
+        } = loop {
+            let addr = self.server_addr()?;
+            self.status.send_replace(ClientStatus::Connecting {
+                server: addr.clone(),
+            });
+            match self.connect(&connector, &addr, 0, &mut None).await {
+                Ok(connected) => break connected,
+                Err(e) if self.fail_over() => {
+                    let next = self.server();
+                    warn!(
+                        "Connection error: {}, trying {}:{} next",
+                        e, next.host, next.port
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        let options = self.link_options(&mut binary);
+        self.status.send_replace(ClientStatus::Connected {
+            server: peer_addr,
+            announcement: binary.announcement.take(),
+            handshake: timing,
+        });
+        let Resumable { link, tunnel, .. } = current;
+        {
+            let mut state = self.state.write().await;
+            state.connected = true;
+            if let Some(previous) = state.embedded.replace(tunnel.clone()) {
+                previous.tasks.cancel();
+            }
+        }
+
+        let state = Arc::clone(&self.state);
+        let events = Arc::clone(&self.events);
+        self.tasks.spawn("embedded session", async move {
+            match link
+                .run(attachment, stream, leftover, peer_received, options)
+                .await
+            {
+                Ok(_) => {
+                    info!("Session {} closed", link.id());
+                    events.on_disconnected(None);
+                }
+                Err(e) => {
+                    warn!("Session {} lost its connection: {:#}", link.id(), e);
+                    events.on_disconnected(Some(&e.to_string()));
+                }
+            }
+            link.close();
+            tunnel.tasks.cancel();
+            let mut state = state.write().await;
+            // Unless another session took its place
+            if state
+                .embedded
+                .as_ref()
+                .is_some_and(|current| current.out.same_channel(&tunnel.out))
+            {
+                state.embedded = None;
+                state.connected = false;
+            }
+        });
+        Ok(())
+    }
+
+    /// Open a channel to `host:port` through the session from
+    /// [`open_session`](Self::open_session). The server resolves `host`;
+    /// `routes`, `direct` and `dns_mode` don't apply.
+    pub async fn open_stream(&self, host: &str, port: u16) -> io::Result<TunnelIo> {
+        let tunnel = self
+            .state
+            .read()
+            .await
+            .embedded
+            .clone()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "No session open"))?;
+        let req = ConnectRequest {
+            host: host.to_string(),
+            port,
+        };
+        Ok(tunnel.open_stream(req).await?.into_io())
+    }
+
+    /// Apply `config` to the running client. New `direct` rules apply to
+    /// the next SOCKS request, without touching the listeners or channels
+    /// already open. If the servers or credentials changed, the connection
+    /// is dropped and the next one is made with them. Other settings need
+    /// a restart.
+    pub fn reload(&self, config: &ClientConfig) -> anyhow::Result<()> {
+        self.check_tun_direct(config)?;
+        let direct = DestinationAcl::new(&config.direct)?;
+        let servers = config.servers();
+        if servers.iter().any(|server| server.host.is_empty()) {
+            anyhow::bail!("Server hostname is required");
+        }
+        self.direct.send_replace(Arc::new(direct));
+
+        let login = Login::from_config(config);
+        let login_changed = *self.login.read().unwrap() != login;
+        let servers_changed = *self.servers.read().unwrap() != servers;
+        if servers_changed {
+            *self.servers.write().unwrap() = servers;
+            self.server.store(0, Ordering::Relaxed);
+            self.round_start.store(0, Ordering::Relaxed);
+            // Probes of the old list don't line up with the new one
+            self.latency.send_replace(Vec::new());
+        }
+        if login_changed {
+            *self.login.write().unwrap() = login;
+        }
+        if servers_changed || login_changed {
+            info!("Servers or credentials changed, reconnecting");
+            self.relogin.send_modify(|generation| *generation += 1);
+        }
+        Ok(())
+    }
+
+    /// Drop the connection to the server and make a fresh one, e.g.
+    /// after the network changed. Open channels are closed.
+    pub fn force_reconnect(&self) {
+        info!("Reconnecting on request");
+        self.relogin.send_modify(|generation| *generation += 1);
+    }
+
+    /// Stop all sessions, waiting briefly for their tasks
+    pub async fn shutdown(&self) {
+        if !self.tasks.shutdown(SHUTDOWN_TIMEOUT).await {
+            warn!(
+                "Tasks still running after {}s, exiting anyway",
+                SHUTDOWN_TIMEOUT.as_secs()
+            );
+        }
     }
 
     /// Run the client with auto-reconnect
     pub async fn run(&self) -> anyhow::Result<()> {
+        crate::config::check_binary_verb(&self.config.binary_verb)?;
+        if self.config.pool_size == 0 {
+            anyhow::bail!("pool_size must be at least 1");
+        }
+        if let Some(url) = &self.config.upstream_proxy {
+            let proxy = UpstreamProxy::parse(url)?;
+            info!("Connecting through upstream proxy {}", proxy);
+            if self.config.knock_port.is_some() {
+                warn!("Knocks go straight to the server, not through the upstream proxy");
+            }
+        }
+        self.check_tun_direct(&self.config)?;
+        self.check_ca_expiry();
+        let connector = crate::tls::client_connector(self.config.ca_cert.as_deref())?;
+        self.direct
+            .send_replace(Arc::new(DestinationAcl::new(&self.config.direct)?));
+        let listeners = self
+            .config
+            .socks_listeners()
+            .iter()
+            .map(SocksListener::new)
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let mut bound = Vec::with_capacity(listeners.len());
+        for listener in listeners {
+            let socket = listener.bind().await?;
+            bound.push((listener, socket));
+        }
+        if let Some(pac) = &self.config.pac {
+            // Browsers can't log in to a SOCKS proxy
+            let Some((_, socket)) = bound.iter().find(|(l, _)| l.credentials.is_none()) else {
+                anyhow::bail!("pac needs a SOCKS listener without auth");
+            };
+            let proxy = socket.local_addr()?;
+            let addr = pac.bind_addr()?;
+            let listener = TcpListener::bind(addr)
+                .await
+                .map_err(|e| anyhow::anyhow!("Can't serve the PAC file on {addr}: {e}"))?;
+            info!(
+                "PAC file at http://{}{}",
+                listener.local_addr()?,
+                crate::pac::PATHS[0]
+            );
+            let direct = self.direct.subscribe();
+            self.tasks.spawn("PAC server", async move {
+                if let Err(e) = crate::pac::serve(listener, direct, proxy).await {
+                    warn!("PAC server failed: {}", e);
+                }
+            });
+        }
+        let dns_stub = match &self.config.dns_stub {
+            Some(stub) => {
+                let addr = stub.bind_addr()?;
+                let stub = DnsStub::bind(addr)
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Can't serve DNS on {addr}: {e}"))?;
+                info!("DNS stub resolver listening on {}", stub.local_addr()?);
+                Some(stub)
+            }
+            None => None,
+        };
+        let listeners = Listeners {
+            socks: bound,
+            dns_stub,
+        };
+        let on_demand = self
+            .config
+            .on_demand
+            .as_ref()
+            .map(|on_demand| Duration::from_secs(on_demand.idle_timeout));
+        let started = match (&self.config.transparent, &self.config.tun) {
+            (Some(_), Some(_)) => anyhow::bail!("transparent and tun can't both be configured"),
+            (Some(transparent), None) => match Redirector::start(transparent) {
+                Ok(redirector) => Some(Arc::new(redirector)),
+                Err(e) => {
+                    warn!("Transparent mode unavailable: {:#}", e);
+                    None
+                }
+            },
+            (None, Some(tun)) => {
+                let servers = self.server_ips().await?;
+                match Redirector::start_tun(tun, &servers) {
+                    Ok(redirector) => Some(Arc::new(redirector)),
+                    Err(e) => {
+                        warn!("Tun mode unavailable: {:#}", e);
+                        None
+                    }
+                }
+            }
+            (None, None) => None,
+        };
+        if let Some(redirector) = started {
+            let _ = self.redirector.set(redirector);
+        }
+
+        // Probing runs alongside; its results are looked at whenever a
+        // new round of connection attempts begins
+        if self.probing().is_some() {
+            self.probe_round(&connector).await;
+            self.choose_server(true);
+        }
+        tokio::select! {
+            result = self.reconnect(&connector, &listeners, on_demand) => result,
+            never = self.probe_servers(&connector) => match never {},
+        }
+    }
+
+    /// Connect and serve, reconnecting whenever the connection is lost
+    async fn reconnect(
+        &self,
+        connector: &TlsConnector,
+        listeners: &Listeners,
+        on_demand: Option<Duration>,
+    ) -> anyhow::Result<()> {
         let mut reconnect_delay = 2;
-        const MAX_RECONNECT_DELAY: u64 = 30;
+        let mut sessions = Sessions {
+            main: None,
+            pool: (1..self.config.pool_size).map(|_| None).collect(),
+        };
 
         loop {
-            match self.connect_and_serve().await {
+            // On demand, a session that's still alive is resumed at once;
+            // otherwise wait until the proxy is used
+            let mut first = None;
+            if on_demand.is_some() && sessions.main.is_none() {
+                let socks_addrs = listeners
+                    .socks
+                    .iter()
+                    .map(|(_, socket)| socket.local_addr())
+                    .collect::<io::Result<_>>()?;
+                self.status
+                    .send_replace(ClientStatus::Standby { socks_addrs });
+                first = listeners.demand().await?;
+                info!("Proxy in use, connecting");
+            }
+            match self
+                .connect_and_serve(connector, listeners, first, &mut sessions)
+                .await
+            {
                 Ok(()) => {
                     info!("Connection closed gracefully");
                     reconnect_delay = 2;
                 }
                 Err(e) => {
+                    // A server that couldn't be reached at all gives way to
+                    // the next; waiting starts once they all have failed
+                    let reached = !matches!(*self.status.borrow(), ClientStatus::Connecting { .. });
+                    if !reached && self.fail_over() {
+                        let next = self.server();
+                        warn!(
+                            "Connection error: {}, trying {}:{} next",
+                            e, next.host, next.port
+                        );
+                        continue;
+                    }
                     tracing::warn!(
                         "Connection error: {}, reconnecting in {}s...",
                         e,
                         reconnect_delay
                     );
+                    self.status.send_replace(ClientStatus::Reconnecting {
+                        delay: Duration::from_secs(reconnect_delay),
+                        error: e.to_string(),
+                    });
                     tokio::time::sleep(tokio::time::Duration::from_secs(reconnect_delay)).await;
                     reconnect_delay = (reconnect_delay * 2).min(MAX_RECONNECT_DELAY);
                 }
             }
+            // A session left to resume stays with its server
+            let resumable = sessions
+                .main
+                .as_ref()
+                .is_some_and(|main| !main.session.is_finished());
+            self.choose_server(!resumable);
+        }
+    }
+
+    /// Addresses the client connects out to: the servers, or the upstream
+    /// proxy in front of them. Tun mode keeps them off the tun device from
+    /// the start; servers dialed later are pinned by [`Self::pin`].
+    async fn server_ips(&self) -> anyhow::Result<Vec<IpAddr>> {
+        let hosts = match &self.config.upstream_proxy {
+            Some(url) => {
+                let proxy = UpstreamProxy::parse(url)?;
+                vec![(proxy.host, proxy.port)]
+            }
+            None => self
+                .config
+                .servers()
+                .into_iter()
+                .map(|server| (server.host, server.port))
+                .collect(),
+        };
+        let mut ips = Vec::new();
+        for (host, port) in hosts {
+            let addrs = tokio::net::lookup_host((host.as_str(), port))
+                .await
+                .map_err(|e| anyhow::anyhow!("Can't resolve {host}: {e}"))?;
+            for addr in addrs {
+                if !ips.contains(&addr.ip()) {
+                    ips.push(addr.ip());
+                }
+            }
+        }
+        Ok(ips)
+    }
+
+    /// Tun mode routes direct connections into its own device and back
+    /// through the tunnel, so it can't have `direct` rules or listeners
+    fn check_tun_direct(&self, config: &ClientConfig) -> anyhow::Result<()> {
+        if self.config.tun.is_none() {
+            return Ok(());
+        }
+        if !config.direct.is_empty() {
+            anyhow::bail!("direct rules can't be used with tun: the tun device would capture them");
+        }
+        let listeners = config.socks_listeners();
+        if let Some(listener) = listeners.iter().find(|l| l.route == Route::Direct) {
+            anyhow::bail!(
+                "SOCKS listener on port {} routes direct, which can't be used with tun",
+                listener.port
+            );
+        }
+        Ok(())
+    }
+
+    /// Keep `addrs` off the tun device before dialing them, so a server
+    /// added by a reload or named in a redirect doesn't loop back into the
+    /// tunnel. Does nothing outside tun mode.
+    async fn pin(&self, addrs: &[SocketAddr]) -> anyhow::Result<()> {
+        let Some(redirector) = self.redirector.get() else {
+            return Ok(());
+        };
+        let redirector = Arc::clone(redirector);
+        let ips: Vec<IpAddr> = addrs.iter().map(SocketAddr::ip).collect();
+        // Looking up and adding routes runs commands
+        tokio::task::spawn_blocking(move || ips.into_iter().try_for_each(|ip| redirector.pin(ip)))
+            .await?
+    }
+
+    /// Warn if the configured CA certificate is expired or about to expire
+    fn check_ca_expiry(&self) {
+        let Some(path) = &self.config.ca_cert else {
+            return;
+        };
+        let info = std::fs::read(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|pem| crate::tls::CertInfo::from_pem(&pem));
+        match info {
+            Ok(cert) => {
+                cert.check_expiry("CA certificate", self.config.cert_warn_days);
+            }
+            Err(e) => tracing::warn!("Could not inspect CA certificate {}: {}", path, e),
+        }
+    }
+
+    /// Connect to server and serve requests, resuming the sessions in
+    /// `sessions` if the server still has them. A session whose connection
+    /// was lost is left there for the next attempt.
+    async fn connect_and_serve(
+        &self,
+        connector: &TlsConnector,
+        listeners: &Listeners,
+        mut first: Option<(usize, TcpStream, SocketAddr)>,
+        sessions: &mut Sessions,
+    ) -> anyhow::Result<()> {
+        let mut relogins = self.relogin.subscribe();
+        // 1. Connect to server
+        let addr = match self.state.write().await.redirect.take() {
+            Some(node) => node,
+            None => self.server_addr()?,
+        };
+        info!("Connecting to {}...", addr);
+        self.status.send_replace(ClientStatus::Connecting {
+            server: addr.clone(),
+        });
+
+        let Connected {
+            mut current,
+            attachment,
+            stream,
+            leftover,
+            peer_received,
+            peer_addr,
+            mut binary,
+            timing,
+        } = self
+            .connect(connector, &addr, 0, &mut sessions.main)
+            .await?;
+
+        // 3. Set state to connected
+        {
+            let mut state = self.state.write().await;
+            state.connected = true;
+            state.link = Some(Arc::clone(&current.link));
+        }
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.status.send_replace(ClientStatus::Connected {
+            server: peer_addr,
+            announcement: binary.announcement.take(),
+            handshake: timing,
+        });
+
+        // 4. Carry the session; SOCKS5 requests open channels through it,
+        // or through whichever pool session is least busy
+        let tunnel = current.tunnel.clone();
+        let pool = TunnelPool::default();
+        self.state.write().await.serving = Some((tunnel.clone(), pool.clone()));
+        let pooling = !sessions.pool.is_empty();
+        let pooled = futures_util::future::join_all(
+            sessions
+                .pool
+                .iter_mut()
+                .enumerate()
+                .map(|(i, slot)| self.serve_pooled(connector, i + 1, slot, &pool)),
+        );
+        let transparent = match self.redirector.get() {
+            Some(redirector) => Some((
+                TcpListener::bind(redirector.listen_addr()).await?,
+                Arc::clone(redirector),
+            )),
+            None => None,
+        };
+        let idle_timeout = match self.config.channel_idle_timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        let redirected = {
+            let tunnel = tunnel.clone();
+            let traffic = self.traffic();
+            async move {
+                match transparent {
+                    Some((listener, redirector)) => {
+                        serve_transparent(listener, redirector, tunnel, traffic, idle_timeout).await
+                    }
+                    None => std::future::pending().await,
+                }
+            }
+        };
+        let stub = {
+            let tunnel = tunnel.clone();
+            let tasks = current.tunnel.tasks.clone();
+            async move {
+                match &listeners.dns_stub {
+                    Some(stub) => stub.serve(tunnel, tasks).await,
+                    None => std::future::pending().await,
+                }
+            }
+        };
+        let dns = self.config.dns;
+        let mut socks_servers = Vec::with_capacity(listeners.socks.len());
+        let mut socks_addrs = Vec::with_capacity(listeners.socks.len());
+        for (i, (listener, socket)) in listeners.socks.iter().enumerate() {
+            let tunnel = tunnel.clone();
+            let pool = pool.clone();
+            let direct = self.direct.subscribe();
+            let route = listener.route;
+            let mut server = crate::socks5::Socks5Server::new(listener.addr, move |req| {
+                let tunnel = pool.pick(&tunnel);
+                let direct = Arc::clone(&direct.borrow());
+                async move {
+                    let req = match dns {
+                        DnsMode::Remote => req,
+                        DnsMode::Local => resolve_locally(req).await?,
+                    };
+                    match route {
+                        Route::Direct => return connect_direct(&req).await,
+                        Route::Rules => {
+                            if let Some(rule) = direct.check_host(&req.host) {
+                                debug!("{}:{} matches direct rule {}", req.host, req.port, rule);
+                                return connect_direct(&req).await;
+                            }
+                        }
+                        Route::Tunnel => {}
+                    }
+                    tunnel.open(req).await
+                }
+            })
+            .with_stats(self.traffic())
+            .with_tasks(current.tunnel.tasks.clone())
+            .with_idle_timeout(idle_timeout)
+            .with_allowed_clients(listener.allowed_clients.clone());
+            if let Some((username, password)) = &listener.credentials {
+                server = server.with_password(username.clone(), password.clone());
+            }
+            if let Some((_, stream, addr)) = first.take_if(|(index, _, _)| *index == i) {
+                server.handle(stream, addr);
+            }
+            socks_addrs.push(socket.local_addr()?);
+            socks_servers.push(server.serve(socket));
+        }
+        self.status
+            .send_replace(ClientStatus::Ready { socks_addrs });
+
+        let options = self.link_options(&mut binary);
+        // On demand, hang up once nothing has used the tunnel for a while
+        let idle = {
+            let tunnel = tunnel.clone();
+            let pool = pool.clone();
+            let limit = self.config.on_demand.as_ref().map(|o| o.idle_timeout);
+            async move {
+                let Some(limit) = limit else {
+                    return std::future::pending().await;
+                };
+                let limit = Duration::from_secs(limit);
+                let mut last_used = tokio::time::Instant::now();
+                let mut ticker = tokio::time::interval(IDLE_CHECK_INTERVAL);
+                loop {
+                    ticker.tick().await;
+                    if tunnel.in_use() || pool.in_use() {
+                        last_used = tokio::time::Instant::now();
+                    } else if last_used.elapsed() >= limit {
+                        info!("Tunnel unused for {}s, disconnecting", limit.as_secs());
+                        return;
+                    }
+                }
+            }
+        };
+        let mut idled = false;
+        let mut relogin = false;
+        let result = tokio::select! {
+            // Ends without error only once the session is over
+            result = current.link.run(attachment, stream, leftover, peer_received, options) => {
+                result.map(drop)
+            }
+            // Each listener serves until it fails
+            result = futures_util::future::try_join_all(socks_servers) => {
+                result.map(drop).map_err(Into::into)
+            }
+            result = redirected => result,
+            result = stub => result.map_err(Into::into),
+            _ = &mut current.session => Ok(()),
+            // Pool sessions reconnect on their own and never finish
+            _ = pooled, if pooling => Ok(()),
+            _ = idle => {
+                idled = true;
+                Ok(())
+            }
+            _ = relogins.changed() => {
+                relogin = true;
+                Ok(())
+            }
+        };
+        {
+            let mut state = self.state.write().await;
+            state.connected = false;
+            state.serving = None;
+            state.link = None;
+        }
+        let error = result.as_ref().err().map(ToString::to_string);
+        self.events.on_disconnected(error.as_deref());
+        // Sessions can't be resumed on another server or as another user
+        if idled || relogin {
+            current.tunnel.tasks.cancel();
+            for pooled in sessions.pool.iter_mut().filter_map(Option::take) {
+                pooled.tunnel.tasks.cancel();
+            }
+        }
+        if result.is_err() && !current.session.is_finished() {
+            sessions.main = Some(current);
+        }
+        result
+    }
+
+    /// Keep pool session `index` connected while the main one is, and
+    /// offered to `pool` while it's up. Its channels go to the others
+    /// while it reconnects.
+    async fn serve_pooled(
+        &self,
+        connector: &TlsConnector,
+        index: usize,
+        slot: &mut Option<Resumable>,
+        pool: &TunnelPool,
+    ) {
+        let mut delay = 2;
+        loop {
+            match self.carry_pooled(connector, index, slot, pool).await {
+                Ok(()) => {
+                    debug!("Pool session {} closed", index);
+                    delay = 2;
+                }
+                Err(e) => {
+                    warn!(
+                        "Pool session {} lost: {}, reconnecting in {}s...",
+                        index, e, delay
+                    );
+                    delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                }
+            }
+            pool.leave(index);
+            tokio::time::sleep(Duration::from_secs(delay)).await;
+        }
+    }
+
+    /// Connect pool session `index` and carry it until the connection
+    /// ends. The session stays in `slot` so it's resumed on reconnect,
+    /// even if this is dropped.
+    async fn carry_pooled(
+        &self,
+        connector: &TlsConnector,
+        index: usize,
+        slot: &mut Option<Resumable>,
+        pool: &TunnelPool,
+    ) -> anyhow::Result<()> {
+        let addr = self.server_addr()?;
+        let Connected {
+            mut current,
+            attachment,
+            stream,
+            leftover,
+            peer_received,
+            mut binary,
+            ..
+        } = self.connect(connector, &addr, index, slot).await?;
+        // Only the main session follows affinity redirects
+        current.affinity = None;
+        let options = self.link_options(&mut binary);
+        let current = slot.insert(current);
+        pool.join(index, current.tunnel.clone());
+        debug!("Pool session {} connected", index);
+        let result = tokio::select! {
+            result = current.link.run(attachment, stream, leftover, peer_received, options) => {
+                result.map(drop)
+            }
+            _ = &mut current.session => Ok(()),
+        };
+        if result.is_ok() || current.session.is_finished() {
+            *slot = None;
+        }
+        result
+    }
+
+    /// The server in use
+    pub(crate) fn server(&self) -> ServerEntry {
+        let servers = self.servers.read().unwrap();
+        servers[self.server.load(Ordering::Relaxed) % servers.len()].clone()
+    }
+
+    fn login(&self) -> Login {
+        self.login.read().unwrap().clone()
+    }
+
+    /// Move on to the next server after failing to reach this one. False
+    /// once the whole list has been tried, back at the server the round
+    /// started from.
+    fn fail_over(&self) -> bool {
+        let count = self.servers.read().unwrap().len();
+        let next = (self.server.load(Ordering::Relaxed) + 1) % count;
+        self.server.store(next, Ordering::Relaxed);
+        next != self.round_start.load(Ordering::Relaxed)
+    }
+
+    /// Start a round of connection attempts from the server in use or,
+    /// with `switch`, from the fastest by the latest probes if it's
+    /// clearly faster
+    fn choose_server(&self, switch: bool) {
+        let current = self.server.load(Ordering::Relaxed);
+        if switch && let Some(probe) = self.probing() {
+            let rtts: Vec<_> = self.latency.borrow().iter().map(|l| l.rtt).collect();
+            let margin = Duration::from_millis(probe.margin_ms);
+            let fastest = fastest(&rtts, current, margin);
+            let server = self.servers.read().unwrap().get(fastest).cloned();
+            if fastest != current
+                && let Some(server) = server
+            {
+                info!(
+                    "Switching to {}:{}, the fastest server ({} ms)",
+                    server.host,
+                    server.port,
+                    rtts[fastest].unwrap_or_default().as_millis()
+                );
+                self.server.store(fastest, Ordering::Relaxed);
+            }
+        }
+        self.round_start
+            .store(self.server.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    /// Latency probe settings, when there's a choice of servers
+    fn probing(&self) -> Option<&LatencyProbeConfig> {
+        self.config
+            .latency_probe
+            .as_ref()
+            .filter(|_| self.servers.read().unwrap().len() > 1)
+    }
+
+    /// Probe every server each `interval`, publishing the results. Rounds
+    /// are skipped while an on-demand tunnel is on standby.
+    async fn probe_servers(&self, connector: &TlsConnector) -> Infallible {
+        let Some(probe) = self.probing() else {
+            return std::future::pending().await;
+        };
+        let interval = Duration::from_secs(probe.interval.max(1));
+        loop {
+            tokio::time::sleep(interval).await;
+            if !matches!(*self.status.borrow(), ClientStatus::Standby { .. }) {
+                self.probe_round(connector).await;
+            }
+        }
+    }
+
+    /// Probe all servers at once and publish their latencies
+    async fn probe_round(&self, connector: &TlsConnector) {
+        let servers = self.servers.read().unwrap().clone();
+        let probes = servers.iter().map(|server| async move {
+            let rtt = tokio::time::timeout(PROBE_TIMEOUT, self.probe(connector, server)).await;
+            let rtt = match rtt {
+                Ok(Ok(rtt)) => Some(rtt),
+                Ok(Err(e)) => {
+                    debug!("Probe of {}:{} failed: {:#}", server.host, server.port, e);
+                    None
+                }
+                Err(_) => {
+                    debug!("Probe of {}:{} timed out", server.host, server.port);
+                    None
+                }
+            };
+            ServerLatency {
+                server: format!("{}:{}", server.host, server.port),
+                rtt,
+            }
+        });
+        let latencies = futures_util::future::join_all(probes).await;
+        self.latency.send_replace(latencies);
+    }
+
+    /// Time from connecting to `server` to its SMTP greeting, then QUIT
+    async fn probe(
+        &self,
+        connector: &TlsConnector,
+        server: &ServerEntry,
+    ) -> anyhow::Result<Duration> {
+        if let Some(port) = self.config.knock_port {
+            self.knock(server, port).await?;
+        }
+        let addr = self.addr_of(server)?;
+        let mut buf = BytesMut::with_capacity(512);
+        let start = Instant::now();
+        let stream = self.dial(&addr).await?;
+        let mut stream = if self.config.implicit_tls {
+            let name = ServerName::try_from(server.host.clone())?;
+            Either::Right(connector.connect(name, stream).await?)
+        } else {
+            Either::Left(stream)
+        };
+        read_greeting(&mut stream, &mut buf).await?;
+        let rtt = start.elapsed();
+        let _ = stream.write_all(b"QUIT\r\n").await;
+        Ok(rtt)
+    }
+
+    /// Open a TCP connection to `addr`, through the upstream proxy if
+    /// there is one
+    async fn dial(&self, addr: &str) -> anyhow::Result<TcpStream> {
+        #[cfg(feature = "testing")]
+        crate::chaos::delay_connect().await;
+        let Some(url) = &self.config.upstream_proxy else {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host(addr).await?.collect();
+            self.pin(&addrs).await?;
+            return Ok(TcpStream::connect(&addrs[..]).await?);
+        };
+        let proxy = UpstreamProxy::parse(url)?;
+        let (host, port) =
+            split_host_port(addr, 0).ok_or_else(|| anyhow::anyhow!("Bad server address {addr}"))?;
+        debug!("Connecting to {} through {}", addr, proxy);
+        proxy.connect(host, port).await
+    }
+
+    /// Where to connect: the server's current port if it rotates
+    fn server_addr(&self) -> anyhow::Result<String> {
+        self.addr_of(&self.server())
+    }
+
+    /// Where to reach `server`, on its current port if it rotates
+    fn addr_of(&self, server: &ServerEntry) -> anyhow::Result<String> {
+        let port = match &self.config.port_rotation {
+            Some(rotation) => PortSchedule::from_config(rotation)?.current_port(),
+            None => server.port,
+        };
+        Ok(format!("{}:{}", server.host, port))
+    }
+
+    /// Connect to `addr` and authenticate, resuming the session in
+    /// `resumable` if the server still has it. A new session is numbered
+    /// `index` in [`ChannelStats`].
+    async fn connect(
+        &self,
+        connector: &TlsConnector,
+        addr: &str,
+        index: usize,
+        resumable: &mut Option<Resumable>,
+    ) -> anyhow::Result<Connected> {
+        if let Some(port) = self.config.knock_port {
+            self.knock(&self.server(), port).await?;
+        }
+        let mut stopwatch = Stopwatch::start();
+        let stream = self.dial(addr).await?;
+        stopwatch.lap("connect");
+        crate::platform::configure_stream(&stream);
+        let peer_addr = stream.peer_addr()?;
+        if self.config.upstream_proxy.is_some() {
+            info!("Connected to {} through {}", addr, peer_addr);
+        } else {
+            info!("Connected to {}", peer_addr);
+        }
+        let port = split_host_port(addr, 0).map_or(peer_addr.port(), |(_, port)| port);
+
+        // 2. SMTP handshake. Attaching first stops the session's frame
+        // count from moving while the server is told about it.
+        if resumable.as_ref().is_some_and(|r| r.session.is_finished()) {
+            *resumable = None;
+        }
+        let attachment = match resumable.as_ref() {
+            Some(r) => Some(r.link.attach().await),
+            None => None,
+        };
+        let resume = resumable
+            .as_ref()
+            .filter(|r| r.resumes)
+            .map(|r| (r.link.id(), r.link.received(), r.affinity.clone()));
+        stopwatch.skip();
+        let (stream, leftover, mut binary) = self
+            .smtp_handshake(stream, port, connector, resume, &mut stopwatch)
+            .await?;
+        let timing = stopwatch.timing;
+        info!("Handshake complete: {}", timing);
+
+        let (mut current, attachment) = match (resumable.take(), attachment) {
+            (Some(current), Some(attachment)) if binary.resumed => {
+                info!("Resumed session {}", current.link.id());
+                (current, attachment)
+            }
+            (previous, _) => {
+                if let Some(previous) = previous {
+                    warn!(
+                        "Session {} expired, its connections were dropped",
+                        previous.link.id()
+                    );
+                    previous.tunnel.tasks.cancel();
+                }
+                if binary.session.is_none() {
+                    debug!("Server can't resume sessions");
+                }
+                let id = binary.session.unwrap_or_else(SessionId::random);
+                let (link, inbound, outbound) = Link::new(id);
+                let (tunnel, session) = TunnelHandle::spawn(
+                    inbound,
+                    outbound,
+                    binary.features,
+                    self.tasks.child(),
+                    SessionEvents {
+                        index,
+                        events: Arc::clone(&self.events),
+                    },
+                );
+                let attachment = link.attach().await;
+                let current = Resumable {
+                    link,
+                    tunnel,
+                    session,
+                    affinity: None,
+                    resumes: binary.session.is_some(),
+                };
+                (current, attachment)
+            }
+        };
+        let peer_received = if binary.resumed { binary.received } else { 0 };
+        current.affinity = binary.affinity.take();
+        self.events.on_connected(peer_addr);
+        Ok(Connected {
+            current,
+            attachment,
+            stream,
+            leftover,
+            peer_received,
+            peer_addr,
+            binary,
+            timing,
+        })
+    }
+
+    /// How to carry a session agreed on in `binary`; takes its keys
+    fn link_options(&self, binary: &mut BinaryMode) -> LinkOptions {
+        LinkOptions {
+            heartbeat: Heartbeat::from_config(&self.config),
+            idle_timeout: None,
+            batching: Batching {
+                max_delay: Duration::from_millis(self.config.write_batch_delay_ms),
+                max_bytes: self.config.write_batch_bytes,
+            },
+            channel_turn: self.config.channel_turn_bytes,
+            large_frames: binary.features.contains(Features::LARGE_FRAMES),
+            compression: binary
+                .features
+                .contains(Features::COMPRESSION)
+                .then_some(Compression {
+                    level: self.config.compression_level,
+                    min_size: self.config.compression_min_size,
+                }),
+            padding: self
+                .config
+                .padding
+                .as_ref()
+                .filter(|_| binary.features.contains(Features::PADDING))
+                .map(Padding::from_config),
+            checksum: binary.features.contains(Features::CHECKSUM),
+            sequence: binary.features.contains(Features::SEQUENCE),
+            keys: binary.keys.take(),
+            // Router builds skip counting frames
+            frame_stats: (!cfg!(feature = "minimal")).then(|| self.frame_stats()),
+        }
+    }
+
+    /// Send a knock to `server`'s knock port so it accepts our connection
+    async fn knock(&self, server: &ServerEntry, port: u16) -> anyhow::Result<()> {
+        let host = &server.host;
+        let target = tokio::net::lookup_host((host.as_str(), port))
+            .await?
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("No address for {host}"))?;
+        let bind: SocketAddr = match target {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (std::net::Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        self.pin(&[target]).await?;
+        let socket = UdpSocket::bind(bind).await?;
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let login = self.login();
+        let packet = crate::knock::packet(&login.secret, &login.username, timestamp);
+        socket.send_to(&packet, target).await?;
+        debug!("Knocked on {}", target);
+        tokio::time::sleep(KNOCK_LEAD).await;
+        Ok(())
+    }
+
+    /// Perform SMTP handshake and upgrade to TLS
+    async fn smtp_handshake(
+        &self,
+        stream: TcpStream,
+        port: u16,
+        connector: &TlsConnector,
+        resume: Option<(SessionId, u64, Option<String>)>,
+        stopwatch: &mut Stopwatch,
+    ) -> anyhow::Result<(DataStream, BytesMut, BinaryMode)> {
+        let mut buf = BytesMut::with_capacity(1024);
+
+        // 1-4. Greeting and TLS: with implicit TLS the handshake comes
+        // first and the greeting arrives inside it
+        let mut stream = if self.config.implicit_tls {
+            let mut stream = self.start_tls(stream, port, connector, stopwatch).await?;
+            read_greeting(&mut stream, &mut buf).await?;
+            stopwatch.lap("greeting");
+            stream
+        } else {
+            self.starttls(stream, port, connector, &mut buf, stopwatch)
+                .await?
+        };
+        let binding = crate::tls::channel_binding(stream.get_ref().1);
+
+        // 5. EHLO again (post-TLS)
+        stream.write_all(b"EHLO tunnel-client.local\r\n").await?;
+
+        // Read EHLO response
+        loop {
+            let line = read_line(&mut stream, &mut buf)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Server closed connection"))?;
+            debug!("EHLO (post-TLS) response: {}", line);
+
+            if line.starts_with("250 ") {
+                break;
+            }
+            if !line.starts_with("250-") {
+                return Err(anyhow::anyhow!("EHLO (post-TLS) failed: {line}"));
+            }
         }
-    }
+        stopwatch.lap("tls-ehlo");
 
-    /// Connect to server and serve requests
-    async fn connect_and_serve(&self) -> anyhow::Result<()> {
-        // 1. Connect to server
-        let addr = format!("{}:{}", self.config.server_host, self.config.server_port);
-        info!("Connecting to {}...", addr);
+        // 6. AUTH, bound to this TLS connection
+        let login = self.login();
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let token = AuthToken::generate_bound(
+            &login.secret,
+            &login.username,
+            timestamp,
+            binding.as_ref().map(|b| &b[..]),
+        );
+        stream
+            .write_all(format!("AUTH PLAIN {token}\r\n").as_bytes())
+            .await?;
+        let line = read_line(&mut stream, &mut buf)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Server closed connection"))?;
 
-        let stream = TcpStream::connect(&addr).await?;
-        let peer_addr = stream.peer_addr()?;
-        info!("Connected to {}", peer_addr);
+        if !line.starts_with("235") {
+            self.events.on_auth_failure(&line);
+            return Err(anyhow::anyhow!("Authentication failed: {line}"));
+        }
+        debug!("Auth success: {}", line);
+        stopwatch.lap("auth");
+        let affinity = line
+            .split_whitespace()
+            .find_map(|word| word.strip_prefix("affinity="))
+            .map(str::to_string);
 
-        // 2. SMTP handshake
-        let _stream = self.smtp_handshake(stream).await?;
-        info!("SMTP handshake complete, binary mode active");
+        // 7. With the bdat data channel, open a mail transaction; all that
+        // follows goes as its message data
+        let mut stream = match self.config.data_channel {
+            DataChannel::Binary => Either::Left(stream),
+            DataChannel::Bdat => {
+                let address = format!("<{}@{}>", login.username, self.server().host);
+                for command in [format!("MAIL FROM:{address}"), format!("RCPT TO:{address}")] {
+                    stream
+                        .write_all(format!("{command}\r\n").as_bytes())
+                        .await?;
+                    let line = read_line(&mut stream, &mut buf)
+                        .await?
+                        .ok_or_else(|| anyhow::anyhow!("Server closed connection"))?;
+                    if !line.starts_with("250") {
+                        return Err(anyhow::anyhow!("{command} refused: {line}"));
+                    }
+                }
+                stopwatch.lap("mail");
+                Either::Right(Chunked::new(stream, buf.split()))
+            }
+        };
 
-        // 3. Set state to connected
-        {
-            let mut state = self.state.write().await;
-            state.connected = true;
+        // 8. Switch to binary mode, picking up the previous session if the
+        // server still has it. A server without it may name the node that
+        // does, to be tried next.
+        let verb = &self.config.binary_verb;
+        let mut binary = None;
+        if let Some((id, received, token)) = resume {
+            let command = match token {
+                Some(token) => format!("{verb} RESUME {id} {received} {token}\r\n"),
+                None => format!("{verb} RESUME {id} {received}\r\n"),
+            };
+            stream.write_all(command.as_bytes()).await?;
+            let line = read_line(&mut stream, &mut buf)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Server closed connection"))?;
+            if line.starts_with("299") {
+                debug!("Binary mode active: {}", line);
+                binary = Some(BinaryMode::parse(&line, true)?);
+            } else if line.starts_with("551")
+                && let Some(node) = line
+                    .split_once('<')
+                    .and_then(|(_, rest)| rest.split_once('>'))
+                    .map(|(node, _)| node.to_string())
+            {
+                info!("Session {} is held by {}, reconnecting there", id, node);
+                self.state.write().await.redirect = Some(node);
+                return Err(anyhow::anyhow!("Session {id} is on another server"));
+            } else {
+                debug!("Session {} not resumed: {}", id, line);
+            }
         }
+        let mut binary = match binary {
+            Some(binary) => binary,
+            None => {
+                stream.write_all(format!("{verb}\r\n").as_bytes()).await?;
+                let line = read_line(&mut stream, &mut buf)
+                    .await?
+                    .ok_or_else(|| anyhow::anyhow!("Server closed connection"))?;
 
-        // 4. Start SOCKS5 server
-        let socks_bind = self.config.socks_bind_addr()?;
-
-        // Create SOCKS5 server
-        let socks_server = crate::socks5::Socks5Server::new(socks_bind, move |req| {
-            let host = req.host;
-            let port = req.port;
-            async move {
-                // Connect directly for now (simplified)
-                let addr = format!("{host}:{port}");
-                match TcpStream::connect(&addr).await {
-                    Ok(stream) => {
-                        let local_addr = stream.local_addr()?;
-                        Ok(crate::socks5::ProxyStream::new(local_addr, stream))
-                    }
-                    Err(e) => Err(e),
+                if !line.starts_with("299") {
+                    return Err(anyhow::anyhow!("Binary mode failed: {line}"));
                 }
+                debug!("Binary mode active: {}", line);
+                BinaryMode::parse(&line, false)?
             }
-        });
+        };
+        stopwatch.lap("binary");
 
-        // Run SOCKS5 server
-        socks_server.run().await?;
+        // 9. Agree on a protocol version before any session frames
+        let mut offer = Hello::local();
+        if !self.config.frame_checksum {
+            offer.features = offer.features.without(Features::CHECKSUM);
+        }
+        if !self.config.frame_sequence {
+            offer.features = offer.features.without(Features::SEQUENCE);
+        }
+        let kex = if self.config.encrypt_frames {
+            let kex = KeyExchange::new(Role::Client)?;
+            offer.key_exchange = Some(kex.message(&login.secret, &login.username));
+            Some(kex)
+        } else {
+            offer.features = offer.features.without(Features::ENCRYPTION);
+            None
+        };
+        let offered = offer.features;
+        let hello = hello_client(&mut stream, &mut buf, offer).await?;
+        stopwatch.lap("hello");
+        debug!(
+            "Protocol version {} (features: {})",
+            hello.version, hello.features
+        );
+        if let Some(kex) = kex
+            && let Some(message) = hello.key_exchange
+            && hello.features.contains(Features::ENCRYPTION)
+        {
+            let transcript = Features::transcript(offered, hello.features);
+            binary.keys = Some(
+                kex.finish(&login.secret, &login.username, &message, &transcript)
+                    .map_err(|e| anyhow::anyhow!("Server failed the key exchange: {e}"))?,
+            );
+        } else if self.config.encrypt_frames {
+            // Falling back to TLS would hand a stripping proxy the plaintext
+            anyhow::bail!(
+                "Server didn't agree to frame encryption; \
+                 refusing to rely on TLS alone with encrypt_frames set"
+            );
+        }
+        if let Some(announcement) = &hello.announcement {
+            // Shown on the user's terminal, so no escape sequences
+            let announcement: String = announcement
+                .chars()
+                .map(|c| if c.is_control() { ' ' } else { c })
+                .collect();
+            info!("Server announcement: {}", announcement);
+            binary.announcement = Some(announcement);
+        }
+        binary.features = hello.features;
+        binary.affinity = affinity;
 
-        Ok(())
+        Ok((stream, buf, binary))
     }
 
-    /// Perform SMTP handshake and upgrade to TLS
-    async fn smtp_handshake(&self, mut stream: TcpStream) -> anyhow::Result<TcpStream> {
-        let mut buf = BytesMut::with_capacity(1024);
-
+    /// Greeting, EHLO and STARTTLS in plaintext, then the TLS handshake
+    async fn starttls(
+        &self,
+        mut stream: TcpStream,
+        port: u16,
+        connector: &TlsConnector,
+        buf: &mut BytesMut,
+        stopwatch: &mut Stopwatch,
+    ) -> anyhow::Result<TlsStream<TcpStream>> {
         // 1. Wait for greeting
-        let line = self
-            .read_smtp_line(&mut stream, &mut buf)
-            .await?
-            .ok_or_else(|| anyhow::anyhow!("Server closed connection"))?;
-
-        if !line.starts_with("220") {
-            return Err(anyhow::anyhow!("Unexpected greeting: {line}"));
-        }
-        debug!("Server greeting: {}", line);
+        read_greeting(&mut stream, buf).await?;
+        stopwatch.lap("greeting");
 
         // 2. Send EHLO
         stream.write_all(b"EHLO tunnel-client.local\r\n").await?;
 
         // Read EHLO response (multi-line)
         loop {
-            let line = self
-                .read_smtp_line(&mut stream, &mut buf)
+            let line = read_line(&mut stream, buf)
                 .await?
                 .ok_or_else(|| anyhow::anyhow!("Server closed connection"))?;
             debug!("EHLO response: {}", line);
@@ -150,11 +1919,11 @@ impl Client {
                 return Err(anyhow::anyhow!("EHLO failed: {line}"));
             }
         }
+        stopwatch.lap("ehlo");
 
         // 3. STARTTLS
         stream.write_all(b"STARTTLS\r\n").await?;
-        let line = self
-            .read_smtp_line(&mut stream, &mut buf)
+        let line = read_line(&mut stream, buf)
             .await?
             .ok_or_else(|| anyhow::anyhow!("Server closed connection"))?;
 
@@ -162,84 +1931,1143 @@ impl Client {
             return Err(anyhow::anyhow!("STARTTLS failed: {line}"));
         }
         debug!("STARTTLS response: {}", line);
+        stopwatch.lap("starttls");
 
-        // 4. Upgrade TLS - simplified for compilation
-        // In full implementation, we'd use tokio-rustls here
-
-        // 5. EHLO again (post-TLS)
-        stream.write_all(b"EHLO tunnel-client.local\r\n").await?;
+        // 4. Upgrade TLS
+        if !buf.is_empty() {
+            // Anything sent before the handshake could have been injected
+            return Err(anyhow::anyhow!("Unexpected data after STARTTLS response"));
+        }
+        self.start_tls(stream, port, connector, stopwatch).await
+    }
 
-        // Read EHLO response
-        loop {
-            let line = self
-                .read_smtp_line(&mut stream, &mut buf)
-                .await?
-                .ok_or_else(|| anyhow::anyhow!("Server closed connection"))?;
-            debug!("EHLO (post-TLS) response: {}", line);
+    /// TLS handshake, verifying the certificate against the server's name;
+    /// `port` is the server's, for DANE
+    async fn start_tls(
+        &self,
+        stream: TcpStream,
+        port: u16,
+        connector: &TlsConnector,
+        stopwatch: &mut Stopwatch,
+    ) -> anyhow::Result<TlsStream<TcpStream>> {
+        let host = self.server().host;
+        let server_name = ServerName::try_from(host.clone())
+            .map_err(|e| anyhow::anyhow!("Invalid server name {host}: {e}"))?;
+        let stream = connector
+            .connect(server_name, stream)
+            .await
+            .map_err(|e| anyhow::anyhow!("TLS handshake failed: {e}"))?;
+        stopwatch.lap("tls");
+        let (_, conn) = stream.get_ref();
+        debug!(
+            "TLS established ({:?}, {:?})",
+            conn.protocol_version(),
+            conn.negotiated_cipher_suite().map(|s| s.suite())
+        );
+        if let Some(cert) = conn.peer_certificates().and_then(|certs| certs.first())
+            && let Ok(info) = crate::tls::CertInfo::from_der(cert)
+        {
+            info.check_expiry("Server certificate", self.config.cert_warn_days);
+        }
+        if let Some(dane) = &self.config.dane {
+            let chain = conn.peer_certificates().unwrap_or_default();
+            self.check_dane(dane, port, chain).await?;
+            stopwatch.lap("dane");
+        }
+        Ok(stream)
+    }
 
-            if line.starts_with("250 ") {
-                break;
+    /// Match the server's certificate chain against its TLSA records
+    async fn check_dane(
+        &self,
+        dane: &DaneConfig,
+        port: u16,
+        chain: &[CertificateDer<'_>],
+    ) -> anyhow::Result<()> {
+        let host = self.server().host;
+        let resolver = match &dane.resolver {
+            Some(resolver) => crate::dane::parse_resolver(resolver),
+            None => crate::dane::system_resolver(),
+        };
+        let records = match resolver {
+            Ok(resolver) => crate::dane::lookup(resolver, &host, port).await,
+            Err(e) => Err(e),
+        };
+        let records = match records {
+            Ok(records) => records,
+            Err(e) if dane.require => anyhow::bail!("TLSA lookup for {host} failed: {e:#}"),
+            Err(e) => {
+                warn!("TLSA lookup for {} failed: {:#}", host, e);
+                return Ok(());
             }
-            if !line.starts_with("250-") {
-                return Err(anyhow::anyhow!("EHLO (post-TLS) failed: {line}"));
+        };
+        if records.is_empty() {
+            if dane.require {
+                anyhow::bail!("No DNSSEC-validated TLSA records for {host}:{port}");
             }
+            debug!("No TLSA records for {}:{}", host, port);
+            return Ok(());
         }
+        if !records.iter().any(|record| record.matches(chain)) {
+            anyhow::bail!("Server certificate matches none of the TLSA records for {host}:{port}");
+        }
+        debug!("Server certificate matches its TLSA records");
+        Ok(())
+    }
+}
 
-        // 6. AUTH
-        let token = AuthToken::generate_now(&self.config.secret, &self.config.username);
-        stream
-            .write_all(format!("AUTH PLAIN {token}\r\n").as_bytes())
-            .await?;
-        let line = self
-            .read_smtp_line(&mut stream, &mut buf)
-            .await?
-            .ok_or_else(|| anyhow::anyhow!("Server closed connection"))?;
+impl TunnelHandle {
+    /// Channels, lookups or UDP associations are open
+    fn in_use(&self) -> bool {
+        !self.channels.is_empty() || !self.resolves.is_empty() || !self.associations.is_empty()
+    }
 
-        if !line.starts_with("235") {
-            return Err(anyhow::anyhow!("Authentication failed: {line}"));
+    /// Start the frame loop for an authenticated session.
+    ///
+    /// Frames come from and go to the session's [`Link`], so channels
+    /// outlive the connection. The returned task ends when the link is
+    /// closed; `tasks` is cancelled with it, tearing down open channels.
+    fn spawn(
+        inbound: mpsc::Receiver<Frame>,
+        out: mpsc::Sender<Frame>,
+        features: Features,
+        tasks: TaskGroup,
+        events: SessionEvents,
+    ) -> (Self, JoinHandle<()>) {
+        let handle = Self {
+            out,
+            channels: ChannelRegistry::new(),
+            resolves: ChannelRegistry::new(),
+            associations: ChannelRegistry::new(),
+            features,
+            tasks,
+            events,
+        };
+
+        let tunnel = handle.clone();
+        let session = handle.tasks.spawn("session", async move {
+            tunnel.read_frames(inbound).await;
+            for id in tunnel.channels.collect(|id, _| id) {
+                if let Some(channel) = tunnel.close_channel(id) {
+                    channel.send_window.close();
+                }
+            }
+            // Dropping the senders fails pending lookups and ends
+            // associations' receives
+            tunnel.resolves.drain();
+            tunnel.associations.drain();
+            tunnel.tasks.cancel();
+        });
+        (handle, session)
+    }
+
+    /// Carry a new session over `stream`, with no SMTP handshake or TLS:
+    /// the client end of the in-memory transport
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    pub(crate) fn over_stream<S>(stream: S, tasks: TaskGroup, events: SessionEvents) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (link, inbound, outbound) = Link::new(SessionId::random());
+        let session = Arc::clone(&link);
+        tasks.spawn("in-memory link", async move {
+            let attachment = link.attach().await;
+            if let Err(e) = link
+                .run(
+                    attachment,
+                    stream,
+                    BytesMut::new(),
+                    0,
+                    LinkOptions::default(),
+                )
+                .await
+            {
+                debug!("In-memory link failed: {:#}", e);
+            }
+            session.close();
+        });
+        Self::spawn(inbound, outbound, Features::SUPPORTED, tasks, events).0
+    }
+
+    /// Open channels of this session
+    fn channel_stats(&self) -> Vec<ChannelStats> {
+        self.channels.collect(|id, c| self.stats(id, c))
+    }
+
+    fn stats(&self, id: u16, channel: &Channel) -> ChannelStats {
+        ChannelStats {
+            session: self.events.index,
+            id,
+            destination: channel.destination.clone(),
+            age: channel.opened.elapsed(),
+            sent: channel.sent,
+            received: channel.received,
+            rtt: channel.rtt,
         }
-        debug!("Auth success: {}", line);
+    }
 
-        // 7. Switch to binary mode
-        stream.write_all(b"BINARY\r\n").await?;
-        let line = self
-            .read_smtp_line(&mut stream, &mut buf)
-            .await?
-            .ok_or_else(|| anyhow::anyhow!("Server closed connection"))?;
+    /// Remove a channel, reporting it closed if it had opened
+    fn close_channel(&self, id: u16) -> Option<Channel> {
+        let channel = self.channels.close(id)?;
+        if channel.rtt.is_some() {
+            self.events
+                .events
+                .on_channel_close(&self.stats(id, &channel));
+        }
+        Some(channel)
+    }
+
+    /// Open a channel to `host:port` for a SOCKS5 request
+    async fn open(&self, req: ConnectRequest) -> io::Result<ProxyStream> {
+        let stream = self.open_stream(req).await?;
+        let bound = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
+        Ok(ProxyStream::from_io(bound, stream.into_io()))
+    }
 
-        if !line.starts_with("299") {
-            return Err(anyhow::anyhow!("Binary mode failed: {line}"));
+    /// Open a channel to `host:port`
+    pub(crate) async fn open_stream(&self, req: ConnectRequest) -> io::Result<TunnelStream> {
+        let (pending, response) = oneshot::channel();
+        let id = self
+            .channels
+            .allocate(Channel {
+                pending: Some(pending),
+                tx: None,
+                send_window: SendWindow::new(),
+                recv_window: RecvWindow::new(),
+                shut_down: false,
+                destination: format!("{}:{}", req.host, req.port),
+                opened: Instant::now(),
+                rtt: None,
+                sent: 0,
+                received: 0,
+            })
+            .ok_or_else(|| io::Error::other("No free channel IDs"))?;
+
+        // The server schedules its side of the channel to match
+        let priority = Priority::from_port(req.port);
+        let meta = ConnectMeta {
+            priority: (priority != Priority::Normal).then_some(priority),
+            ..ConnectMeta::default()
+        };
+        if self
+            .out
+            .send(Frame::connect_with_meta(id, &req.host, req.port, &meta))
+            .await
+            .is_err()
+        {
+            self.close_channel(id);
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "Tunnel closed"));
         }
-        debug!("Binary mode active: {}", line);
 
-        Ok(stream)
+        match tokio::time::timeout(CHANNEL_OPEN_TIMEOUT, response).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(io::Error::new(io::ErrorKind::NotConnected, "Tunnel closed")),
+            Err(_) => {
+                // A late CONNECT_OK finds no channel and is answered with CLOSE
+                self.close_channel(id);
+                let _ = self.out.send(Frame::close(id)).await;
+                Err(io::ErrorKind::TimedOut.into())
+            }
+        }
     }
 
-    /// Read an SMTP line
-    async fn read_smtp_line(
+    /// Have the server resolve `host`, so the lookup doesn't leak to the
+    /// local network
+    pub(crate) async fn resolve(
         &self,
-        stream: &mut TcpStream,
-        buf: &mut BytesMut,
-    ) -> anyhow::Result<Option<String>> {
-        loop {
-            if let Some(pos) = buf.windows(2).position(|w| w == b"\r\n") {
-                let line = buf.split_to(pos);
-                buf.advance(2); // Skip \r\n
-                return Ok(Some(String::from_utf8_lossy(&line).to_string()));
+        host: &str,
+        family: AddressFamily,
+    ) -> io::Result<Vec<IpAddr>> {
+        if !self.features.contains(Features::RESOLVE) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Server doesn't support RESOLVE",
+            ));
+        }
+        if host.is_empty() || host.len() > MAX_RESOLVE_HOST_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Invalid hostname",
+            ));
+        }
+        let (pending, response) = oneshot::channel();
+        let id = self
+            .resolves
+            .allocate(pending)
+            .ok_or_else(|| io::Error::other("No free request IDs"))?;
+        if self
+            .out
+            .send(Frame::resolve(id, host, family))
+            .await
+            .is_err()
+        {
+            self.resolves.close(id);
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "Tunnel closed"));
+        }
+
+        match tokio::time::timeout(RESOLVE_TIMEOUT, response).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(io::Error::new(io::ErrorKind::NotConnected, "Tunnel closed")),
+            Err(_) => {
+                self.resolves.close(id);
+                Err(io::ErrorKind::TimedOut.into())
+            }
+        }
+    }
+
+    /// Open a UDP association
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    pub(crate) fn udp(&self) -> io::Result<UdpAssociation> {
+        if !self.features.contains(Features::UDP) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Server doesn't support UDP",
+            ));
+        }
+        let (tx, rx) = mpsc::channel(UDP_QUEUE);
+        let id = self
+            .associations
+            .allocate(tx)
+            .ok_or_else(|| io::Error::other("No free association IDs"))?;
+        Ok(UdpAssociation {
+            id,
+            out: self.out.clone(),
+            rx,
+            associations: self.associations.clone(),
+        })
+    }
+
+    /// Dispatch frames from the server until the session ends
+    async fn read_frames(&self, mut inbound: mpsc::Receiver<Frame>) {
+        while let Some(frame) = inbound.recv().await {
+            let id = frame.channel_id;
+            match frame.frame_type {
+                FrameType::ConnectOk => self.connect_ok(id).await,
+                FrameType::ConnectFail => {
+                    let Some((code, reason)) = frame.parse_connect_fail() else {
+                        continue;
+                    };
+                    if let Some(pending) = self.close_channel(id).and_then(|c| c.pending) {
+                        let err = if reason.is_empty() {
+                            io::Error::from(code.io_kind())
+                        } else {
+                            io::Error::new(code.io_kind(), reason)
+                        };
+                        let _ = pending.send(Err(err));
+                    }
+                }
+                FrameType::Data => {
+                    let len = frame.payload.len();
+                    let tx = self.channels.with(id, |c| {
+                        let tx = c.tx.clone()?;
+                        c.received += len as u64;
+                        Some((tx, c.recv_window.receive(len)))
+                    });
+                    match tx.flatten() {
+                        // The SOCKS side may already be gone; its pump sends CLOSE
+                        Some((tx, true)) => {
+                            let _ = tx.send(frame.payload);
+                        }
+                        Some((_, false)) => {
+                            debug!("Channel {} overran its flow-control window", id);
+                            self.reset(id).await;
+                        }
+                        None => trace!("DATA for unknown channel {}", id),
+                    }
+                }
+                FrameType::WindowUpdate => match frame.parse_window_update() {
+                    Some(increment) => {
+                        if self.channels.with(id, |c| c.send_window.grant(increment)) == Some(false)
+                        {
+                            debug!("Channel {} granted more than its window", id);
+                            self.reset(id).await;
+                        }
+                    }
+                    None => debug!("Malformed WINDOW_UPDATE on channel {}", id),
+                },
+                FrameType::Shutdown => self.shutdown_by_server(id),
+                FrameType::Close => self.closed_by_server(id),
+                FrameType::Datagram => {
+                    let Some((host, port, data)) = frame.parse_datagram() else {
+                        debug!("Malformed DATAGRAM (association {})", id);
+                        continue;
+                    };
+                    let Ok(ip) = host.parse::<IpAddr>() else {
+                        debug!("DATAGRAM from non-IP source {}", host);
+                        continue;
+                    };
+                    match self.associations.with(id, |tx| tx.clone()) {
+                        // A full queue drops, as a socket buffer would
+                        Some(tx) => {
+                            let _ = tx.try_send((SocketAddr::new(ip, port), data));
+                        }
+                        None => trace!("DATAGRAM for unknown association {}", id),
+                    }
+                }
+                FrameType::ResolveResult => {
+                    let Some(pending) = self.resolves.close(id) else {
+                        trace!("RESOLVE_RESULT for unknown request {}", id);
+                        continue;
+                    };
+                    let result = match frame.parse_resolve_result() {
+                        Some(Ok(addrs)) => Ok(addrs),
+                        Some(Err(reason)) => Err(io::Error::new(io::ErrorKind::NotFound, reason)),
+                        None => Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Malformed RESOLVE_RESULT",
+                        )),
+                    };
+                    let _ = pending.send(result);
+                }
+                // Handled by the link
+                FrameType::Keepalive
+                | FrameType::KeepaliveAck
+                | FrameType::Ack
+                | FrameType::Hello
+                | FrameType::Padding => {}
+                FrameType::Connect | FrameType::Resolve => debug!(
+                    "Unexpected {:?} from server on channel {}",
+                    frame.frame_type, id
+                ),
+            }
+        }
+    }
+
+    /// The server has nothing more to send on a channel
+    fn shutdown_by_server(&self, id: u16) {
+        // Dropping the sender gives the SOCKS side EOF once queued data is
+        // delivered; the entry goes once both sides have shut down
+        let done = self.channels.with(id, |c| {
+            c.tx = None;
+            c.shut_down
+        });
+        if done == Some(true) {
+            self.close_channel(id);
+        }
+    }
+
+    /// The server aborted a channel
+    fn closed_by_server(&self, id: u16) {
+        // Drop the sender so the SOCKS side sees EOF; the entry stays until
+        // its pump finishes so the ID isn't reused early
+        let pending = self.channels.with(id, |c| {
+            c.tx = None;
+            c.send_window.close();
+            c.pending.is_some()
+        });
+        if pending == Some(true) {
+            self.close_channel(id);
+        }
+    }
+
+    /// Abort a channel from our side, e.g. when the server broke flow
+    /// control or the SOCKS client went away
+    async fn reset(&self, id: u16) {
+        self.closed_by_server(id);
+        let _ = self.out.send(Frame::close(id)).await;
+    }
+
+    /// Hand a freshly opened channel to its waiting SOCKS request
+    async fn connect_ok(&self, id: u16) {
+        // Data counts as consumed once it's queued for the SOCKS side, so
+        // keep that queue to a single chunk
+        let (down_tx, down_rx) = mpsc::channel(1);
+        let (up_tx, up_rx) = mpsc::channel(CHANNEL_QUEUE_SIZE);
+        let (deliver_tx, deliver_rx) = mpsc::unbounded_channel();
+        let pending = self.channels.with(id, |channel| {
+            let pending = channel.pending.take()?;
+            channel.tx = Some(deliver_tx);
+            channel.rtt = Some(channel.opened.elapsed());
+            Some((
+                pending,
+                channel.send_window.clone(),
+                channel.recv_window.clone(),
+                self.stats(id, channel),
+            ))
+        });
+        let (pending, send_window, recv_window, stats) = match pending {
+            Some(Some(pending)) => pending,
+            Some(None) => return, // Duplicate CONNECT_OK
+            None => {
+                // The SOCKS request already gave up
+                let _ = self.out.send(Frame::close(id)).await;
+                return;
+            }
+        };
+
+        self.events.events.on_channel_open(&stats);
+        if pending.send(Ok(TunnelStream::new(down_rx, up_tx))).is_err() {
+            self.close_channel(id);
+            let _ = self.out.send(Frame::close(id)).await;
+            return;
+        }
+        self.tasks.spawn(
+            "deliver",
+            self.clone().deliver(id, deliver_rx, down_tx, recv_window),
+        );
+        self.tasks
+            .spawn("pump", self.clone().pump(id, up_rx, send_window));
+    }
+
+    /// Forward data from the SOCKS side as DATA frames, then shut down our
+    /// side of the channel
+    async fn pump(self, id: u16, mut up_rx: mpsc::Receiver<Bytes>, window: SendWindow) {
+        while let Some(mut data) = up_rx.recv().await {
+            while !data.is_empty() {
+                // A closed window means the channel was aborted
+                let Ok(granted) = window.reserve(data.len()).await else {
+                    self.close_channel(id);
+                    let _ = self.out.send(Frame::close(id)).await;
+                    return;
+                };
+                let chunk = data.split_to(granted);
+                if self.out.send(Frame::data(id, chunk)).await.is_err() {
+                    return;
+                }
+                self.channels.with(id, |c| c.sent += granted as u64);
             }
+        }
+        let done = self.channels.with(id, |c| {
+            c.shut_down = true;
+            c.tx.is_none()
+        });
+        if done == Some(true) {
+            self.close_channel(id);
+        }
+        let _ = self.out.send(Frame::shutdown(id)).await;
+    }
 
-            let mut temp = vec![0u8; 1024];
-            let n = stream.read(&mut temp).await?;
-            if n == 0 {
-                return Ok(None);
+    /// Pass server data to the SOCKS side, returning window credit as it's
+    /// taken. Ends, giving the SOCKS side EOF, when the channel closes, or
+    /// closes the channel if the SOCKS side is dropped first (e.g. idle).
+    async fn deliver(
+        self,
+        id: u16,
+        mut rx: mpsc::UnboundedReceiver<Bytes>,
+        down: mpsc::Sender<Bytes>,
+        window: RecvWindow,
+    ) {
+        loop {
+            let data = tokio::select! {
+                data = rx.recv() => match data {
+                    Some(data) => data,
+                    None => return,
+                },
+                _ = down.closed() => {
+                    self.reset(id).await;
+                    return;
+                }
+            };
+            let len = data.len();
+            if down.send(data).await.is_err() {
+                // The SOCKS client is gone; stop the server sending more
+                self.reset(id).await;
+                return;
+            }
+            if let Some(increment) = window.consume(len)
+                && self
+                    .out
+                    .send(Frame::window_update(id, increment))
+                    .await
+                    .is_err()
+            {
+                return;
             }
-            buf.extend_from_slice(&temp[..n]);
         }
     }
 }
 
+/// Accept connections redirected by transparent mode and carry them over
+/// the tunnel to their original destinations
+async fn serve_transparent(
+    listener: TcpListener,
+    redirector: Arc<Redirector>,
+    tunnel: TunnelHandle,
+    traffic: Arc<TrafficStats>,
+    idle_timeout: Option<Duration>,
+) -> anyhow::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let Some(dst) = redirector.original_destination(peer) else {
+            debug!("Dropping unexpected transparent connection from {}", peer);
+            continue;
+        };
+        let traffic = Arc::clone(&traffic);
+        let connection = tunnel.clone();
+        tunnel.tasks.spawn("transparent", async move {
+            let req = ConnectRequest {
+                host: dst.ip().to_string(),
+                port: dst.port(),
+            };
+            let result = match connection.open(req).await {
+                Ok(remote) => remote.with_idle_timeout(idle_timeout).proxy(stream).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok((sent, received)) => {
+                    traffic.record(sent, received);
+                    debug!(
+                        "Transparent {} closed ({} bytes sent, {} bytes received)",
+                        dst, sent, received
+                    );
+                }
+                Err(e) => debug!("Transparent connection to {} failed: {}", dst, e),
+            }
+        });
+    }
+}
+
 /// Run the client
 pub async fn run_client(config: ClientConfig) -> anyhow::Result<()> {
     let client = Client::new(config);
     client.run().await
 }
+
+/// Read the server's 220 greeting
+/// The server to prefer over `current`: the fastest to answer its probe,
+/// if it beat `current` by more than `margin` or `current` didn't answer
+fn fastest(rtts: &[Option<Duration>], current: usize, margin: Duration) -> usize {
+    let best = rtts
+        .iter()
+        .enumerate()
+        .filter_map(|(i, rtt)| Some((i, (*rtt)?)))
+        .min_by_key(|&(_, rtt)| rtt);
+    match (best, rtts.get(current).copied().flatten()) {
+        (Some((best, rtt)), Some(current_rtt)) if rtt + margin < current_rtt => best,
+        (Some((best, _)), None) => best,
+        _ => current,
+    }
+}
+
+async fn read_greeting<S>(stream: &mut S, buf: &mut BytesMut) -> anyhow::Result<()>
+where
+    S: AsyncRead + Unpin,
+{
+    let line = read_line(stream, buf)
+        .await?
+        .ok_or_else(|| anyhow::anyhow!("Server closed connection"))?;
+
+    if !line.starts_with("220") {
+        return Err(anyhow::anyhow!("Unexpected greeting: {line}"));
+    }
+    debug!("Server greeting: {}", line);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{ConnectError, FrameCodec, FrameError};
+    use futures_util::{SinkExt, Stream, StreamExt};
+    use tokio::io::AsyncReadExt;
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    fn request(host: &str, port: u16) -> ConnectRequest {
+        ConnectRequest {
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_fallback() {
+        let taken = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut listener = SocksListener {
+            addr: taken.local_addr().unwrap(),
+            fallback_ports: Vec::new(),
+            route: Route::Rules,
+            credentials: None,
+            allowed_clients: Vec::new(),
+        };
+        let err = listener.bind().await.unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(crate::Error::SocksPortInUse { port, .. }) if *port == listener.addr.port()
+        ));
+
+        // 0 takes any free port
+        listener.fallback_ports = vec![listener.addr.port(), 0];
+        let bound = listener.bind().await.unwrap();
+        assert_ne!(bound.local_addr().unwrap().port(), listener.addr.port());
+    }
+
+    /// Carry a new session over `stream` as `connect_and_serve` does
+    fn spawn_tunnel<S>(stream: S) -> TunnelHandle
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        TunnelHandle::over_stream(stream, TaskGroup::new(), SessionEvents::none())
+    }
+
+    /// Frames from the client, without the link's ACKs
+    fn session_frames<R>(reader: R) -> impl Stream<Item = Result<Frame, FrameError>> + Unpin
+    where
+        R: AsyncRead + Unpin,
+    {
+        FramedRead::new(reader, FrameCodec::new()).filter(|frame| {
+            let ack = matches!(frame, Ok(frame) if frame.frame_type == FrameType::Ack);
+            std::future::ready(!ack)
+        })
+    }
+
+    #[test]
+    fn test_parse_binary_mode() {
+        let id = SessionId::random();
+        let line = format!("299 Binary mode activated session={id} received=42");
+        let binary = BinaryMode::parse(&line, true).unwrap();
+        assert_eq!(binary.session, Some(id));
+        assert_eq!(binary.received, 42);
+
+        // Servers without resumption don't send a session ID
+        let binary = BinaryMode::parse("299 Binary mode activated", false).unwrap();
+        assert_eq!(binary.session, None);
+        assert!(BinaryMode::parse("299 Binary mode activated session=xyz", false).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_channel_round_trip() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let tunnel = spawn_tunnel(client);
+        let (done, hang_up) = oneshot::channel::<()>();
+
+        // Minimal server: accept one channel and answer once the client has
+        // finished sending
+        let server = tokio::spawn(async move {
+            let (reader, writer) = tokio::io::split(server);
+            let mut frames = session_frames(reader);
+            let mut sink = FramedWrite::new(writer, FrameCodec::new());
+
+            let connect = frames.next().await.unwrap().unwrap();
+            assert_eq!(
+                connect.parse_connect().unwrap(),
+                ("example.com".into(), 443)
+            );
+            let id = connect.channel_id;
+            sink.send(Frame::connect_ok(id)).await.unwrap();
+
+            let data = frames.next().await.unwrap().unwrap();
+            assert_eq!(data.frame_type, FrameType::Data);
+            assert_eq!(&data.payload[..], b"ping");
+            let shutdown = frames.next().await.unwrap().unwrap();
+            assert_eq!(shutdown.frame_type, FrameType::Shutdown);
+            assert_eq!(shutdown.channel_id, id);
+
+            sink.send(Frame::data(id, &b"pong"[..])).await.unwrap();
+            sink.send(Frame::shutdown(id)).await.unwrap();
+            // Hang up only when told: the session ending would drop the
+            // channel before "pong" reaches the SOCKS side
+            let _ = hang_up.await;
+        });
+
+        let stream = tunnel.open(request("example.com", 443)).await.unwrap();
+        // Duplex pair standing in for the SOCKS client socket
+        let (mut socks, proxied) = tokio::io::duplex(1024);
+        let proxy = tokio::spawn(stream.proxy(proxied));
+
+        // Half-close: the reply still arrives after we stop writing
+        socks.write_all(b"ping").await.unwrap();
+        socks.shutdown().await.unwrap();
+        let mut reply = Vec::new();
+        socks.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"pong");
+
+        proxy.await.unwrap().unwrap();
+        drop(done);
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_open_stream() {
+        let client = Client::new(ClientConfig::default());
+        let err = client.open_stream("example.com", 443).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+
+        // A session as open_session leaves it
+        let (near, far) = tokio::io::duplex(64 * 1024);
+        client.state.write().await.embedded = Some(spawn_tunnel(near));
+        tokio::spawn(async move {
+            let (reader, writer) = tokio::io::split(far);
+            let mut frames = session_frames(reader);
+            let mut sink = FramedWrite::new(writer, FrameCodec::new());
+            let connect = frames.next().await.unwrap().unwrap();
+            assert_eq!(connect.frame_type, FrameType::Connect);
+            let id = connect.channel_id;
+            sink.send(Frame::connect_ok(id)).await.unwrap();
+            sink.send(Frame::data(id, &b"hello"[..])).await.unwrap();
+            sink.send(Frame::shutdown(id)).await.unwrap();
+            // Keep the session open while the client reads
+            while frames.next().await.is_some() {}
+        });
+        let mut stream = client.open_stream("example.com", 443).await.unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"hello");
+
+        // Still open: we haven't shut down our side
+        let channels = client.channels().await;
+        assert_eq!(channels.len(), 1);
+        assert_eq!(channels[0].destination, "example.com:443");
+        assert_eq!((channels[0].sent, channels[0].received), (0, 5));
+        assert!(channels[0].rtt.is_some());
+    }
+
+    /// Channel events as `open host:port` and `close host:port`
+    #[derive(Default)]
+    struct Recorder(std::sync::Mutex<Vec<String>>);
+
+    impl ClientEvents for Recorder {
+        fn on_channel_open(&self, channel: &ChannelStats) {
+            let event = format!("open {}", channel.destination);
+            self.0.lock().unwrap().push(event);
+        }
+
+        fn on_channel_close(&self, channel: &ChannelStats) {
+            let event = format!("close {} {}", channel.destination, channel.received);
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_channel_events() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let recorder = Arc::new(Recorder::default());
+        let events = SessionEvents {
+            index: 0,
+            events: Arc::clone(&recorder) as Arc<dyn ClientEvents>,
+        };
+        let tunnel = TunnelHandle::over_stream(client, TaskGroup::new(), events);
+        tokio::spawn(async move {
+            let (reader, writer) = tokio::io::split(server);
+            let mut frames = session_frames(reader);
+            let mut sink = FramedWrite::new(writer, FrameCodec::new());
+            // One refused and one opened, then closed by the server
+            let refused = frames.next().await.unwrap().unwrap();
+            sink.send(Frame::connect_fail(
+                refused.channel_id,
+                ConnectError::Refused,
+                "",
+            ))
+            .await
+            .unwrap();
+            let id = frames.next().await.unwrap().unwrap().channel_id;
+            sink.send(Frame::connect_ok(id)).await.unwrap();
+            sink.send(Frame::data(id, &b"hello"[..])).await.unwrap();
+            sink.send(Frame::close(id)).await.unwrap();
+            while frames.next().await.is_some() {}
+        });
+
+        assert!(tunnel.open_stream(request("10.0.0.1", 22)).await.is_err());
+        let mut stream = tunnel
+            .open_stream(request("example.com", 443))
+            .await
+            .unwrap()
+            .into_io();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.unwrap();
+        drop(stream);
+        // The entry goes once our side has stopped too
+        while !tunnel.channels.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            ["open example.com:443", "close example.com:443 5"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let mut config = ClientConfig {
+            servers: vec![
+                ServerEntry {
+                    host: "a.example".into(),
+                    port: 587,
+                },
+                ServerEntry {
+                    host: "b.example".into(),
+                    port: 587,
+                },
+            ],
+            ..ClientConfig::default()
+        };
+        let client = Client::new(config.clone());
+        client.server.store(1, Ordering::Relaxed);
+
+        // New direct rules alone keep the connection
+        config.direct = vec!["10.0.0.0/8".into()];
+        let mut relogins = client.relogin.subscribe();
+        client.reload(&config).unwrap();
+        let direct = Arc::clone(&client.direct.borrow());
+        assert!(direct.check_host("10.1.2.3").is_some());
+        assert_eq!(client.server().host, "b.example");
+        assert!(!relogins.has_changed().unwrap());
+
+        // A new server list starts over from its first server
+        config.servers.remove(0);
+        client.reload(&config).unwrap();
+        assert_eq!(client.server().host, "b.example");
+        assert_eq!(client.server.load(Ordering::Relaxed), 0);
+        assert!(relogins.has_changed().unwrap());
+
+        // Seen even if nothing was waiting when it changed
+        relogins.mark_unchanged();
+        config.secret = "changed".into();
+        client.reload(&config).unwrap();
+        assert_eq!(client.login().secret, "changed");
+        assert!(relogins.has_changed().unwrap());
+
+        // A bad config changes nothing
+        config.direct = vec!["*.".into()];
+        assert!(client.reload(&config).is_err());
+        assert!(client.direct.borrow().check_host("10.1.2.3").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_tun_refuses_direct() {
+        let mut config = ClientConfig {
+            tun: Some(crate::config::TunConfig {
+                name: None,
+                address: Ipv4Addr::new(198, 18, 0, 1),
+                port: 1082,
+                routes: Vec::new(),
+            }),
+            servers: vec![ServerEntry {
+                host: "a.example".into(),
+                port: 587,
+            }],
+            ..ClientConfig::default()
+        };
+        let client = Client::new(config.clone());
+        client.reload(&config).unwrap();
+
+        // Direct connections would land in the tun device
+        config.direct = vec!["10.0.0.0/8".into()];
+        assert!(client.reload(&config).is_err());
+        assert!(client.direct.borrow().check_host("10.1.2.3").is_none());
+        config.direct.clear();
+        config.listeners = vec![ListenerConfig {
+            host: "127.0.0.1".to_string(),
+            port: 1080,
+            fallback_ports: Vec::new(),
+            route: Route::Direct,
+            auth: None,
+            allowed_clients: Vec::new(),
+        }];
+        assert!(client.reload(&config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_connect_fail() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let tunnel = spawn_tunnel(client);
+
+        tokio::spawn(async move {
+            let (reader, writer) = tokio::io::split(server);
+            let mut frames = session_frames(reader);
+            let mut sink = FramedWrite::new(writer, FrameCodec::new());
+            let connect = frames.next().await.unwrap().unwrap();
+            sink.send(Frame::connect_fail(
+                connect.channel_id,
+                ConnectError::Refused,
+                "Connection refused",
+            ))
+            .await
+            .unwrap();
+            // Keep the session open while the client reads the reply
+            let _ = frames.next().await;
+        });
+
+        let err = tunnel.open(request("10.0.0.1", 22)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(err.to_string(), "Connection refused");
+        assert!(tunnel.channels.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connect_direct() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let stream = connect_direct(&request("127.0.0.1", port)).await.unwrap();
+        assert!(stream.local_addr().ip().is_loopback());
+
+        drop(listener);
+        let err = connect_direct(&request("127.0.0.1", port))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn test_fail_over() {
+        let servers = ["a.example.com", "b.example.com", "c.example.com"]
+            .map(|host| ServerEntry {
+                host: host.to_string(),
+                port: 587,
+            })
+            .to_vec();
+        let client = Client::new(ClientConfig {
+            servers,
+            ..Default::default()
+        });
+        assert_eq!(client.server_addr().unwrap(), "a.example.com:587");
+        assert!(client.fail_over());
+        assert_eq!(client.server().host, "b.example.com");
+        assert!(client.fail_over());
+        // Back to the top once the list is used up
+        assert!(!client.fail_over());
+        assert_eq!(client.server().host, "a.example.com");
+
+        // Without a list there's nothing to fail over to
+        let client = Client::new(ClientConfig {
+            server_host: "mail.example.com".to_string(),
+            ..Default::default()
+        });
+        assert!(!client.fail_over());
+        assert_eq!(client.server_addr().unwrap(), "mail.example.com:587");
+    }
+
+    #[test]
+    fn test_handshake_timing() {
+        let timing = HandshakeTiming {
+            phases: vec![
+                ("connect", Duration::from_millis(12)),
+                ("greeting", Duration::from_millis(40)),
+                ("tls", Duration::from_micros(35_600)),
+            ],
+        };
+        assert_eq!(timing.total(), Duration::from_micros(87_600));
+        assert_eq!(
+            timing.to_string(),
+            "connect 12.0 ms, greeting 40.0 ms, tls 35.6 ms (total 87.6 ms)"
+        );
+
+        let mut stopwatch = Stopwatch::start();
+        stopwatch.lap("connect");
+        stopwatch.skip();
+        stopwatch.lap("greeting");
+        let phases: Vec<_> = stopwatch.timing.phases.iter().map(|(p, _)| *p).collect();
+        assert_eq!(phases, ["connect", "greeting"]);
+    }
+
+    #[test]
+    fn test_fastest_server() {
+        let ms = |ms| Some(Duration::from_millis(ms));
+        let margin = Duration::from_millis(30);
+        let rtts = [ms(80), ms(60), None];
+        // 20 ms faster isn't enough to move
+        assert_eq!(fastest(&rtts, 0, margin), 0);
+        assert_eq!(fastest(&[ms(100), ms(60)], 0, margin), 1);
+        // A server that didn't answer gives way to any that did
+        assert_eq!(fastest(&rtts, 2, margin), 1);
+        assert_eq!(fastest(&[None, None], 1, margin), 1);
+
+        // Failover goes round from the chosen server
+        let servers = ["a.example.com", "b.example.com", "c.example.com"]
+            .map(|host| ServerEntry {
+                host: host.to_string(),
+                port: 587,
+            })
+            .to_vec();
+        let client = Client::new(ClientConfig {
+            servers,
+            latency_probe: Some(LatencyProbeConfig {
+                interval: 300,
+                margin_ms: 30,
+            }),
+            ..Default::default()
+        });
+        client.latency.send_replace(
+            [ms(90), ms(95), ms(20)]
+                .into_iter()
+                .map(|rtt| ServerLatency {
+                    server: String::new(),
+                    rtt,
+                })
+                .collect(),
+        );
+        client.choose_server(false);
+        assert_eq!(client.server().host, "a.example.com");
+        client.choose_server(true);
+        assert_eq!(client.server().host, "c.example.com");
+        assert!(client.fail_over());
+        assert!(client.fail_over());
+        assert!(!client.fail_over());
+        assert_eq!(client.server().host, "c.example.com");
+    }
+
+    #[tokio::test]
+    async fn test_pool_pick() {
+        let (main_end, _main_server) = tokio::io::duplex(64 * 1024);
+        let (a_end, _a_server) = tokio::io::duplex(64 * 1024);
+        let (b_end, _b_server) = tokio::io::duplex(64 * 1024);
+        let (main, a, b) = (
+            spawn_tunnel(main_end),
+            spawn_tunnel(a_end),
+            spawn_tunnel(b_end),
+        );
+        let pool = TunnelPool::default();
+        assert!(pool.pick(&main).out.same_channel(&main.out));
+
+        // A channel waiting on the main session sends the next elsewhere
+        let opening = main.clone();
+        tokio::spawn(async move { opening.open_stream(request("example.com", 443)).await });
+        while main.channels.is_empty() {
+            tokio::task::yield_now().await;
+        }
+        pool.join(1, a.clone());
+        pool.join(2, b.clone());
+        assert!(pool.pick(&main).out.same_channel(&a.out));
+
+        // A session that drops out gets no more
+        pool.leave(1);
+        assert!(pool.pick(&main).out.same_channel(&b.out));
+        pool.leave(2);
+        assert!(pool.pick(&main).out.same_channel(&main.out));
+    }
+
+    #[tokio::test]
+    async fn test_demand() {
+        let mut socks = Vec::new();
+        for _ in 0..2 {
+            let config = ListenerConfig {
+                host: "127.0.0.1".to_string(),
+                port: 0,
+                fallback_ports: Vec::new(),
+                route: Route::Rules,
+                auth: None,
+                allowed_clients: Vec::new(),
+            };
+            let listener = SocksListener::new(&config).unwrap();
+            let socket = listener.bind().await.unwrap();
+            socks.push((listener, socket));
+        }
+        let target = socks[1].1.local_addr().unwrap();
+        let listeners = Listeners {
+            socks,
+            dns_stub: Some(DnsStub::bind("127.0.0.1:0".parse().unwrap()).await.unwrap()),
+        };
+
+        let client = TcpStream::connect(target).await.unwrap();
+        let (index, _, addr) = listeners.demand().await.unwrap().unwrap();
+        assert_eq!((index, addr), (1, client.local_addr().unwrap()));
+
+        let stub = listeners.dns_stub.as_ref().unwrap().local_addr().unwrap();
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        udp.send_to(b"query", stub).await.unwrap();
+        assert!(listeners.demand().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_resolve_locally() {
+        let resolved = resolve_locally(request("localhost", 80)).await.unwrap();
+        assert!(resolved.host.parse::<IpAddr>().unwrap().is_loopback());
+        assert_eq!(resolved.port, 80);
+
+        let unchanged = resolve_locally(request("::1", 443)).await.unwrap();
+        assert_eq!(unchanged.host, "::1");
+        let err = resolve_locally(request("nonexistent.invalid", 80))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::HostUnreachable);
+    }
+}
@@ -0,0 +1,97 @@
+//! hyper connector through the tunnel
+//!
+//! [`TunnelConnector`] is a connector for hyper-util's client: each
+//! connection the client makes is a channel opened with
+//! [`Client::open_stream`], so a Rust application can send its HTTP through
+//! the tunnel without going through a local SOCKS listener. The server
+//! resolves and dials each host. For HTTPS, wrap the connector in a TLS
+//! connector such as hyper-rustls' `HttpsConnector`.
+
+use crate::client::Client;
+use crate::socks5::TunnelIo;
+use http::Uri;
+use hyper_util::client::legacy::connect::{Connected, Connection};
+use hyper_util::rt::TokioIo;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_service::Service;
+
+/// Opens hyper's connections as channels through a [`Client`]'s session
+/// from [`Client::open_session`]
+#[derive(Clone)]
+pub struct TunnelConnector {
+    client: Arc<Client>,
+}
+
+impl TunnelConnector {
+    pub fn new(client: Arc<Client>) -> Self {
+        Self { client }
+    }
+}
+
+impl Service<Uri> for TunnelConnector {
+    type Response = TokioIo<TunnelIo>;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let client = Arc::clone(&self.client);
+        Box::pin(async move {
+            let (host, port) = target(&uri)?;
+            let stream = client.open_stream(&host, port).await?;
+            Ok(TokioIo::new(stream))
+        })
+    }
+}
+
+impl Connection for TunnelIo {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+/// Host and port `uri` points at, the port defaulting by scheme
+fn target(uri: &Uri) -> io::Result<(String, u16)> {
+    let host = uri
+        .host()
+        .filter(|host| !host.is_empty())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "URI has no host"))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = match (uri.port_u16(), uri.scheme_str()) {
+        (Some(port), _) => port,
+        (None, Some("https")) => 443,
+        (None, _) => 80,
+    };
+    Ok((host.to_string(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ClientConfig;
+
+    #[tokio::test]
+    async fn test_tunnel_connector() {
+        let target = |uri: &str| target(&uri.parse().unwrap()).unwrap();
+        assert_eq!(target("http://example.com/"), ("example.com".into(), 80));
+        assert_eq!(target("https://example.com/a"), ("example.com".into(), 443));
+        assert_eq!(target("http://[::1]:8080/"), ("::1".into(), 8080));
+        assert!(super::target(&"/relative".parse().unwrap()).is_err());
+
+        // Without a session there's nothing to connect through
+        let client = Arc::new(Client::new(ClientConfig::default()));
+        let mut connector = TunnelConnector::new(client);
+        let err = connector
+            .call("http://example.com/".parse().unwrap())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+    }
+}
@@ -0,0 +1,335 @@
+//! Control socket for GUIs and scripts
+//!
+//! With `control` configured the client listens on a Unix socket and
+//! answers requests about its state: the connection's status, the open
+//! channels and the bytes carried. It can also be told to reconnect,
+//! reload its configuration or shut down, unless the socket is read-only.
+//!
+//! Each connection carries one request and one response, both JSON; the
+//! caller shuts down its write half to end the request:
+//!
+//! ```bash
+//! echo '{"command":"channels"}' | nc -UN /run/smtp-tunnel/client.sock
+//! ```
+
+use crate::client::{ChannelStats, Client, ClientStatus};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// Largest request accepted
+pub const MAX_REQUEST: u64 = 64 * 1024;
+
+/// Pause after a failed accept, so a persistent error doesn't spin
+#[cfg(unix)]
+const ACCEPT_ERROR_DELAY: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// A request to the client
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Status,
+    Channels,
+    Reconnect,
+    Reload,
+    Shutdown,
+}
+
+/// The client's answer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Response {
+    Status(Status),
+    Channels { channels: Vec<Channel> },
+    Done,
+    Error { message: String },
+}
+
+impl Response {
+    pub fn error(message: impl Into<String>) -> Self {
+        Self::Error {
+            message: message.into(),
+        }
+    }
+}
+
+impl Request {
+    /// Whether the request changes anything
+    pub fn is_write(&self) -> bool {
+        !matches!(self, Self::Status | Self::Channels)
+    }
+}
+
+/// The connection, and the traffic through it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Status {
+    /// `idle`, `connecting`, `connected`, `ready`, `standby` or
+    /// `reconnecting`
+    pub state: String,
+    /// `host:port` of the server in use
+    pub server: String,
+    /// Why the connection was lost, while reconnecting
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The server's announcement, once connected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub announcement: Option<String>,
+    /// Addresses the SOCKS proxies listen on, once they do
+    #[serde(default)]
+    pub socks: Vec<String>,
+    /// Channels open
+    pub channels: usize,
+    /// Bytes sent through the tunnel by closed channels
+    pub sent: u64,
+    /// Bytes received through the tunnel by closed channels
+    pub received: u64,
+    /// Times the connection was made again after the first
+    #[serde(default)]
+    pub reconnects: u64,
+    /// Milliseconds for the server to answer a keepalive, once one has
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtt_ms: Option<u64>,
+}
+
+/// One open channel; [`ChannelStats`] in JSON-friendly units
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Channel {
+    pub session: usize,
+    pub id: u16,
+    pub destination: String,
+    /// Milliseconds since the channel was requested
+    pub age_ms: u64,
+    pub sent: u64,
+    pub received: u64,
+    /// Milliseconds from CONNECT to CONNECT_OK, once it's open
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rtt_ms: Option<u64>,
+}
+
+impl From<&ChannelStats> for Channel {
+    fn from(stats: &ChannelStats) -> Self {
+        Self {
+            session: stats.session,
+            id: stats.id,
+            destination: stats.destination.clone(),
+            age_ms: stats.age.as_millis() as u64,
+            sent: stats.sent,
+            received: stats.received,
+            rtt_ms: stats.rtt.map(|rtt| rtt.as_millis() as u64),
+        }
+    }
+}
+
+/// What the control socket leaves to the program running the client,
+/// which knows where the configuration came from and how to stop
+pub trait Controller: Send + Sync {
+    /// Re-read the configuration and apply it
+    fn reload(&self) -> anyhow::Result<()>;
+
+    /// Stop the client and exit
+    fn shutdown(&self);
+}
+
+/// Carry out `request`
+pub async fn answer(
+    client: &Client,
+    controller: &dyn Controller,
+    read_only: bool,
+    request: Request,
+) -> Response {
+    if read_only && request.is_write() {
+        return Response::error("The control socket is read-only");
+    }
+    match request {
+        Request::Status => Response::Status(status(client).await),
+        Request::Channels => Response::Channels {
+            channels: client.channels().await.iter().map(Channel::from).collect(),
+        },
+        Request::Reconnect => {
+            client.force_reconnect();
+            Response::Done
+        }
+        Request::Reload => match controller.reload() {
+            Ok(()) => Response::Done,
+            Err(e) => Response::error(format!("{e:#}")),
+        },
+        Request::Shutdown => {
+            controller.shutdown();
+            Response::Done
+        }
+    }
+}
+
+async fn status(client: &Client) -> Status {
+    let server = client.server();
+    let mut status = Status {
+        state: String::new(),
+        server: format!("{}:{}", server.host, server.port),
+        error: None,
+        announcement: None,
+        socks: Vec::new(),
+        channels: client.channels().await.len(),
+        sent: client.traffic().sent(),
+        received: client.traffic().received(),
+        reconnects: client.reconnects(),
+        rtt_ms: client.server_rtt().await.map(|rtt| rtt.as_millis() as u64),
+    };
+    let current = client.status().borrow().clone();
+    status.state = match current {
+        ClientStatus::Idle => "idle",
+        ClientStatus::Connecting { .. } => "connecting",
+        ClientStatus::Connected { announcement, .. } => {
+            status.announcement = announcement;
+            "connected"
+        }
+        ClientStatus::Ready { socks_addrs } => {
+            status.socks = socks_addrs.iter().map(ToString::to_string).collect();
+            "ready"
+        }
+        ClientStatus::Standby { socks_addrs } => {
+            status.socks = socks_addrs.iter().map(ToString::to_string).collect();
+            "standby"
+        }
+        ClientStatus::Reconnecting { error, .. } => {
+            status.error = Some(error);
+            "reconnecting"
+        }
+    }
+    .to_string();
+    status
+}
+
+/// Listen on the control socket at `path`, replacing a stale one,
+/// accessible to its owner only
+#[cfg(unix)]
+pub fn bind(path: &str) -> anyhow::Result<tokio::net::UnixListener> {
+    crate::platform::bind_private_socket(std::path::Path::new(path)).map_err(|e| match e.kind() {
+        std::io::ErrorKind::AddrInUse => {
+            anyhow::anyhow!("Control socket {} is in use by another client", path)
+        }
+        _ => anyhow::anyhow!("Can't bind control socket {}: {}", path, e),
+    })
+}
+
+/// Answer requests on `listener` until the client stops
+#[cfg(unix)]
+pub async fn serve(
+    listener: tokio::net::UnixListener,
+    client: Arc<Client>,
+    controller: Arc<dyn Controller>,
+    read_only: bool,
+) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tracing::debug;
+
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                debug!("Control socket accept error: {}", e);
+                tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        let client = Arc::clone(&client);
+        let controller = Arc::clone(&controller);
+        tokio::spawn(async move {
+            let mut request = String::new();
+            let response = match (&mut stream)
+                .take(MAX_REQUEST)
+                .read_to_string(&mut request)
+                .await
+            {
+                Ok(_) => match serde_json::from_str(&request) {
+                    Ok(request) => answer(&client, &*controller, read_only, request).await,
+                    Err(e) => Response::error(format!("Invalid request: {e}")),
+                },
+                Err(e) => Response::error(format!("Invalid request: {e}")),
+            };
+            let mut response = serde_json::to_string(&response).unwrap_or_default();
+            response.push('\n');
+            if let Err(e) = stream.write_all(response.as_bytes()).await {
+                debug!("Control socket write error: {}", e);
+            }
+        });
+    }
+}
+
+/// Send `request` to the client at `path`. `Ok(None)` means nothing is
+/// listening there, i.e. the client isn't running.
+#[cfg(unix)]
+pub fn call(path: &std::path::Path, request: &Request) -> anyhow::Result<Option<Response>> {
+    let request = serde_json::to_string(request)?;
+    let response = crate::platform::unix_request(path, request.as_bytes())
+        .map_err(|e| anyhow::anyhow!("Can't reach {}: {}", path.display(), e))?;
+    response
+        .map(|response| serde_json::from_str(&response))
+        .transpose()
+        .map_err(Into::into)
+}
+
+/// Send `request` to the client at `path`. `Ok(None)` means nothing is
+/// listening there, i.e. the client isn't running.
+#[cfg(not(unix))]
+pub fn call(_path: &std::path::Path, _request: &Request) -> anyhow::Result<Option<Response>> {
+    anyhow::bail!("The control socket is only available on Unix")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ClientConfig;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[derive(Default)]
+    struct Recorder {
+        stopped: AtomicBool,
+    }
+
+    impl Controller for Recorder {
+        fn reload(&self) -> anyhow::Result<()> {
+            anyhow::bail!("config.yaml: invalid")
+        }
+
+        fn shutdown(&self) {
+            self.stopped.store(true, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_control_requests() {
+        let json = serde_json::to_string(&Request::Channels).unwrap();
+        assert_eq!(json, r#"{"command":"channels"}"#);
+        assert!(Request::Shutdown.is_write());
+        assert!(!Request::Status.is_write());
+
+        let config = ClientConfig {
+            server_host: "mail.example.com".to_string(),
+            ..ClientConfig::default()
+        };
+        let client = Client::new(config);
+        let recorder = Recorder::default();
+        let Response::Status(status) = answer(&client, &recorder, false, Request::Status).await
+        else {
+            panic!("wrong response");
+        };
+        assert_eq!(status.state, "idle");
+        assert_eq!(status.server, "mail.example.com:587");
+        let json = serde_json::to_string(&Response::Status(status)).unwrap();
+        assert!(json.starts_with(r#"{"status":"status","state":"idle","#));
+
+        assert!(matches!(
+            answer(&client, &recorder, false, Request::Reload).await,
+            Response::Error { message } if message == "config.yaml: invalid"
+        ));
+        assert!(matches!(
+            answer(&client, &recorder, true, Request::Shutdown).await,
+            Response::Error { .. }
+        ));
+        assert!(!recorder.stopped.load(Ordering::Relaxed));
+        assert!(matches!(
+            answer(&client, &recorder, false, Request::Shutdown).await,
+            Response::Done
+        ));
+        assert!(recorder.stopped.load(Ordering::Relaxed));
+    }
+}
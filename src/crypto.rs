@@ -1,7 +1,10 @@
 //! Cryptography utilities for SMTP Tunnel
 
 use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use hkdf::Hkdf;
 use hmac::{Hmac, Mac};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::rand::SystemRandom;
 use sha2::Sha256;
 use std::collections::HashMap;
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -16,10 +19,28 @@ impl AuthToken {
     /// Generate an authentication token
     /// Format: base64(username:timestamp:hmac)
     pub fn generate(secret: &str, username: &str, timestamp: u64) -> String {
+        Self::generate_bound(secret, username, timestamp, None)
+    }
+
+    /// Generate a token bound to a TLS channel.
+    ///
+    /// `binding` is the TLS exporter value of the connection the token is
+    /// sent on; it's mixed into the HMAC so a token captured by a MITM can't
+    /// be replayed on the attacker's own connection to the real server.
+    pub fn generate_bound(
+        secret: &str,
+        username: &str,
+        timestamp: u64,
+        binding: Option<&[u8]>,
+    ) -> String {
         let message = format!("smtp-tunnel-auth:{username}:{timestamp}");
         let mut mac =
             HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
         mac.update(message.as_bytes());
+        if let Some(binding) = binding {
+            mac.update(b":");
+            mac.update(binding);
+        }
         let result = mac.finalize();
         let hmac_bytes = result.into_bytes();
         let hmac_b64 = BASE64.encode(hmac_bytes);
@@ -89,6 +110,17 @@ impl AuthToken {
         token_b64: &str,
         users: &HashMap<String, UserSecret>,
         max_age_secs: u64,
+    ) -> (bool, Option<String>) {
+        Self::verify_multi_user_bound(token_b64, users, max_age_secs, None)
+    }
+
+    /// Verify against multiple users, requiring the token to be bound to
+    /// `binding` (see [`AuthToken::generate_bound`])
+    pub fn verify_multi_user_bound(
+        token_b64: &str,
+        users: &HashMap<String, UserSecret>,
+        max_age_secs: u64,
+        binding: Option<&[u8]>,
     ) -> (bool, Option<String>) {
         let decoded = match BASE64.decode(token_b64.as_bytes()) {
             Ok(d) => match String::from_utf8(d) {
@@ -126,7 +158,7 @@ impl AuthToken {
         };
 
         // Verify HMAC
-        let expected = Self::generate(&user.secret, username, timestamp);
+        let expected = Self::generate_bound(&user.secret, username, timestamp, binding);
         let valid = expected.len() == token_b64.len()
             && expected
                 .as_bytes()
@@ -155,6 +187,250 @@ impl UserSecret {
     }
 }
 
+/// Session affinity token, naming the server node that holds a client's
+/// session. Signed with a secret the nodes share, so any of them can tell
+/// where a reconnecting client belongs.
+/// Format: base64(node:username:expires:hmac)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AffinityToken {
+    pub node: String,
+    pub username: String,
+    /// Unix time after which the token is ignored
+    pub expires: u64,
+}
+
+impl AffinityToken {
+    /// Encode and sign the token
+    pub fn sign(&self, secret: &str) -> String {
+        let mac = self.mac(secret);
+        let token = format!(
+            "{}:{}:{}:{}",
+            self.node,
+            self.username,
+            self.expires,
+            BASE64.encode(mac)
+        );
+        BASE64.encode(token.as_bytes())
+    }
+
+    /// Decode a token, checking its signature and that it hasn't expired
+    /// at `now`
+    pub fn verify(token_b64: &str, secret: &str, now: u64) -> Option<Self> {
+        let decoded = String::from_utf8(BASE64.decode(token_b64.as_bytes()).ok()?).ok()?;
+        let parts: Vec<&str> = decoded.split(':').collect();
+        let [node, username, expires, mac] = parts[..] else {
+            return None;
+        };
+        let token = Self {
+            node: node.to_string(),
+            username: username.to_string(),
+            expires: expires.parse().ok()?,
+        };
+        if now > token.expires {
+            return None;
+        }
+        let mac = BASE64.decode(mac).ok()?;
+        let expected = token.mac(secret);
+        // Constant-time comparison
+        let valid = mac.len() == expected.len()
+            && expected
+                .iter()
+                .zip(&mac)
+                .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+                == 0;
+        valid.then_some(token)
+    }
+
+    fn mac(&self, secret: &str) -> [u8; 32] {
+        let mut mac =
+            HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+        mac.update(
+            format!(
+                "smtp-tunnel-affinity:{}:{}:{}",
+                self.node, self.username, self.expires
+            )
+            .as_bytes(),
+        );
+        mac.finalize().into_bytes().into()
+    }
+}
+
+/// Length of an X25519 public key
+pub const KEX_PUBLIC_KEY_LEN: usize = 32;
+
+/// Length of a key exchange message: public key followed by its HMAC-SHA256
+pub const KEX_MESSAGE_LEN: usize = KEX_PUBLIC_KEY_LEN + 32;
+
+/// Length of each derived session key
+pub const SESSION_KEY_LEN: usize = 32;
+
+/// Which end of the tunnel a key exchange runs on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+impl Role {
+    fn label(self) -> &'static [u8] {
+        match self {
+            Role::Client => b"client",
+            Role::Server => b"server",
+        }
+    }
+
+    fn peer(self) -> Self {
+        match self {
+            Role::Client => Role::Server,
+            Role::Server => Role::Client,
+        }
+    }
+}
+
+/// Ephemeral X25519 key exchange authenticated by the user's secret.
+///
+/// Each side sends its ephemeral public key with an HMAC over it keyed by the
+/// shared secret, so only the real user and server can take part. Session keys
+/// come from the ephemeral DH output, so captured traffic stays unreadable even
+/// if the static secret leaks later. The keys also cover a transcript of
+/// what was negotiated around the exchange, so a middlebox that edits it
+/// leaves the two sides with keys that don't match.
+pub struct KeyExchange {
+    role: Role,
+    private_key: EphemeralPrivateKey,
+    public_key: [u8; KEX_PUBLIC_KEY_LEN],
+}
+
+impl KeyExchange {
+    /// Generate a fresh ephemeral key pair
+    pub fn new(role: Role) -> crate::Result<Self> {
+        let rng = SystemRandom::new();
+        let private_key = EphemeralPrivateKey::generate(&X25519, &rng)
+            .map_err(|_| crate::Error::Protocol("key generation failed".into()))?;
+        let mut public_key = [0u8; KEX_PUBLIC_KEY_LEN];
+        public_key.copy_from_slice(
+            private_key
+                .compute_public_key()
+                .map_err(|_| crate::Error::Protocol("key generation failed".into()))?
+                .as_ref(),
+        );
+        Ok(Self {
+            role,
+            private_key,
+            public_key,
+        })
+    }
+
+    /// Message to send to the peer: public key + HMAC
+    pub fn message(&self, secret: &str, username: &str) -> [u8; KEX_MESSAGE_LEN] {
+        let mut message = [0u8; KEX_MESSAGE_LEN];
+        message[..KEX_PUBLIC_KEY_LEN].copy_from_slice(&self.public_key);
+        let tag = kex_mac(secret, username, self.role, &self.public_key).finalize();
+        message[KEX_PUBLIC_KEY_LEN..].copy_from_slice(&tag.into_bytes());
+        message
+    }
+
+    /// Verify the peer's message and derive the session keys, bound to
+    /// `transcript`, which both sides must have seen alike
+    pub fn finish(
+        self,
+        secret: &str,
+        username: &str,
+        peer_message: &[u8],
+        transcript: &[u8],
+    ) -> crate::Result<SessionKeys> {
+        if peer_message.len() != KEX_MESSAGE_LEN {
+            return Err(crate::Error::Protocol(format!(
+                "key exchange message must be {KEX_MESSAGE_LEN} bytes, got {}",
+                peer_message.len()
+            )));
+        }
+        let (peer_public, tag) = peer_message.split_at(KEX_PUBLIC_KEY_LEN);
+        kex_mac(secret, username, self.role.peer(), peer_public)
+            .verify_slice(tag)
+            .map_err(|_| crate::Error::AuthFailed)?;
+
+        // Salt binds both public keys in a fixed client/server order
+        let mut salt = [0u8; 2 * KEX_PUBLIC_KEY_LEN];
+        let (client_public, server_public) = match self.role {
+            Role::Client => (&self.public_key[..], peer_public),
+            Role::Server => (peer_public, &self.public_key[..]),
+        };
+        salt[..KEX_PUBLIC_KEY_LEN].copy_from_slice(client_public);
+        salt[KEX_PUBLIC_KEY_LEN..].copy_from_slice(server_public);
+
+        let role = self.role;
+        agreement::agree_ephemeral(
+            self.private_key,
+            &UnparsedPublicKey::new(&X25519, peer_public),
+            |shared| SessionKeys::derive(role, shared, &salt, username, transcript),
+        )
+        .map_err(|_| crate::Error::Protocol("key agreement failed".into()))
+    }
+}
+
+/// HMAC over a key exchange public key, bound to the sender's role and user
+fn kex_mac(secret: &str, username: &str, role: Role, public_key: &[u8]) -> HmacSha256 {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(b"smtp-tunnel-kex:");
+    mac.update(role.label());
+    mac.update(b":");
+    mac.update(username.as_bytes());
+    mac.update(b":");
+    mac.update(public_key);
+    mac
+}
+
+/// Per-session keys derived from a [`KeyExchange`]
+#[derive(Clone)]
+pub struct SessionKeys {
+    /// Key for data we send
+    pub send: [u8; SESSION_KEY_LEN],
+    /// Key for data we receive
+    pub recv: [u8; SESSION_KEY_LEN],
+}
+
+impl SessionKeys {
+    /// HKDF-SHA256 over the DH output, split into one key per direction
+    fn derive(role: Role, shared: &[u8], salt: &[u8], username: &str, transcript: &[u8]) -> Self {
+        let hk = Hkdf::<Sha256>::new(Some(salt), shared);
+        let mut okm = [0u8; 2 * SESSION_KEY_LEN];
+        hk.expand_multi_info(
+            &[
+                b"smtp-tunnel-session:",
+                username.as_bytes(),
+                b":",
+                transcript,
+            ],
+            &mut okm,
+        )
+        .expect("HKDF output length is valid");
+
+        let mut client_to_server = [0u8; SESSION_KEY_LEN];
+        let mut server_to_client = [0u8; SESSION_KEY_LEN];
+        client_to_server.copy_from_slice(&okm[..SESSION_KEY_LEN]);
+        server_to_client.copy_from_slice(&okm[SESSION_KEY_LEN..]);
+
+        match role {
+            Role::Client => Self {
+                send: client_to_server,
+                recv: server_to_client,
+            },
+            Role::Server => Self {
+                send: server_to_client,
+                recv: client_to_server,
+            },
+        }
+    }
+}
+
+impl std::fmt::Debug for SessionKeys {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SessionKeys { .. }")
+    }
+}
+
 /// Generate a random secret
 pub fn generate_secret() -> String {
     use rand::Rng;
@@ -213,4 +489,117 @@ mod tests {
 
         assert!(!valid);
     }
+
+    #[test]
+    fn test_token_channel_binding() {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let users = HashMap::from([("alice".to_string(), UserSecret::new("secret"))]);
+        let token = AuthToken::generate_bound("secret", "alice", timestamp, Some(b"channel-a"));
+
+        let (valid, user) =
+            AuthToken::verify_multi_user_bound(&token, &users, 300, Some(b"channel-a"));
+        assert!(valid);
+        assert_eq!(user.as_deref(), Some("alice"));
+
+        // Replayed on a different TLS connection, or without binding
+        let (valid, _) =
+            AuthToken::verify_multi_user_bound(&token, &users, 300, Some(b"channel-b"));
+        assert!(!valid);
+        let (valid, _) = AuthToken::verify_multi_user(&token, &users, 300);
+        assert!(!valid);
+    }
+
+    #[test]
+    fn test_affinity_token() {
+        let token = AffinityToken {
+            node: "node-a".to_string(),
+            username: "alice".to_string(),
+            expires: 1000,
+        };
+        let signed = token.sign("cluster");
+        assert_eq!(AffinityToken::verify(&signed, "cluster", 999), Some(token));
+        assert_eq!(AffinityToken::verify(&signed, "cluster", 1001), None);
+        assert_eq!(AffinityToken::verify(&signed, "other", 999), None);
+
+        // Re-pointing the token at another node breaks the signature
+        let decoded = String::from_utf8(BASE64.decode(&signed).unwrap()).unwrap();
+        let forged = BASE64.encode(decoded.replacen("node-a", "node-b", 1));
+        assert_eq!(AffinityToken::verify(&forged, "cluster", 999), None);
+        assert_eq!(AffinityToken::verify("not a token", "cluster", 999), None);
+    }
+
+    #[test]
+    fn test_key_exchange() {
+        let client = KeyExchange::new(Role::Client).unwrap();
+        let server = KeyExchange::new(Role::Server).unwrap();
+        let client_msg = client.message("secret", "alice");
+        let server_msg = server.message("secret", "alice");
+
+        let client_keys = client
+            .finish("secret", "alice", &server_msg, b"hello")
+            .unwrap();
+        let server_keys = server
+            .finish("secret", "alice", &client_msg, b"hello")
+            .unwrap();
+
+        assert_eq!(client_keys.send, server_keys.recv);
+        assert_eq!(client_keys.recv, server_keys.send);
+        assert_ne!(client_keys.send, client_keys.recv);
+    }
+
+    #[test]
+    fn test_key_exchange_wrong_secret() {
+        let client = KeyExchange::new(Role::Client).unwrap();
+        let server = KeyExchange::new(Role::Server).unwrap();
+        let client_msg = client.message("wrong-secret", "alice");
+
+        assert!(matches!(
+            server.finish("secret", "alice", &client_msg, b"hello"),
+            Err(crate::Error::AuthFailed)
+        ));
+    }
+
+    #[test]
+    fn test_key_exchange_rejects_tampering() {
+        let client = KeyExchange::new(Role::Client).unwrap();
+        let mut client_msg = client.message("secret", "alice");
+        client_msg[0] ^= 1;
+
+        let server = KeyExchange::new(Role::Server).unwrap();
+        assert!(
+            server
+                .finish("secret", "alice", &client_msg, b"hello")
+                .is_err()
+        );
+
+        // A message can't be reflected back to its sender
+        let server = KeyExchange::new(Role::Server).unwrap();
+        let server_msg = server.message("secret", "alice");
+        let other = KeyExchange::new(Role::Server).unwrap();
+        assert!(
+            other
+                .finish("secret", "alice", &server_msg, b"hello")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_key_exchange_binds_transcript() {
+        let client = KeyExchange::new(Role::Client).unwrap();
+        let server = KeyExchange::new(Role::Server).unwrap();
+        let client_msg = client.message("secret", "alice");
+        let server_msg = server.message("secret", "alice");
+
+        // Each side saw a different negotiation, so the keys disagree
+        let client_keys = client
+            .finish("secret", "alice", &server_msg, b"hello")
+            .unwrap();
+        let server_keys = server
+            .finish("secret", "alice", &client_msg, b"edited")
+            .unwrap();
+        assert_ne!(client_keys.send, server_keys.recv);
+    }
 }
@@ -0,0 +1,242 @@
+//! DANE checks of the server certificate
+//!
+//! A sending MTA that does DANE looks up `_port._tcp.host` TLSA records and
+//! refuses a peer whose certificate matches none of them. With `dane`
+//! configured the client does the same before authenticating, so a
+//! certificate that passes the CA check must also be the one published in
+//! DNS. As in Postfix, only records the resolver vouches for with DNSSEC
+//! (the AD bit) count; the resolver should be a validating one you trust.
+
+use sha2::{Digest, Sha256, Sha512};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+/// How long to wait for the resolver's answer
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// TLSA record type
+const TYPE_TLSA: u16 = 52;
+/// EDNS0 pseudo-record type
+const TYPE_OPT: u16 = 41;
+
+/// Largest response accepted over UDP, advertised with EDNS0
+const MAX_RESPONSE: usize = 4096;
+
+/// One TLSA record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tlsa {
+    /// 0 PKIX-TA, 1 PKIX-EE, 2 DANE-TA, 3 DANE-EE
+    pub usage: u8,
+    /// 0 full certificate, 1 SubjectPublicKeyInfo
+    pub selector: u8,
+    /// 0 exact, 1 SHA-256, 2 SHA-512
+    pub matching: u8,
+    pub data: Vec<u8>,
+}
+
+impl Tlsa {
+    /// Whether the record matches one of the certificates it applies to:
+    /// the server's own for end-entity usages, any in the chain otherwise
+    pub fn matches(&self, chain: &[impl AsRef<[u8]>]) -> bool {
+        let candidates = match self.usage {
+            1 | 3 => &chain[..chain.len().min(1)],
+            0 | 2 => chain,
+            _ => return false,
+        };
+        candidates
+            .iter()
+            .any(|cert| self.matches_cert(cert.as_ref()))
+    }
+
+    fn matches_cert(&self, der: &[u8]) -> bool {
+        match self.selector {
+            0 => self.matches_data(der),
+            1 => x509_parser::parse_x509_certificate(der)
+                .is_ok_and(|(_, cert)| self.matches_data(cert.public_key().raw)),
+            _ => false,
+        }
+    }
+
+    fn matches_data(&self, selected: &[u8]) -> bool {
+        match self.matching {
+            0 => selected == self.data,
+            1 => Sha256::digest(selected)[..] == self.data[..],
+            2 => Sha512::digest(selected)[..] == self.data[..],
+            _ => false,
+        }
+    }
+}
+
+/// DNSSEC-validated TLSA records for `port` on `host`, asked of `resolver`.
+/// Records the resolver didn't validate are left out, as if absent.
+pub async fn lookup(resolver: SocketAddr, host: &str, port: u16) -> anyhow::Result<Vec<Tlsa>> {
+    let name = format!("_{}._tcp.{}", port, host.trim_end_matches('.'));
+    let id = rand::random::<u16>();
+    let query = build_query(id, &name)?;
+
+    let bind: SocketAddr = if resolver.is_ipv4() {
+        ([0, 0, 0, 0], 0).into()
+    } else {
+        ([0u16; 8], 0).into()
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(resolver).await?;
+    socket.send(&query).await?;
+
+    let mut response = vec![0u8; MAX_RESPONSE];
+    let answer = tokio::time::timeout(QUERY_TIMEOUT, async {
+        loop {
+            let len = socket.recv(&mut response).await?;
+            // Ignore stray datagrams that aren't the answer to this query
+            if response[..len].starts_with(&id.to_be_bytes()) {
+                return anyhow::Ok(parse_response(&response[..len])?);
+            }
+        }
+    })
+    .await
+    .map_err(|_| anyhow::anyhow!("No answer from {} for {}", resolver, name))??;
+    Ok(answer)
+}
+
+/// First nameserver in /etc/resolv.conf
+pub fn system_resolver() -> anyhow::Result<SocketAddr> {
+    let conf = std::fs::read_to_string("/etc/resolv.conf")?;
+    conf.lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .find_map(|addr| addr.trim().parse().ok())
+        .map(|ip| SocketAddr::new(ip, 53))
+        .ok_or_else(|| anyhow::anyhow!("No nameserver in /etc/resolv.conf"))
+}
+
+/// Parse a configured resolver: an address, optionally with a port
+pub fn parse_resolver(resolver: &str) -> anyhow::Result<SocketAddr> {
+    resolver
+        .parse()
+        .or_else(|_| resolver.parse().map(|ip| SocketAddr::new(ip, 53)))
+        .map_err(|_| anyhow::anyhow!("Invalid DNS resolver address: {}", resolver))
+}
+
+/// Recursive TLSA query for `name`, asking for DNSSEC validation
+fn build_query(id: u16, name: &str) -> anyhow::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(64);
+    query.extend_from_slice(&id.to_be_bytes());
+    // RD and AD: recursion, and say whether the answer validated
+    query.extend_from_slice(&0x0120u16.to_be_bytes());
+    // One question, one additional record (the OPT)
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 1]);
+    for label in name.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            anyhow::bail!("Invalid DNS name: {}", name);
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_TLSA.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+    // OPT: root name, UDP payload size, DO bit set
+    query.push(0);
+    query.extend_from_slice(&TYPE_OPT.to_be_bytes());
+    query.extend_from_slice(&(MAX_RESPONSE as u16).to_be_bytes());
+    query.extend_from_slice(&[0, 0, 0x80, 0, 0, 0]);
+    Ok(query)
+}
+
+/// TLSA records in a response, empty unless it's DNSSEC-validated
+fn parse_response(msg: &[u8]) -> anyhow::Result<Vec<Tlsa>> {
+    let invalid = || anyhow::anyhow!("Malformed DNS response");
+    let header = msg.get(..12).ok_or_else(invalid)?;
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    if flags & 0x8000 == 0 {
+        return Err(invalid());
+    }
+    if flags & 0x0200 != 0 {
+        anyhow::bail!("DNS response truncated");
+    }
+    match flags & 0x000f {
+        0 => {}
+        // NXDOMAIN: no records
+        3 => return Ok(Vec::new()),
+        rcode => anyhow::bail!("DNS lookup failed (rcode {})", rcode),
+    }
+    if flags & 0x0020 == 0 {
+        return Ok(Vec::new());
+    }
+
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    let answers = u16::from_be_bytes([header[6], header[7]]);
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(msg, pos).ok_or_else(invalid)? + 4;
+    }
+    let mut records = Vec::new();
+    for _ in 0..answers {
+        pos = skip_name(msg, pos).ok_or_else(invalid)?;
+        let fixed = msg.get(pos..pos + 10).ok_or_else(invalid)?;
+        let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let len = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        pos += 10;
+        let rdata = msg.get(pos..pos + len).ok_or_else(invalid)?;
+        pos += len;
+        // CNAMEs and RRSIGs come along; only the TLSA records matter
+        if rtype == TYPE_TLSA && rdata.len() > 3 {
+            records.push(Tlsa {
+                usage: rdata[0],
+                selector: rdata[1],
+                matching: rdata[2],
+                data: rdata[3..].to_vec(),
+            });
+        }
+    }
+    Ok(records)
+}
+
+/// Offset just past the (possibly compressed) name at `pos`
+pub(crate) fn skip_name(msg: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *msg.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            // A pointer ends the name
+            len if len & 0xc0 == 0xc0 => return msg.get(pos + 1).map(|_| pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_match() {
+        // Selector 0 hashes the certificate as is, parsed or not
+        let cert = b"certificate DER";
+        let digest = Sha256::digest(cert);
+
+        // The query's header and question, answered with a validated
+        // CNAME and then the TLSA record
+        let mut msg = build_query(0x1234, "_25._tcp.mx.example.org").unwrap();
+        msg.truncate(msg.len() - 11);
+        msg[2..4].copy_from_slice(&0x81a0u16.to_be_bytes());
+        msg[6..8].copy_from_slice(&2u16.to_be_bytes());
+        msg[10..12].copy_from_slice(&0u16.to_be_bytes());
+        msg.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 1, 0, 0, 2, 0xc0, 12]);
+        msg.extend_from_slice(&[0xc0, 12, 0, 52, 0, 1, 0, 0, 1, 0, 0, 35, 3, 0, 1]);
+        msg.extend_from_slice(&digest);
+
+        let records = parse_response(&msg).unwrap();
+        assert_eq!(records.len(), 1);
+        assert!(records[0].matches(&[cert]));
+        let other = Tlsa {
+            data: vec![0; 32],
+            ..records[0].clone()
+        };
+        assert!(!other.matches(&[cert]));
+
+        // Without the AD bit the records don't count
+        msg[3] &= !0x20;
+        assert!(parse_response(&msg).unwrap().is_empty());
+    }
+}
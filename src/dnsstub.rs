@@ -0,0 +1,316 @@
+//! Local DNS stub resolver
+//!
+//! Applications that don't use the SOCKS proxy still look names up through
+//! the system resolver, which asks the local network in plaintext. With
+//! `dns_stub` configured the client answers DNS itself, over UDP and TCP,
+//! by sending each question through the tunnel as a RESOLVE, so lookups
+//! leave from the server's resolver instead. Point the system resolver (or
+//! a local forwarder) at it.
+//!
+//! Only A and AAAA questions are answered; other types get an empty
+//! answer, so applications fall back to addresses. Queries arriving while
+//! the tunnel is down wait for it to come back.
+
+use crate::client::TunnelHandle;
+use crate::proto::AddressFamily;
+use crate::tasks::TaskGroup;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tracing::{debug, trace};
+
+/// TTL given with each answer; short, as the server's view may change
+const TTL: u32 = 60;
+
+/// Largest response sent over UDP, without EDNS0
+const MAX_UDP_RESPONSE: usize = 512;
+
+/// How long a TCP client may leave its connection idle
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+const CLASS_IN: u16 = 1;
+
+/// Response codes
+const RCODE_FORMERR: u8 = 1;
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+const RCODE_NOTIMP: u8 = 4;
+
+/// The stub's sockets, bound once and served by each tunnel connection
+pub struct DnsStub {
+    udp: Arc<UdpSocket>,
+    tcp: TcpListener,
+}
+
+impl DnsStub {
+    /// Listen on `addr` over UDP and TCP
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let udp = UdpSocket::bind(addr).await?;
+        // Take the port UDP got, for an `addr` with port 0
+        let tcp = TcpListener::bind(udp.local_addr()?).await?;
+        Ok(Self {
+            udp: Arc::new(udp),
+            tcp,
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.udp.local_addr()
+    }
+
+    /// Wait for a UDP query, leaving it to be answered
+    pub(crate) async fn readable(&self) -> io::Result<()> {
+        self.udp.readable().await
+    }
+
+    /// Answer queries through `tunnel` until a socket fails. Each query is
+    /// answered in a task of `tasks`.
+    pub(crate) async fn serve(&self, tunnel: TunnelHandle, tasks: TaskGroup) -> io::Result<()> {
+        let mut buf = vec![0u8; u16::MAX as usize];
+        loop {
+            tokio::select! {
+                result = self.udp.recv_from(&mut buf) => {
+                    let (len, peer) = result?;
+                    let query = buf[..len].to_vec();
+                    let udp = Arc::clone(&self.udp);
+                    let tunnel = tunnel.clone();
+                    tasks.spawn(format!("DNS query from {peer}"), async move {
+                        let response = answer(&query, &tunnel, MAX_UDP_RESPONSE).await;
+                        if let Some(response) = response
+                            && let Err(e) = udp.send_to(&response, peer).await
+                        {
+                            debug!("DNS response to {} failed: {}", peer, e);
+                        }
+                    });
+                }
+                result = self.tcp.accept() => {
+                    let (stream, peer) = result?;
+                    let tunnel = tunnel.clone();
+                    tasks.spawn(format!("DNS connection from {peer}"), async move {
+                        if let Err(e) = serve_tcp(stream, &tunnel).await {
+                            trace!("DNS connection from {} ended: {}", peer, e);
+                        }
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Answer length-prefixed queries on one TCP connection
+async fn serve_tcp(mut stream: TcpStream, tunnel: &TunnelHandle) -> io::Result<()> {
+    loop {
+        let len = match tokio::time::timeout(TCP_IDLE_TIMEOUT, stream.read_u16()).await {
+            Ok(Ok(len)) => len,
+            Ok(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Ok(()),
+        };
+        let mut query = vec![0u8; len as usize];
+        stream.read_exact(&mut query).await?;
+        let Some(response) = answer(&query, tunnel, u16::MAX as usize).await else {
+            return Ok(());
+        };
+        let mut framed = Vec::with_capacity(2 + response.len());
+        framed.extend_from_slice(&(response.len() as u16).to_be_bytes());
+        framed.extend_from_slice(&response);
+        stream.write_all(&framed).await?;
+    }
+}
+
+/// The response to `query`, at most `max_len` bytes, or None for a message
+/// not worth answering
+async fn answer(query: &[u8], tunnel: &TunnelHandle, max_len: usize) -> Option<Vec<u8>> {
+    let question = match parse_query(query) {
+        Ok(question) => question,
+        Err(Some(rcode)) => return Some(error_response(query, rcode)),
+        Err(None) => return None,
+    };
+    if question.qclass != CLASS_IN || !matches!(question.qtype, TYPE_A | TYPE_AAAA) {
+        return Some(response(query, &question, 0, &[], max_len));
+    }
+    // Both families, so a name with only the other kind of address gets
+    // an empty answer rather than NXDOMAIN
+    let (rcode, addrs) = match tunnel.resolve(&question.name, AddressFamily::Any).await {
+        Ok(addrs) => (0, addrs),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (RCODE_NXDOMAIN, Vec::new()),
+        Err(e) => {
+            debug!("DNS lookup of {} failed: {}", question.name, e);
+            (RCODE_SERVFAIL, Vec::new())
+        }
+    };
+    trace!("DNS {} type {}: {:?}", question.name, question.qtype, addrs);
+    Some(response(query, &question, rcode, &addrs, max_len))
+}
+
+/// A query's single question
+#[derive(Debug, PartialEq, Eq)]
+struct Question {
+    name: String,
+    qtype: u16,
+    qclass: u16,
+    /// Where the question section ends in the query
+    end: usize,
+}
+
+/// Parse a standard query with one question. Errors carry the response
+/// code to refuse it with, or None to drop it (too short to answer, or a
+/// response rather than a query).
+fn parse_query(msg: &[u8]) -> Result<Question, Option<u8>> {
+    if msg.len() < 12 || msg[2] & 0x80 != 0 {
+        return Err(None);
+    }
+    if (msg[2] >> 3) & 0x0F != 0 {
+        return Err(Some(RCODE_NOTIMP));
+    }
+    if u16::from_be_bytes([msg[4], msg[5]]) != 1 {
+        return Err(Some(RCODE_FORMERR));
+    }
+    let mut labels = Vec::new();
+    let mut pos = 12;
+    loop {
+        let len = *msg.get(pos).ok_or(Some(RCODE_FORMERR))? as usize;
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+        // Compression has nothing to point back to in a lone question
+        let label = msg.get(pos..pos + len).filter(|_| len < 64);
+        let label = label.ok_or(Some(RCODE_FORMERR))?;
+        if !label.iter().all(|b| b.is_ascii_graphic() && *b != b'.') {
+            return Err(Some(RCODE_FORMERR));
+        }
+        labels.push(String::from_utf8_lossy(label).into_owned());
+        pos += len;
+    }
+    let fixed = msg.get(pos..pos + 4).ok_or(Some(RCODE_FORMERR))?;
+    Ok(Question {
+        name: labels.join("."),
+        qtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+        qclass: u16::from_be_bytes([fixed[2], fixed[3]]),
+        end: pos + 4,
+    })
+}
+
+/// Response header: the query's ID and RD flag, with `rcode`
+fn header(query: &[u8], rcode: u8, questions: u16, answers: u16) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(MAX_UDP_RESPONSE);
+    msg.extend_from_slice(&query[..2]);
+    // QR, RD as asked, RA
+    msg.push(0x80 | (query[2] & 0x01));
+    msg.push(0x80 | rcode);
+    msg.extend_from_slice(&questions.to_be_bytes());
+    msg.extend_from_slice(&answers.to_be_bytes());
+    msg.extend_from_slice(&[0, 0, 0, 0]);
+    msg
+}
+
+/// Refuse `query` with `rcode`
+fn error_response(query: &[u8], rcode: u8) -> Vec<u8> {
+    header(query, rcode, 0, 0)
+}
+
+/// Answer `question` with as many of `addrs` as fit in `max_len`
+fn response(
+    query: &[u8],
+    question: &Question,
+    rcode: u8,
+    addrs: &[IpAddr],
+    max_len: usize,
+) -> Vec<u8> {
+    let mut records = Vec::new();
+    let mut len = question.end;
+    for addr in addrs {
+        let (rtype, data) = match (addr, question.qtype) {
+            (IpAddr::V4(v4), TYPE_A) => (TYPE_A, v4.octets().to_vec()),
+            (IpAddr::V6(v6), TYPE_AAAA) => (TYPE_AAAA, v6.octets().to_vec()),
+            _ => continue,
+        };
+        let mut record = vec![0xC0, 12];
+        record.extend_from_slice(&rtype.to_be_bytes());
+        record.extend_from_slice(&CLASS_IN.to_be_bytes());
+        record.extend_from_slice(&TTL.to_be_bytes());
+        record.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        record.extend_from_slice(&data);
+        if len + record.len() > max_len {
+            break;
+        }
+        len += record.len();
+        records.push(record);
+    }
+    let mut msg = header(query, rcode, 1, records.len() as u16);
+    msg.extend_from_slice(&query[12..question.end]);
+    msg.extend(records.concat());
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut msg = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        for label in name.split('.') {
+            msg.push(label.len() as u8);
+            msg.extend_from_slice(label.as_bytes());
+        }
+        msg.push(0);
+        msg.extend_from_slice(&qtype.to_be_bytes());
+        msg.extend_from_slice(&CLASS_IN.to_be_bytes());
+        msg
+    }
+
+    #[test]
+    fn test_query_and_response() {
+        let msg = query("www.example.com", TYPE_A);
+        let question = parse_query(&msg).unwrap();
+        assert_eq!(question.name, "www.example.com");
+        assert_eq!((question.qtype, question.end), (TYPE_A, msg.len()));
+
+        let addrs: Vec<IpAddr> = vec![
+            "192.0.2.1".parse().unwrap(),
+            "2001:db8::1".parse().unwrap(),
+            "192.0.2.2".parse().unwrap(),
+        ];
+        let resp = response(&msg, &question, 0, &addrs, MAX_UDP_RESPONSE);
+        // ID kept, QR RD RA set, NOERROR, one question, the two A records
+        assert_eq!(
+            &resp[..12],
+            &[0x12, 0x34, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0]
+        );
+        assert_eq!(&resp[12..msg.len()], &msg[12..]);
+        assert_eq!(
+            &resp[msg.len()..msg.len() + 16],
+            &[0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 192, 0, 2, 1]
+        );
+        assert_eq!(resp.len(), msg.len() + 32);
+
+        // Only what fits
+        let resp = response(&msg, &question, 0, &addrs, msg.len() + 20);
+        assert_eq!(&resp[6..8], &[0, 1]);
+
+        let resp = response(&msg, &question, RCODE_NXDOMAIN, &[], MAX_UDP_RESPONSE);
+        assert_eq!(resp[3], 0x80 | RCODE_NXDOMAIN);
+        assert_eq!(&resp[6..8], &[0, 0]);
+
+        // Responses, truncated and malformed queries
+        let mut reply = msg.clone();
+        reply[2] |= 0x80;
+        assert_eq!(parse_query(&reply), Err(None));
+        assert_eq!(parse_query(&msg[..8]), Err(None));
+        assert_eq!(parse_query(&msg[..16]), Err(Some(RCODE_FORMERR)));
+        let mut notify = msg.clone();
+        notify[2] = 4 << 3;
+        assert_eq!(parse_query(&notify), Err(Some(RCODE_NOTIMP)));
+        assert_eq!(
+            parse_query(&query("a\0b.example", TYPE_A)),
+            Err(Some(RCODE_FORMERR))
+        );
+        assert_eq!(error_response(&msg, RCODE_FORMERR)[3], 0x81);
+    }
+}
@@ -0,0 +1,60 @@
+//! Minimal HTTP/1.0 response parsing
+//!
+//! A few features fetch a small document over a plain or TLS socket they
+//! open themselves (the HTTP users store, DNS over HTTPS, the leak test's
+//! IP echo). They send HTTP/1.0 requests, so a response is a head and a
+//! body that runs to the end of the connection.
+
+/// A parsed HTTP response
+pub(crate) struct HttpResponse<'a> {
+    pub(crate) status: u16,
+    #[cfg_attr(not(feature = "server"), allow(dead_code))]
+    pub(crate) etag: Option<String>,
+    pub(crate) body: &'a [u8],
+}
+
+pub(crate) fn parse_http_response(response: &[u8]) -> anyhow::Result<HttpResponse<'_>> {
+    let invalid = || anyhow::anyhow!("Malformed HTTP response");
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or_else(invalid)?;
+    let head = std::str::from_utf8(&response[..end]).map_err(|_| invalid())?;
+    let body = &response[end + 4..];
+    let mut lines = head.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(invalid)?;
+    let mut etag = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("etag") {
+            etag = Some(value.to_string());
+        } else if name.eq_ignore_ascii_case("content-length")
+            && value.parse::<usize>().ok() != Some(body.len())
+        {
+            anyhow::bail!("HTTP response body truncated");
+        }
+    }
+    Ok(HttpResponse { status, etag, body })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_http_response() {
+        let raw = b"HTTP/1.0 200 OK\r\nETag: \"v1\"\r\nContent-Length: 5\r\n\r\nusers";
+        let response = parse_http_response(raw).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.etag.as_deref(), Some("\"v1\""));
+        assert_eq!(response.body, b"users");
+        assert!(parse_http_response(&raw[..raw.len() - 1]).is_err());
+    }
+}
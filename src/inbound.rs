@@ -0,0 +1,274 @@
+//! Inbound mail
+//!
+//! A host that advertises a mail server but refuses every message is easy
+//! to tell apart from a real one. With `camouflage.inbound_mail` configured
+//! the server accepts ordinary mail for the listed recipients, delivering
+//! it to a maildir and/or forwarding it to an upstream MTA. Mail for anyone
+//! else is refused as an unknown user, as Postfix would.
+
+use crate::config::InboundMailConfig;
+use crate::proto::read_line;
+use bytes::BytesMut;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// Most recipients accepted for one message
+pub const MAX_RECIPIENTS: usize = 100;
+
+/// Longest a message line may get before the message counts as too large
+const MAX_DATA_LINE: usize = 64 * 1024;
+
+/// How long forwarding one message to the upstream MTA may take
+const FORWARD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Sender and recipients of the message being received
+#[derive(Debug, Clone, Default)]
+pub struct Envelope {
+    /// Reverse path; empty for bounces
+    pub from: String,
+    pub recipients: Vec<String>,
+}
+
+/// Address in a `MAIL FROM:<...>` or `RCPT TO:<...>` argument, after the
+/// `keyword`. ESMTP parameters following the path are ignored.
+pub fn parse_path<'a>(arg: &'a str, keyword: &str) -> Option<&'a str> {
+    let (head, rest) = arg.split_once(':')?;
+    if !head.trim().eq_ignore_ascii_case(keyword) {
+        return None;
+    }
+    let rest = rest.trim_start().strip_prefix('<')?;
+    let (path, _params) = rest.split_once('>')?;
+    if path.contains(['<', ' ', '\r', '\n']) {
+        return None;
+    }
+    Some(path)
+}
+
+/// Whether mail for `address` is accepted: listed exactly, or its domain
+/// listed as `@domain`
+pub fn accepts(config: &InboundMailConfig, address: &str) -> bool {
+    let domain = address.rsplit_once('@').map(|(_, domain)| domain);
+    config
+        .recipients
+        .iter()
+        .any(|entry| match entry.strip_prefix('@') {
+            Some(listed) => domain.is_some_and(|domain| domain.eq_ignore_ascii_case(listed)),
+            None => entry.eq_ignore_ascii_case(address),
+        })
+}
+
+/// Read a message after DATA, up to the lone "." line, undoing dot
+/// stuffing. Returns None if it grew past `max_size`; the rest is still
+/// read so the connection stays in step.
+pub async fn read_data<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+    max_size: usize,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut message = Vec::new();
+    let mut too_large = false;
+    loop {
+        while let Some(pos) = buf.windows(2).position(|w| w == b"\r\n") {
+            let line = buf.split_to(pos + 2);
+            let line = &line[..pos];
+            if line == b"." {
+                return Ok((!too_large).then_some(message));
+            }
+            let line = line.strip_prefix(b".").unwrap_or(line);
+            if message.len() + line.len() + 2 > max_size {
+                too_large = true;
+                message = Vec::new();
+            }
+            if !too_large {
+                message.extend_from_slice(line);
+                message.extend_from_slice(b"\r\n");
+            }
+        }
+        if buf.len() > MAX_DATA_LINE {
+            // Keep the last byte in case it's the start of a line break
+            too_large = true;
+            let _ = buf.split_to(buf.len() - 1);
+        }
+        buf.reserve(4096);
+        if stream.read_buf(buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+/// Deliver a received message wherever the config says
+pub async fn deliver(
+    config: &InboundMailConfig,
+    hostname: &str,
+    client: IpAddr,
+    envelope: &Envelope,
+    message: &[u8],
+) -> anyhow::Result<()> {
+    let now = time::OffsetDateTime::now_utc()
+        .format(&time::format_description::well_known::Rfc2822)
+        .unwrap_or_default();
+    let mut stamped = format!(
+        "Return-Path: <{}>\r\nReceived: from [{}] by {} with ESMTP; {}\r\n",
+        envelope.from, client, hostname, now
+    )
+    .into_bytes();
+    stamped.extend_from_slice(message);
+
+    if let Some(maildir) = &config.maildir {
+        write_maildir(Path::new(maildir), hostname, &stamped).await?;
+    }
+    if let Some(upstream) = &config.forward {
+        tokio::time::timeout(
+            FORWARD_TIMEOUT,
+            forward(upstream, hostname, envelope, &stamped),
+        )
+        .await
+        .map_err(|_| anyhow::anyhow!("Timed out forwarding to {}", upstream))??;
+    }
+    Ok(())
+}
+
+/// Write a message into `maildir/new`, via `tmp` so readers never see it
+/// half written
+async fn write_maildir(maildir: &Path, hostname: &str, message: &[u8]) -> io::Result<()> {
+    for sub in ["tmp", "new", "cur"] {
+        tokio::fs::create_dir_all(maildir.join(sub)).await?;
+    }
+    let name = format!(
+        "{}.{}.{}",
+        crate::rotation::unix_now(),
+        hex::encode(rand::random::<[u8; 8]>()),
+        hostname.replace(['/', ':'], "_")
+    );
+    let tmp = maildir.join("tmp").join(&name);
+    tokio::fs::write(&tmp, message).await?;
+    tokio::fs::rename(&tmp, maildir.join("new").join(&name)).await
+}
+
+/// Relay a message to `upstream` (host:port) over plain SMTP
+async fn forward(
+    upstream: &str,
+    hostname: &str,
+    envelope: &Envelope,
+    message: &[u8],
+) -> anyhow::Result<()> {
+    let mut stream = TcpStream::connect(upstream).await?;
+    let mut buf = BytesMut::new();
+    expect(&mut stream, &mut buf, 220).await?;
+    command(&mut stream, &mut buf, &format!("EHLO {hostname}"), 250).await?;
+    command(
+        &mut stream,
+        &mut buf,
+        &format!("MAIL FROM:<{}>", envelope.from),
+        250,
+    )
+    .await?;
+    for recipient in &envelope.recipients {
+        command(
+            &mut stream,
+            &mut buf,
+            &format!("RCPT TO:<{recipient}>"),
+            250,
+        )
+        .await?;
+    }
+    command(&mut stream, &mut buf, "DATA", 354).await?;
+
+    let mut data = Vec::with_capacity(message.len() + 16);
+    for line in message.split_inclusive(|&b| b == b'\n') {
+        if line.starts_with(b".") {
+            data.push(b'.');
+        }
+        data.extend_from_slice(line);
+    }
+    if !data.ends_with(b"\r\n") {
+        data.extend_from_slice(b"\r\n");
+    }
+    data.extend_from_slice(b".\r\n");
+    stream.write_all(&data).await?;
+    expect(&mut stream, &mut buf, 250).await?;
+
+    let _ = stream.write_all(b"QUIT\r\n").await;
+    Ok(())
+}
+
+async fn command<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+    line: &str,
+    code: u16,
+) -> anyhow::Result<()> {
+    stream.write_all(format!("{line}\r\n").as_bytes()).await?;
+    expect(stream, buf, code).await
+}
+
+/// Read a (possibly multi-line) reply and check its code
+async fn expect<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+    code: u16,
+) -> anyhow::Result<()> {
+    loop {
+        let line = read_line(stream, buf)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Upstream closed the connection"))?;
+        let got: u16 = line
+            .get(..3)
+            .and_then(|c| c.parse().ok())
+            .ok_or_else(|| anyhow::anyhow!("Malformed upstream reply {:?}", line))?;
+        if got != code {
+            anyhow::bail!("Upstream replied {:?}", line);
+        }
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paths_and_recipients() {
+        assert_eq!(
+            parse_path("FROM:<a@example.org> SIZE=100", "FROM"),
+            Some("a@example.org")
+        );
+        assert_eq!(parse_path("from: <>", "FROM"), Some(""));
+        assert_eq!(parse_path("TO:<b@example.org>", "FROM"), None);
+        assert_eq!(parse_path("TO:b@example.org", "TO"), None);
+
+        let config = InboundMailConfig {
+            recipients: vec!["postmaster@mx.example.com".into(), "@example.org".into()],
+            ..Default::default()
+        };
+        assert!(accepts(&config, "Postmaster@mx.example.com"));
+        assert!(accepts(&config, "anyone@EXAMPLE.org"));
+        assert!(!accepts(&config, "root@mx.example.com"));
+        assert!(!accepts(&config, "example.org"));
+    }
+
+    #[tokio::test]
+    async fn test_read_data() {
+        let mut stream = &b"Subject: hi\r\n\r\n..leading dot\r\n.\r\nQUIT\r\n"[..];
+        let mut buf = BytesMut::new();
+        let message = read_data(&mut stream, &mut buf, 1024).await.unwrap();
+        assert_eq!(
+            message.as_deref(),
+            Some(&b"Subject: hi\r\n\r\n.leading dot\r\n"[..])
+        );
+        // The next command stays buffered
+        assert_eq!(&buf[..], b"QUIT\r\n");
+
+        let mut stream = &b"0123456789\r\n0123456789\r\n.\r\n"[..];
+        let message = read_data(&mut stream, &mut BytesMut::new(), 16)
+            .await
+            .unwrap();
+        assert_eq!(message, None);
+    }
+}
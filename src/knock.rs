@@ -0,0 +1,209 @@
+//! Single-packet authorization
+//!
+//! With a knock port configured the server answers SMTP only to addresses
+//! that recently sent it a valid knock: one UDP datagram carrying a username,
+//! timestamp and random nonce, signed with that user's secret. Everyone else
+//! has their connection reset before the greeting, so scanners see a closed
+//! port. Knocks are never answered, and each nonce is accepted once.
+
+use crate::config::UsersConfig;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Knocks whose timestamp is further than this from our clock are ignored
+pub const MAX_CLOCK_SKEW: Duration = Duration::from_secs(60);
+
+const NONCE_LEN: usize = 16;
+const MAC_LEN: usize = 32;
+
+/// Build a knock packet for `username`:
+/// len(1) + username + timestamp(8) + nonce(16) + HMAC-SHA256(32)
+pub fn packet(secret: &str, username: &str, timestamp: u64) -> Vec<u8> {
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    let mut packet = Vec::with_capacity(1 + username.len() + 8 + NONCE_LEN + MAC_LEN);
+    packet.push(username.len() as u8);
+    packet.extend_from_slice(username.as_bytes());
+    packet.extend_from_slice(&timestamp.to_be_bytes());
+    packet.extend_from_slice(&nonce);
+    let mac = sign(secret, &packet);
+    packet.extend_from_slice(&mac);
+    packet
+}
+
+fn sign(secret: &str, signed: &[u8]) -> [u8; MAC_LEN] {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC can take key of any size");
+    mac.update(b"smtp-tunnel-knock:");
+    mac.update(signed);
+    mac.finalize().into_bytes().into()
+}
+
+/// A knock's parts, before its signature is checked
+struct Knock<'a> {
+    username: &'a str,
+    timestamp: u64,
+    nonce: [u8; NONCE_LEN],
+    signed: &'a [u8],
+    mac: &'a [u8],
+}
+
+impl<'a> Knock<'a> {
+    fn parse(packet: &'a [u8]) -> Option<Self> {
+        let (&len, rest) = packet.split_first()?;
+        let len = len as usize;
+        if rest.len() != len + 8 + NONCE_LEN + MAC_LEN {
+            return None;
+        }
+        let username = std::str::from_utf8(&rest[..len]).ok()?;
+        let timestamp = u64::from_be_bytes(rest[len..len + 8].try_into().ok()?);
+        let nonce = rest[len + 8..len + 8 + NONCE_LEN].try_into().ok()?;
+        let (signed, mac) = packet.split_at(packet.len() - MAC_LEN);
+        Some(Self {
+            username,
+            timestamp,
+            nonce,
+            signed,
+            mac,
+        })
+    }
+}
+
+/// Addresses allowed to connect, opened by knocks
+#[derive(Debug)]
+pub struct KnockGate {
+    /// How long a knock keeps its address open
+    window: Duration,
+    open: Mutex<HashMap<IpAddr, Instant>>,
+    /// Nonces accepted within the last [`MAX_CLOCK_SKEW`] * 2, to stop replays
+    seen: Mutex<HashMap<[u8; NONCE_LEN], Instant>>,
+}
+
+impl KnockGate {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            open: Mutex::default(),
+            seen: Mutex::default(),
+        }
+    }
+
+    /// Check a knock from `from`, opening the gate for it if valid.
+    /// Returns the user who knocked.
+    pub fn knock(&self, packet: &[u8], from: IpAddr, users: &UsersConfig) -> Option<String> {
+        let knock = Knock::parse(packet)?;
+        let now_secs = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+        if now_secs.abs_diff(knock.timestamp) > MAX_CLOCK_SKEW.as_secs() {
+            return None;
+        }
+        let user = users.get_user(knock.username)?;
+        let expected = sign(&user.secret, knock.signed);
+        // Constant-time comparison
+        let diff = expected
+            .iter()
+            .zip(knock.mac)
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        if diff != 0 {
+            return None;
+        }
+
+        let now = Instant::now();
+        {
+            let mut seen = self.seen.lock().unwrap();
+            seen.retain(|_, at| now.duration_since(*at) < MAX_CLOCK_SKEW * 2);
+            if seen.insert(knock.nonce, now).is_some() {
+                return None;
+            }
+        }
+        self.open(from, now);
+        Some(knock.username.to_string())
+    }
+
+    /// Let `addr` connect for the next `window`
+    pub fn open(&self, addr: IpAddr, now: Instant) {
+        let mut open = self.open.lock().unwrap();
+        open.retain(|_, until| *until > now);
+        open.insert(addr, now + self.window);
+    }
+
+    /// Stop letting `addr` connect until it knocks again
+    pub fn close(&self, addr: IpAddr) {
+        self.open.lock().unwrap().remove(&addr);
+    }
+
+    /// Whether `addr` knocked recently enough to connect
+    pub fn is_open(&self, addr: IpAddr) -> bool {
+        self.open
+            .lock()
+            .unwrap()
+            .get(&addr)
+            .is_some_and(|until| *until > Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UserEntry;
+
+    fn users() -> UsersConfig {
+        let mut users = UsersConfig::default();
+        users.users.insert(
+            "alice".to_string(),
+            UserEntry {
+                secret: "s3cret".to_string(),
+                whitelist: Vec::new(),
+                logging: true,
+            },
+        );
+        users
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    #[test]
+    fn test_knock_opens_gate_once() {
+        let gate = KnockGate::new(Duration::from_secs(30));
+        let users = users();
+        let addr: IpAddr = "192.0.2.7".parse().unwrap();
+        assert!(!gate.is_open(addr));
+
+        let packet = packet("s3cret", "alice", now());
+        assert_eq!(gate.knock(&packet, addr, &users).as_deref(), Some("alice"));
+        assert!(gate.is_open(addr));
+        assert!(!gate.is_open("192.0.2.8".parse().unwrap()));
+
+        // Replaying the same packet from elsewhere does nothing
+        let other: IpAddr = "198.51.100.1".parse().unwrap();
+        assert_eq!(gate.knock(&packet, other, &users), None);
+        assert!(!gate.is_open(other));
+    }
+
+    #[test]
+    fn test_knock_rejects_bad_packets() {
+        let gate = KnockGate::new(Duration::from_secs(30));
+        let users = users();
+        let addr: IpAddr = "192.0.2.7".parse().unwrap();
+
+        let wrong_secret = packet("guess", "alice", now());
+        let unknown_user = packet("s3cret", "mallory", now());
+        let stale = packet("s3cret", "alice", now() - 2 * MAX_CLOCK_SKEW.as_secs());
+        let mut tampered = packet("s3cret", "alice", now());
+        tampered[1] ^= 1;
+        for packet in [&wrong_secret, &unknown_user, &stale, &tampered] {
+            assert_eq!(gate.knock(packet, addr, &users), None);
+        }
+        assert_eq!(gate.knock(b"GET / HTTP/1.1\r\n", addr, &users), None);
+        assert!(!gate.is_open(addr));
+    }
+}
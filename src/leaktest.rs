@@ -0,0 +1,290 @@
+//! End-to-end leak test
+//!
+//! `smtp-tunnel-client leaktest` checks, through the running client's SOCKS
+//! proxy, that what should go through the tunnel does: an IP echo service
+//! fetched through the proxy must see a different public address than one
+//! fetched directly, and hostnames must be resolved by the server. It also
+//! lists the paths by which traffic leaves the machine directly anyway:
+//! `direct` rules, local DNS resolution, transparent mode's DNS and IPv6,
+//! and UDP, which the proxy doesn't carry and applications may send
+//! directly instead.
+
+use crate::config::{ClientConfig, DnsMode, ListenerAuth};
+use crate::http::parse_http_response;
+use crate::socks5::{ATYP_IPV4, CMD_UDP_ASSOCIATE, VERSION};
+use crate::upstream::{socks5_connect, socks5_login, socks5_reply};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// IP echo service asked for the public address
+pub const DEFAULT_IP_URL: &str = "http://api.ipify.org/";
+
+/// Name whose A record is the address of the resolver asking for it
+const RESOLVER_ECHO: &str = "whoami.akamai.net";
+
+/// How long one fetch or proxy request may take
+const TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Largest IP echo response read
+const MAX_RESPONSE: u64 = 16 * 1024;
+
+/// One check's result
+#[derive(Debug, Clone)]
+pub struct Finding {
+    pub check: String,
+    /// Traffic that leaves the machine outside the tunnel
+    pub leak: bool,
+    pub detail: String,
+}
+
+/// Everything the leak test found
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub proxy: SocketAddr,
+    pub findings: Vec<Finding>,
+}
+
+impl Report {
+    fn ok(&mut self, check: impl Into<String>, detail: impl Into<String>) {
+        self.push(check, false, detail);
+    }
+
+    fn leak(&mut self, check: impl Into<String>, detail: impl Into<String>) {
+        self.push(check, true, detail);
+    }
+
+    fn push(&mut self, check: impl Into<String>, leak: bool, detail: impl Into<String>) {
+        self.findings.push(Finding {
+            check: check.into(),
+            leak,
+            detail: detail.into(),
+        });
+    }
+
+    /// Number of leaks found
+    pub fn leaks(&self) -> usize {
+        self.findings.iter().filter(|f| f.leak).count()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Leak test through {}", self.proxy)?;
+        for finding in &self.findings {
+            let mark = if finding.leak { "LEAK" } else { "ok" };
+            writeln!(f, "  {:5} {}: {}", mark, finding.check, finding.detail)?;
+        }
+        match self.leaks() {
+            0 => writeln!(f, "No leaks found"),
+            n => writeln!(f, "{n} leaks found"),
+        }
+    }
+}
+
+/// Test the proxy the client described by `config` runs (its first
+/// listener), asking `ip_url` (plain http://) for the public address
+pub async fn run(config: &ClientConfig, ip_url: &str) -> anyhow::Result<Report> {
+    let (host, port, path) = parse_url(ip_url)?;
+    let listener = config.socks_listeners().remove(0);
+    let auth = listener.auth.as_ref();
+    let mut proxy = listener.bind_addr()?;
+    if proxy.ip().is_unspecified() {
+        proxy.set_ip(match proxy {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    TcpStream::connect(proxy)
+        .await
+        .map_err(|e| anyhow::anyhow!("No proxy at {proxy} ({e}); start the client first"))?;
+    let mut report = Report {
+        proxy,
+        findings: Vec::new(),
+    };
+
+    // The hostname goes to the proxy, so the server resolves it too
+    let tunneled = async {
+        let mut stream = socks_connect(proxy, auth, host, port).await?;
+        fetch_ip(&mut stream, host, path).await
+    };
+    let direct = async {
+        let mut stream = tokio::time::timeout(TIMEOUT, TcpStream::connect((host, port))).await??;
+        fetch_ip(&mut stream, host, path).await
+    };
+    let (tunneled, direct) = tokio::join!(tunneled, direct);
+    match (tunneled, direct) {
+        (Err(e), _) => report.leak(
+            "egress",
+            format!("couldn't reach {host} through the tunnel: {e:#}"),
+        ),
+        (Ok(tunneled), Ok(direct)) if tunneled == direct => report.leak(
+            "egress",
+            format!("tunneled traffic leaves from this machine's address {direct}"),
+        ),
+        (Ok(tunneled), Ok(direct)) => report.ok(
+            "egress",
+            format!("tunneled traffic leaves from {tunneled}, this machine from {direct}"),
+        ),
+        (Ok(tunneled), Err(e)) => report.ok(
+            "egress",
+            format!("tunneled traffic leaves from {tunneled}; direct fetch failed ({e:#})"),
+        ),
+    }
+
+    let resolver = tokio::net::lookup_host((RESOLVER_ECHO, 0))
+        .await
+        .ok()
+        .and_then(|mut addrs| addrs.next())
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_else(|| "the local resolver".to_string());
+    match config.dns {
+        DnsMode::Local => report.leak(
+            "dns",
+            format!("dns: local resolves SOCKS hostnames here, through {resolver}"),
+        ),
+        DnsMode::Remote => report.ok(
+            "dns",
+            format!(
+                "SOCKS hostnames are resolved by the server; applications that resolve \
+                 names themselves (socks5:// rather than socks5h://) ask {resolver}"
+            ),
+        ),
+    }
+
+    if let Some(stub) = &config.dns_stub {
+        report.ok(
+            "dns",
+            format!(
+                "lookups sent to {}:{} are resolved by the server, for the system \
+                 resolver to use",
+                stub.host, stub.port
+            ),
+        );
+    }
+
+    for rule in &config.direct {
+        report.leak("direct", format!("connections to {rule} bypass the tunnel"));
+    }
+
+    match socks_udp_associate(proxy, auth).await {
+        Ok(()) => report.ok("udp", "the proxy relays UDP"),
+        Err(e) => report.leak(
+            "udp",
+            format!(
+                "the proxy doesn't relay UDP ({e:#}); applications falling back to \
+                 direct UDP (QUIC, WebRTC, DNS) bypass the tunnel"
+            ),
+        ),
+    }
+
+    if let Some(transparent) = &config.transparent {
+        report.leak(
+            "transparent",
+            format!(
+                "DNS lookups and IPv6 from {} go directly",
+                transparent.processes.join(", ")
+            ),
+        );
+    }
+    Ok(report)
+}
+
+/// Split "http://host[:port]/path"
+fn parse_url(url: &str) -> anyhow::Result<(&str, u16, &str)> {
+    let invalid = || anyhow::anyhow!("Invalid IP echo URL {url}: need http://host[:port]/path");
+    let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+        None => (authority, 80),
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host, port, path))
+}
+
+/// GET `path` and read the address in the body
+async fn fetch_ip<S>(stream: &mut S, host: &str, path: &str) -> anyhow::Result<IpAddr>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let request = format!(
+        "GET {path} HTTP/1.0\r\nHost: {host}\r\nUser-Agent: smtp-tunnel/{}\r\n\r\n",
+        crate::VERSION
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    tokio::time::timeout(
+        TIMEOUT,
+        stream.take(MAX_RESPONSE).read_to_end(&mut response),
+    )
+    .await??;
+    let response = parse_http_response(&response)?;
+    if response.status != 200 {
+        anyhow::bail!("{host} answered HTTP {}", response.status);
+    }
+    let body = String::from_utf8_lossy(response.body);
+    body.trim()
+        .parse()
+        .map_err(|_| anyhow::anyhow!("{host} didn't answer with an address"))
+}
+
+/// Open a SOCKS5 CONNECT to `host:port`
+async fn socks_connect(
+    proxy: SocketAddr,
+    auth: Option<&ListenerAuth>,
+    host: &str,
+    port: u16,
+) -> anyhow::Result<TcpStream> {
+    let mut stream = socks_greet(proxy, auth).await?;
+    tokio::time::timeout(TIMEOUT, socks5_connect(&mut stream, host, port)).await??;
+    Ok(stream)
+}
+
+/// Ask for a SOCKS5 UDP association
+async fn socks_udp_associate(proxy: SocketAddr, auth: Option<&ListenerAuth>) -> anyhow::Result<()> {
+    let mut stream = socks_greet(proxy, auth).await?;
+    stream
+        .write_all(&[VERSION, CMD_UDP_ASSOCIATE, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    tokio::time::timeout(TIMEOUT, socks5_reply(&mut stream)).await?
+}
+
+/// Connect to the proxy and log in with `auth`, or agree on no
+/// authentication
+async fn socks_greet(proxy: SocketAddr, auth: Option<&ListenerAuth>) -> anyhow::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    let auth = auth.map(|auth| (auth.username.as_str(), auth.password.as_str()));
+    socks5_login(&mut stream, auth).await?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            parse_url("http://api.ipify.org/").unwrap(),
+            ("api.ipify.org", 80, "/")
+        );
+        assert_eq!(
+            parse_url("http://127.0.0.1:8080/ip?format=text").unwrap(),
+            ("127.0.0.1", 8080, "/ip?format=text")
+        );
+        assert_eq!(
+            parse_url("http://ifconfig.me").unwrap(),
+            ("ifconfig.me", 80, "/")
+        );
+        assert!(parse_url("https://api.ipify.org/").is_err());
+        assert!(parse_url("http://:80/").is_err());
+    }
+}
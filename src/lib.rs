@@ -13,12 +13,81 @@
 //! └─────────────┘      └─────────────┘      └─────────────┘      └──────────────┘
 //! ```
 
+//! ## Features
+//!
+//! The protocol core (`proto`, `crypto`, `config`) is always built. The
+//! `client` and `server` features add the two halves of the tunnel, and
+//! `tools` the user and certificate tooling; an embedder or a router build
+//! enables only the half it runs.
+
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod acl;
+#[cfg(any(feature = "server", feature = "tools"))]
+pub mod admin;
+#[cfg(feature = "server")]
+pub mod audit;
+#[cfg(feature = "server")]
+pub mod camouflage;
+pub mod channel;
+#[cfg(feature = "testing")]
+pub mod chaos;
+#[cfg(feature = "client")]
 pub mod client;
 pub mod config;
+#[cfg(feature = "hyper")]
+pub mod connector;
+#[cfg(all(feature = "client", not(feature = "minimal")))]
+pub mod control;
 pub mod crypto;
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod dane;
+#[cfg(feature = "client")]
+pub mod dnsstub;
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod http;
+#[cfg(feature = "server")]
+pub mod inbound;
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod knock;
+#[cfg(feature = "client")]
+pub mod leaktest;
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod link;
+pub mod logging;
+pub mod metrics;
+#[cfg(feature = "client")]
+pub mod pac;
+#[cfg(feature = "tools")]
+pub mod pcap;
+pub mod platform;
 pub mod proto;
+#[cfg(feature = "server")]
+pub mod records;
+#[cfg(feature = "server")]
+pub mod resolver;
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod rotation;
+#[cfg(feature = "client")]
+pub mod routes;
+#[cfg(feature = "server")]
 pub mod server;
+#[cfg(feature = "tower")]
+pub mod service;
+#[cfg(feature = "client")]
 pub mod socks5;
+#[cfg(any(feature = "client", feature = "server"))]
+pub mod tasks;
+#[cfg(feature = "server")]
+pub mod throttle;
+pub mod tls;
+#[cfg(feature = "client")]
+pub mod transparent;
+#[cfg(all(feature = "client", feature = "server"))]
+pub mod transport;
+#[cfg(feature = "client")]
+pub mod upstream;
+#[cfg(feature = "server")]
+pub mod users;
 
 // Re-export commonly used items
 pub use config::{ClientConfig, Config, ServerConfig, UserEntry, UsersConfig};
@@ -44,6 +113,26 @@ pub enum Error {
 
     #[error("Protocol error: {0}")]
     Protocol(String),
+
+    #[error(
+        "SOCKS port {port} on {host} is already in use{}; set socks_port to a free port, or 0 for any",
+        fallback_note(.fallbacks)
+    )]
+    SocksPortInUse {
+        host: std::net::IpAddr,
+        port: u16,
+        fallbacks: Vec<u16>,
+    },
+}
+
+fn fallback_note(fallbacks: &[u16]) -> String {
+    match fallbacks {
+        [] => String::new(),
+        ports => {
+            let ports: Vec<String> = ports.iter().map(u16::to_string).collect();
+            format!(", as are {}", ports.join(", "))
+        }
+    }
 }
 
 /// Result type for SMTP Tunnel
@@ -51,3 +140,52 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 /// Version information
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Cargo features this build was compiled with
+pub fn enabled_features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(feature = "client") {
+        features.push("client");
+    }
+    if cfg!(feature = "server") {
+        features.push("server");
+    }
+    if cfg!(feature = "tools") {
+        features.push("tools");
+    }
+    if cfg!(feature = "compression") {
+        features.push("compression");
+    }
+    if cfg!(feature = "minimal") {
+        features.push("minimal");
+    }
+    if cfg!(feature = "sqlite") {
+        features.push("sqlite");
+    }
+    if cfg!(feature = "windivert") {
+        features.push("windivert");
+    }
+    if cfg!(feature = "hyper") {
+        features.push("hyper");
+    }
+    if cfg!(feature = "tower") {
+        features.push("tower");
+    }
+    if cfg!(feature = "testing") {
+        features.push("testing");
+    }
+    if cfg!(feature = "tls-aws-lc") {
+        features.push("tls-aws-lc");
+    } else if cfg!(feature = "tls-ring") {
+        features.push("tls-ring");
+    }
+    features
+}
+
+/// Buffer size used when copying between proxied sockets
+#[cfg(not(feature = "minimal"))]
+pub const IO_BUFFER_SIZE: usize = 16 * 1024;
+
+/// Buffer size used when copying between proxied sockets (router builds)
+#[cfg(feature = "minimal")]
+pub const IO_BUFFER_SIZE: usize = 4 * 1024;
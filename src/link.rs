@@ -0,0 +1,1126 @@
+//! Resumable frame link between a session and its connection
+//!
+//! A [`Link`] sits between a session's frame loop and the TLS connection it
+//! currently runs over. Every frame that carries session state is numbered
+//! by its position in the stream and kept until the peer acknowledges it
+//! with an ACK frame. When the connection drops, the session can be
+//! attached to a new one: each side tells the other how many frames it has
+//! received and the rest are replayed, so open channels carry on as if
+//! nothing happened.
+//!
+//! Keepalives and ACKs belong to one connection and are handled here; the
+//! frame loops never see them.
+
+use crate::config::ClientConfig;
+use crate::crypto::SessionKeys;
+use crate::metrics::FrameStats;
+use crate::proto::compress::{self, Compression};
+use crate::proto::padding::Padding;
+use crate::proto::schedule::Scheduler;
+use crate::proto::seal::{SEAL_OVERHEAD, SealedCodec};
+use crate::proto::sequence::{SEQUENCE_SIZE, Sequencer, Verdict};
+use crate::proto::{Frame, FrameCodec, FrameType, MAX_LARGE_PAYLOAD_SIZE, MAX_PAYLOAD_SIZE};
+use anyhow::{Context, anyhow, bail};
+use bytes::BytesMut;
+use futures_util::{SinkExt, StreamExt};
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{Notify, OwnedMutexGuard, mpsc, watch};
+use tokio_util::codec::{FramedRead, FramedWrite};
+use tracing::{debug, trace, warn};
+
+/// Frames queued for the frame loop before the link stops reading
+const INBOUND_QUEUE_SIZE: usize = 64;
+
+/// Frames queued for the peer before the frame loop waits on the link
+const OUTBOUND_QUEUE_SIZE: usize = 256;
+
+/// Acknowledge after this many received frames...
+const ACK_EVERY: u64 = 32;
+
+/// ...or at least this often while frames keep arriving
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Unacknowledged frame bytes kept for replay before the connection is
+/// given up on. A live peer acknowledges long before this; one that doesn't
+/// would otherwise make us hold everything sent.
+#[cfg(not(feature = "minimal"))]
+const MAX_REPLAY_BYTES: usize = 16 * 1024 * 1024;
+
+/// Unacknowledged frame bytes kept for replay (router builds)
+#[cfg(feature = "minimal")]
+const MAX_REPLAY_BYTES: usize = 4 * 1024 * 1024;
+
+/// How long a closing connection waits for its last frames to go out and
+/// the peer to close its side
+pub const LINGER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Random identifier a client presents to resume its session
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SessionId([u8; 16]);
+
+impl SessionId {
+    pub fn random() -> Self {
+        use rand::RngCore;
+        let mut id = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut id);
+        Self(id)
+    }
+}
+
+impl fmt::Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SessionId({self})")
+    }
+}
+
+impl FromStr for SessionId {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let bytes = hex::decode(s)?;
+        let id = bytes
+            .try_into()
+            .map_err(|_| anyhow!("Session ID must be 16 bytes"))?;
+        Ok(Self(id))
+    }
+}
+
+/// Keepalive schedule for a connection
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: Duration,
+    /// Unanswered keepalives in a row before the connection counts as dead
+    pub misses: u32,
+}
+
+impl Heartbeat {
+    /// Schedule from the client config (None when disabled)
+    pub fn from_config(config: &ClientConfig) -> Option<Self> {
+        (config.keepalive_interval > 0).then(|| Self {
+            interval: Duration::from_secs(config.keepalive_interval),
+            misses: config.keepalive_misses.max(1),
+        })
+    }
+}
+
+/// Nagle-style coalescing of queued frames into one write
+#[derive(Debug, Clone, Copy)]
+pub struct Batching {
+    /// Longest a frame waits for others to share its write (zero: send
+    /// whatever is queued right away)
+    pub max_delay: Duration,
+    /// Write once this many bytes are pending, whatever the delay
+    pub max_bytes: usize,
+}
+
+impl Default for Batching {
+    fn default() -> Self {
+        Self {
+            max_delay: Duration::ZERO,
+            max_bytes: 16 * 1024,
+        }
+    }
+}
+
+/// How a link carries its connection
+#[derive(Debug, Clone, Default)]
+pub struct LinkOptions {
+    /// Send keepalives, failing once too many go unanswered
+    pub heartbeat: Option<Heartbeat>,
+    /// Fail when nothing arrives for this long
+    pub idle_timeout: Option<Duration>,
+    pub batching: Batching,
+    /// Payload bytes a channel may send before the next one at its
+    /// priority gets a turn (0 = one frame each)
+    pub channel_turn: usize,
+    /// Both sides agreed on frames over 64 KiB in HELLO
+    pub large_frames: bool,
+    /// Both sides agreed on compression in HELLO: compressed frames are
+    /// accepted, and DATA is sent compressed per these settings
+    pub compression: Option<Compression>,
+    /// Both sides agreed on padding in HELLO and ours is configured
+    pub padding: Option<Padding>,
+    /// Both sides agreed on frame checksums in HELLO
+    pub checksum: bool,
+    /// Both sides agreed on per-channel sequence numbers in HELLO
+    pub sequence: bool,
+    /// Keys from the key exchange in HELLO, if both sides agreed on
+    /// sealing frame payloads
+    pub keys: Option<SessionKeys>,
+    /// Where frames sent and received are counted
+    pub frame_stats: Option<Arc<FrameStats>>,
+}
+
+/// Why a connection stopped carrying its link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Detached {
+    /// Another connection attached to the link
+    Replaced,
+    /// The session is over
+    Closed,
+}
+
+/// Session frames sent but not yet acknowledged
+#[derive(Debug, Default)]
+struct Replay {
+    frames: VecDeque<Frame>,
+    /// Sequence number of the first frame in `frames`
+    base: u64,
+    /// Encoded size of `frames`
+    bytes: usize,
+}
+
+impl Replay {
+    /// Keep a frame about to be sent. Fails once the peer has left more
+    /// than [`MAX_REPLAY_BYTES`] unacknowledged; the frame is still kept,
+    /// so it goes out on resume like any other.
+    fn push(&mut self, frame: Frame) -> anyhow::Result<()> {
+        self.bytes += frame.encoded_len();
+        self.frames.push_back(frame);
+        if self.bytes > MAX_REPLAY_BYTES {
+            bail!("Peer left {} bytes of frames unacknowledged", self.bytes);
+        }
+        Ok(())
+    }
+
+    /// Frames sent so far, acknowledged or not
+    fn sent(&self) -> u64 {
+        self.base + self.frames.len() as u64
+    }
+
+    /// Drop the frames the peer has received
+    fn ack(&mut self, received: u64) -> anyhow::Result<()> {
+        if received > self.sent() {
+            bail!(
+                "Peer acknowledged {} frames but only {} were sent",
+                received,
+                self.sent()
+            );
+        }
+        while self.base < received {
+            if let Some(frame) = self.frames.pop_front() {
+                self.bytes -= frame.encoded_len();
+            }
+            self.base += 1;
+        }
+        Ok(())
+    }
+
+    /// Frames to send again to a peer that has received `received`
+    fn resume(&mut self, received: u64) -> anyhow::Result<Vec<Frame>> {
+        if received < self.base {
+            bail!(
+                "Peer lost frames {}..{} that were already acknowledged",
+                received,
+                self.base
+            );
+        }
+        self.ack(received)?;
+        Ok(self.frames.iter().cloned().collect())
+    }
+}
+
+/// One session's frame stream, able to move between connections
+pub struct Link {
+    id: SessionId,
+    /// Frames for the frame loop; taken when the session closes
+    inbound: Mutex<Option<mpsc::Sender<Frame>>>,
+    /// Frames from the frame loop; held by the attached connection
+    outbound: Arc<tokio::sync::Mutex<mpsc::Receiver<Frame>>>,
+    /// For a CLOSE of our own, without keeping the session alive
+    outbound_tx: mpsc::WeakSender<Frame>,
+    replay: Mutex<Replay>,
+    /// Frames taken from the frame loop but not yet written; kept across
+    /// connections like `outbound`
+    schedule: Mutex<Scheduler>,
+    /// Session frames received and handed to the frame loop
+    received: AtomicU64,
+    /// Channels' sequence numbers, if agreed; they carry on across
+    /// connections like the frames in `replay`
+    sequencer: Mutex<Sequencer>,
+    /// Round trip of the latest answered keepalive
+    rtt: Mutex<Option<Duration>>,
+    /// Bumped when a connection attaches, telling the previous one to stop
+    generation: watch::Sender<u64>,
+}
+
+impl fmt::Debug for Link {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Link")
+            .field("id", &self.id)
+            .field("received", &self.received())
+            .finish_non_exhaustive()
+    }
+}
+
+/// Exclusive use of a link, from [`Link::attach`]
+pub struct Attachment {
+    generation: u64,
+    outbound: OwnedMutexGuard<mpsc::Receiver<Frame>>,
+}
+
+impl Attachment {
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+impl Link {
+    /// Create a link, returning it along with the frame loop's ends: the
+    /// receiver for frames from the peer and the sender for frames to it
+    pub fn new(id: SessionId) -> (Arc<Self>, mpsc::Receiver<Frame>, mpsc::Sender<Frame>) {
+        let (inbound_tx, inbound_rx) = mpsc::channel(INBOUND_QUEUE_SIZE);
+        let (outbound_tx, outbound_rx) = mpsc::channel(OUTBOUND_QUEUE_SIZE);
+        let link = Arc::new(Self {
+            id,
+            inbound: Mutex::new(Some(inbound_tx)),
+            outbound: Arc::new(tokio::sync::Mutex::new(outbound_rx)),
+            outbound_tx: outbound_tx.downgrade(),
+            replay: Mutex::default(),
+            schedule: Mutex::default(),
+            received: AtomicU64::new(0),
+            sequencer: Mutex::default(),
+            rtt: Mutex::default(),
+            generation: watch::Sender::new(0),
+        });
+        (link, inbound_rx, outbound_tx)
+    }
+
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    /// Session frames received so far
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Acquire)
+    }
+
+    /// Round trip of the latest keepalive the peer answered, if any were
+    /// sent
+    pub fn rtt(&self) -> Option<Duration> {
+        *self.rtt.lock().unwrap()
+    }
+
+    /// Take over the link, stopping the connection that carries it now.
+    ///
+    /// Once this returns [`received`](Self::received) stays put until the
+    /// attachment is [run](Self::run), so it can be sent to the peer.
+    pub async fn attach(&self) -> Attachment {
+        let mut generation = 0;
+        self.generation.send_modify(|current| {
+            *current += 1;
+            generation = *current;
+        });
+        let outbound = Arc::clone(&self.outbound).lock_owned().await;
+        Attachment {
+            generation,
+            outbound,
+        }
+    }
+
+    /// Wait up to `grace` for a connection to replace the one attached as
+    /// `generation`; false if none did
+    pub async fn wait_for_resume(&self, generation: u64, grace: Duration) -> bool {
+        let mut attached = self.generation.subscribe();
+        matches!(
+            tokio::time::timeout(grace, attached.wait_for(|current| *current != generation)).await,
+            Ok(Ok(_))
+        )
+    }
+
+    /// Whether frames are waiting in the scheduler
+    fn scheduled(&self) -> bool {
+        !self.schedule.lock().unwrap().is_empty()
+    }
+
+    /// End the session: the frame loop sees its inbound frames end
+    pub fn close(&self) {
+        self.inbound.lock().unwrap().take();
+    }
+
+    /// Carry the link over `stream` until the connection fails, another
+    /// connection attaches or the session ends.
+    ///
+    /// `peer_received` is the peer's count of session frames from us; any
+    /// we sent beyond it are replayed first. Errors mean the connection was
+    /// lost and the session may be resumed on another.
+    pub async fn run<S>(
+        &self,
+        attachment: Attachment,
+        stream: S,
+        leftover: BytesMut,
+        peer_received: u64,
+        options: LinkOptions,
+    ) -> anyhow::Result<Detached>
+    where
+        S: AsyncRead + AsyncWrite,
+    {
+        let Attachment {
+            generation,
+            mut outbound,
+        } = attachment;
+        let Some(inbound) = self.inbound.lock().unwrap().clone() else {
+            return Ok(Detached::Closed);
+        };
+        let mut attached = self.generation.subscribe();
+
+        let (reader, writer) = tokio::io::split(stream);
+        let reader = AsyncReadExt::chain(std::io::Cursor::new(leftover), reader);
+        let (codec, max_payload) = if options.large_frames {
+            (FrameCodec::large(), MAX_LARGE_PAYLOAD_SIZE)
+        } else {
+            (FrameCodec::new(), MAX_PAYLOAD_SIZE)
+        };
+        let codec = if options.checksum {
+            codec.with_checksum()
+        } else {
+            codec
+        };
+        // Sealing adds a tag, which must still fit in the peer's limit
+        let max_payload = match options.keys {
+            Some(_) => max_payload - SEAL_OVERHEAD,
+            None => max_payload,
+        };
+        // Room for the sequence number in DATA
+        let max_data = if options.sequence {
+            max_payload - SEQUENCE_SIZE
+        } else {
+            max_payload
+        };
+        let keys = options.keys.as_ref();
+        let mut frames = FramedRead::new(reader, SealedCodec::new(codec, keys.map(|k| &k.recv)));
+        let mut sink = FramedWrite::new(writer, SealedCodec::new(codec, keys.map(|k| &k.send)));
+        sink.set_backpressure_boundary(options.batching.max_bytes);
+        self.schedule
+            .lock()
+            .unwrap()
+            .set_channel_turn(options.channel_turn);
+
+        let missed = self.replay.lock().unwrap().resume(peer_received)?;
+        if !missed.is_empty() {
+            debug!("Session {}: replaying {} frames", self.id, missed.len());
+        }
+        let sent = |frame: &Frame| {
+            if let Some(stats) = &options.frame_stats {
+                stats.sent(frame);
+            }
+        };
+        for frame in missed {
+            sent(&frame);
+            sink.feed(frame).await?;
+        }
+        sink.flush().await?;
+
+        // Keepalives and their answers, sent alongside session frames
+        let (control_tx, mut control_rx) = mpsc::channel::<Frame>(4);
+        let unanswered = AtomicU32::new(0);
+        // When the oldest unanswered keepalive went out
+        let keepalive_sent = Mutex::new(None::<Instant>);
+        let ack_due = Notify::new();
+
+        let read = async {
+            loop {
+                let next = match options.idle_timeout {
+                    Some(limit) => tokio::time::timeout(limit, frames.next())
+                        .await
+                        .map_err(|_| anyhow!("No frames for {}s", limit.as_secs()))?,
+                    None => frames.next().await,
+                };
+                let mut frame = next.ok_or_else(|| anyhow!("Connection closed"))??;
+                if let Some(stats) = &options.frame_stats {
+                    stats.received(&frame);
+                }
+                if frame.compressed {
+                    if options.compression.is_none() {
+                        bail!("Compressed frame without compression agreed");
+                    }
+                    frame.payload = compress::decompress(&frame.payload, max_payload)
+                        .context("Bad compressed frame")?;
+                    frame.compressed = false;
+                }
+                // Any frame shows the connection is alive
+                unanswered.store(0, Ordering::Relaxed);
+                if options.sequence {
+                    let verdict = self.sequencer.lock().unwrap().check(&mut frame);
+                    match verdict {
+                        Verdict::Deliver => {}
+                        // Not counted: the peer only sent it once
+                        Verdict::Duplicate => {
+                            debug!(
+                                "Session {}: dropped a repeated frame on channel {}",
+                                self.id, frame.channel_id
+                            );
+                            continue;
+                        }
+                        Verdict::Discard => {
+                            trace!(
+                                "Session {}: DATA for reset channel {}",
+                                self.id, frame.channel_id
+                            );
+                            self.received.fetch_add(1, Ordering::AcqRel);
+                            continue;
+                        }
+                        // Both ends drop the channel: ours sees a CLOSE in
+                        // place of the frame, and the peer gets one
+                        Verdict::Gap { expected, got } => {
+                            debug!(
+                                "Session {}: channel {} skipped from frame {} to {}, resetting it",
+                                self.id, frame.channel_id, expected, got
+                            );
+                            frame = Frame::close(frame.channel_id);
+                            if let Some(tx) = self.outbound_tx.upgrade() {
+                                let _ = tx.send(Frame::close(frame.channel_id)).await;
+                            }
+                        }
+                    }
+                }
+                match frame.frame_type {
+                    FrameType::Ack => {
+                        let received = frame.parse_ack().ok_or_else(|| anyhow!("Malformed ACK"))?;
+                        self.replay.lock().unwrap().ack(received)?;
+                    }
+                    FrameType::Keepalive => {
+                        // A full queue means the writer is stuck; the peer
+                        // will count the miss
+                        let _ = control_tx.try_send(frame.keepalive_ack());
+                    }
+                    FrameType::KeepaliveAck => {
+                        if let Some(sent) = keepalive_sent.lock().unwrap().take() {
+                            *self.rtt.lock().unwrap() = Some(sent.elapsed());
+                        }
+                    }
+                    FrameType::Padding => {}
+                    FrameType::Hello => bail!("Unexpected HELLO mid-session"),
+                    _ => {
+                        self.schedule.lock().unwrap().learn(&frame);
+                        // Counted only once delivered, so a frame lost to a
+                        // dropped connection is replayed
+                        if inbound.send(frame).await.is_err() {
+                            return Ok(Detached::Closed);
+                        }
+                        let received = self.received.fetch_add(1, Ordering::AcqRel) + 1;
+                        if received.is_multiple_of(ACK_EVERY) {
+                            ack_due.notify_one();
+                        }
+                    }
+                }
+            }
+        };
+
+        let write = async {
+            let mut acked = self.received();
+            let mut ack_timer = tokio::time::interval(ACK_INTERVAL);
+            ack_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                tokio::select! {
+                    // Frames already scheduled go out before waiting for more
+                    next = async {
+                        if self.scheduled() {
+                            Some(None)
+                        } else {
+                            outbound.recv().await.map(Some)
+                        }
+                    } => {
+                        let Some(mut next) = next else {
+                            return Ok(Detached::Closed);
+                        };
+                        let Batching { max_delay, max_bytes } = options.batching;
+                        let deadline = tokio::time::Instant::now() + max_delay;
+                        let mut pending = 0;
+                        loop {
+                            // Take what's queued so the scheduler can choose
+                            // among it, then give stragglers until the
+                            // deadline to join the write
+                            let frame = {
+                                let mut schedule = self.schedule.lock().unwrap();
+                                if let Some(frame) = next.take() {
+                                    schedule.push(frame);
+                                }
+                                while schedule.len() < OUTBOUND_QUEUE_SIZE {
+                                    let Ok(frame) = outbound.try_recv() else {
+                                        break;
+                                    };
+                                    schedule.push(frame);
+                                }
+                                schedule.pop()
+                            };
+                            let frame = match frame {
+                                Some(frame) => frame,
+                                None if !max_delay.is_zero() => {
+                                    match tokio::time::timeout_at(deadline, outbound.recv()).await {
+                                        Ok(Some(frame)) => frame,
+                                        _ => break,
+                                    }
+                                }
+                                None => break,
+                            };
+                            for mut frame in split_payload(frame, max_data) {
+                                if options.sequence {
+                                    self.sequencer.lock().unwrap().stamp(&mut frame);
+                                }
+                                if frame.frame_type == FrameType::Data
+                                    && let Some(compressed) = options
+                                        .compression
+                                        .and_then(|c| c.compress(&frame.payload))
+                                {
+                                    frame.payload = compressed;
+                                    frame.compressed = true;
+                                }
+                                let filler = options.padding.and_then(|p| p.pad(&frame));
+                                pending += frame.encoded_len();
+                                // Kept before it's written: if the write fails
+                                // the frame goes out again on resume
+                                self.replay.lock().unwrap().push(frame.clone())?;
+                                #[cfg(feature = "testing")]
+                                if crate::chaos::drop_frame() {
+                                    anyhow::bail!("Chaos: dropped a frame and its connection");
+                                }
+                                sent(&frame);
+                                sink.feed(frame).await?;
+                                if let Some(filler) = filler {
+                                    pending += filler.encoded_len();
+                                    sent(&filler);
+                                    sink.feed(filler).await?;
+                                }
+                            }
+                            if pending >= max_bytes {
+                                break;
+                            }
+                        }
+                        sink.flush().await?;
+                        // With frames always queued the writer never waits;
+                        // let the reader sharing this task have its turn
+                        tokio::task::consume_budget().await;
+                        continue;
+                    }
+                    Some(frame) = control_rx.recv() => {
+                        sent(&frame);
+                        sink.send(frame).await?;
+                        continue;
+                    }
+                    _ = ack_due.notified() => {}
+                    _ = ack_timer.tick() => {}
+                }
+                let received = self.received();
+                if received != acked {
+                    let ack = Frame::ack(received);
+                    sent(&ack);
+                    sink.send(ack).await?;
+                    acked = received;
+                }
+            }
+        };
+
+        let heartbeat = async {
+            let Some(heartbeat) = options.heartbeat else {
+                return std::future::pending().await;
+            };
+            let mut ticker = tokio::time::interval(heartbeat.interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            ticker.tick().await;
+
+            loop {
+                ticker.tick().await;
+                let unanswered = unanswered.fetch_add(1, Ordering::Relaxed);
+                if unanswered >= heartbeat.misses {
+                    warn!(
+                        "Server stopped responding ({} keepalives unanswered)",
+                        unanswered
+                    );
+                    bail!("Keepalive timeout");
+                }
+                if unanswered > 0 {
+                    debug!("Keepalive unanswered ({}/{})", unanswered, heartbeat.misses);
+                }
+                // Queued without waiting: if the writer is stuck behind a
+                // dead connection, the skipped beat still counts as a miss
+                keepalive_sent
+                    .lock()
+                    .unwrap()
+                    .get_or_insert_with(Instant::now);
+                let _ = control_tx.try_send(Frame::keepalive());
+            }
+        };
+
+        // Dummy frames at random intervals, so idle and busy sessions look
+        // alike
+        let dummies = async {
+            let Some(padding) = options.padding else {
+                return std::future::pending().await;
+            };
+            while let Some(delay) = padding.next_dummy() {
+                tokio::time::sleep(delay).await;
+                let _ = control_tx.try_send(padding.dummy());
+            }
+            std::future::pending().await
+        };
+
+        let result = tokio::select! {
+            result = read => result,
+            result = write => result,
+            result = heartbeat => result,
+            result = dummies => result,
+            _ = attached.wait_for(|current| *current != generation) => Ok(Detached::Replaced),
+        };
+        if let Ok(Detached::Closed) = result {
+            // Frames the session queued on its way out still go to the
+            // peer, ahead of a clean close of our side
+            let finish = async {
+                loop {
+                    let frame = {
+                        let mut schedule = self.schedule.lock().unwrap();
+                        while let Ok(frame) = outbound.try_recv() {
+                            schedule.push(frame);
+                        }
+                        schedule.pop()
+                    };
+                    let Some(frame) = frame else {
+                        break;
+                    };
+                    for mut frame in split_payload(frame, max_data) {
+                        if options.sequence {
+                            self.sequencer.lock().unwrap().stamp(&mut frame);
+                        }
+                        sent(&frame);
+                        sink.feed(frame).await?;
+                    }
+                }
+                sink.close().await?;
+                // Dropping the connection with data unread would reset it,
+                // which can cost the peer whatever it hadn't read yet
+                while let Some(Ok(_)) = frames.next().await {}
+                anyhow::Ok(())
+            };
+            match tokio::time::timeout(LINGER_TIMEOUT, finish).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => debug!("Session {}: closing failed: {:#}", self.id, e),
+                Err(_) => debug!("Session {}: peer didn't close in time", self.id),
+            }
+        }
+        result
+    }
+}
+
+/// Flush `stream` and close our side of it (a TLS close_notify, or `BDAT 0
+/// LAST`), then read and discard until the peer closes too, for up to
+/// [`LINGER_TIMEOUT`]
+pub async fn close_gracefully<S>(stream: &mut S)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let close = async {
+        stream.shutdown().await?;
+        let mut scratch = [0u8; 1024];
+        while stream.read(&mut scratch).await? > 0 {}
+        std::io::Result::Ok(())
+    };
+    let _ = tokio::time::timeout(LINGER_TIMEOUT, close).await;
+}
+
+/// `frame` cut into frames of at most `max_payload` bytes. Only DATA frames
+/// get that large; channels hand over whatever they read and leave the
+/// sizing to the link, which knows what the peer accepts.
+fn split_payload(frame: Frame, max_payload: usize) -> impl Iterator<Item = Frame> {
+    let mut rest = Some(frame);
+    std::iter::from_fn(move || {
+        let frame = rest.as_mut()?;
+        if frame.payload.len() <= max_payload {
+            return rest.take();
+        }
+        let head = frame.payload.split_to(max_payload);
+        Some(Frame::new(frame.frame_type, frame.channel_id, head))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_session_id_round_trip() {
+        let id = SessionId::random();
+        assert_eq!(id.to_string().parse::<SessionId>().unwrap(), id);
+        assert!("abcd".parse::<SessionId>().is_err());
+        assert!("not hex".parse::<SessionId>().is_err());
+    }
+
+    #[test]
+    fn test_replay_is_bounded() {
+        let mut replay = Replay::default();
+        let frame = Frame::data(1, vec![0u8; 16 * 1024]);
+        let fits = MAX_REPLAY_BYTES / frame.encoded_len();
+        for _ in 0..fits {
+            replay.push(frame.clone()).unwrap();
+        }
+        assert!(replay.push(frame.clone()).is_err());
+        // The frame that broke the cap is still replayed
+        assert_eq!(replay.sent(), fits as u64 + 1);
+
+        replay.ack(2).unwrap();
+        assert_eq!(replay.bytes, (fits - 1) * frame.encoded_len());
+        replay.push(frame).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_link_replays_after_reconnect() {
+        let id = SessionId::random();
+        let (a, _a_in, a_out) = Link::new(id);
+        let (b, mut b_in, _b_out) = Link::new(id);
+
+        // First connection: two frames leave A but never reach B
+        let (near, mut far) = tokio::io::duplex(64 * 1024);
+        let attachment = a.attach().await;
+        let first = tokio::spawn({
+            let a = Arc::clone(&a);
+            async move {
+                a.run(attachment, near, BytesMut::new(), 0, LinkOptions::default())
+                    .await
+            }
+        });
+        a_out.send(Frame::data(1, &b"one"[..])).await.unwrap();
+        a_out.send(Frame::data(1, &b"two"[..])).await.unwrap();
+        let mut lost = vec![0u8; 2 * (5 + 3)];
+        tokio::io::AsyncReadExt::read_exact(&mut far, &mut lost)
+            .await
+            .unwrap();
+        drop(far);
+        assert!(first.await.unwrap().is_err());
+
+        // Second connection: A replays what B says it's missing
+        let (a_stream, b_stream) = tokio::io::duplex(64 * 1024);
+        let (a_attachment, b_attachment) = (a.attach().await, b.attach().await);
+        let (a_received, b_received) = (a.received(), b.received());
+        for (link, attachment, stream, peer_received) in [
+            (&a, a_attachment, a_stream, b_received),
+            (&b, b_attachment, b_stream, a_received),
+        ] {
+            let link = Arc::clone(link);
+            tokio::spawn(async move {
+                link.run(
+                    attachment,
+                    stream,
+                    BytesMut::new(),
+                    peer_received,
+                    LinkOptions::default(),
+                )
+                .await
+            });
+        }
+        a_out.send(Frame::data(1, &b"three"[..])).await.unwrap();
+
+        for expected in ["one", "two", "three"] {
+            let frame = b_in.recv().await.unwrap();
+            assert_eq!(frame.payload, Bytes::from(expected));
+        }
+        assert_eq!(b.received(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_link_flushes_on_close() {
+        let (link, inbound, outbound) = Link::new(SessionId::random());
+        // A small pipe, so the last frames are still queued when the
+        // session ends
+        let (near, far) = tokio::io::duplex(512);
+        let attachment = link.attach().await;
+        let run = tokio::spawn(async move {
+            link.run(attachment, near, BytesMut::new(), 0, LinkOptions::default())
+                .await
+        });
+        for i in 0..50u8 {
+            outbound.send(Frame::data(1, vec![i; 100])).await.unwrap();
+        }
+        outbound.send(Frame::close(1)).await.unwrap();
+        drop(inbound);
+
+        // A frame from the peer finds the session gone
+        let (reader, writer) = tokio::io::split(far);
+        let mut sink = FramedWrite::new(writer, FrameCodec::new());
+        sink.send(Frame::data(2, &b"late"[..])).await.unwrap();
+        let mut frames = FramedRead::new(reader, FrameCodec::new());
+        for i in 0..50u8 {
+            let frame = frames.next().await.unwrap().unwrap();
+            assert_eq!(frame.payload, Bytes::from(vec![i; 100]));
+        }
+        let close = frames.next().await.unwrap().unwrap();
+        assert_eq!(close.frame_type, FrameType::Close);
+        // Then a clean end of stream rather than a dropped connection
+        assert!(frames.next().await.is_none());
+        drop((frames, sink));
+        assert_eq!(run.await.unwrap().unwrap(), Detached::Closed);
+    }
+
+    #[tokio::test]
+    async fn test_link_batches_small_frames() {
+        let (link, _inbound, outbound) = Link::new(SessionId::random());
+        let (near, far) = tokio::io::duplex(64 * 1024);
+        let options = LinkOptions {
+            batching: Batching {
+                max_delay: Duration::from_secs(1),
+                max_bytes: 1024,
+            },
+            ..LinkOptions::default()
+        };
+        let attachment = link.attach().await;
+        tokio::spawn(async move {
+            link.run(attachment, near, BytesMut::new(), 0, options)
+                .await
+        });
+        let mut frames = FramedRead::new(far, FrameCodec::new());
+
+        // A lone small frame waits for company
+        outbound.send(Frame::data(1, &b"a"[..])).await.unwrap();
+        let held = tokio::time::timeout(Duration::from_millis(50), frames.next()).await;
+        assert!(held.is_err());
+
+        // Filling the batch writes it without waiting out the delay
+        outbound
+            .send(Frame::data(1, vec![0u8; 1024]))
+            .await
+            .unwrap();
+        let first = tokio::time::timeout(Duration::from_millis(500), frames.next())
+            .await
+            .expect("full batch held back")
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.payload, Bytes::from("a"));
+        let second = frames.next().await.unwrap().unwrap();
+        assert_eq!(second.payload.len(), 1024);
+    }
+
+    #[tokio::test]
+    async fn test_link_splits_large_data() {
+        let (link, _inbound, outbound) = Link::new(SessionId::random());
+        let (near, far) = tokio::io::duplex(64 * 1024);
+        let attachment = link.attach().await;
+        tokio::spawn(async move {
+            link.run(attachment, near, BytesMut::new(), 0, LinkOptions::default())
+                .await
+        });
+        let mut frames = FramedRead::new(far, FrameCodec::new());
+
+        // Without large frames agreed, the peer only takes 64 KiB at a time
+        let data = Bytes::from(vec![1u8; 2 * MAX_PAYLOAD_SIZE + 1]);
+        outbound.send(Frame::data(1, data.clone())).await.unwrap();
+        let mut received = BytesMut::new();
+        for expected in [MAX_PAYLOAD_SIZE, MAX_PAYLOAD_SIZE, 1] {
+            let frame = frames.next().await.unwrap().unwrap();
+            assert_eq!(frame.payload.len(), expected);
+            received.extend_from_slice(&frame.payload);
+        }
+        assert_eq!(received, data);
+    }
+
+    #[tokio::test]
+    async fn test_link_sequence() {
+        let (link, mut inbound, outbound) = Link::new(SessionId::random());
+        let (near, far) = tokio::io::duplex(64 * 1024);
+        let options = LinkOptions {
+            sequence: true,
+            ..LinkOptions::default()
+        };
+        let attachment = link.attach().await;
+        tokio::spawn(async move {
+            link.run(attachment, near, BytesMut::new(), 0, options)
+                .await
+        });
+        let (reader, writer) = tokio::io::split(far);
+        let mut frames = FramedRead::new(reader, FrameCodec::new());
+        let mut sink = FramedWrite::new(writer, FrameCodec::new());
+
+        // Outgoing DATA carries its number on the channel
+        outbound.send(Frame::data(1, &b"hello"[..])).await.unwrap();
+        let frame = frames.next().await.unwrap().unwrap();
+        assert_eq!(&frame.payload[..], b"\0\0\0\0hello");
+
+        // A repeat is dropped; a gap closes the channel both ways
+        let mut peer = Sequencer::default();
+        let mut frame = Frame::data(2, &b"one"[..]);
+        peer.stamp(&mut frame);
+        sink.send(frame.clone()).await.unwrap();
+        sink.send(frame).await.unwrap();
+        let mut skipped = Frame::data(2, &b"two"[..]);
+        peer.stamp(&mut skipped);
+        let mut frame = Frame::data(2, &b"three"[..]);
+        peer.stamp(&mut frame);
+        sink.send(frame).await.unwrap();
+        let received = inbound.recv().await.unwrap();
+        assert_eq!(&received.payload[..], b"one");
+        let received = inbound.recv().await.unwrap();
+        assert_eq!(received.frame_type, FrameType::Close);
+        let frame = frames.next().await.unwrap().unwrap();
+        assert_eq!(frame.frame_type, FrameType::Close);
+        assert_eq!(frame.channel_id, 2);
+    }
+
+    #[cfg(feature = "compression")]
+    #[tokio::test]
+    async fn test_link_compression() {
+        let (link, mut inbound, outbound) = Link::new(SessionId::random());
+        let (near, far) = tokio::io::duplex(64 * 1024);
+        let compression = Compression {
+            level: 3,
+            min_size: 64,
+        };
+        let options = LinkOptions {
+            compression: Some(compression),
+            ..LinkOptions::default()
+        };
+        let attachment = link.attach().await;
+        tokio::spawn(async move {
+            link.run(attachment, near, BytesMut::new(), 0, options)
+                .await
+        });
+        let (reader, writer) = tokio::io::split(far);
+        let mut frames = FramedRead::new(reader, FrameCodec::new());
+        let mut sink = FramedWrite::new(writer, FrameCodec::new());
+        let text = Bytes::from("RCPT TO:<bob@example.com>\r\n".repeat(50));
+
+        // Outgoing DATA is compressed, small frames are left alone
+        outbound.send(Frame::data(1, text.clone())).await.unwrap();
+        outbound.send(Frame::data(1, &b"tiny"[..])).await.unwrap();
+        let frame = frames.next().await.unwrap().unwrap();
+        assert!(frame.compressed);
+        assert!(frame.payload.len() < text.len());
+        assert_eq!(
+            compress::decompress(&frame.payload, MAX_PAYLOAD_SIZE).unwrap(),
+            text
+        );
+        let frame = frames.next().await.unwrap().unwrap();
+        assert!(!frame.compressed);
+
+        // Incoming compressed DATA is handed on decompressed
+        let mut frame = Frame::data(2, compression.compress(&text).unwrap());
+        frame.compressed = true;
+        sink.send(frame).await.unwrap();
+        let received = inbound.recv().await.unwrap();
+        assert!(!received.compressed);
+        assert_eq!(received.payload, text);
+    }
+
+    #[tokio::test]
+    async fn test_link_padding() {
+        let (link, mut inbound, outbound) = Link::new(SessionId::random());
+        let (near, far) = tokio::io::duplex(64 * 1024);
+        let options = LinkOptions {
+            padding: Some(Padding {
+                bucket_size: 256,
+                dummy_interval: None,
+                dummy_max_size: 0,
+            }),
+            ..LinkOptions::default()
+        };
+        let attachment = link.attach().await;
+        let session = tokio::spawn({
+            let link = Arc::clone(&link);
+            async move {
+                link.run(attachment, near, BytesMut::new(), 0, options)
+                    .await
+            }
+        });
+        let (reader, writer) = tokio::io::split(far);
+        let mut frames = FramedRead::new(reader, FrameCodec::new());
+        let mut sink = FramedWrite::new(writer, FrameCodec::new());
+
+        // DATA is followed by filler up to the bucket size
+        outbound.send(Frame::data(1, &b"hello"[..])).await.unwrap();
+        let data = frames.next().await.unwrap().unwrap();
+        let filler = frames.next().await.unwrap().unwrap();
+        assert_eq!(filler.frame_type, FrameType::Padding);
+        assert_eq!(data.encoded_len() + filler.encoded_len(), 256);
+
+        // Incoming filler is dropped without counting as a session frame
+        sink.send(Frame::padding(Bytes::from_static(&[0; 100])))
+            .await
+            .unwrap();
+        sink.send(Frame::data(2, &b"world"[..])).await.unwrap();
+        let received = inbound.recv().await.unwrap();
+        assert_eq!(received.payload, Bytes::from("world"));
+        assert_eq!(link.received(), 1);
+        assert!(!session.is_finished());
+    }
+
+    #[tokio::test]
+    async fn test_link_keepalive_timeout() {
+        let (link, _inbound, _outbound) = Link::new(SessionId::random());
+        let (near, far) = tokio::io::duplex(64 * 1024);
+        let options = LinkOptions {
+            heartbeat: Some(Heartbeat {
+                interval: Duration::from_millis(20),
+                misses: 2,
+            }),
+            ..LinkOptions::default()
+        };
+        let attachment = link.attach().await;
+
+        // The peer reads the keepalives but never answers
+        let (reader, _writer) = tokio::io::split(far);
+        let mut frames = FramedRead::new(reader, FrameCodec::new());
+        let session = tokio::spawn({
+            let link = Arc::clone(&link);
+            async move {
+                link.run(attachment, near, BytesMut::new(), 0, options)
+                    .await
+            }
+        });
+        let keepalive = frames.next().await.unwrap().unwrap();
+        assert_eq!(keepalive.frame_type, FrameType::Keepalive);
+        tokio::spawn(async move { while frames.next().await.is_some() {} });
+
+        let result = tokio::time::timeout(Duration::from_secs(5), session)
+            .await
+            .expect("dead connection not detected")
+            .unwrap();
+        assert_eq!(result.unwrap_err().to_string(), "Keepalive timeout");
+    }
+
+    #[tokio::test]
+    async fn test_link_keepalive_rtt() {
+        let (link, _inbound, _outbound) = Link::new(SessionId::random());
+        let (near, far) = tokio::io::duplex(64 * 1024);
+        let options = LinkOptions {
+            heartbeat: Some(Heartbeat {
+                interval: Duration::from_millis(20),
+                misses: 3,
+            }),
+            ..LinkOptions::default()
+        };
+        let attachment = link.attach().await;
+        tokio::spawn({
+            let link = Arc::clone(&link);
+            async move {
+                link.run(attachment, near, BytesMut::new(), 0, options)
+                    .await
+            }
+        });
+        assert_eq!(link.rtt(), None);
+
+        let (reader, writer) = tokio::io::split(far);
+        let mut frames = FramedRead::new(reader, FrameCodec::new());
+        let mut sink = FramedWrite::new(writer, FrameCodec::new());
+        let keepalive = frames.next().await.unwrap().unwrap();
+        assert_eq!(keepalive.frame_type, FrameType::Keepalive);
+        sink.send(keepalive.keepalive_ack()).await.unwrap();
+        tokio::spawn(async move { while frames.next().await.is_some() {} });
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while link.rtt().is_none() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("keepalive answer not timed");
+    }
+}
@@ -0,0 +1,62 @@
+//! Logging setup with per-module filters and runtime adjustment
+//!
+//! Filters use `RUST_LOG` syntax, e.g. `info,smtp_tunnel::socks5=debug`, so
+//! the SMTP layer and the SOCKS layer can be debugged independently.
+
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Registry, fmt, reload};
+
+/// Default filter when nothing is configured
+pub const DEFAULT_FILTER: &str = "info";
+
+/// Handle for changing the active log filter at runtime
+#[derive(Clone)]
+pub struct LogHandle {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogHandle {
+    /// Replace the active filter
+    pub fn set_filter(&self, filter: &str) -> anyhow::Result<()> {
+        let filter = EnvFilter::try_new(filter)?;
+        self.handle.reload(filter)?;
+        Ok(())
+    }
+
+    /// Current filter as a string
+    pub fn current(&self) -> String {
+        self.handle
+            .with_current(|f| f.to_string())
+            .unwrap_or_default()
+    }
+}
+
+/// Pick the effective filter.
+///
+/// Precedence: `RUST_LOG` environment variable, then an explicit command
+/// line filter, then `--debug`, then the config file, then `info`.
+pub fn resolve_filter(cli: Option<&str>, debug: bool, config: Option<&str>) -> String {
+    if let Ok(env) = std::env::var(EnvFilter::DEFAULT_ENV)
+        && !env.is_empty()
+    {
+        return env;
+    }
+    if let Some(cli) = cli {
+        return cli.to_string();
+    }
+    if debug {
+        return "debug".to_string();
+    }
+    config.unwrap_or(DEFAULT_FILTER).to_string()
+}
+
+/// Install the global subscriber with a reloadable filter
+pub fn init(filter: &str) -> anyhow::Result<LogHandle> {
+    let (filter_layer, handle) = reload::Layer::new(EnvFilter::try_new(filter)?);
+    tracing_subscriber::registry()
+        .with(filter_layer)
+        .with(fmt::layer())
+        .try_init()?;
+    Ok(LogHandle { handle })
+}
@@ -0,0 +1,393 @@
+//! Runtime metrics
+//!
+//! Lock-free counters updated on the session path, plus process resource
+//! usage sampled on demand. The server logs a snapshot periodically.
+//!
+//! Both ends also count the frames their links send and receive, by type
+//! and by encoded size, to show keepalive storms, floods of tiny DATA
+//! frames or one side closing channels the other never does. The server
+//! logs them with its metrics and answers `smtp-tunnel-server metrics`; the
+//! client logs them on SIGUSR1 and at exit.
+//!
+//! Protocol violations are counted by kind, so operators can see probing
+//! trends; with `max_violations` set a session is also ended once it has
+//! made that many.
+
+use crate::proto::{Frame, FrameType};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// Kinds of protocol violation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// SMTP command the server doesn't know
+    SmtpUnknown,
+    /// SMTP command out of order
+    SmtpSequence,
+    /// SMTP line or arguments that don't parse
+    SmtpSyntax,
+    /// AUTH that failed
+    SmtpAuth,
+    /// Frame that doesn't decode or whose payload doesn't parse
+    FrameMalformed,
+    /// Frame that makes no sense in the session's state
+    FrameUnexpected,
+    /// Data or window grant beyond a channel's flow-control window
+    FlowControl,
+}
+
+impl Violation {
+    pub const ALL: [Self; 7] = [
+        Self::SmtpUnknown,
+        Self::SmtpSequence,
+        Self::SmtpSyntax,
+        Self::SmtpAuth,
+        Self::FrameMalformed,
+        Self::FrameUnexpected,
+        Self::FlowControl,
+    ];
+
+    /// Name used in metrics output
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::SmtpUnknown => "smtp_unknown",
+            Self::SmtpSequence => "smtp_sequence",
+            Self::SmtpSyntax => "smtp_syntax",
+            Self::SmtpAuth => "smtp_auth",
+            Self::FrameMalformed => "frame_malformed",
+            Self::FrameUnexpected => "frame_unexpected",
+            Self::FlowControl => "flow_control",
+        }
+    }
+}
+
+/// Server-wide counters, shared by all sessions
+#[derive(Debug, Default)]
+pub struct ServerMetrics {
+    sessions_total: AtomicU64,
+    sessions_active: AtomicU64,
+    sessions_refused: AtomicU64,
+    non_smtp: AtomicU64,
+    /// Indexed like [`Violation::ALL`]
+    violations: [AtomicU64; Violation::ALL.len()],
+    frames: Arc<FrameStats>,
+}
+
+impl ServerMetrics {
+    /// Count a new session; it stays active until the guard is dropped
+    pub fn session_started(self: &Arc<Self>) -> ActiveSession {
+        self.sessions_total.fetch_add(1, Ordering::Relaxed);
+        self.sessions_active.fetch_add(1, Ordering::Relaxed);
+        ActiveSession(Arc::clone(self))
+    }
+
+    /// Count a connection turned away before its session started
+    pub fn session_refused(&self) {
+        self.sessions_refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a connection dropped for speaking another protocol
+    pub fn non_smtp(&self) {
+        self.non_smtp.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count a protocol violation
+    pub fn violation(&self, kind: Violation) {
+        self.violations[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Frame counters for all sessions' links
+    pub fn frames(&self) -> Arc<FrameStats> {
+        Arc::clone(&self.frames)
+    }
+
+    /// Current counters along with the process's file descriptor usage
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            sessions_active: self.sessions_active.load(Ordering::Relaxed),
+            sessions_total: self.sessions_total.load(Ordering::Relaxed),
+            sessions_refused: self.sessions_refused.load(Ordering::Relaxed),
+            non_smtp: self.non_smtp.load(Ordering::Relaxed),
+            violations: std::array::from_fn(|i| self.violations[i].load(Ordering::Relaxed)),
+            open_fds: crate::platform::open_fds(),
+            fd_limit: crate::platform::fd_limit().map(|limit| limit.soft),
+        }
+    }
+}
+
+/// One session's protocol violations, counted into the server metrics
+#[derive(Debug)]
+pub struct Violations {
+    metrics: Arc<ServerMetrics>,
+    count: AtomicU32,
+    /// Violations tolerated before the session is ended (0 = no limit)
+    limit: u32,
+}
+
+impl Violations {
+    pub fn new(metrics: Arc<ServerMetrics>, limit: u32) -> Self {
+        Self {
+            metrics,
+            count: AtomicU32::new(0),
+            limit,
+        }
+    }
+
+    /// Record a violation, failing once the session has made too many
+    pub fn record(&self, kind: Violation) -> anyhow::Result<()> {
+        self.metrics.violation(kind);
+        let count = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        if self.limit != 0 && count >= self.limit {
+            anyhow::bail!(
+                "Too many protocol violations ({}, last {})",
+                count,
+                kind.name()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Marks a session as active for as long as it's held
+#[derive(Debug)]
+pub struct ActiveSession(Arc<ServerMetrics>);
+
+impl Drop for ActiveSession {
+    fn drop(&mut self) {
+        self.0.sessions_active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Point-in-time view of the server metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub sessions_active: u64,
+    pub sessions_total: u64,
+    pub sessions_refused: u64,
+    /// Connections that opened with TLS or HTTP instead of SMTP
+    pub non_smtp: u64,
+    /// Protocol violations, indexed like [`Violation::ALL`]
+    pub violations: [u64; Violation::ALL.len()],
+    /// Open file descriptors (None where the platform doesn't expose them)
+    pub open_fds: Option<u64>,
+    /// Soft open-file limit
+    pub fd_limit: Option<u64>,
+}
+
+impl fmt::Display for MetricsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sessions={} total={} refused={} non_smtp={}",
+            self.sessions_active, self.sessions_total, self.sessions_refused, self.non_smtp
+        )?;
+        for (kind, count) in Violation::ALL.iter().zip(self.violations) {
+            if count > 0 {
+                write!(f, " {}={}", kind.name(), count)?;
+            }
+        }
+        if let Some(open) = self.open_fds {
+            write!(f, " fds={open}")?;
+        }
+        if let Some(limit) = self.fd_limit {
+            write!(f, " fd_limit={limit}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Upper bounds of the frame size buckets; larger frames get one more
+pub const FRAME_SIZE_BUCKETS: [usize; 5] = [64, 256, 1024, 4096, 16384];
+
+/// Frames sent and received over tunnel connections, by type and size
+#[derive(Debug, Default)]
+pub struct FrameStats {
+    sent: FrameCounters,
+    received: FrameCounters,
+}
+
+impl FrameStats {
+    /// Count a frame written to the connection
+    pub fn sent(&self, frame: &Frame) {
+        self.sent.record(frame);
+    }
+
+    /// Count a frame read from the connection
+    pub fn received(&self, frame: &Frame) {
+        self.received.record(frame);
+    }
+
+    pub fn snapshot(&self) -> FrameStatsSnapshot {
+        FrameStatsSnapshot {
+            sent: self.sent.snapshot(),
+            received: self.received.snapshot(),
+        }
+    }
+}
+
+/// One direction's frame counters
+#[derive(Debug, Default)]
+struct FrameCounters {
+    /// Indexed like [`FrameType::ALL`]
+    frames: [AtomicU64; FrameType::ALL.len()],
+    bytes: [AtomicU64; FrameType::ALL.len()],
+    /// Indexed like [`FRAME_SIZE_BUCKETS`], plus the larger frames
+    sizes: [AtomicU64; FRAME_SIZE_BUCKETS.len() + 1],
+}
+
+impl FrameCounters {
+    fn record(&self, frame: &Frame) {
+        // Frame types are numbered from 1 in the order of `ALL`
+        let kind = frame.frame_type as usize - 1;
+        let len = frame.encoded_len();
+        let bucket = FRAME_SIZE_BUCKETS
+            .iter()
+            .position(|&max| len <= max)
+            .unwrap_or(FRAME_SIZE_BUCKETS.len());
+        self.frames[kind].fetch_add(1, Ordering::Relaxed);
+        self.bytes[kind].fetch_add(len as u64, Ordering::Relaxed);
+        self.sizes[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> FrameCounts {
+        let types = FrameType::ALL
+            .iter()
+            .enumerate()
+            .filter_map(|(i, kind)| {
+                let frames = self.frames[i].load(Ordering::Relaxed);
+                (frames > 0).then(|| TypeCount {
+                    frame_type: kind.name().to_string(),
+                    frames,
+                    bytes: self.bytes[i].load(Ordering::Relaxed),
+                })
+            })
+            .collect();
+        FrameCounts {
+            types,
+            sizes: self
+                .sizes
+                .iter()
+                .map(|c| c.load(Ordering::Relaxed))
+                .collect(),
+        }
+    }
+}
+
+/// Point-in-time view of the frame counters
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrameStatsSnapshot {
+    pub sent: FrameCounts,
+    pub received: FrameCounts,
+}
+
+/// Frames in one direction
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FrameCounts {
+    /// By type, in protocol order; types never seen are left out
+    pub types: Vec<TypeCount>,
+    /// By encoded size, indexed like [`FRAME_SIZE_BUCKETS`] plus the
+    /// larger frames
+    pub sizes: Vec<u64>,
+}
+
+/// Frames of one type and their encoded bytes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TypeCount {
+    pub frame_type: String,
+    pub frames: u64,
+    pub bytes: u64,
+}
+
+impl fmt::Display for FrameCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.types.is_empty() {
+            return write!(f, "none");
+        }
+        for (i, count) in self.types.iter().enumerate() {
+            let sep = if i == 0 { "" } else { " " };
+            write!(
+                f,
+                "{sep}{}={}/{}B",
+                count.frame_type, count.frames, count.bytes
+            )?;
+        }
+        write!(f, "; sizes")?;
+        for (i, count) in self.sizes.iter().enumerate() {
+            match FRAME_SIZE_BUCKETS.get(i) {
+                Some(max) => write!(f, " <={max}:{count}")?,
+                None => write!(f, " >{}:{count}", FRAME_SIZE_BUCKETS[i - 1])?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_counters() {
+        let metrics = Arc::new(ServerMetrics::default());
+        let first = metrics.session_started();
+        let second = metrics.session_started();
+        metrics.session_refused();
+        metrics.non_smtp();
+        let violations = Violations::new(Arc::clone(&metrics), 2);
+        assert!(violations.record(Violation::SmtpUnknown).is_ok());
+        assert!(violations.record(Violation::FlowControl).is_err());
+        drop(first);
+
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.sessions_active, 1);
+        assert_eq!(snapshot.sessions_total, 2);
+        assert_eq!(snapshot.sessions_refused, 1);
+        assert_eq!(snapshot.non_smtp, 1);
+        assert_eq!(snapshot.violations, [1, 0, 0, 0, 0, 0, 1]);
+
+        drop(second);
+        assert_eq!(metrics.snapshot().sessions_active, 0);
+    }
+
+    #[test]
+    fn test_snapshot_display() {
+        let snapshot = MetricsSnapshot {
+            sessions_active: 2,
+            sessions_total: 9,
+            sessions_refused: 1,
+            non_smtp: 3,
+            violations: [0, 0, 2, 1, 0, 0, 0],
+            open_fds: Some(40),
+            fd_limit: Some(1024),
+        };
+        assert_eq!(
+            snapshot.to_string(),
+            "sessions=2 total=9 refused=1 non_smtp=3 smtp_syntax=2 smtp_auth=1 fds=40 fd_limit=1024"
+        );
+    }
+
+    #[test]
+    fn test_frame_stats() {
+        let stats = FrameStats::default();
+        stats.sent(&Frame::data(1, vec![0u8; 1000]));
+        stats.sent(&Frame::data(1, vec![0u8; 20_000]));
+        stats.sent(&Frame::keepalive());
+        stats.received(&Frame::keepalive().keepalive_ack());
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.sent.types.len(), 2);
+        assert_eq!(snapshot.sent.types[0].frame_type, "data");
+        assert_eq!(snapshot.sent.types[0].frames, 2);
+        assert_eq!(snapshot.sent.sizes, [1, 0, 1, 0, 0, 1]);
+        assert_eq!(snapshot.received.types[0].frame_type, "keepalive_ack");
+        assert_eq!(FrameCounts::default().to_string(), "none");
+        assert!(snapshot.sent.to_string().starts_with("data=2/"));
+        assert!(
+            snapshot
+                .sent
+                .to_string()
+                .ends_with("; sizes <=64:1 <=256:0 <=1024:1 <=4096:0 <=16384:0 >16384:1")
+        );
+    }
+}
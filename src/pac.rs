@@ -0,0 +1,155 @@
+//! Proxy auto-config file server
+//!
+//! With `pac` configured the client serves a generated `proxy.pac` over
+//! plain HTTP, so browsers and operating systems can be pointed at one URL
+//! instead of having the proxy and its exceptions entered by hand. The
+//! script sends destinations matching the `direct` rules straight out and
+//! everything else to the SOCKS proxy.
+//!
+//! Rules are matched against the name or IP literal the browser asks
+//! about; the script never resolves hostnames, which would send lookups to
+//! the local resolver. IPv6 ranges need `isInNetEx`, which Chrome and
+//! Windows provide.
+
+use crate::acl::{DestinationAcl, Rule};
+use ipnet::IpNet;
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::watch;
+use tracing::debug;
+
+/// Paths the script is served at; WPAD clients ask for the second
+pub const PATHS: [&str; 2] = ["/proxy.pac", "/wpad.dat"];
+
+/// Largest request read
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// How long a client may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The script for `proxy`, sending destinations matching `direct` around it
+pub fn script(direct: &DestinationAcl, proxy: SocketAddr) -> String {
+    let mut script = String::from(
+        "function FindProxyForURL(url, host) {\n    host = host.toLowerCase();\n    \
+         var ipv4 = /^\\d+\\.\\d+\\.\\d+\\.\\d+$/.test(host);\n",
+    );
+    for (text, rule) in direct.rules() {
+        let test = match rule {
+            Rule::Host(name) => format!("host == {name:?}"),
+            Rule::Suffix(suffix) => format!("dnsDomainIs(host, {suffix:?})"),
+            Rule::Network(IpNet::V4(net)) => format!(
+                "ipv4 && isInNet(host, \"{}\", \"{}\")",
+                net.network(),
+                net.netmask()
+            ),
+            Rule::Network(IpNet::V6(net)) => format!(
+                "host.indexOf(\":\") >= 0 && typeof isInNetEx == \"function\" \
+                 && isInNetEx(host, \"{net}\")"
+            ),
+        };
+        let _ = writeln!(script, "    // {text}");
+        let _ = writeln!(script, "    if ({test}) return \"DIRECT\";");
+    }
+    let _ = writeln!(script, "    return \"SOCKS5 {proxy}\";\n}}");
+    script
+}
+
+/// Serve the script on `listener` until it fails. A `proxy` on an
+/// unspecified address is given as the address the client reached us on.
+pub async fn serve(
+    listener: TcpListener,
+    direct: watch::Receiver<Arc<DestinationAcl>>,
+    proxy: SocketAddr,
+) -> io::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let direct = Arc::clone(&direct.borrow());
+        tokio::spawn(async move {
+            if let Err(e) = answer(stream, &direct, proxy).await {
+                debug!("PAC request from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Answer one HTTP request
+async fn answer(
+    mut stream: TcpStream,
+    direct: &DestinationAcl,
+    proxy: SocketAddr,
+) -> io::Result<()> {
+    let mut request = Vec::new();
+    let read = async {
+        let mut buf = [0u8; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buf).await?;
+            if n == 0 || request.len() + n > MAX_REQUEST {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Bad request"));
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        Ok(())
+    };
+    tokio::time::timeout(REQUEST_TIMEOUT, read)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Request timed out"))??;
+
+    let line = String::from_utf8_lossy(&request);
+    let mut parts = line.lines().next().unwrap_or_default().split(' ');
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    let path = path.split('?').next().unwrap_or_default();
+
+    let response = if !matches!(method, "GET" | "HEAD") {
+        "HTTP/1.0 405 Method Not Allowed\r\nContent-Length: 0\r\n\r\n".to_string()
+    } else if PATHS.contains(&path) {
+        let mut proxy = proxy;
+        if proxy.ip().is_unspecified() {
+            proxy.set_ip(stream.local_addr()?.ip());
+        }
+        let body = script(direct, proxy);
+        let mut response = format!(
+            "HTTP/1.0 200 OK\r\nContent-Type: application/x-ns-proxy-autoconfig\r\n\
+             Content-Length: {}\r\nCache-Control: no-cache\r\n\r\n",
+            body.len()
+        );
+        if method == "GET" {
+            response.push_str(&body);
+        }
+        response
+    } else {
+        "HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_script() {
+        let rules: Vec<String> = ["10.0.0.0/8", "fd00::/8", "*.corp.example", "intranet"]
+            .iter()
+            .map(|rule| rule.to_string())
+            .collect();
+        let direct = DestinationAcl::new(&rules).unwrap();
+        let script = script(&direct, "127.0.0.1:1080".parse().unwrap());
+
+        assert!(script.starts_with("function FindProxyForURL(url, host) {\n"));
+        assert!(script.contains(
+            "    // 10.0.0.0/8\n    if (ipv4 && isInNet(host, \"10.0.0.0\", \"255.0.0.0\")) \
+             return \"DIRECT\";\n"
+        ));
+        assert!(script.contains("isInNetEx(host, \"fd00::/8\")"));
+        assert!(script.contains("if (dnsDomainIs(host, \".corp.example\")) return \"DIRECT\";"));
+        assert!(script.contains("if (host == \"intranet\") return \"DIRECT\";"));
+        assert!(script.ends_with("    return \"SOCKS5 127.0.0.1:1080\";\n}\n"));
+    }
+}
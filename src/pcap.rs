@@ -0,0 +1,642 @@
+//! Scrubbing packet captures of tunnel traffic
+//!
+//! A capture is the quickest way to see what a DPI box objects to, but the
+//! one a user can take holds their traffic. [`scrub`] rewrites a classic
+//! pcap file in place so it can be shared: packet headers, SMTP commands
+//! and replies, TLS record headers and tunnel frame headers are kept, and
+//! everything they carry is zeroed. AUTH arguments are masked. Sizes and
+//! timing are unchanged, as they're what DPI looks at.
+//!
+//! Frame headers only show up in plaintext: on a connection without TLS,
+//! or one captured behind a frontend that terminates it. Over TLS the
+//! records' headers are all there is to keep.
+//!
+//! TCP streams are followed from their SYN. A stream that started before
+//! the capture, or loses its place to a gap or a truncated packet, is
+//! zeroed from then on, as is anything not on one of the tunnel's ports.
+
+use crate::proto::checksum::CHECKSUM_SIZE;
+use crate::proto::frames::{
+    COMPRESSED_FLAG, EXTENDED_HEADER_SIZE, EXTENDED_LENGTH_FLAG, FRAME_HEADER_SIZE, FrameType,
+};
+use anyhow::{Context, bail};
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// pcap file header size
+const FILE_HEADER_SIZE: usize = 24;
+
+/// pcap record header size
+const RECORD_HEADER_SIZE: usize = 16;
+
+/// TLS record header size: type(1) + version(2) + length(2)
+const TLS_HEADER_SIZE: usize = 5;
+
+/// TLS record type of a handshake, which starts every TLS connection
+const TLS_HANDSHAKE: u8 = 22;
+
+/// TLS record type of encrypted application data
+const TLS_APPLICATION_DATA: u8 = 23;
+
+/// Longer than any SMTP line; the stream isn't SMTP
+const MAX_LINE: usize = 4096;
+
+/// What to keep, beyond the defaults
+#[derive(Debug, Clone)]
+pub struct ScrubOptions {
+    /// The tunnel server's ports; TCP to or from anything else is zeroed
+    pub ports: Vec<u16>,
+    /// The verb switching to binary mode (the client's `binary_verb`)
+    pub binary_verb: String,
+    /// Frames after HELLO are followed by checksums
+    pub checksum: bool,
+}
+
+impl Default for ScrubOptions {
+    fn default() -> Self {
+        Self {
+            ports: vec![587, 465],
+            binary_verb: crate::proto::smtp::BINARY_VERB.to_string(),
+            checksum: false,
+        }
+    }
+}
+
+/// What [`scrub`] went through
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ScrubStats {
+    /// Packets in the capture
+    pub packets: u64,
+    /// Tunnel connections followed from their SYN
+    pub streams: u64,
+    /// Streams zeroed after losing their place
+    pub lost: u64,
+}
+
+/// Scrub a classic pcap file (not pcapng) in place
+pub fn scrub(capture: &mut [u8], options: &ScrubOptions) -> anyhow::Result<ScrubStats> {
+    if capture.len() < FILE_HEADER_SIZE {
+        bail!("Too short for a pcap file");
+    }
+    let magic = [capture[0], capture[1], capture[2], capture[3]];
+    let big_endian = match magic {
+        [0xd4, 0xc3, 0xb2, 0xa1] | [0x4d, 0x3c, 0xb2, 0xa1] => false,
+        [0xa1, 0xb2, 0xc3, 0xd4] | [0xa1, 0xb2, 0x3c, 0x4d] => true,
+        [0x0a, 0x0d, 0x0d, 0x0a] => {
+            bail!("pcapng isn't supported; convert it with editcap -F pcap")
+        }
+        _ => bail!("Not a pcap file"),
+    };
+    let read_u32 = |bytes: &[u8]| {
+        let bytes = bytes[..4].try_into().unwrap();
+        if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    };
+    let link = LinkType::from_u32(read_u32(&capture[20..]))?;
+
+    let mut scrubber = Scrubber {
+        options,
+        streams: HashMap::new(),
+        stats: ScrubStats::default(),
+    };
+    let mut offset = FILE_HEADER_SIZE;
+    while offset < capture.len() {
+        let header = capture
+            .get(offset..offset + RECORD_HEADER_SIZE)
+            .context("Capture ends inside a packet header")?;
+        let captured = read_u32(&header[8..]) as usize;
+        let start = offset + RECORD_HEADER_SIZE;
+        let packet = capture
+            .get_mut(start..start + captured)
+            .context("Capture ends inside a packet")?;
+        scrubber.packet(link, packet);
+        scrubber.stats.packets += 1;
+        offset = start + captured;
+    }
+    Ok(scrubber.stats)
+}
+
+/// Link-layer header types we can find IP behind
+#[derive(Debug, Clone, Copy)]
+enum LinkType {
+    /// BSD loopback: a 4-byte address family
+    Null,
+    Ethernet,
+    /// IPv4 or IPv6, told apart by the version
+    Raw,
+    /// Linux "any" interface
+    LinuxSll,
+    LinuxSll2,
+}
+
+impl LinkType {
+    fn from_u32(value: u32) -> anyhow::Result<Self> {
+        Ok(match value {
+            0 | 108 => Self::Null,
+            1 => Self::Ethernet,
+            12 | 101 => Self::Raw,
+            113 => Self::LinuxSll,
+            276 => Self::LinuxSll2,
+            _ => bail!("Unsupported link type {} in the capture", value),
+        })
+    }
+
+    /// Offset of the IP header in `packet`, if it carries IP
+    fn ip_offset(self, packet: &[u8]) -> Option<usize> {
+        let ethertype = |at: usize| {
+            packet
+                .get(at..at + 2)
+                .map(|b| u16::from_be_bytes([b[0], b[1]]))
+        };
+        match self {
+            Self::Null => Some(4),
+            Self::Raw => Some(0),
+            Self::Ethernet => {
+                let mut at = 12;
+                // Skip VLAN tags
+                while matches!(ethertype(at)?, 0x8100 | 0x88a8) {
+                    at += 4;
+                }
+                matches!(ethertype(at)?, 0x0800 | 0x86dd).then_some(at + 2)
+            }
+            Self::LinuxSll => matches!(ethertype(14)?, 0x0800 | 0x86dd).then_some(16),
+            Self::LinuxSll2 => matches!(ethertype(0)?, 0x0800 | 0x86dd).then_some(20),
+        }
+    }
+}
+
+/// One direction of a TCP connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct StreamKey {
+    src: IpAddr,
+    src_port: u16,
+    dst: IpAddr,
+    dst_port: u16,
+}
+
+/// Where a direction of a tunnel connection is up to
+#[derive(Debug)]
+struct Stream {
+    /// Sequence number of the next byte expected
+    next_seq: u32,
+    mode: Mode,
+}
+
+struct Scrubber<'a> {
+    options: &'a ScrubOptions,
+    streams: HashMap<StreamKey, Stream>,
+    stats: ScrubStats,
+}
+
+impl Scrubber<'_> {
+    fn packet(&mut self, link: LinkType, packet: &mut [u8]) {
+        let Some(ip) = link.ip_offset(packet) else {
+            // ARP and the like
+            return;
+        };
+        let Some(packet) = packet.get_mut(ip..) else {
+            return;
+        };
+        let Some(&first) = packet.first() else {
+            return;
+        };
+        let (header, end, protocol, src, dst, fragment) = match first >> 4 {
+            4 if packet.len() >= 20 => {
+                let header = usize::from(first & 0x0f) * 4;
+                let total = usize::from(u16::from_be_bytes([packet[2], packet[3]]));
+                let flags = u16::from_be_bytes([packet[6], packet[7]]);
+                let src: [u8; 4] = packet[12..16].try_into().unwrap();
+                let dst: [u8; 4] = packet[16..20].try_into().unwrap();
+                // More fragments, or an offset
+                let fragment = flags & 0x3fff != 0;
+                (header, total, packet[9], src.into(), dst.into(), fragment)
+            }
+            6 if packet.len() >= 40 => {
+                let payload = usize::from(u16::from_be_bytes([packet[4], packet[5]]));
+                let src: [u8; 16] = packet[8..24].try_into().unwrap();
+                let dst: [u8; 16] = packet[24..40].try_into().unwrap();
+                (40, 40 + payload, packet[6], src.into(), dst.into(), false)
+            }
+            _ => {
+                zero(packet);
+                return;
+            }
+        };
+        if header > packet.len() {
+            zero(packet);
+            return;
+        }
+        let truncated = end > packet.len();
+        let end = end.clamp(header, packet.len());
+        let body = &mut packet[header..end];
+        match protocol {
+            6 if !fragment => self.tcp(body, src, dst, truncated),
+            // Keep UDP's header, for the ports
+            17 if !fragment && body.len() >= 8 => zero(&mut body[8..]),
+            _ => zero(body),
+        }
+    }
+
+    fn tcp(&mut self, segment: &mut [u8], src: IpAddr, dst: IpAddr, truncated: bool) {
+        if segment.len() < 20 {
+            zero(segment);
+            return;
+        }
+        let header = usize::from(segment[12] >> 4) * 4;
+        if header < 20 || header > segment.len() {
+            zero(segment);
+            return;
+        }
+        let key = StreamKey {
+            src,
+            src_port: u16::from_be_bytes([segment[0], segment[1]]),
+            dst,
+            dst_port: u16::from_be_bytes([segment[2], segment[3]]),
+        };
+        let seq = u32::from_be_bytes(segment[4..8].try_into().unwrap());
+        let syn = segment[13] & 0x02 != 0;
+        let payload = &mut segment[header..];
+
+        let ports = &self.options.ports;
+        if !ports.contains(&key.src_port) && !ports.contains(&key.dst_port) {
+            zero(payload);
+            return;
+        }
+        if syn {
+            self.streams.insert(
+                key,
+                Stream {
+                    next_seq: seq.wrapping_add(1),
+                    mode: Mode::lines(),
+                },
+            );
+            self.stats.streams += 1;
+        }
+        let Some(stream) = self.streams.get_mut(&key) else {
+            // Started before the capture
+            zero(payload);
+            return;
+        };
+        if payload.is_empty() {
+            return;
+        }
+        if seq == stream.next_seq && !truncated {
+            stream.mode.scrub(payload, self.options);
+            stream.next_seq = seq.wrapping_add(payload.len() as u32);
+        } else if seq.wrapping_sub(stream.next_seq) <= u32::MAX / 2 || truncated {
+            // A gap, or bytes missing from the capture: no telling where
+            // the stream is up to after this
+            if !matches!(stream.mode, Mode::Opaque) {
+                stream.mode = Mode::Opaque;
+                self.stats.lost += 1;
+            }
+            zero(payload);
+        } else {
+            // A retransmission of bytes already scrubbed once
+            zero(payload);
+        }
+    }
+}
+
+/// How the next byte of a stream is treated
+#[derive(Debug)]
+enum Mode {
+    /// SMTP commands and replies, kept except for AUTH's arguments
+    Lines { line: Vec<u8> },
+    /// TLS records: headers kept, application data zeroed
+    Tls {
+        header: [u8; TLS_HEADER_SIZE],
+        have: usize,
+        body: usize,
+        keep: bool,
+    },
+    /// Tunnel frames: headers and HELLOs kept, payloads zeroed
+    Frames {
+        header: [u8; EXTENDED_HEADER_SIZE],
+        have: usize,
+        need: usize,
+        body: usize,
+        keep: bool,
+        trailer: usize,
+        seen: u64,
+    },
+    /// Lost track of the stream: everything zeroed
+    Opaque,
+}
+
+impl Mode {
+    fn lines() -> Self {
+        Self::Lines { line: Vec::new() }
+    }
+
+    fn tls() -> Self {
+        Self::Tls {
+            header: [0; TLS_HEADER_SIZE],
+            have: 0,
+            body: 0,
+            keep: false,
+        }
+    }
+
+    fn frames() -> Self {
+        Self::Frames {
+            header: [0; EXTENDED_HEADER_SIZE],
+            have: 0,
+            need: 1,
+            body: 0,
+            keep: false,
+            trailer: 0,
+            seen: 0,
+        }
+    }
+
+    fn scrub(&mut self, data: &mut [u8], options: &ScrubOptions) {
+        for byte in data {
+            self.byte(byte, options);
+        }
+    }
+
+    fn byte(&mut self, byte: &mut u8, options: &ScrubOptions) {
+        match self {
+            Self::Lines { line } => {
+                if line.is_empty() && *byte == TLS_HANDSHAKE {
+                    // STARTTLS was accepted, or this is the SMTPS port
+                    *self = Self::tls();
+                    return self.byte(byte, options);
+                }
+                if line.len() >= MAX_LINE {
+                    *self = Self::Opaque;
+                    return self.byte(byte, options);
+                }
+                let value = *byte;
+                let spaces = line.iter().filter(|&&b| b == b' ').count();
+                if line.len() >= 5
+                    && line[..5].eq_ignore_ascii_case(b"AUTH ")
+                    && spaces >= 2
+                    && value != b'\r'
+                    && value != b'\n'
+                {
+                    *byte = b'X';
+                }
+                line.push(value);
+                if value == b'\n' {
+                    let binary = switches_to_binary(line, &options.binary_verb);
+                    line.clear();
+                    if binary {
+                        *self = Self::frames();
+                    }
+                }
+            }
+            Self::Tls {
+                header,
+                have,
+                body,
+                keep,
+            } => {
+                if *have < TLS_HEADER_SIZE {
+                    header[*have] = *byte;
+                    *have += 1;
+                    if *have == TLS_HEADER_SIZE {
+                        if !(20..=24).contains(&header[0]) {
+                            *self = Self::Opaque;
+                            return zero(std::slice::from_mut(byte));
+                        }
+                        *body = usize::from(u16::from_be_bytes([header[3], header[4]]));
+                        *keep = header[0] != TLS_APPLICATION_DATA;
+                    }
+                } else {
+                    if !*keep {
+                        *byte = 0;
+                    }
+                    *body -= 1;
+                }
+                if *have == TLS_HEADER_SIZE && *body == 0 {
+                    *have = 0;
+                }
+            }
+            Self::Frames {
+                header,
+                have,
+                need,
+                body,
+                keep,
+                trailer,
+                seen,
+            } => {
+                if *have < *need {
+                    if *have == 0 {
+                        let flags = EXTENDED_LENGTH_FLAG | COMPRESSED_FLAG;
+                        if FrameType::from_u8(*byte & !flags).is_none() {
+                            *self = Self::Opaque;
+                            return zero(std::slice::from_mut(byte));
+                        }
+                        *need = if *byte & EXTENDED_LENGTH_FLAG != 0 {
+                            EXTENDED_HEADER_SIZE
+                        } else {
+                            FRAME_HEADER_SIZE
+                        };
+                    }
+                    header[*have] = *byte;
+                    *have += 1;
+                    if *have == *need {
+                        *body = if *need == EXTENDED_HEADER_SIZE {
+                            u32::from_be_bytes(header[3..7].try_into().unwrap()) as usize
+                        } else {
+                            usize::from(u16::from_be_bytes([header[3], header[4]]))
+                        };
+                        let frame_type = header[0] & !(EXTENDED_LENGTH_FLAG | COMPRESSED_FLAG);
+                        *keep = frame_type == FrameType::Hello as u8;
+                        // HELLO itself comes before checksums are agreed
+                        *trailer = if options.checksum && *seen > 0 {
+                            CHECKSUM_SIZE
+                        } else {
+                            0
+                        };
+                        *seen += 1;
+                    }
+                } else if *body > 0 {
+                    if !*keep {
+                        *byte = 0;
+                    }
+                    *body -= 1;
+                } else {
+                    *trailer -= 1;
+                }
+                if *have == *need && *body == 0 && *trailer == 0 {
+                    *have = 0;
+                    *need = 1;
+                }
+            }
+            Self::Opaque => *byte = 0,
+        }
+    }
+}
+
+/// The client's BINARY, or the server's 299 answering it
+fn switches_to_binary(line: &[u8], binary_verb: &str) -> bool {
+    let line = line.trim_ascii_end();
+    let verb = line.split(|&b| b == b' ').next().unwrap_or_default();
+    verb.eq_ignore_ascii_case(binary_verb.as_bytes()) || line.starts_with(b"299 ") || line == b"299"
+}
+
+fn zero(data: &mut [u8]) {
+    data.fill(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::frames::{Frame, FrameCodec};
+    use bytes::BytesMut;
+    use tokio_util::codec::Encoder;
+
+    const CLIENT_PORT: u16 = 50000;
+
+    /// An Ethernet/IPv4/TCP packet from the client (or to it)
+    fn packet(to_server: bool, seq: u32, syn: bool, payload: &[u8]) -> Vec<u8> {
+        let (src_port, dst_port) = if to_server {
+            (CLIENT_PORT, 587)
+        } else {
+            (587, CLIENT_PORT)
+        };
+        let mut packet = vec![0u8; 14];
+        packet[12..14].copy_from_slice(&0x0800u16.to_be_bytes());
+        let mut ip = vec![0x45, 0];
+        ip.extend_from_slice(&((40 + payload.len()) as u16).to_be_bytes());
+        ip.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+        ip.extend_from_slice(&[10, 0, 0, if to_server { 1 } else { 2 }]);
+        ip.extend_from_slice(&[10, 0, 0, if to_server { 2 } else { 1 }]);
+        packet.extend_from_slice(&ip);
+        let mut tcp = Vec::new();
+        tcp.extend_from_slice(&src_port.to_be_bytes());
+        tcp.extend_from_slice(&dst_port.to_be_bytes());
+        tcp.extend_from_slice(&seq.to_be_bytes());
+        tcp.extend_from_slice(&[0; 4]);
+        tcp.extend_from_slice(&[0x50, if syn { 0x02 } else { 0x18 }]);
+        tcp.extend_from_slice(&[0; 6]);
+        packet.extend_from_slice(&tcp);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn capture(packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut capture = vec![0xd4, 0xc3, 0xb2, 0xa1, 2, 0, 4, 0];
+        capture.extend_from_slice(&[0; 8]);
+        capture.extend_from_slice(&65535u32.to_le_bytes());
+        capture.extend_from_slice(&1u32.to_le_bytes());
+        for packet in packets {
+            capture.extend_from_slice(&[0; 8]);
+            capture.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            capture.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            capture.extend_from_slice(packet);
+        }
+        capture
+    }
+
+    /// The TCP payloads in a capture made by [`capture`]
+    fn payloads(capture: &[u8]) -> Vec<Vec<u8>> {
+        let mut payloads = Vec::new();
+        let mut offset = FILE_HEADER_SIZE;
+        while offset < capture.len() {
+            let len = u32::from_le_bytes(capture[offset + 8..offset + 12].try_into().unwrap());
+            let start = offset + RECORD_HEADER_SIZE;
+            let end = start + len as usize;
+            payloads.push(capture[start + 54..end].to_vec());
+            offset = end;
+        }
+        payloads
+    }
+
+    fn encode(frame: Frame) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        FrameCodec::new().encode(frame, &mut buf).unwrap();
+        buf.to_vec()
+    }
+
+    #[test]
+    fn test_scrub_plaintext_session() {
+        let smtp = b"EHLO mail.example.com\r\nAUTH PLAIN c2VjcmV0\r\nBINARY\r\n";
+        let mut frames = encode(Frame::connect(1, "secret.example", 443));
+        frames.extend(encode(Frame::data(1, &b"GET /private"[..])));
+        let first = 1000u32;
+        let mut client = smtp.to_vec();
+        client.extend(&frames[..10]);
+        let packets = [
+            packet(true, first - 1, true, b""),
+            packet(true, first, false, &client),
+            // The rest of the frames, split mid-frame
+            packet(true, first + client.len() as u32, false, &frames[10..]),
+            // A retransmission
+            packet(true, first, false, &client),
+            // Not seen from its SYN
+            packet(false, 7, false, b"220 mail.example.com ESMTP\r\n"),
+        ];
+        let mut scrubbed = capture(&packets);
+        let stats = scrub(&mut scrubbed, &ScrubOptions::default()).unwrap();
+        assert_eq!(
+            stats,
+            ScrubStats {
+                packets: 5,
+                streams: 1,
+                lost: 0
+            }
+        );
+        assert_eq!(scrubbed.len(), capture(&packets).len());
+
+        let payloads = payloads(&scrubbed);
+        let mut stream = payloads[1].clone();
+        stream.extend(&payloads[2]);
+        assert!(stream.starts_with(b"EHLO mail.example.com\r\nAUTH PLAIN XXXXXXXX\r\nBINARY\r\n"));
+        let frames_out = &stream[smtp.len()..];
+        assert_eq!(frames_out.len(), frames.len());
+        // Headers kept, destination and data gone
+        assert_eq!(frames_out[..FRAME_HEADER_SIZE], frames[..FRAME_HEADER_SIZE]);
+        let connect_len = encode(Frame::connect(1, "secret.example", 443)).len();
+        assert!(
+            frames_out[FRAME_HEADER_SIZE..connect_len]
+                .iter()
+                .all(|&b| b == 0)
+        );
+        let data = &frames_out[connect_len..];
+        assert_eq!(
+            data[..FRAME_HEADER_SIZE],
+            frames[connect_len..][..FRAME_HEADER_SIZE]
+        );
+        assert!(data[FRAME_HEADER_SIZE..].iter().all(|&b| b == 0));
+        assert!(payloads[3].iter().all(|&b| b == 0));
+        assert!(payloads[4].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_scrub_tls() {
+        let mut server = b"220 mail.example.com ESMTP\r\n".to_vec();
+        let banner = server.len();
+        // A handshake record, kept, and application data, zeroed
+        server.extend_from_slice(&[22, 3, 3, 0, 2, 0xaa, 0xbb]);
+        server.extend_from_slice(&[23, 3, 3, 0, 3, 1, 2, 3]);
+        let packets = [
+            packet(false, 99, true, b""),
+            packet(false, 100, false, &server),
+            // A gap loses the stream
+            packet(false, 200, false, b"250 OK\r\n"),
+        ];
+        let mut scrubbed = capture(&packets);
+        let stats = scrub(&mut scrubbed, &ScrubOptions::default()).unwrap();
+        assert_eq!(stats.lost, 1);
+        let payloads = payloads(&scrubbed);
+        assert_eq!(&payloads[1][..banner + 7], &server[..banner + 7]);
+        assert_eq!(&payloads[1][banner + 7..], &[23, 3, 3, 0, 3, 0, 0, 0]);
+        assert!(payloads[2].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_scrub_rejects_other_formats() {
+        assert!(scrub(&mut [0u8; 8], &ScrubOptions::default()).is_err());
+        let mut pcapng = capture(&[]);
+        pcapng[..4].copy_from_slice(&[0x0a, 0x0d, 0x0d, 0x0a]);
+        assert!(scrub(&mut pcapng, &ScrubOptions::default()).is_err());
+    }
+}
@@ -0,0 +1,381 @@
+//! Platform-specific socket and process helpers
+//!
+//! Everything that depends on the target OS or architecture lives here behind
+//! `cfg`s, so the rest of the crate builds unchanged on x86_64/aarch64 Linux,
+//! macOS (including Apple Silicon) and Windows. Options the running platform
+//! cannot honour degrade to no-ops and are reported once at startup via
+//! [`Capabilities`] instead of failing deep inside a connection handler.
+
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tracing::{debug, info, warn};
+
+/// Idle time before the first TCP keepalive probe is sent
+pub const KEEPALIVE_TIME: Duration = Duration::from_secs(60);
+
+/// Interval between TCP keepalive probes (where supported)
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Whether the target lets us tune the keepalive probe interval
+const KEEPALIVE_INTERVAL_SUPPORTED: bool = cfg!(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "freebsd",
+    target_os = "windows",
+));
+
+/// Runtime-detected platform capabilities
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    /// TCP keepalive can be enabled on sockets
+    pub tcp_keepalive: bool,
+    /// The keepalive probe interval can be tuned
+    pub tcp_keepalive_interval: bool,
+    /// TCP_NODELAY can be set
+    pub tcp_nodelay: bool,
+    /// Unix signals (SIGHUP reload etc.) are available
+    pub unix_signals: bool,
+    /// Unix file permission bits can be set (start.sh, key files)
+    pub unix_permissions: bool,
+}
+
+impl Capabilities {
+    /// Probe the running platform.
+    ///
+    /// Compile-time `cfg`s only say what the target *might* support; routers
+    /// with stripped kernels and sandboxed environments regularly reject
+    /// options at runtime, so each socket option is tried on a scratch socket.
+    pub fn detect() -> Self {
+        let probe = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP)).ok();
+        let tcp_keepalive = probe.as_ref().is_some_and(|s| {
+            s.set_tcp_keepalive(&TcpKeepalive::new().with_time(KEEPALIVE_TIME))
+                .is_ok()
+        });
+        let tcp_keepalive_interval = KEEPALIVE_INTERVAL_SUPPORTED
+            && tcp_keepalive
+            && probe
+                .as_ref()
+                .is_some_and(|s| s.set_tcp_keepalive(&keepalive_params()).is_ok());
+        let tcp_nodelay = probe
+            .as_ref()
+            .is_some_and(|s| s.set_tcp_nodelay(true).is_ok());
+
+        Self {
+            tcp_keepalive,
+            tcp_keepalive_interval,
+            tcp_nodelay,
+            unix_signals: cfg!(unix),
+            unix_permissions: cfg!(unix),
+        }
+    }
+
+    /// Log the detected capabilities, warning about anything degraded
+    pub fn log_summary(&self) {
+        info!(
+            "Platform: {}/{}",
+            std::env::consts::OS,
+            std::env::consts::ARCH
+        );
+        debug!("Platform capabilities: {:?}", self);
+        if !self.tcp_keepalive {
+            warn!("TCP keepalive unavailable; dead connections rely on protocol timeouts");
+        } else if !self.tcp_keepalive_interval {
+            debug!("TCP keepalive interval not tunable on this platform, using OS default");
+        }
+        if !self.tcp_nodelay {
+            warn!("TCP_NODELAY unavailable; interactive traffic may see extra latency");
+        }
+    }
+}
+
+/// Keepalive parameters for tunnel connections
+fn keepalive_params() -> TcpKeepalive {
+    let params = TcpKeepalive::new().with_time(KEEPALIVE_TIME);
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "windows",
+    ))]
+    let params = params.with_interval(KEEPALIVE_INTERVAL);
+    params
+}
+
+/// Apply the tunnel's socket options (nodelay + keepalive) to a stream.
+///
+/// Failures are logged and ignored: a missing socket option is never a
+/// reason to drop an otherwise healthy connection.
+pub fn configure_stream(stream: &TcpStream) {
+    let sock = SockRef::from(stream);
+    if let Err(e) = sock.set_tcp_nodelay(true) {
+        debug!("Failed to set TCP_NODELAY: {}", e);
+    }
+    if let Err(e) = sock.set_tcp_keepalive(&keepalive_params()) {
+        debug!("Failed to enable TCP keepalive: {}", e);
+    }
+}
+
+/// Best-effort write that doesn't wait for the socket to be polled ready;
+/// short messages always fit in a fresh connection's send buffer
+pub fn send_now(stream: &TcpStream, data: &[u8]) -> io::Result<usize> {
+    SockRef::from(stream).send(data)
+}
+
+/// Drop a connection with a TCP reset instead of a normal close
+pub fn reset(stream: TcpStream) {
+    if let Err(e) = SockRef::from(&stream).set_linger(Some(Duration::ZERO)) {
+        debug!("Failed to set SO_LINGER: {}", e);
+    }
+}
+
+/// Bind a UDP socket on an ephemeral port for sending anywhere: dual-stack
+/// where the host allows it, else IPv4 only
+pub fn bind_udp_any() -> io::Result<tokio::net::UdpSocket> {
+    let dual_stack = || -> io::Result<Socket> {
+        let socket = Socket::new(Domain::IPV6, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_only_v6(false)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)).into())?;
+        Ok(socket)
+    };
+    let socket = match dual_stack() {
+        Ok(socket) => socket,
+        Err(e) => {
+            debug!("No dual-stack UDP socket ({}), using IPv4", e);
+            let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+            socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)).into())?;
+            socket
+        }
+    };
+    socket.set_nonblocking(true)?;
+    tokio::net::UdpSocket::from_std(socket.into())
+}
+
+/// Mark a file as executable (no-op where permission bits don't exist)
+pub fn set_executable(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mut perms = std::fs::metadata(path)?.permissions();
+        perms.set_mode(0o755);
+        std::fs::set_permissions(path, perms)?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Soft and hard limits on open file descriptors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FdLimit {
+    pub soft: u64,
+    pub hard: u64,
+}
+
+/// Current open-file limit (None where the platform has no such limit)
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)] // rlim_t isn't u64 on every target
+pub fn fd_limit() -> Option<FdLimit> {
+    let mut rl = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit only writes to the struct we pass in
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rl) } != 0 {
+        return None;
+    }
+    Some(FdLimit {
+        soft: rl.rlim_cur as u64,
+        hard: rl.rlim_max as u64,
+    })
+}
+
+/// Current open-file limit (None where the platform has no such limit)
+#[cfg(not(unix))]
+pub fn fd_limit() -> Option<FdLimit> {
+    None
+}
+
+/// Raise the soft open-file limit as far as the hard limit allows.
+///
+/// Returns the limit in effect afterwards; failing to raise it is not an
+/// error, the caller just has to live with the current soft limit.
+#[cfg(unix)]
+pub fn raise_fd_limit() -> Option<FdLimit> {
+    let current = fd_limit()?;
+    // macOS rejects soft limits above OPEN_MAX even when the hard limit is unlimited
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    let target = current.hard.min(10240);
+    #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+    let target = current.hard;
+    if current.soft >= target {
+        return Some(current);
+    }
+    let rl = libc::rlimit {
+        rlim_cur: target as libc::rlim_t,
+        rlim_max: current.hard as libc::rlim_t,
+    };
+    // SAFETY: setrlimit only reads the struct we pass in
+    if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &rl) } != 0 {
+        debug!(
+            "Failed to raise open-file limit to {}: {}",
+            target,
+            io::Error::last_os_error()
+        );
+        return Some(current);
+    }
+    fd_limit()
+}
+
+/// Raise the soft open-file limit as far as the hard limit allows.
+#[cfg(not(unix))]
+pub fn raise_fd_limit() -> Option<FdLimit> {
+    None
+}
+
+/// Number of file descriptors currently open in this process, where the
+/// platform exposes them as a directory listing
+pub fn open_fds() -> Option<u64> {
+    let dir = if cfg!(any(target_os = "linux", target_os = "android")) {
+        "/proc/self/fd"
+    } else if cfg!(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd"
+    )) {
+        "/dev/fd"
+    } else {
+        return None;
+    };
+    // The listing includes the descriptor read_dir itself holds open
+    let count = std::fs::read_dir(dir).ok()?.count() as u64;
+    Some(count.saturating_sub(1))
+}
+
+/// Listen on a Unix socket at `path` that only its owner can connect to,
+/// replacing a stale one. Fails with [`io::ErrorKind::AddrInUse`] if a
+/// process is still listening there.
+#[cfg(unix)]
+pub fn bind_private_socket(path: &Path) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    if std::os::unix::net::UnixStream::connect(path).is_ok() {
+        return Err(io::ErrorKind::AddrInUse.into());
+    }
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no socket file name"))?;
+    // The socket is created under the umask, so bind it in a directory only
+    // we can enter, restrict it there and then move it into place. Nobody
+    // can connect in between.
+    let mut staging = std::ffi::OsString::from(".");
+    staging.push(name);
+    staging.push(format!(".{}", std::process::id()));
+    let dir = path.with_file_name(staging);
+    std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let staged = dir.join("socket");
+    let result = tokio::net::UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&staged);
+    let _ = std::fs::remove_dir(&dir);
+    result
+}
+
+/// Send `request` to the Unix socket at `path`, shutting down the write
+/// half to end it, and read the whole reply. `Ok(None)` means nothing is
+/// listening there.
+#[cfg(unix)]
+pub fn unix_request(path: &Path, request: &[u8]) -> io::Result<Option<String>> {
+    use std::io::{Read, Write};
+
+    let mut stream = match std::os::unix::net::UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            return Ok(None);
+        }
+        Err(e) => return Err(e),
+    };
+    stream.write_all(request)?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(Some(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_fd_usage() {
+        let limit = fd_limit().unwrap();
+        assert!(limit.soft <= limit.hard);
+
+        // Other tests open sockets concurrently, so only sanity-check the count
+        let open = open_fds().unwrap();
+        assert!(open > 0 && open <= limit.soft);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_private_socket() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("smtp-tunnel-socket-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("admin.sock");
+        // A stale socket file is replaced
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert_eq!(unix_request(&path, b"ping").unwrap(), None);
+
+        let listener = bind_private_socket(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // Only the socket is left behind
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        let server = tokio::spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = String::new();
+            stream.read_to_string(&mut request).await.unwrap();
+            stream
+                .write_all(request.to_uppercase().as_bytes())
+                .await
+                .unwrap();
+            listener
+        });
+        let response = tokio::task::spawn_blocking({
+            let path = path.clone();
+            move || unix_request(&path, b"ping")
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(response.as_deref(), Some("PING"));
+        // A live listener isn't replaced
+        let _listener = server.await.unwrap();
+        assert_eq!(
+            bind_private_socket(&path).map(drop).unwrap_err().kind(),
+            io::ErrorKind::AddrInUse
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,53 @@
+//! Frame checksums
+//!
+//! TLS already authenticates every byte, but a session may be carried by
+//! something that doesn't: a frontend that terminates TLS and forwards
+//! plaintext, or a test transport. When both sides agree on
+//! [`Features::CHECKSUM`](super::hello::Features::CHECKSUM) in HELLO, each
+//! frame is followed by a CRC32C of its header and payload, and a frame
+//! that doesn't match fails the connection instead of being acted on.
+
+/// Bytes the checksum adds after each frame
+pub const CHECKSUM_SIZE: usize = 4;
+
+/// CRC32C (Castagnoli) polynomial, bit-reversed
+const POLYNOMIAL: u32 = 0x82f6_3b78;
+
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC32C of `data`
+pub fn crc32c(data: &[u8]) -> u32 {
+    !data.iter().fold(!0u32, |crc, &byte| {
+        TABLE[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(&[0u8; 32]), 0x8a91_36aa);
+    }
+}
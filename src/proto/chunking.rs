@@ -0,0 +1,256 @@
+//! BDAT chunk framing (RFC 3030 CHUNKING)
+//!
+//! With the `bdat` data channel a session doesn't switch to a custom mode
+//! after AUTH. The client opens a mail transaction (MAIL, RCPT) and
+//! everything after that travels as message data in BDAT chunks: each write
+//! goes out as `BDAT <size>\r\n` followed by that many bytes, and `BDAT 0
+//! LAST` ends the stream. [`Chunked`] does the framing over any stream, so
+//! the BINARY line, HELLO and session frames pass through it unchanged.
+//! Both directions are framed alike.
+
+use bytes::{Buf, BytesMut};
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Largest chunk written; longer writes are split
+pub const MAX_CHUNK: usize = 64 * 1024;
+
+/// Longest chunk header accepted
+const MAX_HEADER: usize = 64;
+
+/// Bytes read from the inner stream at a time
+const READ_SIZE: usize = 8 * 1024;
+
+/// A stream whose data travels in BDAT chunks over `S`
+#[derive(Debug)]
+pub struct Chunked<S> {
+    inner: S,
+    /// Bytes read from `inner` not yet taken apart
+    raw: BytesMut,
+    /// Data left in the chunk being read
+    remaining: usize,
+    /// The chunk being read is the last one
+    last: bool,
+    /// Framed bytes not yet written to `inner`
+    pending: BytesMut,
+    /// `BDAT 0 LAST` has been queued
+    finished: bool,
+}
+
+impl<S> Chunked<S> {
+    /// Frame data over `inner`. `leftover` is anything already read from
+    /// it, starting at a chunk header.
+    pub fn new(inner: S, leftover: BytesMut) -> Self {
+        Self {
+            inner,
+            raw: leftover,
+            remaining: 0,
+            last: false,
+            pending: BytesMut::new(),
+            finished: false,
+        }
+    }
+
+    /// Frame data over `inner` after reading the header of its first
+    /// chunk, `size` bytes long; `leftover` is the start of that chunk
+    pub fn after_header(inner: S, leftover: BytesMut, (size, last): (usize, bool)) -> Self {
+        Self {
+            remaining: size,
+            last,
+            ..Self::new(inner, leftover)
+        }
+    }
+
+    /// Parse the next chunk header out of `raw`, if it's all there
+    fn next_header(&mut self) -> io::Result<bool> {
+        let Some(end) = self.raw.windows(2).position(|w| w == b"\r\n") else {
+            if self.raw.len() > MAX_HEADER {
+                return Err(invalid("Chunk header too long"));
+            }
+            return Ok(false);
+        };
+        let line = self.raw.split_to(end);
+        self.raw.advance(2);
+        let (size, last) = parse_header(&line)?;
+        self.remaining = size;
+        self.last = last;
+        Ok(true)
+    }
+}
+
+/// Parse `BDAT <size> [LAST]`
+pub fn parse_header(line: &[u8]) -> io::Result<(usize, bool)> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("Bad chunk header"))?;
+    let mut words = line.split_ascii_whitespace();
+    if !words
+        .next()
+        .is_some_and(|verb| verb.eq_ignore_ascii_case("BDAT"))
+    {
+        return Err(invalid("Expected BDAT"));
+    }
+    let size = words
+        .next()
+        .and_then(|size| size.parse().ok())
+        .ok_or_else(|| invalid("Bad chunk size"))?;
+    let last = match words.next() {
+        None => false,
+        Some(word) if word.eq_ignore_ascii_case("LAST") => true,
+        Some(_) => return Err(invalid("Bad chunk header")),
+    };
+    if words.next().is_some() {
+        return Err(invalid("Bad chunk header"));
+    }
+    Ok((size, last))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+impl<S: AsyncWrite + Unpin> Chunked<S> {
+    /// Write out everything framed so far
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for Chunked<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        // A command written without a flush still has to go out before
+        // its reply can come back
+        if let Poll::Ready(Err(e)) = this.poll_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        loop {
+            if this.remaining > 0 && !this.raw.is_empty() {
+                let n = this.remaining.min(this.raw.len()).min(buf.remaining());
+                buf.put_slice(&this.raw.split_to(n));
+                this.remaining -= n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.remaining == 0 {
+                if this.last {
+                    return Poll::Ready(Ok(()));
+                }
+                if this.next_header()? {
+                    continue;
+                }
+            }
+
+            let mut scratch = [0u8; READ_SIZE];
+            let mut read = ReadBuf::new(&mut scratch);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            if read.filled().is_empty() {
+                if this.remaining == 0 && this.raw.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.raw.extend_from_slice(read.filled());
+        }
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Chunked<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let data = &buf[..buf.len().min(MAX_CHUNK)];
+        this.pending
+            .extend_from_slice(format!("BDAT {}\r\n", data.len()).as_bytes());
+        this.pending.extend_from_slice(data);
+        // Start sending; whatever doesn't go now goes on the next call
+        if let Poll::Ready(Err(e)) = this.poll_pending(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(data.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.finished {
+            ready!(this.poll_pending(cx))?;
+            this.pending.extend_from_slice(b"BDAT 0 LAST\r\n");
+            this.finished = true;
+        }
+        ready!(this.poll_pending(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_chunked_round_trip() {
+        let (a, mut raw) = tokio::io::duplex(256);
+        let mut writer = Chunked::new(a, BytesMut::new());
+        let message = vec![7u8; 1000];
+        let send = async {
+            writer.write_all(b"BINARY\r\n").await.unwrap();
+            writer.write_all(&message).await.unwrap();
+            writer.shutdown().await.unwrap();
+        };
+        let receive = async {
+            let mut wire = Vec::new();
+            raw.read_to_end(&mut wire).await.unwrap();
+            wire
+        };
+        let ((), wire) = tokio::join!(send, receive);
+        assert!(wire.starts_with(b"BDAT 8\r\nBINARY\r\nBDAT 1000\r\n"));
+        assert!(wire.ends_with(b"BDAT 0 LAST\r\n"));
+
+        // Part of the first header already read, as after a command line
+        let leftover = BytesMut::from(&wire[..5]);
+        let mut reader = Chunked::new(Cursor::new(wire[5..].to_vec()), leftover);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(&data[..8], b"BINARY\r\n");
+        assert_eq!(&data[8..], &message[..]);
+
+        let first = BytesMut::from(&b"BIN"[..]);
+        let mut reader = Chunked::after_header(
+            Cursor::new(b"ARY\r\nBDAT 0 LAST\r\n".to_vec()),
+            first,
+            (8, false),
+        );
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await.unwrap();
+        assert_eq!(data, b"BINARY\r\n");
+
+        let mut bad = Chunked::new(Cursor::new(b"DATA\r\n".to_vec()), BytesMut::new());
+        assert!(bad.read_u8().await.is_err());
+        assert_eq!(parse_header(b"bdat 12 last").unwrap(), (12, true));
+        assert!(parse_header(b"BDAT 12 MORE").is_err());
+    }
+}
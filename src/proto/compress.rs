@@ -0,0 +1,96 @@
+//! DATA payload compression
+//!
+//! When both sides offer [`Features::COMPRESSION`](super::hello::Features)
+//! in HELLO, either may send DATA frames whose payload is a zstd frame,
+//! marked with [`COMPRESSED_FLAG`](super::frames::COMPRESSED_FLAG). Each
+//! payload is compressed on its own, so a frame replayed after a reconnect
+//! decodes the same as the first time. Payloads that don't shrink are sent
+//! as they are.
+
+use bytes::Bytes;
+use std::io;
+
+/// How this side compresses the DATA frames it sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compression {
+    /// zstd level; 0 sends everything uncompressed
+    pub level: i32,
+    /// Payloads smaller than this are not worth compressing
+    pub min_size: usize,
+}
+
+impl Compression {
+    /// `payload` compressed, or None if it is too small or didn't shrink
+    pub fn compress(&self, payload: &[u8]) -> Option<Bytes> {
+        if self.level == 0 || payload.len() < self.min_size {
+            return None;
+        }
+        let compressed = imp::compress(payload, self.level).ok()?;
+        (compressed.len() < payload.len()).then(|| Bytes::from(compressed))
+    }
+}
+
+/// Decompress a payload that expands to at most `limit` bytes
+pub fn decompress(payload: &[u8], limit: usize) -> io::Result<Bytes> {
+    imp::decompress(payload, limit).map(Bytes::from)
+}
+
+#[cfg(feature = "compression")]
+mod imp {
+    use std::io;
+
+    pub fn compress(payload: &[u8], level: i32) -> io::Result<Vec<u8>> {
+        zstd::bulk::compress(payload, level)
+    }
+
+    pub fn decompress(payload: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        zstd::bulk::decompress(payload, limit)
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+mod imp {
+    use std::io;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "built without the compression feature",
+        )
+    }
+
+    pub fn compress(_payload: &[u8], _level: i32) -> io::Result<Vec<u8>> {
+        Err(unsupported())
+    }
+
+    pub fn decompress(_payload: &[u8], _limit: usize) -> io::Result<Vec<u8>> {
+        Err(unsupported())
+    }
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_round_trip() {
+        let compression = Compression {
+            level: 3,
+            min_size: 64,
+        };
+        let text = "Subject: hello\r\n".repeat(100);
+        let compressed = compression.compress(text.as_bytes()).unwrap();
+        assert!(compressed.len() < text.len());
+        assert_eq!(
+            decompress(&compressed, text.len()).unwrap(),
+            text.as_bytes()
+        );
+        // A payload expanding past the limit is refused
+        assert!(decompress(&compressed, text.len() - 1).is_err());
+
+        // Too small, and incompressible
+        assert!(compression.compress(b"short").is_none());
+        let random: Vec<u8> = (0..1024).map(|_| rand::random()).collect();
+        assert!(compression.compress(&random).is_none());
+    }
+}
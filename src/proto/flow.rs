@@ -0,0 +1,171 @@
+//! Per-channel flow control
+//!
+//! Each direction of a channel has a window, HTTP/2 style: the sender may
+//! have at most [`INITIAL_WINDOW`] bytes of DATA unacknowledged, and the
+//! receiver returns credit with WINDOW_UPDATE frames as its consumer drains
+//! the data. A slow consumer therefore stalls only its own channel, and the
+//! shared frame loop never has to wait on it.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use tokio::sync::Semaphore;
+
+/// Bytes a peer may send on a new channel before receiving credit
+#[cfg(not(feature = "minimal"))]
+pub const INITIAL_WINDOW: u32 = 256 * 1024;
+
+/// Bytes a peer may send on a new channel before receiving credit (router builds)
+#[cfg(feature = "minimal")]
+pub const INITIAL_WINDOW: u32 = 64 * 1024;
+
+/// Consumed bytes batched into a single WINDOW_UPDATE
+const UPDATE_THRESHOLD: u32 = INITIAL_WINDOW / 4;
+
+/// Credit for sending DATA on one channel
+#[derive(Debug, Clone)]
+pub struct SendWindow(Arc<Semaphore>);
+
+impl SendWindow {
+    pub fn new() -> Self {
+        Self(Arc::new(Semaphore::new(INITIAL_WINDOW as usize)))
+    }
+
+    /// Wait for credit and take up to `max` bytes of it. Partial credit is
+    /// handed out rather than waiting for the whole amount, so a large write
+    /// can't stall on a nearly exhausted window. Fails once the window is
+    /// closed.
+    pub async fn reserve(&self, max: usize) -> Result<usize, WindowClosed> {
+        let first = self.0.acquire().await.map_err(|_| WindowClosed)?;
+        first.forget();
+        let more = self.0.available_permits().min(max.saturating_sub(1));
+        if more > 0
+            && let Ok(permits) = self.0.try_acquire_many(more as u32)
+        {
+            permits.forget();
+            return Ok(1 + more);
+        }
+        Ok(1)
+    }
+
+    /// Add credit from a WINDOW_UPDATE; false if the peer granted more than
+    /// it was ever sent
+    pub fn grant(&self, increment: u32) -> bool {
+        let available = self.0.available_permits() as u64;
+        if available + u64::from(increment) > u64::from(INITIAL_WINDOW) {
+            return false;
+        }
+        self.0.add_permits(increment as usize);
+        true
+    }
+
+    /// Wake any sender waiting for credit; the channel is going away
+    pub fn close(&self) {
+        self.0.close();
+    }
+}
+
+impl Default for SendWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The channel closed while waiting for send credit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowClosed;
+
+/// Accounting for DATA received on one channel
+#[derive(Debug, Clone)]
+pub struct RecvWindow(Arc<RecvState>);
+
+#[derive(Debug)]
+struct RecvState {
+    /// Bytes the peer may still send
+    available: AtomicU32,
+    /// Consumed bytes not yet returned as credit
+    unacked: AtomicU32,
+}
+
+impl RecvWindow {
+    pub fn new() -> Self {
+        Self(Arc::new(RecvState {
+            available: AtomicU32::new(INITIAL_WINDOW),
+            unacked: AtomicU32::new(0),
+        }))
+    }
+
+    /// Account for an incoming DATA payload; false if it overruns the window
+    pub fn receive(&self, len: usize) -> bool {
+        let Ok(len) = u32::try_from(len) else {
+            return false;
+        };
+        self.0
+            .available
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |available| {
+                available.checked_sub(len)
+            })
+            .is_ok()
+    }
+
+    /// Record `len` bytes handed to the consumer. Returns the increment for
+    /// a WINDOW_UPDATE once enough credit has built up.
+    pub fn consume(&self, len: usize) -> Option<u32> {
+        let unacked = self.0.unacked.fetch_add(len as u32, Ordering::AcqRel) + len as u32;
+        if unacked < UPDATE_THRESHOLD {
+            return None;
+        }
+        let increment = self.0.unacked.swap(0, Ordering::AcqRel);
+        // Reopen the window before the peer can learn about it
+        self.0.available.fetch_add(increment, Ordering::AcqRel);
+        Some(increment)
+    }
+}
+
+impl Default for RecvWindow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_send_window_blocks_until_granted() {
+        let window = SendWindow::new();
+        assert_eq!(window.reserve(1000).await, Ok(1000));
+        let rest = INITIAL_WINDOW as usize - 1000;
+        assert_eq!(window.reserve(usize::MAX).await, Ok(rest));
+
+        let waiting = tokio::spawn({
+            let window = window.clone();
+            async move { window.reserve(500).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!waiting.is_finished());
+
+        // Partial credit is handed out without waiting for the rest
+        assert!(window.grant(100));
+        assert_eq!(waiting.await.unwrap(), Ok(100));
+
+        // Credit beyond what was sent is a protocol violation
+        assert!(!window.grant(INITIAL_WINDOW + 1));
+
+        window.close();
+        assert_eq!(window.reserve(1).await, Err(WindowClosed));
+    }
+
+    #[test]
+    fn test_recv_window_accounting() {
+        let window = RecvWindow::new();
+        assert!(window.receive(INITIAL_WINDOW as usize));
+        assert!(!window.receive(1));
+
+        assert_eq!(window.consume(UPDATE_THRESHOLD as usize - 1), None);
+        assert_eq!(window.consume(1), Some(UPDATE_THRESHOLD));
+        assert!(window.receive(UPDATE_THRESHOLD as usize));
+        assert!(!window.receive(1));
+    }
+}
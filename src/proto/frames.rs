@@ -1,17 +1,39 @@
+use super::checksum::{CHECKSUM_SIZE, crc32c};
+use super::hello::{Features, Hello};
+use super::tlv;
+use crate::crypto::KEX_MESSAGE_LEN;
 use bytes::{Buf, BufMut, Bytes, BytesMut};
 use std::io;
+use std::net::{IpAddr, SocketAddr};
 use thiserror::Error;
 use tokio_util::codec::{Decoder, Encoder};
 
-/// Protocol version
-pub const PROTOCOL_VERSION: u8 = 1;
+/// Protocol version, exchanged in HELLO
+pub const PROTOCOL_VERSION: u8 = 3;
+
+/// Oldest protocol version this build can talk to
+pub const MIN_PROTOCOL_VERSION: u8 = 3;
 
 /// Maximum payload size (64KB)
 pub const MAX_PAYLOAD_SIZE: usize = 65535;
 
+/// Maximum payload size with the extended header (1 MiB)
+pub const MAX_LARGE_PAYLOAD_SIZE: usize = 1024 * 1024;
+
 /// Frame header size: type(1) + channel_id(2) + length(2)
 pub const FRAME_HEADER_SIZE: usize = 5;
 
+/// Extended frame header size: type(1) + channel_id(2) + length(4)
+pub const EXTENDED_HEADER_SIZE: usize = 7;
+
+/// Set in the type byte of a frame with the extended header. Only used
+/// once both sides have agreed on large frames in HELLO.
+pub const EXTENDED_LENGTH_FLAG: u8 = 0x80;
+
+/// Set in the type byte of a frame whose payload is zstd-compressed. Only
+/// used once both sides have agreed on compression in HELLO.
+pub const COMPRESSED_FLAG: u8 = 0x40;
+
 /// Frame types for binary protocol
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
@@ -24,15 +46,70 @@ pub enum FrameType {
     ConnectOk = 0x03,
     /// Connection failed
     ConnectFail = 0x04,
-    /// Close channel
+    /// Abort channel in both directions
     Close = 0x05,
     /// Keepalive
     Keepalive = 0x06,
     /// Keepalive ACK
     KeepaliveAck = 0x07,
+    /// Flow-control credit for a channel
+    WindowUpdate = 0x08,
+    /// Sender is done writing on a channel (TCP half-close)
+    Shutdown = 0x09,
+    /// Count of session frames received, for resumption
+    Ack = 0x0A,
+    /// Protocol version and features, exchanged once per connection
+    Hello = 0x0B,
+    /// Filler to disguise frame sizes, discarded on arrival
+    Padding = 0x0C,
+    /// Ask the server to resolve a hostname
+    Resolve = 0x0D,
+    /// Addresses (or an error) answering a RESOLVE
+    ResolveResult = 0x0E,
+    /// UDP datagram to or from a remote address
+    Datagram = 0x0F,
 }
 
 impl FrameType {
+    pub const ALL: [Self; 15] = [
+        Self::Data,
+        Self::Connect,
+        Self::ConnectOk,
+        Self::ConnectFail,
+        Self::Close,
+        Self::Keepalive,
+        Self::KeepaliveAck,
+        Self::WindowUpdate,
+        Self::Shutdown,
+        Self::Ack,
+        Self::Hello,
+        Self::Padding,
+        Self::Resolve,
+        Self::ResolveResult,
+        Self::Datagram,
+    ];
+
+    /// Name used in metrics output
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Data => "data",
+            Self::Connect => "connect",
+            Self::ConnectOk => "connect_ok",
+            Self::ConnectFail => "connect_fail",
+            Self::Close => "close",
+            Self::Keepalive => "keepalive",
+            Self::KeepaliveAck => "keepalive_ack",
+            Self::WindowUpdate => "window_update",
+            Self::Shutdown => "shutdown",
+            Self::Ack => "ack",
+            Self::Hello => "hello",
+            Self::Padding => "padding",
+            Self::Resolve => "resolve",
+            Self::ResolveResult => "resolve_result",
+            Self::Datagram => "datagram",
+        }
+    }
+
     pub fn from_u8(value: u8) -> Option<Self> {
         match value {
             0x01 => Some(Self::Data),
@@ -42,9 +119,262 @@ impl FrameType {
             0x05 => Some(Self::Close),
             0x06 => Some(Self::Keepalive),
             0x07 => Some(Self::KeepaliveAck),
+            0x08 => Some(Self::WindowUpdate),
+            0x09 => Some(Self::Shutdown),
+            0x0A => Some(Self::Ack),
+            0x0B => Some(Self::Hello),
+            0x0C => Some(Self::Padding),
+            0x0D => Some(Self::Resolve),
+            0x0E => Some(Self::ResolveResult),
+            0x0F => Some(Self::Datagram),
+            _ => None,
+        }
+    }
+}
+
+/// CONNECT metadata tag: original source address (transparent/TPROXY mode)
+pub const CONNECT_TAG_SOURCE: u8 = 0x01;
+
+/// CONNECT metadata tag: application protocol hint
+pub const CONNECT_TAG_PROTOCOL: u8 = 0x02;
+
+/// CONNECT metadata tag: scheduling [`Priority`] of the channel
+pub const CONNECT_TAG_PRIORITY: u8 = 0x03;
+
+/// CONNECT_FAIL tag: human-readable reason
+pub const CONNECT_FAIL_TAG_REASON: u8 = 0x01;
+
+/// CONNECT_FAIL tag: [`ConnectError`] code
+pub const CONNECT_FAIL_TAG_CODE: u8 = 0x02;
+
+/// RESOLVE_RESULT tag: one resolved address (4 or 16 bytes)
+pub const RESOLVE_RESULT_TAG_ADDR: u8 = 0x01;
+
+/// RESOLVE_RESULT tag: why resolution failed
+pub const RESOLVE_RESULT_TAG_ERROR: u8 = 0x02;
+
+/// HELLO TLV tag: operator announcement (UTF-8)
+pub const HELLO_TAG_ANNOUNCEMENT: u8 = 0x01;
+
+/// Longest announcement a server sends in HELLO
+pub const MAX_ANNOUNCEMENT_LEN: usize = 512;
+
+/// Largest datagram a DATAGRAM frame carries with any address, so it fits
+/// a standard frame
+pub const MAX_DATAGRAM_SIZE: usize = MAX_PAYLOAD_SIZE - 1 - 255 - 2;
+
+/// Longest hostname a RESOLVE may carry
+pub const MAX_RESOLVE_HOST_LEN: usize = 255;
+
+/// Addresses a RESOLVE asks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AddressFamily {
+    /// A and AAAA records
+    Any = 0x00,
+    /// A records only
+    Ipv4 = 0x04,
+    /// AAAA records only
+    Ipv6 = 0x06,
+}
+
+impl AddressFamily {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x00 => Some(Self::Any),
+            0x04 => Some(Self::Ipv4),
+            0x06 => Some(Self::Ipv6),
+            _ => None,
+        }
+    }
+
+    /// Whether `ip` is one of the addresses asked for
+    pub fn matches(self, ip: IpAddr) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Ipv4 => ip.is_ipv4(),
+            Self::Ipv6 => ip.is_ipv6(),
+        }
+    }
+}
+
+/// Why a CONNECT failed. Codes up to 0x06 match the SOCKS5 replies of the
+/// same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ConnectError {
+    /// Anything not covered below, and peers that send no code
+    General = 0x01,
+    /// Refused by the server's destination policy
+    NotAllowed = 0x02,
+    NetworkUnreachable = 0x03,
+    HostUnreachable = 0x04,
+    Refused = 0x05,
+    TimedOut = 0x06,
+    /// The destination name didn't resolve
+    HostNotFound = 0x07,
+    /// The server had no capacity for another connect
+    Busy = 0x08,
+    /// The CONNECT itself was invalid
+    Protocol = 0x09,
+}
+
+impl ConnectError {
+    /// Unknown codes read as [`Self::General`]
+    pub fn from_u8(value: u8) -> Self {
+        match value {
+            0x02 => Self::NotAllowed,
+            0x03 => Self::NetworkUnreachable,
+            0x04 => Self::HostUnreachable,
+            0x05 => Self::Refused,
+            0x06 => Self::TimedOut,
+            0x07 => Self::HostNotFound,
+            0x08 => Self::Busy,
+            0x09 => Self::Protocol,
+            _ => Self::General,
+        }
+    }
+
+    /// Code for a failed connect attempt
+    pub fn from_io(err: &io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused => Self::Refused,
+            io::ErrorKind::TimedOut => Self::TimedOut,
+            io::ErrorKind::NetworkUnreachable => Self::NetworkUnreachable,
+            io::ErrorKind::HostUnreachable => Self::HostUnreachable,
+            io::ErrorKind::PermissionDenied => Self::NotAllowed,
+            _ => Self::General,
+        }
+    }
+
+    /// Error kind the client reports the failure as
+    pub fn io_kind(self) -> io::ErrorKind {
+        match self {
+            Self::General => io::ErrorKind::Other,
+            Self::NotAllowed => io::ErrorKind::PermissionDenied,
+            Self::NetworkUnreachable => io::ErrorKind::NetworkUnreachable,
+            Self::HostUnreachable => io::ErrorKind::HostUnreachable,
+            Self::Refused => io::ErrorKind::ConnectionRefused,
+            Self::TimedOut => io::ErrorKind::TimedOut,
+            Self::HostNotFound => io::ErrorKind::NotFound,
+            Self::Busy => io::ErrorKind::ResourceBusy,
+            Self::Protocol => io::ErrorKind::InvalidData,
+        }
+    }
+}
+
+/// Application protocol hint carried in CONNECT metadata
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ProtocolHint {
+    Http = 0x01,
+    Tls = 0x02,
+    Dns = 0x03,
+}
+
+impl ProtocolHint {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(Self::Http),
+            0x02 => Some(Self::Tls),
+            0x03 => Some(Self::Dns),
+            _ => None,
+        }
+    }
+
+    /// Guess the protocol from a well-known destination port
+    pub fn from_port(port: u16) -> Option<Self> {
+        match port {
+            80 | 8080 => Some(Self::Http),
+            443 | 465 | 853 | 993 | 995 | 8443 => Some(Self::Tls),
+            53 => Some(Self::Dns),
             _ => None,
         }
     }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Http => "http",
+            Self::Tls => "tls",
+            Self::Dns => "dns",
+        }
+    }
+}
+
+/// How a channel's frames are scheduled against other channels
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum Priority {
+    /// Latency-sensitive: shells, remote desktops, DNS
+    Interactive = 0x00,
+    #[default]
+    Normal = 0x01,
+    /// Throughput over latency: file transfers
+    Bulk = 0x02,
+}
+
+impl Priority {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x00 => Some(Self::Interactive),
+            0x01 => Some(Self::Normal),
+            0x02 => Some(Self::Bulk),
+            _ => None,
+        }
+    }
+
+    /// Guess the priority from a well-known destination port
+    pub fn from_port(port: u16) -> Self {
+        match port {
+            22 | 23 | 53 | 853 | 3389 | 5060 | 5222 | 5223 | 5900 | 6667 | 6697 => {
+                Self::Interactive
+            }
+            20 | 21 | 119 | 563 | 873 | 6881..=6889 | 51413 => Self::Bulk,
+            _ => Self::Normal,
+        }
+    }
+}
+
+/// Optional CONNECT metadata
+///
+/// Encoded as [`tlv`] entries after host and port.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectMeta {
+    /// Original source address of the proxied connection
+    pub source: Option<SocketAddr>,
+    /// Application protocol hint
+    pub protocol: Option<ProtocolHint>,
+    /// How the sender schedules the channel; the peer should match it
+    pub priority: Option<Priority>,
+}
+
+impl ConnectMeta {
+    fn encode(&self, buf: &mut BytesMut) {
+        if let Some(source) = self.source {
+            tlv::put_socket_addr(buf, CONNECT_TAG_SOURCE, source);
+        }
+        if let Some(protocol) = self.protocol {
+            tlv::put_u8(buf, CONNECT_TAG_PROTOCOL, protocol as u8);
+        }
+        if let Some(priority) = self.priority {
+            tlv::put_u8(buf, CONNECT_TAG_PRIORITY, priority as u8);
+        }
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let mut meta = Self::default();
+        for entry in tlv::parse(buf)? {
+            match entry.tag {
+                CONNECT_TAG_SOURCE => meta.source = entry.as_socket_addr(),
+                CONNECT_TAG_PROTOCOL => {
+                    meta.protocol = entry.as_u8().and_then(ProtocolHint::from_u8)
+                }
+                CONNECT_TAG_PRIORITY => meta.priority = entry.as_u8().and_then(Priority::from_u8),
+                _ => {} // Unknown tags are ignored for forward compatibility
+            }
+        }
+        Some(meta)
+    }
 }
 
 /// Binary protocol frame
@@ -54,6 +384,8 @@ pub struct Frame {
     pub frame_type: FrameType,
     pub channel_id: u16,
     pub payload: Bytes,
+    /// The payload is compressed (see [`super::compress`])
+    pub compressed: bool,
 }
 
 impl Frame {
@@ -63,6 +395,7 @@ impl Frame {
             frame_type,
             channel_id,
             payload: payload.into(),
+            compressed: false,
         }
     }
 
@@ -73,11 +406,17 @@ impl Frame {
 
     /// Create a CONNECT frame
     pub fn connect(channel_id: u16, host: &str, port: u16) -> Self {
+        Self::connect_with_meta(channel_id, host, port, &ConnectMeta::default())
+    }
+
+    /// Create a CONNECT frame carrying optional metadata
+    pub fn connect_with_meta(channel_id: u16, host: &str, port: u16, meta: &ConnectMeta) -> Self {
         let host_bytes = host.as_bytes();
         let mut payload = BytesMut::with_capacity(1 + host_bytes.len() + 2);
         payload.put_u8(host_bytes.len() as u8);
         payload.extend_from_slice(host_bytes);
         payload.put_u16(port);
+        meta.encode(&mut payload);
         Self::new(FrameType::Connect, channel_id, payload.freeze())
     }
 
@@ -87,12 +426,13 @@ impl Frame {
     }
 
     /// Create a CONNECT_FAIL frame
-    pub fn connect_fail(channel_id: u16, reason: &str) -> Self {
-        Self::new(
-            FrameType::ConnectFail,
-            channel_id,
-            Bytes::copy_from_slice(reason.as_bytes()),
-        )
+    pub fn connect_fail(channel_id: u16, code: ConnectError, reason: &str) -> Self {
+        let mut payload = BytesMut::new();
+        tlv::put_u8(&mut payload, CONNECT_FAIL_TAG_CODE, code as u8);
+        if !reason.is_empty() {
+            tlv::put_str(&mut payload, CONNECT_FAIL_TAG_REASON, reason);
+        }
+        Self::new(FrameType::ConnectFail, channel_id, payload.freeze())
     }
 
     /// Create a CLOSE frame
@@ -100,18 +440,151 @@ impl Frame {
         Self::new(FrameType::Close, channel_id, Bytes::new())
     }
 
-    /// Serialize frame to bytes
+    /// Create a SHUTDOWN frame: no more DATA follows from this side, but
+    /// the channel stays open for the other direction
+    pub fn shutdown(channel_id: u16) -> Self {
+        Self::new(FrameType::Shutdown, channel_id, Bytes::new())
+    }
+
+    /// Create a KEEPALIVE frame on the control channel
+    pub fn keepalive() -> Self {
+        Self::new(
+            FrameType::Keepalive,
+            crate::channel::CONTROL_CHANNEL,
+            Bytes::new(),
+        )
+    }
+
+    /// Answer a KEEPALIVE, echoing its payload
+    pub fn keepalive_ack(&self) -> Self {
+        Self::new(
+            FrameType::KeepaliveAck,
+            self.channel_id,
+            self.payload.clone(),
+        )
+    }
+
+    /// Create a WINDOW_UPDATE frame granting `increment` more bytes
+    pub fn window_update(channel_id: u16, increment: u32) -> Self {
+        Self::new(
+            FrameType::WindowUpdate,
+            channel_id,
+            Bytes::copy_from_slice(&increment.to_be_bytes()),
+        )
+    }
+
+    /// Create an ACK frame: `received` session frames have arrived
+    pub fn ack(received: u64) -> Self {
+        Self::new(
+            FrameType::Ack,
+            crate::channel::CONTROL_CHANNEL,
+            Bytes::copy_from_slice(&received.to_be_bytes()),
+        )
+    }
+
+    /// Create a HELLO frame on the control channel
+    pub fn hello(hello: &Hello) -> Self {
+        let mut payload = BytesMut::with_capacity(5 + KEX_MESSAGE_LEN);
+        payload.put_u8(hello.version);
+        payload.put_u32(hello.features.bits());
+        if let Some(message) = &hello.key_exchange {
+            payload.extend_from_slice(message);
+        }
+        if let Some(announcement) = &hello.announcement {
+            tlv::put_str(&mut payload, HELLO_TAG_ANNOUNCEMENT, announcement);
+        }
+        Self::new(
+            FrameType::Hello,
+            crate::channel::CONTROL_CHANNEL,
+            payload.freeze(),
+        )
+    }
+
+    /// Create a PADDING frame on the control channel
+    pub fn padding(filler: Bytes) -> Self {
+        Self::new(FrameType::Padding, crate::channel::CONTROL_CHANNEL, filler)
+    }
+
+    /// Create a RESOLVE frame. `request_id` pairs it with its
+    /// RESOLVE_RESULT and is unrelated to channel IDs.
+    pub fn resolve(request_id: u16, host: &str, family: AddressFamily) -> Self {
+        let mut payload = BytesMut::with_capacity(1 + host.len());
+        payload.put_u8(family as u8);
+        payload.extend_from_slice(host.as_bytes());
+        Self::new(FrameType::Resolve, request_id, payload.freeze())
+    }
+
+    /// Create a RESOLVE_RESULT frame with the addresses found, or why
+    /// there are none
+    pub fn resolve_result(request_id: u16, result: Result<&[IpAddr], &str>) -> Self {
+        let mut payload = BytesMut::new();
+        match result {
+            Ok(addrs) => {
+                for &addr in addrs {
+                    tlv::put_ip_addr(&mut payload, RESOLVE_RESULT_TAG_ADDR, addr);
+                }
+            }
+            Err(reason) => tlv::put_str(&mut payload, RESOLVE_RESULT_TAG_ERROR, reason),
+        }
+        Self::new(FrameType::ResolveResult, request_id, payload.freeze())
+    }
+
+    /// Create a DATAGRAM frame. From the client `host:port` is the
+    /// destination, from the server the source. `association_id` names a
+    /// UDP association, a separate ID space from channels.
+    pub fn datagram(association_id: u16, host: &str, port: u16, data: &[u8]) -> Self {
+        let host_bytes = host.as_bytes();
+        let mut payload = BytesMut::with_capacity(1 + host_bytes.len() + 2 + data.len());
+        payload.put_u8(host_bytes.len() as u8);
+        payload.extend_from_slice(host_bytes);
+        payload.put_u16(port);
+        payload.extend_from_slice(data);
+        Self::new(FrameType::Datagram, association_id, payload.freeze())
+    }
+
+    /// Size of the frame on the wire
+    pub fn encoded_len(&self) -> usize {
+        let header = if self.payload.len() > MAX_PAYLOAD_SIZE {
+            EXTENDED_HEADER_SIZE
+        } else {
+            FRAME_HEADER_SIZE
+        };
+        header + self.payload.len()
+    }
+
+    /// Serialize frame to bytes, with the extended header if the payload
+    /// needs it
     pub fn serialize(&self) -> Bytes {
-        let mut buf = BytesMut::with_capacity(FRAME_HEADER_SIZE + self.payload.len());
-        buf.put_u8(self.frame_type as u8);
-        buf.put_u16(self.channel_id);
-        buf.put_u16(self.payload.len() as u16);
-        buf.extend_from_slice(&self.payload);
+        let mut buf = BytesMut::with_capacity(EXTENDED_HEADER_SIZE + self.payload.len());
+        self.encode_into(&mut buf);
         buf.freeze()
     }
 
+    fn encode_into(&self, buf: &mut BytesMut) {
+        let mut type_byte = self.frame_type as u8;
+        if self.compressed {
+            type_byte |= COMPRESSED_FLAG;
+        }
+        if self.payload.len() > MAX_PAYLOAD_SIZE {
+            buf.put_u8(type_byte | EXTENDED_LENGTH_FLAG);
+            buf.put_u16(self.channel_id);
+            buf.put_u32(self.payload.len() as u32);
+        } else {
+            buf.put_u8(type_byte);
+            buf.put_u16(self.channel_id);
+            buf.put_u16(self.payload.len() as u16);
+        }
+        buf.extend_from_slice(&self.payload);
+    }
+
     /// Parse a CONNECT payload to extract host and port
     pub fn parse_connect(&self) -> Option<(String, u16)> {
+        self.parse_connect_with_meta()
+            .map(|(host, port, _)| (host, port))
+    }
+
+    /// Parse a CONNECT payload including any trailing metadata
+    pub fn parse_connect_with_meta(&self) -> Option<(String, u16, ConnectMeta)> {
         if self.frame_type != FrameType::Connect {
             return None;
         }
@@ -127,7 +600,131 @@ impl Frame {
         let host = String::from_utf8_lossy(host_bytes).to_string();
         buf.advance(host_len);
         let port = buf.get_u16();
-        Some((host, port))
+        let meta = ConnectMeta::decode(buf)?;
+        Some((host, port, meta))
+    }
+
+    /// Parse a DATAGRAM payload to extract the address and data
+    pub fn parse_datagram(&self) -> Option<(String, u16, Bytes)> {
+        if self.frame_type != FrameType::Datagram {
+            return None;
+        }
+        let (&host_len, rest) = self.payload.split_first()?;
+        let host_len = host_len as usize;
+        if host_len == 0 || rest.len() < host_len + 2 {
+            return None;
+        }
+        let host = std::str::from_utf8(&rest[..host_len]).ok()?.to_string();
+        let port = u16::from_be_bytes([rest[host_len], rest[host_len + 1]]);
+        let data = self.payload.slice(1 + host_len + 2..);
+        Some((host, port, data))
+    }
+
+    /// Parse a WINDOW_UPDATE payload to extract the increment
+    pub fn parse_window_update(&self) -> Option<u32> {
+        if self.frame_type != FrameType::WindowUpdate {
+            return None;
+        }
+        let bytes: [u8; 4] = self.payload[..].try_into().ok()?;
+        Some(u32::from_be_bytes(bytes))
+    }
+
+    /// Parse an ACK payload to extract the received count
+    pub fn parse_ack(&self) -> Option<u64> {
+        if self.frame_type != FrameType::Ack {
+            return None;
+        }
+        let bytes: [u8; 8] = self.payload[..].try_into().ok()?;
+        Some(u64::from_be_bytes(bytes))
+    }
+
+    /// Parse a HELLO payload. The key exchange message follows the
+    /// features with [`Features::ENCRYPTION`]; TLV entries follow that with
+    /// [`Features::ANNOUNCEMENT`]. Unknown tags are ignored.
+    pub fn parse_hello(&self) -> Option<Hello> {
+        if self.frame_type != FrameType::Hello {
+            return None;
+        }
+        let mut buf = &self.payload[..];
+        if buf.remaining() < 5 {
+            return None;
+        }
+        let version = buf.get_u8();
+        let features = Features::from_bits(buf.get_u32());
+        let mut key_exchange = None;
+        if features.contains(Features::ENCRYPTION) && buf.len() >= KEX_MESSAGE_LEN {
+            let (message, rest) = buf.split_at(KEX_MESSAGE_LEN);
+            key_exchange = message.try_into().ok();
+            buf = rest;
+        }
+        let mut announcement = None;
+        if features.contains(Features::ANNOUNCEMENT) {
+            for entry in tlv::parse(buf)? {
+                if entry.tag == HELLO_TAG_ANNOUNCEMENT {
+                    announcement = Some(entry.as_str());
+                }
+            }
+        }
+        Some(Hello {
+            version,
+            features,
+            key_exchange,
+            announcement,
+        })
+    }
+
+    /// Parse a RESOLVE payload to extract the hostname and address family
+    pub fn parse_resolve(&self) -> Option<(String, AddressFamily)> {
+        if self.frame_type != FrameType::Resolve {
+            return None;
+        }
+        let (&family, host) = self.payload.split_first()?;
+        if host.is_empty() || host.len() > MAX_RESOLVE_HOST_LEN {
+            return None;
+        }
+        let host = std::str::from_utf8(host).ok()?;
+        Some((host.to_string(), AddressFamily::from_u8(family)?))
+    }
+
+    /// Parse a RESOLVE_RESULT payload: the addresses, or the error reason
+    pub fn parse_resolve_result(&self) -> Option<Result<Vec<IpAddr>, String>> {
+        if self.frame_type != FrameType::ResolveResult {
+            return None;
+        }
+        let entries = tlv::parse(&self.payload)?;
+        if let Some(error) = entries.iter().find(|e| e.tag == RESOLVE_RESULT_TAG_ERROR) {
+            return Some(Err(error.as_str()));
+        }
+        Some(Ok(entries
+            .iter()
+            .filter(|e| e.tag == RESOLVE_RESULT_TAG_ADDR)
+            .filter_map(|e| e.as_ip_addr())
+            .collect()))
+    }
+
+    /// Parse a CONNECT_FAIL payload to extract the failure code and reason.
+    ///
+    /// Payloads that aren't valid TLV are treated as legacy free-text
+    /// reasons, and a missing code as [`ConnectError::General`].
+    pub fn parse_connect_fail(&self) -> Option<(ConnectError, String)> {
+        if self.frame_type != FrameType::ConnectFail {
+            return None;
+        }
+        let Some(entries) = tlv::parse(&self.payload) else {
+            let reason = String::from_utf8_lossy(&self.payload).to_string();
+            return Some((ConnectError::General, reason));
+        };
+        let code = entries
+            .iter()
+            .find(|e| e.tag == CONNECT_FAIL_TAG_CODE)
+            .and_then(|e| e.as_u8())
+            .map_or(ConnectError::General, ConnectError::from_u8);
+        let reason = entries
+            .iter()
+            .find(|e| e.tag == CONNECT_FAIL_TAG_REASON)
+            .map(|e| e.as_str())
+            .unwrap_or_default();
+        Some((code, reason))
     }
 }
 
@@ -140,18 +737,69 @@ pub enum FrameError {
     InvalidType(u8),
     #[error("Payload too large: {0}")]
     PayloadTooLarge(usize),
+    #[error("Extended frame header without large frames negotiated")]
+    UnexpectedExtended,
     #[error("Incomplete frame")]
     Incomplete,
+    #[error("Frame checksum mismatch")]
+    ChecksumMismatch,
+    #[error("Frame failed authentication")]
+    Unauthenticated,
 }
 
 /// Tokio codec for encoding/decoding frames
-pub struct FrameCodec;
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameCodec {
+    large_frames: bool,
+    checksum: bool,
+}
+
+impl FrameCodec {
+    /// Codec for frames with the standard 16-bit length
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Codec that also reads and writes the extended header, for
+    /// payloads up to [`MAX_LARGE_PAYLOAD_SIZE`]
+    pub fn large() -> Self {
+        Self {
+            large_frames: true,
+            ..Self::default()
+        }
+    }
+
+    /// The same codec, with a CRC32C trailer after each frame
+    pub fn with_checksum(self) -> Self {
+        Self {
+            checksum: true,
+            ..self
+        }
+    }
+
+    fn max_payload(&self) -> usize {
+        if self.large_frames {
+            MAX_LARGE_PAYLOAD_SIZE
+        } else {
+            MAX_PAYLOAD_SIZE
+        }
+    }
+}
 
 impl Encoder<Frame> for FrameCodec {
     type Error = FrameError;
 
     fn encode(&mut self, item: Frame, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        dst.extend_from_slice(&item.serialize());
+        if item.payload.len() > self.max_payload() {
+            return Err(FrameError::PayloadTooLarge(item.payload.len()));
+        }
+        dst.reserve(EXTENDED_HEADER_SIZE + item.payload.len() + CHECKSUM_SIZE);
+        let start = dst.len();
+        item.encode_into(dst);
+        if self.checksum {
+            let crc = crc32c(&dst[start..]);
+            dst.put_u32(crc);
+        }
         Ok(())
     }
 }
@@ -167,37 +815,59 @@ impl Decoder for FrameCodec {
         }
 
         // Peek at header to get payload length
-        let frame_type = src[0];
-        let payload_len = u16::from_be_bytes([src[3], src[4]]) as usize;
+        let type_byte = src[0];
+        let extended = type_byte & EXTENDED_LENGTH_FLAG != 0;
+        let (header_len, payload_len) = if extended {
+            if !self.large_frames {
+                return Err(FrameError::UnexpectedExtended);
+            }
+            if src.len() < EXTENDED_HEADER_SIZE {
+                return Ok(None);
+            }
+            let len = u32::from_be_bytes([src[3], src[4], src[5], src[6]]);
+            (EXTENDED_HEADER_SIZE, len as usize)
+        } else {
+            let len = u16::from_be_bytes([src[3], src[4]]);
+            (FRAME_HEADER_SIZE, len as usize)
+        };
 
         // Validate frame type
-        let frame_type =
-            FrameType::from_u8(frame_type).ok_or(FrameError::InvalidType(frame_type))?;
+        let frame_type = FrameType::from_u8(type_byte & !(EXTENDED_LENGTH_FLAG | COMPRESSED_FLAG))
+            .ok_or(FrameError::InvalidType(type_byte))?;
 
         // Check payload size
-        if payload_len > MAX_PAYLOAD_SIZE {
+        if payload_len > self.max_payload() {
             return Err(FrameError::PayloadTooLarge(payload_len));
         }
 
         // Check if we have complete frame
-        let total_len = FRAME_HEADER_SIZE + payload_len;
-        if src.len() < total_len {
+        let total_len = header_len + payload_len;
+        let trailer_len = if self.checksum { CHECKSUM_SIZE } else { 0 };
+        if src.len() < total_len + trailer_len {
             // Reserve space for the full frame
-            src.reserve(total_len - src.len());
+            src.reserve(total_len + trailer_len - src.len());
             return Ok(None);
         }
+        if self.checksum {
+            let expected = u32::from_be_bytes(src[total_len..total_len + 4].try_into().unwrap());
+            if crc32c(&src[..total_len]) != expected {
+                return Err(FrameError::ChecksumMismatch);
+            }
+        }
 
         // Extract frame data
         let mut buf = src.split_to(total_len);
+        src.advance(trailer_len);
         buf.advance(1); // Skip type
         let channel_id = buf.get_u16();
-        buf.advance(2); // Skip length (we already know it)
+        buf.advance(header_len - 3); // Skip length (we already know it)
         let payload = buf.freeze();
 
         Ok(Some(Frame {
             frame_type,
             channel_id,
             payload,
+            compressed: type_byte & COMPRESSED_FLAG != 0,
         }))
     }
 }
@@ -211,7 +881,7 @@ mod tests {
         let frame = Frame::connect(42, "example.com", 443);
         let serialized = frame.serialize();
 
-        let mut codec = FrameCodec;
+        let mut codec = FrameCodec::new();
         let mut buf = BytesMut::from(&serialized[..]);
         let decoded = codec.decode(&mut buf).unwrap().unwrap();
 
@@ -222,9 +892,148 @@ mod tests {
         assert_eq!(port, 443);
     }
 
+    #[test]
+    fn test_connect_meta_roundtrip() {
+        let meta = ConnectMeta {
+            source: Some("192.168.1.20:51000".parse().unwrap()),
+            protocol: Some(ProtocolHint::Tls),
+            priority: Some(Priority::Bulk),
+        };
+        let frame = Frame::connect_with_meta(7, "example.com", 443, &meta);
+        let (host, port, decoded) = frame.parse_connect_with_meta().unwrap();
+
+        assert_eq!(host, "example.com");
+        assert_eq!(port, 443);
+        assert_eq!(decoded, meta);
+    }
+
+    #[test]
+    fn test_connect_meta_unknown_tag_ignored() {
+        let mut payload = BytesMut::new();
+        payload.put_u8(4);
+        payload.extend_from_slice(b"host");
+        payload.put_u16(80);
+        payload.put_u8(0x7f); // Unknown tag
+        payload.put_u16(3);
+        payload.extend_from_slice(b"xyz");
+        payload.put_u8(CONNECT_TAG_PROTOCOL);
+        payload.put_u16(1);
+        payload.put_u8(ProtocolHint::Http as u8);
+
+        let frame = Frame::new(FrameType::Connect, 1, payload.freeze());
+        let (host, port, meta) = frame.parse_connect_with_meta().unwrap();
+
+        assert_eq!(host, "host");
+        assert_eq!(port, 80);
+        assert_eq!(meta.source, None);
+        assert_eq!(meta.protocol, Some(ProtocolHint::Http));
+    }
+
+    #[test]
+    fn test_connect_fail_reason() {
+        let frame = Frame::connect_fail(3, ConnectError::Refused, "Connection refused");
+        assert_eq!(
+            frame.parse_connect_fail(),
+            Some((ConnectError::Refused, "Connection refused".to_string()))
+        );
+        let bare = Frame::connect_fail(3, ConnectError::HostNotFound, "");
+        assert_eq!(
+            bare.parse_connect_fail(),
+            Some((ConnectError::HostNotFound, String::new()))
+        );
+
+        // Reason only, as older servers send
+        let mut payload = BytesMut::new();
+        tlv::put_str(&mut payload, CONNECT_FAIL_TAG_REASON, "No route");
+        let reason_only = Frame::new(FrameType::ConnectFail, 3, payload.freeze());
+        assert_eq!(
+            reason_only.parse_connect_fail(),
+            Some((ConnectError::General, "No route".to_string()))
+        );
+
+        let legacy = Frame::new(FrameType::ConnectFail, 3, &b"timed out"[..]);
+        assert_eq!(
+            legacy.parse_connect_fail(),
+            Some((ConnectError::General, "timed out".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_resolve_roundtrip() {
+        let frame = Frame::resolve(3, "example.com", AddressFamily::Ipv6);
+        assert_eq!(
+            frame.parse_resolve(),
+            Some(("example.com".to_string(), AddressFamily::Ipv6))
+        );
+        assert!(
+            Frame::resolve(3, "", AddressFamily::Any)
+                .parse_resolve()
+                .is_none()
+        );
+
+        let addrs: Vec<IpAddr> = vec!["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()];
+        let result = Frame::resolve_result(3, Ok(&addrs));
+        assert_eq!(result.channel_id, 3);
+        assert_eq!(result.parse_resolve_result(), Some(Ok(addrs)));
+        let failed = Frame::resolve_result(3, Err("no such host"));
+        assert_eq!(
+            failed.parse_resolve_result(),
+            Some(Err("no such host".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_datagram_roundtrip() {
+        let frame = Frame::datagram(9, "1.1.1.1", 53, b"query");
+        assert_eq!(frame.channel_id, 9);
+        let (host, port, data) = frame.parse_datagram().unwrap();
+        assert_eq!(
+            (host.as_str(), port, &data[..]),
+            ("1.1.1.1", 53, &b"query"[..])
+        );
+
+        let empty = Frame::datagram(9, "example.com", 443, b"");
+        assert_eq!(empty.parse_datagram().unwrap().2.len(), 0);
+        let truncated = Frame::new(FrameType::Datagram, 9, &b"\x07example"[..]);
+        assert!(truncated.parse_datagram().is_none());
+    }
+
+    #[test]
+    fn test_window_update_roundtrip() {
+        let frame = Frame::window_update(9, 65536);
+        let mut buf = BytesMut::from(&frame.serialize()[..]);
+        let decoded = FrameCodec::new().decode(&mut buf).unwrap().unwrap();
+
+        assert_eq!(decoded.frame_type, FrameType::WindowUpdate);
+        assert_eq!(decoded.channel_id, 9);
+        assert_eq!(decoded.parse_window_update(), Some(65536));
+
+        let short = Frame::new(FrameType::WindowUpdate, 9, &b"\x00\x01"[..]);
+        assert_eq!(short.parse_window_update(), None);
+    }
+
+    #[test]
+    fn test_hello_announcement() {
+        let hello = Hello {
+            features: Features::ANNOUNCEMENT,
+            announcement: Some("Maintenance tonight".to_string()),
+            ..Hello::local()
+        };
+        let frame = Frame::hello(&hello);
+        assert_eq!(frame.parse_hello(), Some(hello));
+
+        // Unknown tags from later versions are skipped
+        let mut payload = BytesMut::from(&frame.payload[..]);
+        tlv::put_str(&mut payload, 0x7f, "future");
+        let frame = Frame::new(FrameType::Hello, 0, payload.freeze());
+        let parsed = frame.parse_hello().unwrap();
+        assert_eq!(parsed.announcement.as_deref(), Some("Maintenance tonight"));
+        assert_eq!(parsed.key_exchange, None);
+    }
+
     #[test]
     fn test_frame_codec_partial() {
-        let mut codec = FrameCodec;
+        let mut codec = FrameCodec::new();
         let mut buf = BytesMut::from(&[0x01, 0x00, 0x01, 0x00, 0x05][..]); // Incomplete
 
         assert!(codec.decode(&mut buf).unwrap().is_none());
@@ -237,4 +1046,76 @@ mod tests {
         assert_eq!(decoded.channel_id, 1);
         assert_eq!(&decoded.payload[..], b"hello");
     }
+
+    #[test]
+    fn test_frame_compressed_flag() {
+        let mut frame = Frame::data(2, &b"zz"[..]);
+        frame.compressed = true;
+        let mut buf = BytesMut::from(&frame.serialize()[..]);
+        assert_eq!(buf[0], FrameType::Data as u8 | COMPRESSED_FLAG);
+
+        let decoded = FrameCodec::new().decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.frame_type, FrameType::Data);
+        assert!(decoded.compressed);
+    }
+
+    #[test]
+    fn test_frame_codec_extended() {
+        let frame = Frame::data(3, vec![9u8; MAX_PAYLOAD_SIZE + 1]);
+        let mut buf = BytesMut::new();
+        assert!(matches!(
+            FrameCodec::new().encode(frame.clone(), &mut buf),
+            Err(FrameError::PayloadTooLarge(_))
+        ));
+
+        let mut codec = FrameCodec::large();
+        codec.encode(frame, &mut buf).unwrap();
+        assert_eq!(buf[0], FrameType::Data as u8 | EXTENDED_LENGTH_FLAG);
+        assert_eq!(buf.len(), EXTENDED_HEADER_SIZE + MAX_PAYLOAD_SIZE + 1);
+        // Small frames keep the standard header
+        codec.encode(Frame::keepalive(), &mut buf).unwrap();
+
+        let mut standard = buf.clone();
+        assert!(matches!(
+            FrameCodec::new().decode(&mut standard),
+            Err(FrameError::UnexpectedExtended)
+        ));
+
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.channel_id, 3);
+        assert_eq!(decoded.payload.len(), MAX_PAYLOAD_SIZE + 1);
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.frame_type, FrameType::Keepalive);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_frame_codec_checksum() {
+        let mut codec = FrameCodec::new().with_checksum();
+        let mut buf = BytesMut::new();
+        codec
+            .encode(Frame::data(5, b"payload".to_vec()), &mut buf)
+            .unwrap();
+        codec.encode(Frame::keepalive(), &mut buf).unwrap();
+        assert_eq!(buf.len(), 2 * (FRAME_HEADER_SIZE + CHECKSUM_SIZE) + 7);
+
+        // The trailer isn't read until it has all arrived
+        let mut partial = buf.split_to(FRAME_HEADER_SIZE + 7 + 2);
+        assert!(codec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(buf);
+        let mut buf = partial;
+        let mut corrupted = buf.clone();
+
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(&decoded.payload[..], b"payload");
+        let decoded = codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(decoded.frame_type, FrameType::Keepalive);
+        assert!(buf.is_empty());
+
+        corrupted[FRAME_HEADER_SIZE] ^= 1;
+        assert!(matches!(
+            codec.decode(&mut corrupted),
+            Err(FrameError::ChecksumMismatch)
+        ));
+    }
 }
@@ -0,0 +1,379 @@
+//! Protocol version negotiation
+//!
+//! Right after BINARY, before any session frames, the client sends a HELLO
+//! frame with its protocol version and the optional features it supports.
+//! The server answers with the version both will speak and the features
+//! both enabled. A peer too old or too new to talk to gets a HELLO with the
+//! server's own version and the connection is closed, so each side can say
+//! why instead of failing on the first unfamiliar frame.
+//!
+//! A side offering [`Features::ENCRYPTION`] appends its key exchange
+//! message to its HELLO; see [`seal`](super::seal). The session keys cover
+//! the features offered and agreed (see [`Features::transcript`]), so a
+//! middlebox that strips a feature leaves the two sides unable to read
+//! each other's frames. A server may also
+//! send a short operator announcement to clients offering
+//! [`Features::ANNOUNCEMENT`].
+
+use super::frames::{Frame, FrameCodec, FrameType, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION};
+use crate::crypto::KEX_MESSAGE_LEN;
+use anyhow::{Context, bail};
+use bytes::BytesMut;
+use std::fmt;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::Decoder;
+
+/// How long either side waits for the other's HELLO
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Optional protocol features, advertised in HELLO
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Features(u32);
+
+impl Features {
+    pub const NONE: Self = Self(0);
+    /// Compressed DATA payloads
+    pub const COMPRESSION: Self = Self(1 << 0);
+    /// Frames with payloads over 64 KiB
+    pub const LARGE_FRAMES: Self = Self(1 << 1);
+    /// UDP relaying
+    pub const UDP: Self = Self(1 << 2);
+    /// PADDING frames
+    pub const PADDING: Self = Self(1 << 3);
+    /// RESOLVE / RESOLVE_RESULT frames
+    pub const RESOLVE: Self = Self(1 << 4);
+    /// CRC32C trailer on every frame, offered by clients that want it
+    pub const CHECKSUM: Self = Self(1 << 5);
+    /// Payloads sealed under keys exchanged in HELLO, offered by clients
+    /// that want it
+    pub const ENCRYPTION: Self = Self(1 << 6);
+    /// Operator announcement in the server's HELLO
+    pub const ANNOUNCEMENT: Self = Self(1 << 7);
+    /// Per-channel sequence numbers on DATA, offered by clients that want
+    /// them
+    pub const SEQUENCE: Self = Self(1 << 8);
+
+    /// Features this build implements. Minimal builds keep frames small
+    /// to bound per-frame buffering.
+    pub const SUPPORTED: Self = Self(
+        if cfg!(feature = "minimal") {
+            0
+        } else {
+            Self::LARGE_FRAMES.0
+        } | if cfg!(feature = "compression") {
+            Self::COMPRESSION.0
+        } else {
+            0
+        } | Self::PADDING.0
+            | Self::RESOLVE.0
+            | Self::UDP.0
+            | Self::CHECKSUM.0
+            | Self::ENCRYPTION.0
+            | Self::ANNOUNCEMENT.0
+            | Self::SEQUENCE.0,
+    );
+
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Features in both sets
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    /// These features minus `other`
+    pub fn without(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    /// What the key exchange binds: the client's offer, then what was
+    /// agreed
+    pub fn transcript(offered: Self, agreed: Self) -> [u8; 8] {
+        let mut transcript = [0u8; 8];
+        transcript[..4].copy_from_slice(&offered.0.to_be_bytes());
+        transcript[4..].copy_from_slice(&agreed.0.to_be_bytes());
+        transcript
+    }
+}
+
+impl std::ops::BitOr for Features {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [
+            (Self::COMPRESSION, "compression"),
+            (Self::LARGE_FRAMES, "large-frames"),
+            (Self::UDP, "udp"),
+            (Self::PADDING, "padding"),
+            (Self::RESOLVE, "resolve"),
+            (Self::CHECKSUM, "checksum"),
+            (Self::ENCRYPTION, "encryption"),
+            (Self::ANNOUNCEMENT, "announcement"),
+            (Self::SEQUENCE, "sequence"),
+        ];
+        let enabled: Vec<&str> = names
+            .iter()
+            .filter(|(feature, _)| self.contains(*feature))
+            .map(|(_, name)| *name)
+            .collect();
+        if enabled.is_empty() {
+            f.write_str("none")
+        } else {
+            f.write_str(&enabled.join(","))
+        }
+    }
+}
+
+/// Contents of a HELLO frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u8,
+    pub features: Features,
+    /// Sender's key exchange message, with [`Features::ENCRYPTION`]
+    pub key_exchange: Option<[u8; KEX_MESSAGE_LEN]>,
+    /// Server's operator announcement, with [`Features::ANNOUNCEMENT`]
+    pub announcement: Option<String>,
+}
+
+impl Hello {
+    /// What this build offers
+    pub fn local() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            features: Features::SUPPORTED,
+            key_exchange: None,
+            announcement: None,
+        }
+    }
+}
+
+/// Send our HELLO and read the server's answer, returning what was agreed.
+/// `buf` holds bytes already read past the BINARY reply and keeps any
+/// read past the HELLO. `offer` is [`Hello::local`] less any features the
+/// client doesn't want; the result carries the server's key exchange and
+/// announcement.
+pub async fn hello_client<S>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+    offer: Hello,
+) -> anyhow::Result<Hello>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    send(stream, &offer).await?;
+    let answer = receive(stream, buf)
+        .await?
+        .context("Server closed the connection instead of answering HELLO; it may be too old")?;
+
+    if answer.version > offer.version {
+        bail!(
+            "Server requires protocol version {} or newer; this client speaks {}",
+            answer.version,
+            offer.version
+        );
+    }
+    if answer.version < MIN_PROTOCOL_VERSION {
+        bail!(
+            "Server speaks protocol version {}; this client needs at least {}",
+            answer.version,
+            MIN_PROTOCOL_VERSION
+        );
+    }
+    let agreed = Hello {
+        version: answer.version,
+        features: answer.features.intersection(offer.features),
+        key_exchange: answer.key_exchange,
+        announcement: answer.announcement,
+    };
+    check_key_exchange(&agreed)?;
+    Ok(agreed)
+}
+
+/// Read the client's HELLO and answer it, returning what was agreed along
+/// with the client's key exchange, and the features the client offered.
+/// An incompatible client is told our
+/// version before the error is returned. `key_exchange` is our message,
+/// sent if the client asks for encryption, and `announcement` is sent to
+/// clients that can show it.
+pub async fn hello_server<S>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+    key_exchange: Option<[u8; KEX_MESSAGE_LEN]>,
+    announcement: Option<String>,
+) -> anyhow::Result<(Hello, Features)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut local = Hello::local();
+    if key_exchange.is_none() {
+        local.features = local.features.without(Features::ENCRYPTION);
+    }
+    let offer = match receive(stream, buf).await? {
+        Some(offer) => offer,
+        None => bail!("Client closed the connection before HELLO"),
+    };
+    if offer.version < MIN_PROTOCOL_VERSION {
+        send(stream, &local).await?;
+        bail!(
+            "Client speaks protocol version {}; need at least {}",
+            offer.version,
+            MIN_PROTOCOL_VERSION
+        );
+    }
+
+    let features = offer.features.intersection(local.features);
+    let answer = Hello {
+        version: offer.version.min(local.version),
+        features,
+        key_exchange: key_exchange.filter(|_| features.contains(Features::ENCRYPTION)),
+        announcement: announcement.filter(|_| features.contains(Features::ANNOUNCEMENT)),
+    };
+    send(stream, &answer).await?;
+    let agreed = Hello {
+        key_exchange: offer.key_exchange,
+        announcement: None,
+        ..answer
+    };
+    check_key_exchange(&agreed)?;
+    Ok((agreed, offer.features))
+}
+
+/// Encryption needs the peer's key exchange message
+fn check_key_exchange(agreed: &Hello) -> anyhow::Result<()> {
+    if agreed.features.contains(Features::ENCRYPTION) && agreed.key_exchange.is_none() {
+        bail!("Encryption agreed without a key exchange message");
+    }
+    Ok(())
+}
+
+async fn send<S>(stream: &mut S, hello: &Hello) -> anyhow::Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream.write_all(&Frame::hello(hello).serialize()).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// The peer's HELLO, or None if it hung up first
+async fn receive<S>(stream: &mut S, buf: &mut BytesMut) -> anyhow::Result<Option<Hello>>
+where
+    S: AsyncRead + Unpin,
+{
+    let frame = tokio::time::timeout(HELLO_TIMEOUT, async {
+        loop {
+            if let Some(frame) = FrameCodec::new().decode(buf)? {
+                return anyhow::Ok(Some(frame));
+            }
+            if stream.read_buf(buf).await? == 0 {
+                return Ok(None);
+            }
+        }
+    })
+    .await
+    .context("Timed out waiting for HELLO")??;
+
+    let Some(frame) = frame else {
+        return Ok(None);
+    };
+    if frame.frame_type != FrameType::Hello {
+        // Peers from before version negotiation go straight to session frames
+        bail!(
+            "Expected HELLO, got {:?}; the peer predates protocol version {}",
+            frame.frame_type,
+            MIN_PROTOCOL_VERSION
+        );
+    }
+    frame
+        .parse_hello()
+        .map(Some)
+        .context("Malformed HELLO frame")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hello_agrees_on_version() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut buf = BytesMut::new();
+            hello_server(
+                &mut server,
+                &mut buf,
+                Some([1; KEX_MESSAGE_LEN]),
+                Some("Maintenance at 02:00 UTC".to_string()),
+            )
+            .await
+            .unwrap()
+        });
+
+        let offer = Hello {
+            key_exchange: Some([2; KEX_MESSAGE_LEN]),
+            ..Hello::local()
+        };
+        let agreed = hello_client(&mut client, &mut BytesMut::new(), offer.clone())
+            .await
+            .unwrap();
+        // Each side ends up with the other's key exchange
+        assert_eq!(agreed.features, Features::SUPPORTED);
+        assert_eq!(agreed.key_exchange, Some([1; KEX_MESSAGE_LEN]));
+        assert_eq!(
+            agreed.announcement.as_deref(),
+            Some("Maintenance at 02:00 UTC")
+        );
+        assert_eq!(server.await.unwrap(), (offer.clone(), offer.features));
+    }
+
+    #[tokio::test]
+    async fn test_hello_rejects_old_client() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move {
+            let mut buf = BytesMut::new();
+            hello_server(&mut server, &mut buf, None, None).await
+        });
+
+        let old = Hello {
+            version: MIN_PROTOCOL_VERSION - 1,
+            features: Features::NONE,
+            key_exchange: None,
+            announcement: None,
+        };
+        send(&mut client, &old).await.unwrap();
+        let err = server.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("need at least"));
+        // The client still learns which version the server wants
+        let answer = receive(&mut client, &mut BytesMut::new()).await.unwrap();
+        assert_eq!(answer.unwrap().version, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn test_features() {
+        let both = Features::COMPRESSION | Features::UDP;
+        assert!(both.contains(Features::UDP));
+        assert!(!both.contains(Features::LARGE_FRAMES));
+        assert_eq!(
+            both.intersection(Features::UDP | Features::LARGE_FRAMES),
+            Features::UDP
+        );
+        assert_eq!(both.to_string(), "compression,udp");
+        assert_eq!(Features::NONE.to_string(), "none");
+    }
+}
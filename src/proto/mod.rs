@@ -1,5 +1,16 @@
+pub mod checksum;
+pub mod chunking;
+pub mod compress;
+pub mod flow;
 pub mod frames;
+pub mod hello;
+pub mod padding;
+pub mod profile;
+pub mod schedule;
+pub mod seal;
+pub mod sequence;
 pub mod smtp;
+pub mod tlv;
 
 pub use frames::*;
 pub use smtp::*;
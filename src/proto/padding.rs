@@ -0,0 +1,105 @@
+//! Length obfuscation
+//!
+//! Without padding, DATA frame sizes follow the application's writes, which
+//! is enough to fingerprint e.g. a TLS handshake inside the tunnel. When both
+//! sides offer [`Features::PADDING`](super::hello::Features) in HELLO, a side
+//! with padding configured follows each DATA frame with a PADDING frame that
+//! rounds their combined size up to a bucket, and sends dummy PADDING frames
+//! at random intervals. Padding frames are dropped on arrival and are not
+//! session frames, so they are never acknowledged or replayed.
+
+use super::frames::{Frame, FrameType};
+use crate::config::PaddingConfig;
+use bytes::Bytes;
+use rand::Rng;
+use std::time::Duration;
+
+/// Smallest bucket, comfortably above a padding frame's header
+pub const MIN_BUCKET_SIZE: usize = 64;
+
+/// Largest bucket; keeps every padding frame a slice of [`ZEROS`]
+pub const MAX_BUCKET_SIZE: usize = 16 * 1024;
+
+static ZEROS: [u8; MAX_BUCKET_SIZE] = [0; MAX_BUCKET_SIZE];
+
+/// How this side pads the frames it sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Padding {
+    /// DATA frames plus their padding take a multiple of this many bytes
+    pub bucket_size: usize,
+    /// Mean time between dummy frames; None sends none
+    pub dummy_interval: Option<Duration>,
+    /// Largest dummy frame payload
+    pub dummy_max_size: usize,
+}
+
+impl Padding {
+    pub fn from_config(config: &PaddingConfig) -> Self {
+        Self {
+            bucket_size: config.bucket_size,
+            dummy_interval: (config.dummy_interval_ms > 0)
+                .then(|| Duration::from_millis(config.dummy_interval_ms)),
+            dummy_max_size: config.dummy_max_size,
+        }
+    }
+
+    /// Padding to send after `frame`, if it's DATA and not already a
+    /// multiple of the bucket size on the wire
+    pub fn pad(&self, frame: &Frame) -> Option<Frame> {
+        let bucket = self.bucket_size.clamp(MIN_BUCKET_SIZE, MAX_BUCKET_SIZE);
+        if frame.frame_type != FrameType::Data {
+            return None;
+        }
+        let short = match frame.encoded_len() % bucket {
+            0 => return None,
+            rest => bucket - rest,
+        };
+        // The padding frame's own header counts towards the bucket
+        let header = Frame::padding(Bytes::new()).encoded_len();
+        let len = if short >= header {
+            short - header
+        } else {
+            short + bucket - header
+        };
+        Some(Frame::padding(Bytes::from_static(
+            &ZEROS[..len.min(MAX_BUCKET_SIZE)],
+        )))
+    }
+
+    /// Random delay until the next dummy frame, averaging `dummy_interval`
+    pub fn next_dummy(&self) -> Option<Duration> {
+        let interval = self.dummy_interval?;
+        Some(interval.mul_f64(rand::thread_rng().gen_range(0.0..2.0)))
+    }
+
+    /// A dummy frame of random size
+    pub fn dummy(&self) -> Frame {
+        let max = self.dummy_max_size.min(MAX_BUCKET_SIZE);
+        let len = rand::thread_rng().gen_range(0..=max);
+        Frame::padding(Bytes::from_static(&ZEROS[..len]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pad_to_bucket() {
+        let padding = Padding {
+            bucket_size: 512,
+            dummy_interval: None,
+            dummy_max_size: 0,
+        };
+        for len in [0, 1, 100, 502, 506, 507, 512, 3000] {
+            let data = Frame::data(1, vec![7u8; len]);
+            let total = data.encoded_len()
+                + padding
+                    .pad(&data)
+                    .map_or(0, |padding| padding.encoded_len());
+            assert_eq!(total % 512, 0, "payload of {len}");
+        }
+        assert!(padding.pad(&Frame::keepalive()).is_none());
+        assert!(padding.next_dummy().is_none());
+    }
+}
@@ -0,0 +1,591 @@
+//! What the server says in SMTP, as the MTA it imitates would say it
+//!
+//! Every reply outside the tunnel's own protocol comes from a [`Profile`]:
+//! one table per mail server, so the greeting, EHLO and error texts all
+//! agree on which software is answering. The tests compare each profile
+//! with a reference transcript of the real server, so an edit that drifts
+//! from it (an extension it wouldn't offer, STARTTLS advertised once TLS
+//! is already up) fails there rather than in front of a prober.
+//!
+//! Texts may contain `{host}` (our hostname), `{helo}` and `{ip}` (the
+//! client's EHLO name and address), `{address}`, `{id}` and `{date}`.
+//!
+//! An operator can [customize](Profile::customized) a profile with extra
+//! EHLO extensions and reworded replies, so that not every installation
+//! answers with exactly the same text.
+
+use super::smtp::{Response, ResponseCode};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc2822;
+
+/// Longest extension or reply text an operator may configure, leaving
+/// room for the code in a 512-byte SMTP line
+const MAX_CUSTOM_TEXT: usize = 500;
+
+/// A reply code and its text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reply(pub ResponseCode, pub &'static str);
+
+/// A reply an operator can reword, as named in the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplyName {
+    Greeting,
+    /// The first line of the EHLO reply
+    Ehlo,
+    Starttls,
+    AuthSuccess,
+    AuthFailed,
+    SenderOk,
+    RecipientOk,
+    RecipientUnknown,
+    TooManyRecipients,
+    NoValidRecipients,
+    StartMailInput,
+    Queued,
+    MessageTooLarge,
+    LocalError,
+    Goodbye,
+    SyntaxError,
+    CommandUnrecognized,
+    BadSequence,
+    TooManyErrors,
+    ServiceUnavailable,
+}
+
+/// The replies of one mail server
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub name: &'static str,
+    greeting: Reply,
+    /// First EHLO line
+    ehlo_hello: &'static str,
+    /// EHLO extensions before STARTTLS
+    ehlo_plain: &'static [&'static str],
+    /// EHLO extensions once TLS is active
+    ehlo_tls: &'static [&'static str],
+    starttls: Reply,
+    auth_success: Reply,
+    auth_failed: Reply,
+    sender_ok: Reply,
+    recipient_ok: Reply,
+    recipient_unknown: Reply,
+    too_many_recipients: Reply,
+    no_valid_recipients: Reply,
+    start_mail_input: Reply,
+    queued: Reply,
+    message_too_large: Reply,
+    local_error: Reply,
+    goodbye: Reply,
+    syntax_error: Reply,
+    command_unrecognized: Reply,
+    bad_sequence: Reply,
+    too_many_errors: Reply,
+    service_unavailable: Reply,
+    /// A new queue ID in the server's format
+    queue_id: fn() -> String,
+    /// The operator's EHLO extensions, after the profile's own
+    extensions: Vec<String>,
+    /// The operator's texts, replacing the profile's
+    custom: Vec<(ReplyName, String)>,
+}
+
+/// Postfix 3.6 as packaged by Ubuntu, on a submission port with
+/// `smtpd_tls_auth_only`
+pub static POSTFIX: Profile = Profile {
+    name: "Postfix",
+    greeting: Reply(ResponseCode::READY, "{host} ESMTP Postfix (Ubuntu)"),
+    ehlo_hello: "{host}",
+    ehlo_plain: &[
+        "PIPELINING",
+        "SIZE 10240000",
+        "VRFY",
+        "ETRN",
+        "STARTTLS",
+        "ENHANCEDSTATUSCODES",
+        "8BITMIME",
+        "DSN",
+        "SMTPUTF8",
+        "CHUNKING",
+    ],
+    ehlo_tls: &[
+        "PIPELINING",
+        "SIZE 10240000",
+        "VRFY",
+        "ETRN",
+        "AUTH PLAIN LOGIN",
+        "ENHANCEDSTATUSCODES",
+        "8BITMIME",
+        "DSN",
+        "SMTPUTF8",
+        "CHUNKING",
+    ],
+    starttls: Reply(ResponseCode::READY, "2.0.0 Ready to start TLS"),
+    auth_success: Reply(
+        ResponseCode::AUTH_SUCCESS,
+        "2.7.0 Authentication successful",
+    ),
+    auth_failed: Reply(
+        ResponseCode::AUTH_FAILED,
+        "5.7.8 Error: authentication failed: authentication failure",
+    ),
+    sender_ok: Reply(ResponseCode::OK, "2.1.0 Ok"),
+    recipient_ok: Reply(ResponseCode::OK, "2.1.5 Ok"),
+    recipient_unknown: Reply(
+        ResponseCode::MAILBOX_UNAVAILABLE,
+        "5.1.1 <{address}>: Recipient address rejected: User unknown in local recipient table",
+    ),
+    too_many_recipients: Reply(
+        ResponseCode::INSUFFICIENT_STORAGE,
+        "4.5.3 Error: too many recipients",
+    ),
+    no_valid_recipients: Reply(
+        ResponseCode::TRANSACTION_FAILED,
+        "5.5.1 Error: no valid recipients",
+    ),
+    start_mail_input: Reply(ResponseCode::START_INPUT, "End data with <CR><LF>.<CR><LF>"),
+    queued: Reply(ResponseCode::OK, "2.0.0 Ok: queued as {id}"),
+    message_too_large: Reply(
+        ResponseCode::EXCEEDED_STORAGE,
+        "5.3.4 Message size exceeds fixed limit",
+    ),
+    local_error: Reply(
+        ResponseCode::LOCAL_ERROR,
+        "4.3.0 Error: queue file write error",
+    ),
+    goodbye: Reply(ResponseCode::CLOSING, "2.0.0 Bye"),
+    syntax_error: Reply(ResponseCode::SYNTAX_ERROR_PARAMS, "5.5.4 Syntax error"),
+    command_unrecognized: Reply(
+        ResponseCode::COMMAND_UNRECOGNIZED,
+        "5.5.2 Error: command not recognized",
+    ),
+    bad_sequence: Reply(ResponseCode::BAD_SEQUENCE, "5.5.1 Error: need MAIL command"),
+    too_many_errors: Reply(
+        ResponseCode::TEMP_FAIL,
+        "4.7.0 {host} Error: too many errors",
+    ),
+    service_unavailable: Reply(
+        ResponseCode::TEMP_FAIL,
+        "4.3.2 {host} Service not available, closing transmission channel",
+    ),
+    queue_id: postfix_queue_id,
+    extensions: Vec::new(),
+    custom: Vec::new(),
+};
+
+/// Exim 4.96 with Debian's default configuration plus AUTH over TLS
+pub static EXIM: Profile = Profile {
+    name: "Exim",
+    greeting: Reply(ResponseCode::READY, "{host} ESMTP Exim 4.96 {date}"),
+    ehlo_hello: "{host} Hello {helo} [{ip}]",
+    ehlo_plain: &[
+        "SIZE 52428800",
+        "8BITMIME",
+        "PIPELINING",
+        "CHUNKING",
+        "STARTTLS",
+        "PRDR",
+        "HELP",
+    ],
+    ehlo_tls: &[
+        "SIZE 52428800",
+        "8BITMIME",
+        "PIPELINING",
+        "AUTH PLAIN LOGIN",
+        "CHUNKING",
+        "PRDR",
+        "HELP",
+    ],
+    starttls: Reply(ResponseCode::READY, "TLS go ahead"),
+    auth_success: Reply(ResponseCode::AUTH_SUCCESS, "Authentication succeeded"),
+    auth_failed: Reply(ResponseCode::AUTH_FAILED, "Incorrect authentication data"),
+    sender_ok: Reply(ResponseCode::OK, "OK"),
+    recipient_ok: Reply(ResponseCode::OK, "Accepted"),
+    recipient_unknown: Reply(ResponseCode::MAILBOX_UNAVAILABLE, "Unrouteable address"),
+    too_many_recipients: Reply(ResponseCode::INSUFFICIENT_STORAGE, "too many recipients"),
+    no_valid_recipients: Reply(
+        ResponseCode::BAD_SEQUENCE,
+        "Valid RCPT command must precede DATA",
+    ),
+    start_mail_input: Reply(
+        ResponseCode::START_INPUT,
+        "Enter message, ending with \".\" on a line by itself",
+    ),
+    queued: Reply(ResponseCode::OK, "OK id={id}"),
+    message_too_large: Reply(
+        ResponseCode::EXCEEDED_STORAGE,
+        "Message size exceeds maximum permitted",
+    ),
+    local_error: Reply(
+        ResponseCode::LOCAL_ERROR,
+        "Temporary local problem - please try later",
+    ),
+    goodbye: Reply(ResponseCode::CLOSING, "{host} closing connection"),
+    syntax_error: Reply(ResponseCode::SYNTAX_ERROR_PARAMS, "Syntax error"),
+    command_unrecognized: Reply(ResponseCode::SYNTAX_ERROR, "unrecognized command"),
+    bad_sequence: Reply(ResponseCode::BAD_SEQUENCE, "sender not yet given"),
+    too_many_errors: Reply(
+        ResponseCode::TEMP_FAIL,
+        "{host}: too many unrecognized commands",
+    ),
+    service_unavailable: Reply(
+        ResponseCode::TEMP_FAIL,
+        "{host} Too many concurrent SMTP connections; please try again later.",
+    ),
+    queue_id: exim_queue_id,
+    extensions: Vec::new(),
+    custom: Vec::new(),
+};
+
+impl Profile {
+    /// This profile with the operator's `extensions` added to EHLO and
+    /// `replies` in place of its own texts. Extensions may not be ones the
+    /// profile or the tunnel already offers, and texts must each fit on one
+    /// line.
+    pub fn customized(
+        &self,
+        extensions: &[String],
+        replies: &HashMap<ReplyName, String>,
+    ) -> anyhow::Result<Self> {
+        let mut offered: Vec<&str> = self
+            .ehlo_plain
+            .iter()
+            .chain(self.ehlo_tls)
+            .filter_map(|line| line.split(' ').next())
+            .collect();
+        for extension in extensions {
+            check_text(extension).map_err(|e| anyhow::anyhow!("EHLO extension: {e}"))?;
+            let keyword = extension.split(' ').next().unwrap_or_default();
+            if keyword.is_empty()
+                || !keyword
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+            {
+                anyhow::bail!("EHLO extension {extension:?} doesn't start with a keyword");
+            }
+            if offered
+                .iter()
+                .any(|offered| offered.eq_ignore_ascii_case(keyword))
+            {
+                anyhow::bail!("EHLO extension {keyword} is already offered");
+            }
+            offered.push(keyword);
+        }
+        for (name, text) in replies {
+            check_text(text).map_err(|e| anyhow::anyhow!("Reply {name:?}: {e}"))?;
+        }
+        Ok(Self {
+            extensions: extensions.to_vec(),
+            custom: replies
+                .iter()
+                .map(|(name, text)| (*name, text.clone()))
+                .collect(),
+            ..self.clone()
+        })
+    }
+
+    /// The code and text of reply `name`, as customized
+    fn reply(&self, name: ReplyName) -> (ResponseCode, &str) {
+        let Reply(code, text) = match name {
+            ReplyName::Greeting => self.greeting,
+            ReplyName::Ehlo => Reply(ResponseCode::OK, self.ehlo_hello),
+            ReplyName::Starttls => self.starttls,
+            ReplyName::AuthSuccess => self.auth_success,
+            ReplyName::AuthFailed => self.auth_failed,
+            ReplyName::SenderOk => self.sender_ok,
+            ReplyName::RecipientOk => self.recipient_ok,
+            ReplyName::RecipientUnknown => self.recipient_unknown,
+            ReplyName::TooManyRecipients => self.too_many_recipients,
+            ReplyName::NoValidRecipients => self.no_valid_recipients,
+            ReplyName::StartMailInput => self.start_mail_input,
+            ReplyName::Queued => self.queued,
+            ReplyName::MessageTooLarge => self.message_too_large,
+            ReplyName::LocalError => self.local_error,
+            ReplyName::Goodbye => self.goodbye,
+            ReplyName::SyntaxError => self.syntax_error,
+            ReplyName::CommandUnrecognized => self.command_unrecognized,
+            ReplyName::BadSequence => self.bad_sequence,
+            ReplyName::TooManyErrors => self.too_many_errors,
+            ReplyName::ServiceUnavailable => self.service_unavailable,
+        };
+        let text = self
+            .custom
+            .iter()
+            .find(|(custom, _)| *custom == name)
+            .map_or(text, |(_, text)| text.as_str());
+        (code, text)
+    }
+
+    fn render(&self, name: ReplyName, vars: &[(&str, &str)]) -> String {
+        let (code, text) = self.reply(name);
+        Response::simple(code, &fill(text, vars))
+    }
+
+    /// Greeting
+    pub fn greeting(&self, host: &str) -> String {
+        self.greeting_at(host, OffsetDateTime::now_utc())
+    }
+
+    fn greeting_at(&self, host: &str, now: OffsetDateTime) -> String {
+        let date = now.format(&Rfc2822).unwrap_or_default();
+        self.render(ReplyName::Greeting, &[("host", host), ("date", &date)])
+    }
+
+    /// EHLO reply to `helo` from `ip`; AUTH is only offered once TLS is
+    /// active, and STARTTLS only before
+    pub fn ehlo(&self, host: &str, helo: &str, ip: IpAddr, tls: bool) -> String {
+        let ip = ip.to_canonical().to_string();
+        let (_, hello) = self.reply(ReplyName::Ehlo);
+        let hello = fill(hello, &[("host", host), ("helo", helo), ("ip", &ip)]);
+        let mut lines = vec![hello.as_str()];
+        lines.extend(if tls { self.ehlo_tls } else { self.ehlo_plain });
+        lines.extend(self.extensions.iter().map(String::as_str));
+        Response::multi_line(ResponseCode::OK, &lines)
+    }
+
+    /// Go ahead with the TLS handshake
+    pub fn starttls(&self) -> String {
+        self.render(ReplyName::Starttls, &[])
+    }
+
+    pub fn auth_success(&self) -> String {
+        self.render(ReplyName::AuthSuccess, &[])
+    }
+
+    /// Auth success, with a session affinity token for reconnects
+    pub fn auth_success_affinity(&self, token: &str) -> String {
+        let (code, text) = self.reply(ReplyName::AuthSuccess);
+        Response::simple(code, &format!("{text} affinity={token}"))
+    }
+
+    pub fn auth_failed(&self) -> String {
+        self.render(ReplyName::AuthFailed, &[])
+    }
+
+    /// MAIL accepted
+    pub fn sender_ok(&self) -> String {
+        self.render(ReplyName::SenderOk, &[])
+    }
+
+    /// RCPT accepted
+    pub fn recipient_ok(&self) -> String {
+        self.render(ReplyName::RecipientOk, &[])
+    }
+
+    /// Mail for a recipient we don't take
+    pub fn recipient_unknown(&self, address: &str) -> String {
+        self.render(ReplyName::RecipientUnknown, &[("address", address)])
+    }
+
+    /// Recipient limit reached for this message
+    pub fn too_many_recipients(&self) -> String {
+        self.render(ReplyName::TooManyRecipients, &[])
+    }
+
+    /// DATA without any accepted recipient
+    pub fn no_valid_recipients(&self) -> String {
+        self.render(ReplyName::NoValidRecipients, &[])
+    }
+
+    /// Go ahead with the message after DATA
+    pub fn start_mail_input(&self) -> String {
+        self.render(ReplyName::StartMailInput, &[])
+    }
+
+    /// Message accepted for delivery under a new queue ID
+    pub fn queued(&self) -> String {
+        self.queued_as(&(self.queue_id)())
+    }
+
+    fn queued_as(&self, id: &str) -> String {
+        self.render(ReplyName::Queued, &[("id", id)])
+    }
+
+    /// Message over the size limit
+    pub fn message_too_large(&self) -> String {
+        self.render(ReplyName::MessageTooLarge, &[])
+    }
+
+    /// Delivery failed on our side; the sender should retry
+    pub fn local_error(&self) -> String {
+        self.render(ReplyName::LocalError, &[])
+    }
+
+    /// Reply to QUIT
+    pub fn goodbye(&self, host: &str) -> String {
+        self.render(ReplyName::Goodbye, &[("host", host)])
+    }
+
+    /// Arguments that don't parse
+    pub fn syntax_error(&self) -> String {
+        self.render(ReplyName::SyntaxError, &[])
+    }
+
+    pub fn command_unrecognized(&self) -> String {
+        self.render(ReplyName::CommandUnrecognized, &[])
+    }
+
+    /// Command out of order
+    pub fn bad_sequence(&self) -> String {
+        self.render(ReplyName::BadSequence, &[])
+    }
+
+    /// Too many errors (connection will be closed)
+    pub fn too_many_errors(&self, host: &str) -> String {
+        self.render(ReplyName::TooManyErrors, &[("host", host)])
+    }
+
+    /// Server overloaded (connection will be closed)
+    pub fn service_unavailable(&self, host: &str) -> String {
+        self.render(ReplyName::ServiceUnavailable, &[("host", host)])
+    }
+}
+
+/// An operator's text: one line that fits in an SMTP reply
+fn check_text(text: &str) -> anyhow::Result<()> {
+    if text.is_empty() || text.len() > MAX_CUSTOM_TEXT {
+        anyhow::bail!("needs 1 to {MAX_CUSTOM_TEXT} characters");
+    }
+    if !text.bytes().all(|b| (0x20..0x7f).contains(&b)) {
+        anyhow::bail!("{text:?} isn't printable ASCII on one line");
+    }
+    Ok(())
+}
+
+/// `text` with each `{name}` in `vars` replaced
+fn fill(text: &str, vars: &[(&str, &str)]) -> String {
+    vars.iter().fold(text.to_string(), |text, (name, value)| {
+        text.replace(&format!("{{{name}}}"), value)
+    })
+}
+
+/// Ten hex digits, as Postfix's short queue IDs
+fn postfix_queue_id() -> String {
+    hex::encode_upper(rand::random::<[u8; 5]>())
+}
+
+/// Three base-62 groups, as Exim's message IDs before 4.97
+fn exim_queue_id() -> String {
+    const DIGITS: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+    let mut rng = rand::thread_rng();
+    let mut group = |len: usize| -> String {
+        (0..len)
+            .map(|_| DIGITS[rng.gen_range(0..DIGITS.len())] as char)
+            .collect()
+    };
+    format!("{}-{}-{}", group(6), group(6), group(2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOST: &str = "mail.example.com";
+    const HELO: &str = "client.example.org";
+    const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 10));
+
+    /// Server replies in a transcript, one string per reply
+    fn replies(transcript: &str) -> Vec<String> {
+        let mut replies = Vec::new();
+        let mut reply = String::new();
+        for line in transcript.lines() {
+            if let Some(line) = line.strip_prefix("S: ") {
+                reply.push_str(line);
+                reply.push_str("\r\n");
+                if line.as_bytes().get(3) != Some(&b'-') {
+                    replies.push(std::mem::take(&mut reply));
+                }
+            }
+        }
+        replies
+    }
+
+    /// The replies the transcripts record, in their order
+    fn session(profile: &Profile, queue_id: &str) -> Vec<String> {
+        let date = OffsetDateTime::from_unix_timestamp(1_717_243_200).unwrap();
+        vec![
+            profile.greeting_at(HOST, date),
+            profile.ehlo(HOST, HELO, CLIENT, false),
+            profile.starttls(),
+            profile.ehlo(HOST, HELO, CLIENT, true),
+            profile.auth_failed(),
+            profile.auth_success(),
+            profile.sender_ok(),
+            profile.recipient_unknown("nobody@example.com"),
+            profile.recipient_ok(),
+            profile.start_mail_input(),
+            profile.queued_as(queue_id),
+            profile.command_unrecognized(),
+            profile.goodbye(HOST),
+        ]
+    }
+
+    #[test]
+    fn test_profiles_match_transcripts() {
+        for (profile, transcript, queue_id) in [
+            (
+                &POSTFIX,
+                include_str!("transcripts/postfix.txt"),
+                "4F1C62A0B3",
+            ),
+            (
+                &EXIM,
+                include_str!("transcripts/exim.txt"),
+                "1sDh3k-000Abc-2F",
+            ),
+        ] {
+            let expected = replies(transcript);
+            let actual = session(profile, queue_id);
+            assert_eq!(actual.len(), expected.len(), "{}", profile.name);
+            for (actual, expected) in actual.iter().zip(&expected) {
+                assert_eq!(actual, expected, "{}", profile.name);
+            }
+            assert!(!profile.ehlo(HOST, HELO, CLIENT, true).contains("STARTTLS"));
+            assert!(!profile.ehlo(HOST, HELO, CLIENT, false).contains("AUTH"));
+        }
+    }
+
+    #[test]
+    fn test_queue_ids() {
+        let id = postfix_queue_id();
+        assert_eq!(id.len(), 10);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+        let id = exim_queue_id();
+        assert_eq!(id.len(), 16);
+        assert_eq!(id.matches('-').count(), 2);
+    }
+
+    #[test]
+    fn test_customized_profile() {
+        let replies = HashMap::from([
+            (ReplyName::Greeting, "{host} ESMTP ready".to_string()),
+            (ReplyName::Queued, "2.0.0 Ok: {id} queued".to_string()),
+        ]);
+        let profile = POSTFIX
+            .customized(&["DELIVERBY 3600".to_string()], &replies)
+            .unwrap();
+        assert_eq!(
+            profile.greeting(HOST),
+            "220 mail.example.com ESMTP ready\r\n"
+        );
+        assert_eq!(profile.queued_as("ABC"), "250 2.0.0 Ok: ABC queued\r\n");
+        // The rest are the profile's own
+        assert_eq!(profile.goodbye(HOST), POSTFIX.goodbye(HOST));
+        for tls in [false, true] {
+            let ehlo = profile.ehlo(HOST, HELO, CLIENT, tls);
+            assert!(ehlo.ends_with("250-CHUNKING\r\n250 DELIVERBY 3600\r\n"));
+        }
+
+        // Nothing the profile or the tunnel already offers, and one line each
+        let none = HashMap::new();
+        for extension in ["AUTH CRAM-MD5", "size 1", "", "X\r\nY", " X"] {
+            assert!(POSTFIX.customized(&[extension.to_string()], &none).is_err());
+        }
+        let multi_line = HashMap::from([(ReplyName::Goodbye, "Bye\r\n250 Ok".to_string())]);
+        assert!(POSTFIX.customized(&[], &multi_line).is_err());
+    }
+}
@@ -0,0 +1,265 @@
+//! Fair scheduling of outgoing frames
+//!
+//! All channels share one connection, so a bulk download can queue enough
+//! DATA to delay an interactive channel's keystrokes. Outgoing frames wait
+//! in one queue per [`Priority`] and are taken by deficit round robin: each
+//! turn a queue may send up to its weight times [`QUANTUM`] payload bytes.
+//! A channel's frames all share its queue, so they keep their order.
+//!
+//! Within a queue, channels with frames waiting take turns: one may send
+//! up to its turn's byte budget before the next gets to, so a bulk
+//! transfer can't hold up the many small channels sharing its priority.
+//!
+//! Channels get the priority named in their CONNECT, whichever side sent
+//! it; the link passes incoming CONNECTs to [`Scheduler::learn`].
+
+use super::frames::{Frame, FrameType, Priority};
+use std::collections::{HashMap, VecDeque};
+
+/// Payload bytes a weight of one may send per turn
+pub const QUANTUM: usize = 16 * 1024;
+
+/// Queues in turn order, with their weights
+const CLASSES: [(Priority, usize); 3] = [
+    (Priority::Interactive, 8),
+    (Priority::Normal, 4),
+    (Priority::Bulk, 1),
+];
+
+/// Default payload bytes a channel may send per turn within its queue
+pub const CHANNEL_TURN: usize = 16 * 1024;
+
+/// One priority's frames, queued per channel
+#[derive(Debug, Default)]
+struct Queue {
+    /// Channels with frames waiting, in turn order
+    turns: VecDeque<u16>,
+    frames: HashMap<u16, VecDeque<Frame>>,
+    /// Payload bytes the channel at the front has sent this turn
+    sent: usize,
+    len: usize,
+}
+
+impl Queue {
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn push(&mut self, frame: Frame) {
+        let frames = self.frames.entry(frame.channel_id).or_default();
+        if frames.is_empty() {
+            self.turns.push_back(frame.channel_id);
+        }
+        frames.push_back(frame);
+        self.len += 1;
+    }
+
+    fn front(&self) -> Option<&Frame> {
+        self.frames.get(self.turns.front()?)?.front()
+    }
+
+    /// Take the front channel's next frame, passing the turn on once the
+    /// channel has nothing left or has used up `turn` bytes. A channel
+    /// always sends at least one frame per turn.
+    fn pop(&mut self, turn: usize) -> Option<Frame> {
+        let channel = *self.turns.front()?;
+        let frames = self.frames.get_mut(&channel)?;
+        let frame = frames.pop_front()?;
+        self.len -= 1;
+        self.sent += frame.payload.len();
+        if frames.is_empty() {
+            self.frames.remove(&channel);
+            self.turns.pop_front();
+            self.sent = 0;
+        } else if self.sent >= turn {
+            self.turns.rotate_left(1);
+            self.sent = 0;
+        }
+        Some(frame)
+    }
+}
+
+/// Outgoing frames not yet written, by priority
+#[derive(Debug)]
+pub struct Scheduler {
+    queues: [Queue; CLASSES.len()],
+    /// Bytes each queue may still send this turn
+    deficits: [usize; CLASSES.len()],
+    /// Queue whose turn it is
+    current: usize,
+    /// Channels with a priority other than normal
+    priorities: HashMap<u16, Priority>,
+    /// Payload bytes a channel may send before the next in its queue
+    /// gets a turn (0 = one frame each)
+    channel_turn: usize,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self {
+            queues: Default::default(),
+            deficits: Default::default(),
+            current: 0,
+            priorities: HashMap::new(),
+            channel_turn: CHANNEL_TURN,
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the bytes a channel may send per turn
+    pub fn set_channel_turn(&mut self, bytes: usize) {
+        self.channel_turn = bytes;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(Queue::is_empty)
+    }
+
+    /// Frames queued
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(|queue| queue.len).sum()
+    }
+
+    /// Note the priority of a channel the peer opened
+    pub fn learn(&mut self, frame: &Frame) {
+        match frame.frame_type {
+            FrameType::Connect => {
+                let priority = frame
+                    .parse_connect_with_meta()
+                    .and_then(|(_, _, meta)| meta.priority)
+                    .unwrap_or_default();
+                if priority == Priority::Normal {
+                    self.priorities.remove(&frame.channel_id);
+                } else {
+                    self.priorities.insert(frame.channel_id, priority);
+                }
+            }
+            // Anything still queued for the channel is moot once the peer
+            // has closed it
+            FrameType::Close => {
+                self.priorities.remove(&frame.channel_id);
+            }
+            _ => {}
+        }
+    }
+
+    /// Queue a frame to send
+    pub fn push(&mut self, frame: Frame) {
+        let priority = match frame.frame_type {
+            // Lookups and datagrams are small and usually waited on
+            FrameType::Resolve | FrameType::ResolveResult | FrameType::Datagram => {
+                Priority::Interactive
+            }
+            FrameType::Connect => {
+                self.learn(&frame);
+                self.priority(frame.channel_id)
+            }
+            FrameType::Close => {
+                let priority = self.priority(frame.channel_id);
+                self.priorities.remove(&frame.channel_id);
+                priority
+            }
+            _ => self.priority(frame.channel_id),
+        };
+        let class = CLASSES
+            .iter()
+            .position(|(class, _)| *class == priority)
+            .unwrap_or(1);
+        self.queues[class].push(frame);
+    }
+
+    /// Next frame to send
+    pub fn pop(&mut self) -> Option<Frame> {
+        if self.is_empty() {
+            return None;
+        }
+        loop {
+            let class = self.current;
+            let queue = &mut self.queues[class];
+            match queue.front() {
+                Some(front) if front.payload.len() <= self.deficits[class] => {
+                    self.deficits[class] -= front.payload.len();
+                    return queue.pop(self.channel_turn);
+                }
+                Some(_) => {}
+                // An idle queue doesn't save up for later
+                None => self.deficits[class] = 0,
+            }
+            self.current = (class + 1) % CLASSES.len();
+            if !self.queues[self.current].is_empty() {
+                self.deficits[self.current] += CLASSES[self.current].1 * QUANTUM;
+            }
+        }
+    }
+
+    fn priority(&self, channel_id: u16) -> Priority {
+        self.priorities
+            .get(&channel_id)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::ConnectMeta;
+
+    fn connect(id: u16, priority: Priority) -> Frame {
+        let meta = ConnectMeta {
+            priority: Some(priority),
+            ..ConnectMeta::default()
+        };
+        Frame::connect_with_meta(id, "host", 1, &meta)
+    }
+
+    #[test]
+    fn test_weighted_round_robin() {
+        let mut scheduler = Scheduler::new();
+        scheduler.push(connect(1, Priority::Bulk));
+        scheduler.learn(&connect(2, Priority::Interactive));
+        for _ in 0..20 {
+            scheduler.push(Frame::data(1, vec![0u8; QUANTUM]));
+        }
+        for _ in 0..20 {
+            scheduler.push(Frame::data(2, vec![0u8; QUANTUM]));
+        }
+        scheduler.push(Frame::close(1));
+
+        let order: Vec<(FrameType, u16)> = std::iter::from_fn(|| scheduler.pop())
+            .map(|frame| (frame.frame_type, frame.channel_id))
+            .collect();
+        assert_eq!(order.len(), 42);
+        // Each channel's frames keep their order
+        assert_eq!(order[0].0, FrameType::Connect);
+        assert_eq!(order.last(), Some(&(FrameType::Close, 1)));
+        // The interactive channel gets 8 frames to each bulk one
+        let first_ten: Vec<u16> = order[..10].iter().map(|(_, id)| *id).collect();
+        assert_eq!(first_ten, [1, 2, 2, 2, 2, 2, 2, 2, 2, 1]);
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn test_channel_turns() {
+        let mut scheduler = Scheduler::new();
+        scheduler.set_channel_turn(2 * 1024);
+        for _ in 0..8 {
+            scheduler.push(Frame::data(1, vec![0u8; 1024]));
+        }
+        scheduler.push(Frame::data(2, &b"ls"[..]));
+        scheduler.push(Frame::data(3, &b"pwd"[..]));
+        scheduler.push(Frame::data(2, &b"cd"[..]));
+
+        let order: Vec<u16> = std::iter::from_fn(|| scheduler.pop())
+            .map(|frame| frame.channel_id)
+            .collect();
+        // The bulk channel sends 2 KiB, then the others get a turn
+        assert_eq!(order, [1, 1, 2, 2, 3, 1, 1, 1, 1, 1, 1]);
+        assert!(scheduler.is_empty());
+    }
+}
@@ -0,0 +1,149 @@
+//! Per-channel sequence numbers
+//!
+//! The link already numbers session frames for resumption, but only by
+//! position in a stream that TLS delivers exactly once and in order. A
+//! transport without those guarantees (datagrams, or a middlebox that
+//! retransmits on its own) could hand the same DATA over twice or lose one.
+//! When both sides agree on [`Features::SEQUENCE`](super::hello::Features)
+//! in HELLO, each DATA payload starts with a 32-bit count of the DATA
+//! frames sent on its channel before it. The receiver drops a repeat of one
+//! it has seen, and resets the channel when one is missing.
+//!
+//! The counts start over whenever a CONNECT opens the channel ID.
+
+use super::frames::{Frame, FrameType};
+use bytes::{BufMut, BytesMut};
+use std::collections::HashMap;
+
+/// Bytes the sequence number adds to each DATA payload
+pub const SEQUENCE_SIZE: usize = 4;
+
+/// What to do with a DATA frame that arrived
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    /// The next one expected; its sequence number has been stripped
+    Deliver,
+    /// Already delivered once
+    Duplicate,
+    /// Frames before it went missing: the channel is to be reset
+    Gap { expected: u32, got: u32 },
+    /// For a channel already reset over a gap
+    Discard,
+}
+
+/// Sequence numbers of each channel, both ways, for one session
+#[derive(Debug, Default)]
+pub struct Sequencer {
+    /// Next number to send on each channel
+    send: HashMap<u16, u32>,
+    /// Next number expected on each channel; None once it's been reset
+    recv: HashMap<u16, Option<u32>>,
+}
+
+impl Sequencer {
+    /// Number an outgoing frame: prefixes DATA, and starts a channel's
+    /// counts over on CONNECT
+    pub fn stamp(&mut self, frame: &mut Frame) {
+        match frame.frame_type {
+            FrameType::Connect => self.open(frame.channel_id),
+            FrameType::Data => {
+                let next = self.send.entry(frame.channel_id).or_default();
+                let mut payload = BytesMut::with_capacity(SEQUENCE_SIZE + frame.payload.len());
+                payload.put_u32(*next);
+                payload.extend_from_slice(&frame.payload);
+                frame.payload = payload.freeze();
+                *next = next.wrapping_add(1);
+            }
+            _ => {}
+        }
+    }
+
+    /// Check an incoming frame, stripping the sequence number from DATA
+    pub fn check(&mut self, frame: &mut Frame) -> Verdict {
+        match frame.frame_type {
+            FrameType::Connect => {
+                self.open(frame.channel_id);
+                return Verdict::Deliver;
+            }
+            FrameType::Data => {}
+            _ => return Verdict::Deliver,
+        }
+        let Some(number) = frame.payload.get(..SEQUENCE_SIZE) else {
+            // Too short to carry one; treated like a hole in the sequence
+            let expected = self.expected(frame.channel_id).unwrap_or_default();
+            self.recv.insert(frame.channel_id, None);
+            return Verdict::Gap { expected, got: 0 };
+        };
+        let got = u32::from_be_bytes(number.try_into().unwrap());
+        let Some(expected) = self.expected(frame.channel_id) else {
+            return Verdict::Discard;
+        };
+        if got == expected {
+            frame.payload = frame.payload.slice(SEQUENCE_SIZE..);
+            self.recv
+                .insert(frame.channel_id, Some(expected.wrapping_add(1)));
+            Verdict::Deliver
+        } else if expected.wrapping_sub(got) <= u32::MAX / 2 {
+            Verdict::Duplicate
+        } else {
+            self.recv.insert(frame.channel_id, None);
+            Verdict::Gap { expected, got }
+        }
+    }
+
+    /// Next number expected on `channel_id`, None if it was reset
+    fn expected(&self, channel_id: u16) -> Option<u32> {
+        self.recv.get(&channel_id).copied().unwrap_or(Some(0))
+    }
+
+    fn open(&mut self, channel_id: u16) {
+        self.send.remove(&channel_id);
+        self.recv.remove(&channel_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequencer() {
+        let mut sender = Sequencer::default();
+        let mut receiver = Sequencer::default();
+        let mut sent = Vec::new();
+        for data in [&b"one"[..], b"two", b"three", b"four"] {
+            let mut frame = Frame::data(1, data);
+            sender.stamp(&mut frame);
+            assert_eq!(frame.payload.len(), SEQUENCE_SIZE + data.len());
+            sent.push(frame);
+        }
+
+        let mut first = sent[0].clone();
+        assert_eq!(receiver.check(&mut first), Verdict::Deliver);
+        assert_eq!(&first.payload[..], b"one");
+        // A repeat is dropped, and the next still goes through
+        assert_eq!(receiver.check(&mut sent[0].clone()), Verdict::Duplicate);
+        assert_eq!(receiver.check(&mut sent[1].clone()), Verdict::Deliver);
+        // A missing frame resets the channel, and the rest are discarded
+        assert_eq!(
+            receiver.check(&mut sent[3].clone()),
+            Verdict::Gap {
+                expected: 2,
+                got: 3
+            }
+        );
+        assert_eq!(receiver.check(&mut sent[2].clone()), Verdict::Discard);
+
+        // Channels count separately, and CONNECT starts a channel over
+        let mut other = Frame::data(2, &b"x"[..]);
+        sender.stamp(&mut other);
+        assert_eq!(receiver.check(&mut other), Verdict::Deliver);
+        let mut connect = Frame::connect(1, "example.com", 443);
+        sender.stamp(&mut connect);
+        assert_eq!(receiver.check(&mut connect), Verdict::Deliver);
+        let mut reopened = Frame::data(1, &b"again"[..]);
+        sender.stamp(&mut reopened);
+        assert_eq!(receiver.check(&mut reopened), Verdict::Deliver);
+        assert_eq!(&reopened.payload[..], b"again");
+    }
+}
@@ -1,5 +1,14 @@
 /// SMTP Protocol Constants and State Machine
+use bytes::{Buf, BytesMut};
 use std::fmt;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Space reserved in the line buffer before each read
+const LINE_READ_SIZE: usize = 1024;
+
+/// Longest line accepted before giving up on the peer. Generous for SMTP,
+/// whose longest line here is an AUTH token.
+const MAX_LINE_LENGTH: usize = 4096;
 
 /// SMTP response codes
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -14,11 +23,18 @@ impl ResponseCode {
     pub const START_INPUT: Self = Self(354);
     pub const AUTH_CONTINUE: Self = Self(334);
     pub const TEMP_FAIL: Self = Self(421);
+    pub const LOCAL_ERROR: Self = Self(451);
+    pub const INSUFFICIENT_STORAGE: Self = Self(452);
     pub const SYNTAX_ERROR: Self = Self(500);
+    pub const SYNTAX_ERROR_PARAMS: Self = Self(501);
     pub const COMMAND_UNRECOGNIZED: Self = Self(502);
     pub const BAD_SEQUENCE: Self = Self(503);
     pub const AUTH_REQUIRED: Self = Self(530);
     pub const AUTH_FAILED: Self = Self(535);
+    pub const MAILBOX_UNAVAILABLE: Self = Self(550);
+    pub const USER_NOT_LOCAL: Self = Self(551);
+    pub const EXCEEDED_STORAGE: Self = Self(552);
+    pub const TRANSACTION_FAILED: Self = Self(554);
     pub const BINARY_MODE: Self = Self(299);
 }
 
@@ -38,15 +54,28 @@ pub enum Command {
     Mail,
     Rcpt,
     Data,
+    /// RFC 3030 chunk of message data
+    Bdat,
     Quit,
     Binary, // Custom command to switch to binary mode
     Unknown,
 }
 
+/// Verb switching to binary mode unless configured otherwise
+pub const BINARY_VERB: &str = "BINARY";
+
 impl Command {
     pub fn parse(s: &str) -> (Self, &str) {
+        Self::parse_with(s, BINARY_VERB)
+    }
+
+    /// Parse with `binary_verb` (in any case) as the switch to binary mode
+    pub fn parse_with<'a>(s: &'a str, binary_verb: &str) -> (Self, &'a str) {
         let s = s.trim();
         let (cmd, rest) = s.split_once(' ').unwrap_or((s, ""));
+        if cmd.eq_ignore_ascii_case(binary_verb) {
+            return (Self::Binary, rest.trim());
+        }
         let cmd = cmd.to_uppercase();
 
         let command = match cmd.as_str() {
@@ -57,8 +86,8 @@ impl Command {
             "MAIL" => Self::Mail,
             "RCPT" => Self::Rcpt,
             "DATA" => Self::Data,
+            "BDAT" => Self::Bdat,
             "QUIT" => Self::Quit,
-            "BINARY" => Self::Binary,
             _ => Self::Unknown,
         };
 
@@ -67,16 +96,60 @@ impl Command {
 }
 
 /// SMTP State Machine
+///
+/// The tunnel's own commands only exist inside TLS: AUTH needs an EHLO
+/// after STARTTLS (or on the implicit TLS port) and BINARY needs AUTH, so
+/// a plaintext client never sees anything Postfix wouldn't say. BDAT
+/// after AUTH starts the tunnel as well, for the `bdat` data channel.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum State {
+    /// Greeting sent, waiting for EHLO
     Initial,
+    /// EHLO done in plaintext
     Greeted,
+    /// TLS up, waiting for EHLO again
     TlsStarted,
+    /// EHLO done over TLS
+    TlsGreeted,
     Authenticated,
     BinaryMode,
     Quit,
 }
 
+/// What a command does in a given state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    /// Valid; the session is in this state once the command succeeds
+    To(State),
+    /// A known command out of order
+    BadSequence,
+    /// Not a command here. BINARY is one until authenticated, so it
+    /// never shows up before then.
+    Unrecognized,
+}
+
+impl State {
+    /// What `command` does in this state. MAIL, RCPT and DATA leave the
+    /// state alone; their own order is the envelope's business.
+    pub fn on(self, command: Command) -> Transition {
+        use Command::*;
+        let next = match (self, command) {
+            (State::BinaryMode | State::Quit, _) => return Transition::BadSequence,
+            (_, Quit) => State::Quit,
+            (State::Initial | State::Greeted, Ehlo | Helo) => State::Greeted,
+            (State::Greeted, StartTls) => State::TlsStarted,
+            (State::TlsStarted | State::TlsGreeted, Ehlo | Helo) => State::TlsGreeted,
+            (State::Authenticated, Ehlo | Helo) => State::Authenticated,
+            (State::TlsGreeted, Auth) => State::Authenticated,
+            (State::Authenticated, Binary | Bdat) => State::BinaryMode,
+            (State::Greeted | State::TlsGreeted | State::Authenticated, Mail | Rcpt | Data) => self,
+            (_, Binary | Unknown) => return Transition::Unrecognized,
+            _ => return Transition::BadSequence,
+        };
+        Transition::To(next)
+    }
+}
+
 /// SMTP response builder
 pub struct Response;
 
@@ -106,85 +179,113 @@ impl Response {
         result
     }
 
-    /// Greeting response
-    pub fn greeting(hostname: &str) -> String {
+    /// Binary mode activated for a resumable session; `received` is the
+    /// number of session frames the server already has from the client
+    pub fn binary_session(session: &str, received: u64) -> String {
         Self::simple(
-            ResponseCode::READY,
-            &format!("{hostname} ESMTP Postfix (Ubuntu)"),
+            ResponseCode::BINARY_MODE,
+            &format!("Binary mode activated session={session} received={received}"),
         )
     }
 
-    /// EHLO response
-    pub fn ehlo(hostname: &str, starttls: bool) -> String {
-        let mut lines = vec![hostname];
-        if starttls {
-            lines.push("STARTTLS");
-        }
-        lines.push("AUTH PLAIN LOGIN");
-        lines.push("8BITMIME");
-        Self::multi_line(ResponseCode::OK, &lines)
-    }
-
-    /// STARTTLS response
-    pub fn starttls() -> String {
-        Self::simple(ResponseCode::READY, "2.0.0 Ready to start TLS")
+    /// Session to resume has expired or never existed
+    pub fn session_not_found() -> String {
+        Self::simple(ResponseCode::TRANSACTION_FAILED, "5.3.0 Session not found")
     }
 
-    /// Auth success
-    pub fn auth_success() -> String {
+    /// Session to resume is held by another node, reachable at `addr`
+    pub fn session_elsewhere(addr: &str) -> String {
         Self::simple(
-            ResponseCode::AUTH_SUCCESS,
-            "2.7.0 Authentication successful",
+            ResponseCode::USER_NOT_LOCAL,
+            &format!("5.1.6 User not local; please try <{addr}>"),
         )
     }
+}
 
-    /// Auth failed
-    pub fn auth_failed() -> String {
-        Self::simple(ResponseCode::AUTH_FAILED, "5.7.8 Authentication failed")
-    }
-
-    /// Binary mode activated
-    pub fn binary_mode() -> String {
-        Self::simple(ResponseCode::BINARY_MODE, "Binary mode activated")
-    }
-
-    /// Goodbye
-    pub fn goodbye() -> String {
-        Self::simple(ResponseCode::CLOSING, "Bye")
-    }
-
-    /// Syntax error
-    pub fn syntax_error() -> String {
-        Self::simple(ResponseCode::SYNTAX_ERROR, "Syntax error")
-    }
-
-    /// Command not recognized
-    pub fn command_unrecognized() -> String {
-        Self::simple(ResponseCode::COMMAND_UNRECOGNIZED, "Command not recognized")
-    }
+/// Another protocol spoken where SMTP was expected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForeignProtocol {
+    Tls,
+    Http,
+}
 
-    /// Bad sequence
-    pub fn bad_sequence() -> String {
-        Self::simple(ResponseCode::BAD_SEQUENCE, "Bad sequence of commands")
+impl ForeignProtocol {
+    /// Recognize a client's first bytes as TLS or HTTP. Scanners probing
+    /// the port send these straight away instead of waiting for a greeting.
+    pub fn sniff(data: &[u8]) -> Option<Self> {
+        const HTTP_METHODS: &[&[u8]] = &[
+            b"GET ",
+            b"POST ",
+            b"HEAD ",
+            b"PUT ",
+            b"DELETE ",
+            b"OPTIONS ",
+            b"CONNECT ",
+            b"PATCH ",
+            b"PRI * HTTP/2",
+        ];
+        // A TLS handshake record: content type 22, protocol major version 3
+        if let [0x16, 0x03, ..] = data {
+            return Some(Self::Tls);
+        }
+        HTTP_METHODS
+            .iter()
+            .any(|method| data.starts_with(method))
+            .then_some(Self::Http)
     }
+}
 
-    /// Auth required
-    pub fn auth_required() -> String {
-        Self::simple(ResponseCode::AUTH_REQUIRED, "Authentication required")
+impl fmt::Display for ForeignProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tls => write!(f, "TLS ClientHello"),
+            Self::Http => write!(f, "HTTP request"),
+        }
     }
 }
 
 /// Parse an SMTP line, returning (command, arg) or None if empty
-pub fn parse_line(line: &str) -> Option<(Command, String)> {
+pub fn parse_line(line: &str, binary_verb: &str) -> Option<(Command, String)> {
     let line = line.trim();
     if line.is_empty() {
         return None;
     }
 
-    let (cmd, arg) = Command::parse(line);
+    let (cmd, arg) = Command::parse_with(line, binary_verb);
     Some((cmd, arg.to_string()))
 }
 
+/// Read a CRLF-terminated line, or None at EOF.
+///
+/// Reads go straight into `buf`, which keeps anything past the line (such
+/// as frame data pipelined after BINARY) and reuses its allocation for the
+/// next call. A peer sending more than [`MAX_LINE_LENGTH`] bytes without a
+/// line break gets an error. Returns plain `io::Result` so per-line errors
+/// never capture an `anyhow` backtrace on the connection hot path.
+pub async fn read_line<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut BytesMut,
+) -> std::io::Result<Option<String>> {
+    loop {
+        if let Some(pos) = buf.windows(2).position(|w| w == b"\r\n") {
+            let line = buf.split_to(pos);
+            buf.advance(2); // Skip \r\n
+            return Ok(Some(String::from_utf8_lossy(&line).into_owned()));
+        }
+        if buf.len() > MAX_LINE_LENGTH {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Line too long",
+            ));
+        }
+
+        buf.reserve(LINE_READ_SIZE);
+        if stream.read_buf(buf).await? == 0 {
+            return Ok(None);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -196,21 +297,110 @@ mod tests {
         assert_eq!(Command::parse("STARTTLS").0, Command::StartTls);
         assert_eq!(Command::parse("AUTH PLAIN token").0, Command::Auth);
         assert_eq!(Command::parse("BINARY").0, Command::Binary);
+
+        assert_eq!(Command::parse("BDAT 512 LAST"), (Command::Bdat, "512 LAST"));
+
+        // A configured verb replaces BINARY
+        assert_eq!(
+            Command::parse_with("x-exps RESUME 1 2", "X-EXPS"),
+            (Command::Binary, "RESUME 1 2")
+        );
+        assert_eq!(Command::parse_with("BINARY", "X-EXPS").0, Command::Unknown);
+        assert_eq!(Command::parse_with("EHLO x", "X-EXPS").0, Command::Ehlo);
     }
 
     #[test]
-    fn test_response_greeting() {
-        let resp = Response::greeting("mail.example.com");
-        assert!(resp.contains("220"));
-        assert!(resp.contains("mail.example.com"));
-        assert!(resp.contains("Postfix"));
+    fn test_state_transitions() {
+        use Command::*;
+
+        let commands = [
+            Ehlo, Helo, StartTls, Auth, Mail, Rcpt, Data, Bdat, Quit, Binary, Unknown,
+        ];
+        let states = [
+            State::Initial,
+            State::Greeted,
+            State::TlsStarted,
+            State::TlsGreeted,
+            State::Authenticated,
+            State::BinaryMode,
+            State::Quit,
+        ];
+        // Every state against every command: the next state, or the
+        // refusal (503 out of sequence, 502 unrecognized)
+        let table: Vec<String> = states
+            .iter()
+            .map(|state| {
+                let row: Vec<String> = commands
+                    .iter()
+                    .map(|&command| match state.on(command) {
+                        Transition::To(next) => format!("{next:?}"),
+                        Transition::BadSequence => "503".to_string(),
+                        Transition::Unrecognized => "502".to_string(),
+                    })
+                    .collect();
+                format!("{state:?}: {}", row.join(" "))
+            })
+            .collect();
+        assert_eq!(
+            table,
+            [
+                "Initial: Greeted Greeted 503 503 503 503 503 503 Quit 502 502",
+                "Greeted: Greeted Greeted TlsStarted 503 Greeted Greeted Greeted 503 Quit 502 502",
+                "TlsStarted: TlsGreeted TlsGreeted 503 503 503 503 503 503 Quit 502 502",
+                "TlsGreeted: TlsGreeted TlsGreeted 503 Authenticated TlsGreeted TlsGreeted \
+                 TlsGreeted 503 Quit 502 502",
+                "Authenticated: Authenticated Authenticated 503 503 Authenticated Authenticated \
+                 Authenticated BinaryMode Quit BinaryMode 502",
+                "BinaryMode: 503 503 503 503 503 503 503 503 503 503 503",
+                "Quit: 503 503 503 503 503 503 503 503 503 503 503",
+            ]
+        );
     }
 
     #[test]
     fn test_response_multiline() {
-        let resp = Response::ehlo("mail.example.com", true);
-        assert!(resp.contains("250-mail.example.com"));
-        assert!(resp.contains("250-STARTTLS"));
-        assert!(resp.contains("250 8BITMIME"));
+        let resp = Response::multi_line(
+            ResponseCode::OK,
+            &["mail.example.com", "STARTTLS", "8BITMIME"],
+        );
+        assert_eq!(
+            resp,
+            "250-mail.example.com\r\n250-STARTTLS\r\n250 8BITMIME\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_line_keeps_pipelined_data() {
+        let mut stream = &b"EHLO client\r\nBINARY\r\n\x01\x00\x01"[..];
+        let mut buf = BytesMut::new();
+        let line = read_line(&mut stream, &mut buf).await.unwrap();
+        assert_eq!(line.as_deref(), Some("EHLO client"));
+        let line = read_line(&mut stream, &mut buf).await.unwrap();
+        assert_eq!(line.as_deref(), Some("BINARY"));
+        // Frame data after BINARY stays in the buffer
+        assert_eq!(&buf[..], b"\x01\x00\x01");
+        assert_eq!(read_line(&mut stream, &mut buf).await.unwrap(), None);
+
+        let garbage = vec![b'x'; 2 * MAX_LINE_LENGTH];
+        let err = read_line(&mut &garbage[..], &mut BytesMut::new())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_sniff_foreign_protocol() {
+        let client_hello = [0x16, 0x03, 0x01, 0x02, 0x00, 0x01];
+        assert_eq!(
+            ForeignProtocol::sniff(&client_hello),
+            Some(ForeignProtocol::Tls)
+        );
+        assert_eq!(
+            ForeignProtocol::sniff(b"GET / HTTP/1.1\r\nHost: x\r\n"),
+            Some(ForeignProtocol::Http)
+        );
+        assert_eq!(ForeignProtocol::sniff(b"EHLO client.local\r\n"), None);
+        assert_eq!(ForeignProtocol::sniff(b"HELO client.local\r\n"), None);
+        assert_eq!(ForeignProtocol::sniff(b"QUIT\r\n"), None);
     }
 }
@@ -0,0 +1,183 @@
+//! Generic TLV (tag-length-value) encoding for extensible frame payloads
+//!
+//! Wire format per entry: tag(1) + length(2, big-endian) + value(N).
+//! Decoders must skip tags they don't recognise so new fields can be added
+//! without breaking older peers.
+
+use bytes::{Buf, BufMut, BytesMut};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// TLV entry header size: tag(1) + length(2)
+pub const TLV_HEADER_SIZE: usize = 3;
+
+/// A single borrowed TLV entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tlv<'a> {
+    pub tag: u8,
+    pub value: &'a [u8],
+}
+
+impl<'a> Tlv<'a> {
+    /// Value as a single byte
+    pub fn as_u8(&self) -> Option<u8> {
+        match self.value {
+            [v] => Some(*v),
+            _ => None,
+        }
+    }
+
+    /// Value as a big-endian u16
+    pub fn as_u16(&self) -> Option<u16> {
+        <[u8; 2]>::try_from(self.value).ok().map(u16::from_be_bytes)
+    }
+
+    /// Value as a big-endian u32
+    pub fn as_u32(&self) -> Option<u32> {
+        <[u8; 4]>::try_from(self.value).ok().map(u32::from_be_bytes)
+    }
+
+    /// Value as a big-endian u64
+    pub fn as_u64(&self) -> Option<u64> {
+        <[u8; 8]>::try_from(self.value).ok().map(u64::from_be_bytes)
+    }
+
+    /// Value as UTF-8 text (lossy)
+    pub fn as_str(&self) -> String {
+        String::from_utf8_lossy(self.value).to_string()
+    }
+
+    /// Value as an IP address (4 or 16 bytes)
+    pub fn as_ip_addr(&self) -> Option<IpAddr> {
+        if let Ok(octets) = <[u8; 4]>::try_from(self.value) {
+            return Some(Ipv4Addr::from(octets).into());
+        }
+        <[u8; 16]>::try_from(self.value)
+            .ok()
+            .map(|octets| Ipv6Addr::from(octets).into())
+    }
+
+    /// Value as a socket address (4 or 16 address bytes + port)
+    pub fn as_socket_addr(&self) -> Option<SocketAddr> {
+        let v = self.value;
+        match v.len() {
+            6 => {
+                let ip = Ipv4Addr::new(v[0], v[1], v[2], v[3]);
+                Some(SocketAddr::new(ip.into(), u16::from_be_bytes([v[4], v[5]])))
+            }
+            18 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&v[..16]);
+                let port = u16::from_be_bytes([v[16], v[17]]);
+                Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Append a raw TLV entry
+pub fn put(buf: &mut BytesMut, tag: u8, value: &[u8]) {
+    debug_assert!(value.len() <= u16::MAX as usize);
+    buf.reserve(TLV_HEADER_SIZE + value.len());
+    buf.put_u8(tag);
+    buf.put_u16(value.len() as u16);
+    buf.extend_from_slice(value);
+}
+
+/// Append a single-byte TLV entry
+pub fn put_u8(buf: &mut BytesMut, tag: u8, value: u8) {
+    put(buf, tag, &[value]);
+}
+
+/// Append a u16 TLV entry
+pub fn put_u16(buf: &mut BytesMut, tag: u8, value: u16) {
+    put(buf, tag, &value.to_be_bytes());
+}
+
+/// Append a u32 TLV entry
+pub fn put_u32(buf: &mut BytesMut, tag: u8, value: u32) {
+    put(buf, tag, &value.to_be_bytes());
+}
+
+/// Append a u64 TLV entry
+pub fn put_u64(buf: &mut BytesMut, tag: u8, value: u64) {
+    put(buf, tag, &value.to_be_bytes());
+}
+
+/// Append a text TLV entry
+pub fn put_str(buf: &mut BytesMut, tag: u8, value: &str) {
+    put(buf, tag, value.as_bytes());
+}
+
+/// Append an IP address TLV entry
+pub fn put_ip_addr(buf: &mut BytesMut, tag: u8, addr: IpAddr) {
+    match addr {
+        IpAddr::V4(ip) => put(buf, tag, &ip.octets()),
+        IpAddr::V6(ip) => put(buf, tag, &ip.octets()),
+    }
+}
+
+/// Append a socket address TLV entry
+pub fn put_socket_addr(buf: &mut BytesMut, tag: u8, addr: SocketAddr) {
+    let mut value = Vec::with_capacity(18);
+    match addr.ip() {
+        IpAddr::V4(ip) => value.extend_from_slice(&ip.octets()),
+        IpAddr::V6(ip) => value.extend_from_slice(&ip.octets()),
+    }
+    value.extend_from_slice(&addr.port().to_be_bytes());
+    put(buf, tag, &value);
+}
+
+/// Parse a buffer of TLV entries.
+///
+/// Returns `None` if an entry is truncated.
+pub fn parse(mut buf: &[u8]) -> Option<Vec<Tlv<'_>>> {
+    let mut entries = Vec::new();
+    while buf.has_remaining() {
+        if buf.remaining() < TLV_HEADER_SIZE {
+            return None;
+        }
+        let tag = buf[0];
+        let len = u16::from_be_bytes([buf[1], buf[2]]) as usize;
+        buf.advance(TLV_HEADER_SIZE);
+        if buf.remaining() < len {
+            return None;
+        }
+        let (value, rest) = buf.split_at(len);
+        entries.push(Tlv { tag, value });
+        buf = rest;
+    }
+    Some(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tlv_roundtrip() {
+        let mut buf = BytesMut::new();
+        put_u8(&mut buf, 1, 7);
+        put_u16(&mut buf, 2, 0x1234);
+        put_str(&mut buf, 3, "hello");
+        put_socket_addr(&mut buf, 4, "[::1]:8080".parse().unwrap());
+
+        let entries = parse(&buf).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].as_u8(), Some(7));
+        assert_eq!(entries[1].as_u16(), Some(0x1234));
+        assert_eq!(entries[2].as_str(), "hello");
+        assert_eq!(
+            entries[3].as_socket_addr(),
+            Some("[::1]:8080".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_tlv_truncated() {
+        let mut buf = BytesMut::new();
+        put_str(&mut buf, 1, "hello");
+        assert!(parse(&buf[..buf.len() - 1]).is_none());
+        assert!(parse(&buf[..2]).is_none());
+    }
+}
@@ -0,0 +1,115 @@
+//! DNS records for the camouflage hostname
+//!
+//! A mail server whose name has no MX, SPF or DMARC records looks like what
+//! it is. `smtp-tunnel-server dns-records` prints, in zone file syntax, the
+//! records a real MTA at the configured hostname would have, TLSA included
+//! so DANE-checking peers find the certificate pinned.
+
+use crate::config::ServerConfig;
+use crate::tls::CertInfo;
+use std::fmt::Write;
+use std::net::IpAddr;
+
+/// Domain mail for `hostname` belongs to: the hostname minus its first
+/// label, or the hostname itself if that would leave a bare TLD
+pub fn mail_domain(hostname: &str) -> &str {
+    match hostname.split_once('.') {
+        Some((_, rest)) if rest.contains('.') => rest,
+        _ => hostname,
+    }
+}
+
+/// Zone file lines for the server. `ips` are its public addresses; without
+/// any, the config's listen address is used if it's a routable one.
+pub fn zone(
+    config: &ServerConfig,
+    domain: Option<&str>,
+    ips: &[IpAddr],
+    cert: Option<&CertInfo>,
+) -> String {
+    let host = config.hostname.trim_end_matches('.');
+    let domain = domain.unwrap_or_else(|| mail_domain(host));
+    let mut ips = ips.to_vec();
+    if ips.is_empty()
+        && let Ok(ip) = config.host.parse::<IpAddr>()
+        && !ip.is_unspecified()
+        && !ip.is_loopback()
+    {
+        ips.push(ip);
+    }
+
+    let mut zone = String::new();
+    let _ = writeln!(zone, "; Records for {host}, as its mail server would have");
+    if ips.is_empty() {
+        let _ = writeln!(zone, "; {host}. IN A <public address> (pass --ip)");
+    }
+    for ip in &ips {
+        let kind = if ip.is_ipv4() { "A" } else { "AAAA" };
+        let _ = writeln!(zone, "{host}. IN {kind} {ip}");
+    }
+    let _ = writeln!(zone, "{domain}. IN MX 10 {host}.");
+    let _ = writeln!(zone, "{domain}. IN TXT \"v=spf1 mx -all\"");
+    let _ = writeln!(
+        zone,
+        "_dmarc.{domain}. IN TXT \"v=DMARC1; p=reject; rua=mailto:postmaster@{domain}\""
+    );
+
+    let mut ports = vec![config.port];
+    ports.extend(config.smtps_port);
+    match cert {
+        Some(cert) => {
+            let digest = hex::encode(cert.spki_sha256);
+            for port in ports {
+                let _ = writeln!(zone, "_{port}._tcp.{host}. IN TLSA 3 1 1 {digest}");
+            }
+        }
+        None => {
+            let _ = writeln!(zone, "; TLSA records need the certificate file");
+        }
+    }
+
+    let _ = writeln!(
+        zone,
+        "; No DKIM key: the host sends no mail, which the SPF and DMARC records say"
+    );
+    for ip in &ips {
+        let _ = writeln!(
+            zone,
+            "; Reverse DNS: ask whoever runs {ip} for a PTR record to {host}."
+        );
+    }
+    zone
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_zone() {
+        assert_eq!(mail_domain("mail.example.com"), "example.com");
+        assert_eq!(mail_domain("example.com"), "example.com");
+        assert_eq!(mail_domain("localhost"), "localhost");
+
+        let config = ServerConfig {
+            hostname: "mx.example.org".to_string(),
+            port: 25,
+            smtps_port: Some(465),
+            ..ServerConfig::default()
+        };
+        let cert = CertInfo {
+            subject: "CN=mx.example.org".to_string(),
+            issuer: "CN=Example CA".to_string(),
+            not_after: 0,
+            spki_sha256: [0xab; 32],
+        };
+        let ip: IpAddr = "203.0.113.9".parse().unwrap();
+        let zone = zone(&config, None, &[ip], Some(&cert));
+        assert!(zone.contains("mx.example.org. IN A 203.0.113.9\n"));
+        assert!(zone.contains("example.org. IN MX 10 mx.example.org.\n"));
+        assert!(zone.contains("_dmarc.example.org. IN TXT"));
+        let digest = "ab".repeat(32);
+        assert!(zone.contains(&format!("_25._tcp.mx.example.org. IN TLSA 3 1 1 {digest}")));
+        assert!(zone.contains("_465._tcp.mx.example.org. IN TLSA"));
+    }
+}
@@ -0,0 +1,418 @@
+//! Name resolution for tunneled hostnames
+//!
+//! The server resolves the hostnames clients connect to, send datagrams to
+//! or ask about in RESOLVE. By default that's the system resolver, which on
+//! a VPS is usually the provider's: often slow, and free to log every name.
+//! `resolver` sends the lookups elsewhere instead: to plain DNS servers, or
+//! encrypted to a DNS-over-TLS (RFC 7858) or DNS-over-HTTPS (RFC 8484)
+//! upstream. Each lookup asks for A and AAAA records side by side.
+
+use crate::config::ResolverConfig;
+use rustls::pki_types::ServerName;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio_rustls::TlsConnector;
+
+/// How long one upstream may take to answer
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A and AAAA record types
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+/// Largest answer read from a TCP, TLS or HTTPS upstream
+const MAX_RESPONSE: usize = 64 * 1024;
+
+/// Largest plain DNS answer over UDP, without EDNS0
+const MAX_UDP_RESPONSE: usize = 512;
+
+/// DNS over TLS port
+const DOT_PORT: u16 = 853;
+
+/// Where lookups go
+enum Upstream {
+    System,
+    Udp(Vec<SocketAddr>),
+    Tls {
+        servers: Vec<SocketAddr>,
+        name: ServerName<'static>,
+        connector: TlsConnector,
+    },
+    Https {
+        url: String,
+        host: String,
+        port: u16,
+        path: String,
+        connector: TlsConnector,
+    },
+}
+
+/// Resolver for the hostnames in tunneled requests
+pub struct Resolver {
+    upstream: Upstream,
+}
+
+impl std::fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Resolver").field(&self.describe()).finish()
+    }
+}
+
+impl Resolver {
+    pub fn new(config: &ResolverConfig) -> anyhow::Result<Self> {
+        let upstream = match config {
+            ResolverConfig::System => Upstream::System,
+            ResolverConfig::Udp { servers } => Upstream::Udp(parse_servers(servers, 53)?),
+            ResolverConfig::Dot {
+                servers,
+                tls_name,
+                ca_cert,
+            } => {
+                let servers = parse_servers(servers, DOT_PORT)?;
+                let name = match tls_name {
+                    Some(name) => name.clone(),
+                    None => servers[0].ip().to_string(),
+                };
+                Upstream::Tls {
+                    servers,
+                    name: ServerName::try_from(name.clone())
+                        .map_err(|_| anyhow::anyhow!("Invalid resolver tls_name: {name}"))?,
+                    connector: TlsConnector::from(crate::tls::client_config(ca_cert.as_deref())?),
+                }
+            }
+            ResolverConfig::Doh { url, ca_cert } => {
+                let (host, port, path) = parse_https_url(url)?;
+                Upstream::Https {
+                    url: url.clone(),
+                    host,
+                    port,
+                    path,
+                    connector: TlsConnector::from(crate::tls::client_config(ca_cert.as_deref())?),
+                }
+            }
+        };
+        Ok(Self { upstream })
+    }
+
+    /// Where lookups go, for logs
+    pub fn describe(&self) -> String {
+        let list = |servers: &[SocketAddr]| {
+            servers
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match &self.upstream {
+            Upstream::System => "system".to_string(),
+            Upstream::Udp(servers) => format!("udp {}", list(servers)),
+            Upstream::Tls { servers, .. } => format!("dot {}", list(servers)),
+            Upstream::Https { url, .. } => format!("doh {url}"),
+        }
+    }
+
+    /// Addresses of `host`, each with `port`. IP literals are returned as
+    /// they are; a name without addresses is `NotFound`.
+    pub async fn lookup(&self, host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+        #[cfg(feature = "testing")]
+        crate::chaos::lookup(host)?;
+        if let Upstream::System = self.upstream {
+            return Ok(tokio::net::lookup_host((host, port)).await?.collect());
+        }
+
+        let (v4, v6) = tokio::join!(self.query(host, TYPE_A), self.query(host, TYPE_AAAA));
+        let ips = match (v4, v6) {
+            (Err(e), Err(_)) => return Err(io::Error::other(format!("{e:#}"))),
+            (v4, v6) => [v4.unwrap_or_default(), v6.unwrap_or_default()].concat(),
+        };
+        if ips.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No addresses for {host}"),
+            ));
+        }
+        Ok(ips
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .collect())
+    }
+
+    /// Ask the upstream for `host`'s records of type `qtype`
+    async fn query(&self, host: &str, qtype: u16) -> anyhow::Result<Vec<IpAddr>> {
+        let id = rand::random::<u16>();
+        let query = &build_query(id, host, qtype)?;
+        let response = match &self.upstream {
+            Upstream::System => unreachable!("the system resolver isn't queried directly"),
+            Upstream::Udp(servers) => {
+                first_answer(servers, |server| exchange_udp(server, query, id)).await?
+            }
+            Upstream::Tls {
+                servers,
+                name,
+                connector,
+            } => {
+                first_answer(servers, |server| async move {
+                    let tcp = TcpStream::connect(server).await?;
+                    let mut tls = connector.connect(name.clone(), tcp).await?;
+                    exchange_stream(&mut tls, query).await
+                })
+                .await?
+            }
+            Upstream::Https {
+                host,
+                port,
+                path,
+                connector,
+                ..
+            } => tokio::time::timeout(
+                QUERY_TIMEOUT,
+                exchange_https(connector, host, *port, path, query),
+            )
+            .await
+            .map_err(|_| anyhow::anyhow!("No answer from {host}"))??,
+        };
+        parse_response(&response, id, qtype)
+    }
+}
+
+/// The answer of the first of `servers` to give one
+async fn first_answer<F, Fut>(servers: &[SocketAddr], exchange: F) -> anyhow::Result<Vec<u8>>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<Vec<u8>>>,
+{
+    let mut last_error = None;
+    for &server in servers {
+        match tokio::time::timeout(QUERY_TIMEOUT, exchange(server)).await {
+            Ok(Ok(response)) => return Ok(response),
+            Ok(Err(e)) => last_error = Some(e.context(format!("Resolver {server}"))),
+            Err(_) => last_error = Some(anyhow::anyhow!("No answer from resolver {server}")),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No resolvers configured")))
+}
+
+/// Send `query` over UDP, retrying over TCP if the answer is truncated
+async fn exchange_udp(server: SocketAddr, query: &[u8], id: u16) -> anyhow::Result<Vec<u8>> {
+    let bind: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind).await?;
+    socket.connect(server).await?;
+    socket.send(query).await?;
+    let mut response = vec![0u8; MAX_UDP_RESPONSE];
+    loop {
+        let len = socket.recv(&mut response).await?;
+        // Ignore stray datagrams that aren't the answer to this query
+        if len < 4 || response[..2] != id.to_be_bytes() {
+            continue;
+        }
+        response.truncate(len);
+        break;
+    }
+    if response[2] & 0x02 != 0 {
+        let mut tcp = TcpStream::connect(server).await?;
+        return exchange_stream(&mut tcp, query).await;
+    }
+    Ok(response)
+}
+
+/// Send `query` over a TCP or TLS stream, each message length-prefixed
+async fn exchange_stream<S>(stream: &mut S, query: &[u8]) -> anyhow::Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut message = Vec::with_capacity(query.len() + 2);
+    message.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    message.extend_from_slice(query);
+    stream.write_all(&message).await?;
+    stream.flush().await?;
+    let len = stream.read_u16().await? as usize;
+    let mut response = vec![0u8; len];
+    stream.read_exact(&mut response).await?;
+    Ok(response)
+}
+
+/// POST `query` to a DNS-over-HTTPS endpoint
+async fn exchange_https(
+    connector: &TlsConnector,
+    host: &str,
+    port: u16,
+    path: &str,
+    query: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let name = ServerName::try_from(host.to_string())?;
+    let tcp = TcpStream::connect((host, port)).await?;
+    let mut tls = connector.connect(name, tcp).await?;
+    // HTTP/1.0: the body ends when the connection does, never chunked
+    let request = format!(
+        "POST {path} HTTP/1.0\r\nHost: {host}\r\nContent-Type: application/dns-message\r\n\
+         Accept: application/dns-message\r\nContent-Length: {}\r\n\r\n",
+        query.len()
+    );
+    tls.write_all(request.as_bytes()).await?;
+    tls.write_all(query).await?;
+    tls.flush().await?;
+    let mut raw = Vec::new();
+    match (&mut tls)
+        .take(MAX_RESPONSE as u64)
+        .read_to_end(&mut raw)
+        .await
+    {
+        // Servers often close without close_notify; a short body is
+        // caught by the Content-Length check
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {}
+        result => {
+            result?;
+        }
+    }
+    let response = crate::http::parse_http_response(&raw)?;
+    if response.status != 200 {
+        anyhow::bail!("{host} answered HTTP {}", response.status);
+    }
+    Ok(response.body.to_vec())
+}
+
+/// Parse "addr" or "addr:port" entries
+fn parse_servers(servers: &[String], default_port: u16) -> anyhow::Result<Vec<SocketAddr>> {
+    if servers.is_empty() {
+        anyhow::bail!("resolver needs at least one server");
+    }
+    servers
+        .iter()
+        .map(|server| {
+            server
+                .parse()
+                .or_else(|_| server.parse().map(|ip| SocketAddr::new(ip, default_port)))
+                .map_err(|_| anyhow::anyhow!("Invalid resolver address: {server}"))
+        })
+        .collect()
+}
+
+/// Split "https://host[:port]/path"
+fn parse_https_url(url: &str) -> anyhow::Result<(String, u16, String)> {
+    let invalid = || anyhow::anyhow!("Invalid DoH URL {url}: need https://host[:port]/path");
+    let rest = url.strip_prefix("https://").ok_or_else(invalid)?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/dns-query"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+        None => (authority, 443),
+    };
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host.to_string(), port, path.to_string()))
+}
+
+/// Recursive query for `name`'s records of type `qtype`
+fn build_query(id: u16, name: &str, qtype: u16) -> anyhow::Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(32 + name.len());
+    query.extend_from_slice(&id.to_be_bytes());
+    // RD: ask for recursion
+    query.extend_from_slice(&0x0100u16.to_be_bytes());
+    // One question
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            anyhow::bail!("Invalid DNS name: {}", name);
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&1u16.to_be_bytes());
+    Ok(query)
+}
+
+/// Addresses of type `qtype` in the answer to query `id`
+fn parse_response(msg: &[u8], id: u16, qtype: u16) -> anyhow::Result<Vec<IpAddr>> {
+    let invalid = || anyhow::anyhow!("Malformed DNS response");
+    let header = msg.get(..12).ok_or_else(invalid)?;
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    if header[..2] != id.to_be_bytes() || flags & 0x8000 == 0 {
+        return Err(invalid());
+    }
+    match flags & 0x000f {
+        0 => {}
+        // NXDOMAIN: no addresses
+        3 => return Ok(Vec::new()),
+        rcode => anyhow::bail!("DNS lookup failed (rcode {})", rcode),
+    }
+
+    let questions = u16::from_be_bytes([header[4], header[5]]);
+    let answers = u16::from_be_bytes([header[6], header[7]]);
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = crate::dane::skip_name(msg, pos).ok_or_else(invalid)? + 4;
+    }
+    let mut ips = Vec::new();
+    for _ in 0..answers {
+        pos = crate::dane::skip_name(msg, pos).ok_or_else(invalid)?;
+        let fixed = msg.get(pos..pos + 10).ok_or_else(invalid)?;
+        let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let len = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        pos += 10;
+        let rdata = msg.get(pos..pos + len).ok_or_else(invalid)?;
+        pos += len;
+        // The CNAMEs leading to the addresses come along; skip them
+        match (rtype, rdata.len()) {
+            (TYPE_A, 4) if qtype == TYPE_A => {
+                ips.push(IpAddr::from(<[u8; 4]>::try_from(rdata).unwrap()));
+            }
+            (TYPE_AAAA, 16) if qtype == TYPE_AAAA => {
+                ips.push(IpAddr::from(<[u8; 16]>::try_from(rdata).unwrap()));
+            }
+            _ => {}
+        }
+    }
+    Ok(ips)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_response() {
+        // The query's header and question, answered with a CNAME and then
+        // the A record
+        let mut msg = build_query(0x1234, "www.example.org.", TYPE_A).unwrap();
+        msg[2..4].copy_from_slice(&0x8180u16.to_be_bytes());
+        msg[6..8].copy_from_slice(&2u16.to_be_bytes());
+        msg.extend_from_slice(&[0xc0, 12, 0, 5, 0, 1, 0, 0, 1, 0, 0, 2, 0xc0, 16]);
+        msg.extend_from_slice(&[0xc0, 16, 0, 1, 0, 1, 0, 0, 1, 0, 0, 4, 192, 0, 2, 7]);
+
+        assert_eq!(
+            parse_response(&msg, 0x1234, TYPE_A).unwrap(),
+            [IpAddr::from([192, 0, 2, 7])]
+        );
+        assert!(parse_response(&msg, 0x1234, TYPE_AAAA).unwrap().is_empty());
+        assert!(parse_response(&msg, 0x4321, TYPE_A).is_err());
+        assert!(parse_response(&msg[..msg.len() - 1], 0x1234, TYPE_A).is_err());
+
+        // NXDOMAIN
+        msg[3] = 0x83;
+        assert!(parse_response(&msg, 0x1234, TYPE_A).unwrap().is_empty());
+
+        assert_eq!(
+            parse_https_url("https://dns.example/dns-query").unwrap(),
+            ("dns.example".to_string(), 443, "/dns-query".to_string())
+        );
+        assert!(parse_https_url("http://dns.example/dns-query").is_err());
+        assert_eq!(
+            parse_servers(&["9.9.9.9".to_string()], DOT_PORT).unwrap(),
+            ["9.9.9.9:853".parse().unwrap()]
+        );
+    }
+}
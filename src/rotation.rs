@@ -0,0 +1,144 @@
+//! Rotating listen ports
+//!
+//! With a port schedule configured the server also listens on a port that
+//! changes every `period`, picked from a range by an HMAC of the period
+//! number under a secret the clients share. Clients compute the same port
+//! from their own clock. Each port opens `overlap` early and stays open
+//! `overlap` late, covering clock skew and clients that connected just
+//! before the switch.
+
+use crate::config::PortRotationConfig;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::ops::Range;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Which port is in use when
+#[derive(Debug, Clone)]
+pub struct PortSchedule {
+    secret: String,
+    ports: Range<u32>,
+    period: u64,
+    overlap: u64,
+}
+
+impl PortSchedule {
+    pub fn from_config(config: &PortRotationConfig) -> anyhow::Result<Self> {
+        if config.secret.is_empty() {
+            anyhow::bail!("port_rotation needs a secret");
+        }
+        if config.min_port == 0 || config.min_port > config.max_port {
+            anyhow::bail!(
+                "Invalid port_rotation range {}-{}",
+                config.min_port,
+                config.max_port
+            );
+        }
+        if config.period == 0 || config.overlap >= config.period {
+            anyhow::bail!("port_rotation overlap must be shorter than its period");
+        }
+        Ok(Self {
+            secret: config.secret.clone(),
+            ports: u32::from(config.min_port)..u32::from(config.max_port) + 1,
+            period: config.period,
+            overlap: config.overlap,
+        })
+    }
+
+    /// Port for period number `slot`
+    pub fn port(&self, slot: u64) -> u16 {
+        let mut mac = HmacSha256::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC can take key of any size");
+        mac.update(b"smtp-tunnel-port:");
+        mac.update(&slot.to_be_bytes());
+        let digest = mac.finalize().into_bytes();
+        let value = u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]);
+        let span = self.ports.end - self.ports.start;
+        (self.ports.start + value % span) as u16
+    }
+
+    /// Period number at `unix_secs`
+    pub fn slot_at(&self, unix_secs: u64) -> u64 {
+        unix_secs / self.period
+    }
+
+    /// Port to connect to now
+    pub fn current_port(&self) -> u16 {
+        self.port(self.slot_at(unix_now()))
+    }
+
+    /// Periods whose port should be listening at `unix_secs`, overlap
+    /// included
+    pub fn open_slots(&self, unix_secs: u64) -> Range<u64> {
+        let first = self.slot_at(unix_secs.saturating_sub(self.overlap));
+        let last = self.slot_at(unix_secs + self.overlap);
+        first..last + 1
+    }
+
+    /// Time until the set of open slots can next change
+    pub fn until_next_change(&self, unix_secs: u64) -> Duration {
+        let next = |offset: u64| self.period - (unix_secs + offset) % self.period;
+        let secs = next(self.overlap).min(next(self.period - self.overlap));
+        Duration::from_secs(secs)
+    }
+}
+
+/// Seconds since the Unix epoch, by our clock
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule() -> PortSchedule {
+        PortSchedule::from_config(&PortRotationConfig {
+            secret: "shared".to_string(),
+            min_port: 20000,
+            max_port: 20099,
+            period: 3600,
+            overlap: 300,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_ports_follow_secret() {
+        let schedule = schedule();
+        let ports: Vec<u16> = (0..50).map(|slot| schedule.port(slot)).collect();
+        assert!(ports.iter().all(|port| (20000..=20099).contains(port)));
+        // Deterministic, yet not constant
+        assert_eq!(schedule.port(7), ports[7]);
+        assert!(ports.windows(2).any(|pair| pair[0] != pair[1]));
+
+        let other = PortSchedule {
+            secret: "different".to_string(),
+            ..schedule.clone()
+        };
+        assert!((0..50).any(|slot| other.port(slot) != ports[slot as usize]));
+    }
+
+    #[test]
+    fn test_open_slots_overlap() {
+        let schedule = schedule();
+        // Mid-period only the current port is open
+        assert_eq!(schedule.open_slots(10 * 3600 + 1800), 10..11);
+        // Around a switch both are
+        assert_eq!(schedule.open_slots(10 * 3600 + 100), 9..11);
+        assert_eq!(schedule.open_slots(11 * 3600 - 100), 10..12);
+        assert_eq!(
+            schedule.until_next_change(10 * 3600 + 1800),
+            Duration::from_secs(1500)
+        );
+        assert_eq!(
+            schedule.until_next_change(10 * 3600 + 100),
+            Duration::from_secs(200)
+        );
+    }
+}
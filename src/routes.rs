@@ -0,0 +1,733 @@
+//! Route management for modes that take over the default route
+//!
+//! Steering traffic into the tunnel with route overrides would also capture
+//! the client's own connection to the tunnel server and loop it back into
+//! the tunnel. [`RouteGuard`] pins a host route for the server through the
+//! original gateway, installs the overrides, and removes everything again
+//! when dropped.
+//!
+//! Each route is written to a state file before the system table is touched.
+//! If the process dies without cleaning up, [`recover`] removes whatever the
+//! file still lists when routes are next managed. The file lives in a
+//! runtime directory only its owner can write to (`/run/smtp-tunnel`), which
+//! like the routes themselves doesn't survive a reboot; a file another user
+//! could plant would have us delete routes of their choosing.
+//!
+//! Tun mode uses this. Transparent mode picks connections by process and
+//! leaves the routing table alone.
+
+use ipnet::IpNet;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use tracing::{debug, info, warn};
+
+/// Address that follows the default route: TEST-NET-1 is never routed
+/// anywhere more specific
+const DEFAULT_ROUTE_PROBE: Ipv4Addr = Ipv4Addr::new(192, 0, 2, 1);
+
+/// Where the next hop of a route is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NextHop {
+    /// Through a gateway, optionally pinned to an interface
+    Gateway {
+        address: IpAddr,
+        interface: Option<String>,
+    },
+    /// Straight out of an interface (tun devices)
+    Interface(String),
+}
+
+impl NextHop {
+    /// Interface the route leaves through, if known
+    fn interface(&self) -> Option<&str> {
+        match self {
+            Self::Gateway { interface, .. } => interface.as_deref(),
+            Self::Interface(interface) => Some(interface),
+        }
+    }
+}
+
+/// A route installed by this process
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    pub destination: IpNet,
+    pub next_hop: NextHop,
+}
+
+impl Route {
+    /// Host route for a single address
+    pub fn host(address: IpAddr, next_hop: NextHop) -> Self {
+        Self {
+            destination: IpNet::from(address),
+            next_hop,
+        }
+    }
+}
+
+/// `ip route` style: `10.0.0.0/8 via 192.168.1.1 dev eth0`
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.destination)?;
+        match &self.next_hop {
+            NextHop::Gateway { address, interface } => {
+                write!(f, " via {address}")?;
+                if let Some(interface) = interface {
+                    write!(f, " dev {interface}")?;
+                }
+                Ok(())
+            }
+            NextHop::Interface(interface) => write!(f, " dev {interface}"),
+        }
+    }
+}
+
+impl FromStr for Route {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut words = s.split_whitespace();
+        let destination = words
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("empty route"))?
+            .parse()?;
+        let (mut gateway, mut interface) = (None, None);
+        while let Some(key) = words.next() {
+            let value = words
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("missing value for '{key}' in route '{s}'"))?;
+            match key {
+                "via" => gateway = Some(value.parse()?),
+                "dev" => interface = Some(value.to_string()),
+                _ => anyhow::bail!("unknown key '{key}' in route '{s}'"),
+            }
+        }
+        let next_hop = match (gateway, interface) {
+            (Some(address), interface) => NextHop::Gateway { address, interface },
+            (None, Some(interface)) => NextHop::Interface(interface),
+            (None, None) => anyhow::bail!("route '{s}' has no gateway or interface"),
+        };
+        Ok(Self {
+            destination,
+            next_hop,
+        })
+    }
+}
+
+/// Routes installed by this process, removed again on drop
+#[derive(Debug)]
+pub struct RouteGuard {
+    state_file: PathBuf,
+    installed: Vec<Route>,
+    /// Where the default route went before [`override_default`]
+    ///
+    /// [`override_default`]: Self::override_default
+    original_default: Option<NextHop>,
+}
+
+impl RouteGuard {
+    /// Start tracking routes in `state_file`, removing any left behind by an
+    /// earlier run first
+    pub fn new(state_file: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let state_file = state_file.into();
+        recover(&state_file)?;
+        Ok(Self {
+            state_file,
+            installed: Vec::new(),
+            original_default: None,
+        })
+    }
+
+    /// Install a route
+    pub fn add(&mut self, route: Route) -> anyhow::Result<()> {
+        // Recorded first so a crash mid-install still cleans it up
+        self.installed.push(route.clone());
+        self.save()?;
+        if let Err(e) = run(&mut route_command(Action::Add, &route)) {
+            self.installed.pop();
+            self.save()?;
+            return Err(e.context(format!("failed to add route {route}")));
+        }
+        debug!("Added route {}", route);
+        Ok(())
+    }
+
+    /// Keep the tunnel server reachable through the current gateway once
+    /// the default route is overridden. Servers can be pinned after that
+    /// too, e.g. when a reload adds one; pinning one twice does nothing.
+    pub fn pin_server(&mut self, server: IpAddr) -> anyhow::Result<()> {
+        let current = next_hop_for(server)?;
+        match pin_route(
+            &self.installed,
+            self.original_default.as_ref(),
+            server,
+            current,
+        )? {
+            Some(route) => self.add(route),
+            None => Ok(()),
+        }
+    }
+
+    /// Override the IPv4 default route without replacing it, using two /1
+    /// routes that are more specific than 0.0.0.0/0
+    pub fn override_default(&mut self, next_hop: NextHop) -> anyhow::Result<()> {
+        self.original_default = next_hop_for(DEFAULT_ROUTE_PROBE.into()).ok();
+        for half in [Ipv4Addr::new(0, 0, 0, 0), Ipv4Addr::new(128, 0, 0, 0)] {
+            let destination = IpNet::new(half.into(), 1)?;
+            self.add(Route {
+                destination,
+                next_hop: next_hop.clone(),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Remove every route installed so far, newest first
+    pub fn remove_all(&mut self) {
+        while let Some(route) = self.installed.pop() {
+            match run(&mut route_command(Action::Delete, &route)) {
+                Ok(_) => debug!("Removed route {}", route),
+                Err(e) => warn!("Failed to remove route {}: {:#}", route, e),
+            }
+        }
+        if let Err(e) = remove_state(&self.state_file) {
+            warn!("Failed to remove {}: {}", self.state_file.display(), e);
+        }
+    }
+
+    fn save(&self) -> anyhow::Result<()> {
+        write_state(&self.state_file, &self.installed)
+    }
+}
+
+impl Drop for RouteGuard {
+    fn drop(&mut self) {
+        self.remove_all();
+    }
+}
+
+/// Host route keeping `server` off the routes in `installed`, given the
+/// next hop the system picks for it now. A server the system already sends
+/// through one of them goes the way the default route did before it was
+/// overridden. None if it's pinned already.
+fn pin_route(
+    installed: &[Route],
+    original_default: Option<&NextHop>,
+    server: IpAddr,
+    current: NextHop,
+) -> anyhow::Result<Option<Route>> {
+    let host = IpNet::from(server);
+    if installed.iter().any(|route| route.destination == host) {
+        return Ok(None);
+    }
+    let captured = installed.iter().any(|route| {
+        matches!(&route.next_hop, NextHop::Interface(interface)
+            if current.interface() == Some(interface.as_str()))
+    });
+    let next_hop = match (captured, original_default) {
+        (false, _) => current,
+        (true, Some(original)) => original.clone(),
+        (true, None) => {
+            anyhow::bail!("{server} is inside a tun route and can't be kept out of the tunnel")
+        }
+    };
+    Ok(Some(Route::host(server, next_hop)))
+}
+
+/// Default state file location, creating its directory if needed
+pub fn default_state_file() -> anyhow::Result<PathBuf> {
+    let dir = state_dir();
+    create_private_dir(&dir)
+        .map_err(|e| anyhow::anyhow!("Can't use {} for route state: {e}", dir.display()))?;
+    Ok(dir.join("routes"))
+}
+
+#[cfg(unix)]
+fn state_dir() -> PathBuf {
+    // /var/run on systems without /run, like macOS
+    let run = if Path::new("/run").is_dir() {
+        "/run"
+    } else {
+        "/var/run"
+    };
+    Path::new(run).join("smtp-tunnel")
+}
+
+#[cfg(not(unix))]
+fn state_dir() -> PathBuf {
+    std::env::var_os("ProgramData")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("smtp-tunnel")
+}
+
+/// Create `dir` writable by us alone, or check that an existing one is
+#[cfg(unix)]
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    match std::fs::DirBuilder::new().mode(0o700).create(dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => return Err(e),
+        _ => {}
+    }
+    let meta = std::fs::symlink_metadata(dir)?;
+    if !meta.is_dir() {
+        return Err(std::io::Error::other("not a directory"));
+    }
+    check_owner(&meta)?;
+    if meta.mode() & 0o022 != 0 {
+        return Err(std::io::Error::other("writable by other users"));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    std::fs::create_dir_all(dir)
+}
+
+/// Refuse files owned by another user
+#[cfg(unix)]
+fn check_owner(meta: &std::fs::Metadata) -> std::io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    // SAFETY: geteuid has no preconditions
+    if meta.uid() != unsafe { libc::geteuid() } {
+        return Err(std::io::Error::other("owned by another user"));
+    }
+    Ok(())
+}
+
+/// Open options that never follow a symlink, creating files private
+fn no_follow() -> OpenOptions {
+    #[allow(unused_mut)]
+    let mut options = OpenOptions::new();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.custom_flags(libc::O_NOFOLLOW).mode(0o600);
+    }
+    options
+}
+
+/// Remove routes left behind by a run that didn't exit cleanly. Returns how
+/// many were listed.
+pub fn recover(state_file: &Path) -> anyhow::Result<usize> {
+    let routes = read_state(state_file)?;
+    if routes.is_empty() {
+        return Ok(0);
+    }
+    info!(
+        "Removing {} route(s) left behind by a previous run",
+        routes.len()
+    );
+    for route in routes.iter().rev() {
+        // Already gone if the system rebooted or the interface went away
+        if let Err(e) = run(&mut route_command(Action::Delete, route)) {
+            debug!("Stale route {} not removed: {:#}", route, e);
+        }
+    }
+    remove_state(state_file)?;
+    Ok(routes.len())
+}
+
+fn read_state(path: &Path) -> anyhow::Result<Vec<Route>> {
+    let mut file = match no_follow().read(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => anyhow::bail!("Can't open {}: {e}", path.display()),
+    };
+    #[cfg(unix)]
+    check_owner(&file.metadata()?)
+        .map_err(|e| anyhow::anyhow!("Refusing {}: {e}", path.display()))?;
+    let mut text = String::new();
+    file.read_to_string(&mut text)?;
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Replace the state file. A fresh file is created exclusively beside it
+/// and renamed over it, so nothing already at either path is written
+/// through.
+fn write_state(path: &Path, routes: &[Route]) -> anyhow::Result<()> {
+    let temp = path.with_extension("new");
+    remove_state(&temp)?;
+    let mut file = no_follow().write(true).create_new(true).open(&temp)?;
+    for route in routes {
+        writeln!(file, "{route}")?;
+    }
+    file.sync_all()?;
+    std::fs::rename(&temp, path)?;
+    Ok(())
+}
+
+fn remove_state(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Add,
+    Delete,
+}
+
+/// Command that adds or deletes a route on this platform
+fn route_command(action: Action, route: &Route) -> Command {
+    if cfg!(target_os = "linux") {
+        linux_command(action, route)
+    } else if cfg!(windows) {
+        windows_command(action, route)
+    } else {
+        bsd_command(action, route)
+    }
+}
+
+/// `ip route add 10.0.0.0/8 via 192.168.1.1 dev eth0`
+fn linux_command(action: Action, route: &Route) -> Command {
+    let mut cmd = Command::new("ip");
+    let verb = match action {
+        Action::Add => "add",
+        Action::Delete => "del",
+    };
+    cmd.arg("route")
+        .arg(verb)
+        .arg(route.destination.to_string());
+    match &route.next_hop {
+        NextHop::Gateway { address, interface } => {
+            cmd.arg("via").arg(address.to_string());
+            if let Some(interface) = interface {
+                cmd.arg("dev").arg(interface);
+            }
+        }
+        NextHop::Interface(interface) => {
+            cmd.arg("dev").arg(interface);
+        }
+    }
+    cmd
+}
+
+/// `route -n add -net 10.0.0.0/8 192.168.1.1` (macOS and the BSDs)
+fn bsd_command(action: Action, route: &Route) -> Command {
+    let mut cmd = Command::new("route");
+    let verb = match action {
+        Action::Add => "add",
+        Action::Delete => "delete",
+    };
+    cmd.arg("-n").arg(verb);
+    if let IpNet::V6(_) = route.destination {
+        cmd.arg("-inet6");
+    }
+    cmd.arg("-net").arg(route.destination.to_string());
+    match &route.next_hop {
+        NextHop::Gateway { address, .. } => {
+            cmd.arg(address.to_string());
+        }
+        NextHop::Interface(interface) => {
+            cmd.arg("-interface").arg(interface);
+        }
+    }
+    cmd
+}
+
+/// `route ADD 10.0.0.0 MASK 255.0.0.0 192.168.1.1`, or netsh for routes
+/// straight out of an interface
+fn windows_command(action: Action, route: &Route) -> Command {
+    match &route.next_hop {
+        NextHop::Gateway { address, .. } => {
+            let mut cmd = Command::new("route");
+            cmd.arg(match action {
+                Action::Add => "ADD",
+                Action::Delete => "DELETE",
+            });
+            match route.destination {
+                IpNet::V4(net) => {
+                    cmd.arg(net.network().to_string())
+                        .arg("MASK")
+                        .arg(net.netmask().to_string());
+                }
+                IpNet::V6(net) => {
+                    cmd.arg(net.to_string());
+                }
+            }
+            cmd.arg(address.to_string());
+            cmd
+        }
+        NextHop::Interface(interface) => {
+            let mut cmd = Command::new("netsh");
+            let family = match route.destination {
+                IpNet::V4(_) => "ipv4",
+                IpNet::V6(_) => "ipv6",
+            };
+            let verb = match action {
+                Action::Add => "add",
+                Action::Delete => "delete",
+            };
+            cmd.args(["interface", family, verb, "route"])
+                .arg(format!("prefix={}", route.destination))
+                .arg(format!("interface={interface}"))
+                .arg("store=active");
+            cmd
+        }
+    }
+}
+
+/// The gateway (and interface) the system currently uses for `addr`
+pub fn next_hop_for(addr: IpAddr) -> anyhow::Result<NextHop> {
+    let addr = addr.to_string();
+    let next_hop = if cfg!(target_os = "linux") {
+        parse_ip_route_get(&run(Command::new("ip").args(["route", "get", &addr]))?)
+    } else if cfg!(windows) {
+        let script =
+            format!("(Find-NetRoute -RemoteIPAddress {addr} | Select-Object -Last 1).NextHop");
+        let output = run(Command::new("powershell").args(["-NoProfile", "-Command", &script]))?;
+        output.trim().parse().ok().map(|address| NextHop::Gateway {
+            address,
+            interface: None,
+        })
+    } else {
+        parse_route_get(&run(Command::new("route").args(["-n", "get", &addr]))?)
+    };
+    next_hop.ok_or_else(|| anyhow::anyhow!("no route to {addr}"))
+}
+
+/// Parse `ip route get` output: `1.2.3.4 via 192.168.1.1 dev wlan0 src ...`
+fn parse_ip_route_get(output: &str) -> Option<NextHop> {
+    let words: Vec<&str> = output.split_whitespace().collect();
+    let value = |key: &str| {
+        words
+            .windows(2)
+            .find(|pair| pair[0] == key)
+            .map(|pair| pair[1])
+    };
+    let interface = value("dev").map(str::to_string);
+    match value("via").and_then(|via| via.parse().ok()) {
+        Some(address) => Some(NextHop::Gateway { address, interface }),
+        None => interface.map(NextHop::Interface),
+    }
+}
+
+/// Parse `route -n get` output (`gateway: ...` / `interface: ...` lines)
+fn parse_route_get(output: &str) -> Option<NextHop> {
+    let value = |key: &str| {
+        output.lines().find_map(|line| {
+            let (k, v) = line.split_once(':')?;
+            (k.trim() == key).then(|| v.trim())
+        })
+    };
+    let interface = value("interface").map(str::to_string);
+    match value("gateway").and_then(|gw| gw.parse().ok()) {
+        Some(address) => Some(NextHop::Gateway { address, interface }),
+        None => interface.map(NextHop::Interface),
+    }
+}
+
+/// Run a command, returning its stdout or failing with its stderr
+pub(crate) fn run(cmd: &mut Command) -> anyhow::Result<String> {
+    let program = cmd.get_program().to_string_lossy().into_owned();
+    let output = cmd
+        .output()
+        .map_err(|e| anyhow::anyhow!("failed to run {program}: {e}"))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{program} failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_route_text_roundtrip() {
+        for text in [
+            "10.0.0.0/8 via 192.168.1.1 dev eth0",
+            "203.0.113.7/32 via 192.168.1.1",
+            "0.0.0.0/1 dev utun4",
+            "2001:db8::/32 via fe80::1 dev en0",
+        ] {
+            let route: Route = text.parse().unwrap();
+            assert_eq!(route.to_string(), text);
+        }
+        assert!("10.0.0.0/8".parse::<Route>().is_err());
+        assert!("10.0.0.0/8 via".parse::<Route>().is_err());
+    }
+
+    /// Fresh directory for one test; tempfile is only built with `tools`
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("smtp-tunnel-routes-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_state_file() {
+        let dir = scratch_dir("state");
+        let path = dir.join("routes");
+        let routes: Vec<Route> = ["198.51.100.1/32 via 10.0.0.1", "0.0.0.0/1 dev tun0"]
+            .iter()
+            .map(|r| r.parse().unwrap())
+            .collect();
+        write_state(&path, &routes).unwrap();
+        assert_eq!(read_state(&path).unwrap(), routes);
+
+        remove_state(&path).unwrap();
+        assert!(read_state(&path).unwrap().is_empty());
+        // Removing twice is fine
+        remove_state(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_state_file_not_followed() {
+        let dir = scratch_dir("symlink");
+        let target = dir.join("target");
+        std::fs::write(&target, "198.51.100.1/32 via 10.0.0.1\n").unwrap();
+        let path = dir.join("routes");
+        std::os::unix::fs::symlink(&target, &path).unwrap();
+        assert!(read_state(&path).is_err());
+
+        // Writing replaces the link instead of writing through it
+        write_state(&path, &[]).unwrap();
+        assert!(!std::fs::symlink_metadata(&path).unwrap().is_symlink());
+        assert_eq!(
+            std::fs::read_to_string(&target).unwrap(),
+            "198.51.100.1/32 via 10.0.0.1\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_private_dir() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let parent = scratch_dir("private");
+        let dir = parent.join("state");
+        create_private_dir(&dir).unwrap();
+        let mode = std::fs::metadata(&dir).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        // Existing and still private is fine; opened up to others isn't
+        create_private_dir(&dir).unwrap();
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(create_private_dir(&dir).is_err());
+        std::fs::remove_dir_all(&parent).unwrap();
+    }
+
+    #[test]
+    fn test_parse_next_hop() {
+        let linux = "203.0.113.7 via 192.168.1.1 dev wlan0 src 192.168.1.20 uid 0 \n    cache \n";
+        assert_eq!(
+            parse_ip_route_get(linux),
+            Some(NextHop::Gateway {
+                address: "192.168.1.1".parse().unwrap(),
+                interface: Some("wlan0".into()),
+            })
+        );
+        let onlink = "10.8.0.5 dev tun0 src 10.8.0.2 uid 0";
+        assert_eq!(
+            parse_ip_route_get(onlink),
+            Some(NextHop::Interface("tun0".into()))
+        );
+
+        let macos = "   route to: 203.0.113.7\ndestination: default\n       mask: default\n    gateway: 192.168.1.1\n  interface: en0\n      flags: <UP,GATEWAY,DONE,STATIC,PRCLONING>\n";
+        assert_eq!(
+            parse_route_get(macos),
+            Some(NextHop::Gateway {
+                address: "192.168.1.1".parse().unwrap(),
+                interface: Some("en0".into()),
+            })
+        );
+    }
+
+    #[test]
+    fn test_pin_route_after_takeover() {
+        let installed: Vec<Route> = [
+            "198.51.100.1/32 via 192.168.1.1 dev eth0",
+            "0.0.0.0/1 dev tun0",
+            "128.0.0.0/1 dev tun0",
+        ]
+        .iter()
+        .map(|r| r.parse().unwrap())
+        .collect();
+        let original: NextHop = "0.0.0.0/0 via 192.168.1.1 dev eth0"
+            .parse::<Route>()
+            .unwrap()
+            .next_hop;
+        let pin = |server: &str, current: NextHop| {
+            pin_route(
+                &installed,
+                Some(&original),
+                server.parse().unwrap(),
+                current,
+            )
+            .unwrap()
+        };
+
+        // A server added by a reload resolves into the device; it goes the
+        // way the default route did instead
+        assert_eq!(
+            pin("203.0.113.7", NextHop::Interface("tun0".into())),
+            Some(Route::host(
+                "203.0.113.7".parse().unwrap(),
+                original.clone()
+            ))
+        );
+        // One on a more specific route is pinned to that
+        let lan = NextHop::Interface("eth1".into());
+        assert_eq!(
+            pin("10.0.0.5", lan.clone()),
+            Some(Route::host("10.0.0.5".parse().unwrap(), lan))
+        );
+        // Pinned already
+        assert_eq!(pin("198.51.100.1", NextHop::Interface("tun0".into())), None);
+
+        // Without the old default there's nowhere to send it
+        let server = "203.0.113.7".parse().unwrap();
+        assert!(pin_route(&installed, None, server, NextHop::Interface("tun0".into())).is_err());
+    }
+
+    #[test]
+    fn test_route_commands() {
+        let route: Route = "203.0.113.7/32 via 192.168.1.1 dev eth0".parse().unwrap();
+        let args = |cmd: Command| -> Vec<String> {
+            std::iter::once(cmd.get_program())
+                .chain(cmd.get_args())
+                .map(|a| a.to_string_lossy().into_owned())
+                .collect()
+        };
+
+        assert_eq!(
+            args(linux_command(Action::Add, &route)).join(" "),
+            "ip route add 203.0.113.7/32 via 192.168.1.1 dev eth0"
+        );
+        assert_eq!(
+            args(bsd_command(Action::Delete, &route)).join(" "),
+            "route -n delete -net 203.0.113.7/32 192.168.1.1"
+        );
+        assert_eq!(
+            args(windows_command(Action::Add, &route)).join(" "),
+            "route ADD 203.0.113.7 MASK 255.255.255.255 192.168.1.1"
+        );
+
+        let tun: Route = "0.0.0.0/1 dev utun4".parse().unwrap();
+        assert_eq!(
+            args(bsd_command(Action::Add, &tun)).join(" "),
+            "route -n add -net 0.0.0.0/1 -interface utun4"
+        );
+    }
+}
@@ -2,52 +2,314 @@
 //!
 //! Accepts SMTP connections, authenticates clients, and forwards traffic.
 
-use crate::config::{ServerConfig, UsersConfig};
-use crate::crypto::AuthToken;
+use crate::acl::{DestinationAcl, HoneypotEntry, HoneypotLog};
+#[cfg(not(feature = "minimal"))]
+use crate::admin;
+use crate::camouflage;
+use crate::channel::{Activity, ChannelRegistry};
+use crate::config::{DataChannel, ServerConfig, UsersConfig};
+use crate::crypto::{AffinityToken, AuthToken, KeyExchange, Role};
+use crate::inbound::{self, Envelope};
+use crate::knock::KnockGate;
+use crate::link::{Attachment, Batching, Detached, Link, LinkOptions, SessionId, close_gracefully};
+use crate::metrics::{ServerMetrics, Violation, Violations};
+use crate::platform::FdLimit;
+use crate::proto::chunking::{self, Chunked};
+use crate::proto::compress::Compression;
+use crate::proto::flow::{RecvWindow, SendWindow};
+use crate::proto::hello::{Features, hello_server};
+use crate::proto::padding::Padding;
+use crate::proto::profile::Profile;
 use crate::proto::*;
-use bytes::{Buf, BytesMut};
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use crate::resolver::Resolver;
+use crate::rotation::PortSchedule;
+use crate::tasks::TaskGroup;
+use crate::throttle::{DestinationThrottle, Refusal};
+use crate::tls::CertInfo;
+use crate::users::{UserEvent, UserStore};
+use bytes::{Bytes, BytesMut};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{RwLock, mpsc};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{RwLock, Semaphore, mpsc};
+use tokio::task::JoinHandle;
+use tokio_rustls::rustls::pki_types::ServerName;
 use tracing::{debug, info, trace, warn};
 
+/// How long the startup self-test may take before it's considered failed
+const SELF_TEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often the served certificate's expiry is re-checked
+const CERT_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Timeout for outbound connections opened on behalf of a channel
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a session whose connection dropped waits for the client to
+/// resume it
+const RESUME_GRACE: Duration = Duration::from_secs(60);
+
+/// How long shutdown waits for connection and session tasks to stop
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a CONNECT may wait for an outbound connect slot
+const CONNECT_QUEUE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Range of delays (ms) before answering a request rejected in honeypot mode
+const HONEYPOT_DELAY_MS: std::ops::Range<u64> = 200..2500;
+
+/// File descriptors kept free below the open-file limit; new sessions are
+/// refused with 421 once fewer than this many remain
+const FD_SAFETY_MARGIN: u64 = 64;
+
+/// Pause after a failed accept, so e.g. running out of file descriptors
+/// doesn't spin
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+/// How long an estimated open descriptor count is trusted before the
+/// descriptors are counted again
+const FD_RECOUNT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often a metrics snapshot is logged
+const METRICS_LOG_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How long a UDP association is kept without datagrams either way
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Client datagrams queued per UDP association; more are dropped
+const UDP_RELAY_QUEUE: usize = 64;
+
+/// Largest read from a channel's destination; a full send window
+const MAX_CHANNEL_READ: usize = crate::proto::flow::INITIAL_WINDOW as usize;
+
 /// Server state
 pub struct Server {
     config: ServerConfig,
     users: Arc<RwLock<UsersConfig>>,
+    /// Where users are loaded from and saved to
+    store: Arc<dyn UserStore>,
     tls_acceptor: tokio_rustls::TlsAcceptor,
+    cert_info: Option<CertInfo>,
+    acl: Arc<DestinationAcl>,
+    honeypot: Option<Arc<HoneypotLog>>,
+    resolver: Arc<Resolver>,
+    connect_slots: Arc<Semaphore>,
+    channel_slots: Arc<Semaphore>,
+    throttle: Arc<DestinationThrottle>,
+    metrics: Arc<ServerMetrics>,
+    fd_limit: Option<FdLimit>,
+    /// Addresses allowed to connect, when knocking is required
+    knock: Option<Arc<KnockGate>>,
+    /// Binary-mode sessions a reconnecting client can resume
+    sessions: Sessions,
+    /// Operator announcement sent in HELLO; replaced on SIGHUP
+    announcement: Arc<std::sync::RwLock<Option<String>>>,
+    /// Replies of the MTA the server imitates, as the operator customized
+    /// them
+    smtp: Arc<Profile>,
+    /// Connection and session tasks
+    tasks: TaskGroup,
+}
+
+type Sessions = Arc<std::sync::Mutex<HashMap<SessionId, Resumable>>>;
+
+/// A binary-mode session, kept while it can be resumed
+#[derive(Clone)]
+struct Resumable {
+    username: String,
+    link: Arc<Link>,
+    /// Whether the session logs its connects; follows the user's settings
+    log_connects: Arc<AtomicBool>,
+    /// Address of the client currently carrying the session
+    client_ip: Arc<std::sync::Mutex<IpAddr>>,
+    /// The session's frame loop and channels
+    tasks: TaskGroup,
+}
+
+/// Ends a session when dropped: forgets it, closes its link and cancels
+/// its tasks, dropping their sockets. Being a drop guard, this also runs
+/// when the task holding it panics.
+struct SessionGuard {
+    sessions: Sessions,
+    session: Option<Resumable>,
+}
+
+impl SessionGuard {
+    fn new(sessions: &Sessions, session: Resumable) -> Self {
+        Self {
+            sessions: Arc::clone(sessions),
+            session: Some(session),
+        }
+    }
+
+    /// Leave the session running
+    fn disarm(mut self) {
+        self.session = None;
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let Some(session) = self.session.take() else {
+            return;
+        };
+        // A panic elsewhere may have poisoned the lock; the map is still fine
+        self.sessions
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .remove(&session.link.id());
+        session.link.close();
+        session.tasks.cancel();
+    }
+}
+
+/// Log what changed from `old` to `new` users and bring live sessions in
+/// line: sessions of removed users, or of users whose secret changed, end
+/// at once; others follow the new logging setting, and end if their client
+/// is no longer whitelisted.
+fn apply_user_changes(sessions: &Sessions, log_users: bool, old: &UsersConfig, new: &UsersConfig) {
+    let events = crate::users::diff(old, new);
+    if events.is_empty() {
+        return;
+    }
+    let mut revoked = HashSet::new();
+    let mut changed = HashSet::new();
+    for event in &events {
+        info!("Users changed: {}", event);
+        match event {
+            UserEvent::Removed(user) | UserEvent::SecretChanged(user) => {
+                revoked.insert(user.as_str());
+            }
+            UserEvent::PolicyChanged(user) => {
+                changed.insert(user.as_str());
+            }
+            UserEvent::Added(_) => {}
+        }
+    }
+
+    let mut ended = Vec::new();
+    sessions
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .retain(|_, session| {
+            let username = session.username.as_str();
+            let reason = if revoked.contains(username) {
+                "credentials revoked"
+            } else if changed.contains(username)
+                && let Some(user) = new.get_user(username)
+            {
+                session
+                    .log_connects
+                    .store(log_users && user.logging, Ordering::Relaxed);
+                if user.allows(*session.client_ip.lock().unwrap()) {
+                    return true;
+                }
+                "address no longer whitelisted"
+            } else {
+                return true;
+            };
+            ended.push((session.clone(), reason));
+            false
+        });
+    for (session, reason) in ended {
+        info!(
+            "Ending session {} for {}: {}",
+            session.link.id(),
+            session.username,
+            reason
+        );
+        session.link.close();
+        session.tasks.cancel();
+    }
+}
+
+/// Open descriptor estimate for the accept loop. Each accepted connection
+/// counts one more; the descriptors are only counted again when the
+/// estimate nears the limit or has gone stale, not on every accept.
+struct FdCount {
+    limit: u64,
+    open: u64,
+    counted: std::time::Instant,
+}
+
+impl FdCount {
+    fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            open: crate::platform::open_fds().unwrap_or(0),
+            counted: std::time::Instant::now(),
+        }
+    }
+
+    /// Count an accepted connection, returning the open descriptors when
+    /// within the safety margin of the limit
+    fn accepted(&mut self) -> Option<u64> {
+        self.open += 1;
+        // Closed connections aren't subtracted, so recount before refusing
+        if self.near_limit() || self.counted.elapsed() >= FD_RECOUNT_INTERVAL {
+            self.open = crate::platform::open_fds()?;
+            self.counted = std::time::Instant::now();
+        }
+        self.near_limit().then_some(self.open)
+    }
+
+    fn near_limit(&self) -> bool {
+        self.open.saturating_add(FD_SAFETY_MARGIN) >= self.limit
+    }
 }
 
 /// Session state for a connected client
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 struct Session {
     username: Option<String>,
     state: smtp::State,
     binary_mode: bool,
-    channels: HashMap<u16, Channel>,
     client_addr: SocketAddr,
 }
 
-/// A tunneled channel
+/// Per-session state shared with channel tasks
+struct SessionContext {
+    username: String,
+    client_addr: SocketAddr,
+    log_connects: Arc<AtomicBool>,
+    acl: Arc<DestinationAcl>,
+    honeypot: Option<Arc<HoneypotLog>>,
+    resolver: Arc<Resolver>,
+    connect_slots: Arc<Semaphore>,
+    /// Channels allowed open at once across all sessions
+    channel_slots: Arc<Semaphore>,
+    /// Connections open to, and recently made to, each destination host
+    throttle: Arc<DestinationThrottle>,
+    /// Close channels carrying no data for this long
+    channel_idle_timeout: Option<Duration>,
+    violations: Violations,
+    /// The session's frame loop and channel tasks, cancelled when it ends
+    tasks: TaskGroup,
+}
+
+/// A UDP association: the client's datagrams, queued for its relay task
 #[derive(Debug)]
-#[allow(dead_code)]
-struct Channel {
-    tx: mpsc::Sender<Vec<u8>>,
-    _task: tokio::task::JoinHandle<()>,
+struct UdpRelay {
+    tx: mpsc::Sender<(String, u16, Bytes)>,
+    task: JoinHandle<()>,
 }
 
-impl Clone for Channel {
-    fn clone(&self) -> Self {
-        // This is a placeholder - in practice, we wouldn't clone channels often
-        let (tx, _) = mpsc::channel(1);
-        Self {
-            tx,
-            _task: tokio::spawn(async {}),
-        }
-    }
+/// A tunneled channel: queue of client data for its egress task and the
+/// flow-control windows of both directions
+#[derive(Debug)]
+struct Channel {
+    /// Unbounded, but the client can't queue more than its send window.
+    /// None once the client has shut down its side.
+    tx: Option<mpsc::UnboundedSender<Bytes>>,
+    send_window: SendWindow,
+    recv_window: RecvWindow,
+    task: JoinHandle<()>,
+    /// Tells this channel apart from a later one reusing its ID
+    serial: u64,
 }
 
 impl Server {
@@ -65,46 +327,562 @@ impl Server {
         let key = rustls_pemfile::private_key(&mut key_file.as_slice())?
             .ok_or_else(|| anyhow::anyhow!("No private key found"))?;
 
-        let tls_config = tokio_rustls::rustls::ServerConfig::builder()
-            .with_no_client_auth()
-            .with_single_cert(certs, key)?;
+        let cert_info = certs.first().and_then(|der| match CertInfo::from_der(der) {
+            Ok(info) => Some(info),
+            Err(e) => {
+                warn!("Could not inspect certificate: {}", e);
+                None
+            }
+        });
+
+        let tls_config =
+            tokio_rustls::rustls::ServerConfig::builder_with_provider(crate::tls::provider())
+                .with_safe_default_protocol_versions()?
+                .with_no_client_auth()
+                .with_single_cert(certs, key)?;
 
         let tls_acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(tls_config));
 
+        let acl = DestinationAcl::new(&config.blocked_destinations)?;
+        let honeypot = match &config.honeypot_log {
+            Some(path) => Some(Arc::new(HoneypotLog::open(path).await?)),
+            None => None,
+        };
+        let resolver = Resolver::new(&config.resolver)?;
+        if let Some(affinity) = &config.affinity
+            && (affinity.node.is_empty() || affinity.node.contains([':', ' ']))
+        {
+            anyhow::bail!("Invalid affinity node name {:?}", affinity.node);
+        }
+        if let Some(inbound) = &config.camouflage.inbound_mail
+            && inbound.maildir.is_none()
+            && inbound.forward.is_none()
+        {
+            anyhow::bail!("inbound_mail needs a maildir or a forward address");
+        }
+        let connect_slots = connect_slots(&config);
+        let channel_slots = channel_slots(&config);
+        let throttle = DestinationThrottle::new(config.destination_limits.as_ref());
+        let fd_limit = crate::platform::raise_fd_limit();
+        let knock = config
+            .knock
+            .as_ref()
+            .map(|knock| Arc::new(KnockGate::new(Duration::from_secs(knock.window))));
+
+        crate::config::check_binary_verb(&config.binary_verb)?;
+        check_announcement(config.announcement.as_deref())?;
+        let announcement = Arc::new(std::sync::RwLock::new(config.announcement.clone()));
+        let camouflage = &config.camouflage;
+        let smtp = camouflage
+            .profile
+            .replies()
+            .customized(&camouflage.extensions, &camouflage.replies)
+            .map_err(|e| anyhow::anyhow!("Invalid camouflage: {e}"))?;
+        let store = Arc::from(crate::users::open(&config)?);
+
         Ok(Self {
             config,
             users: Arc::new(RwLock::new(users)),
+            store,
             tls_acceptor,
+            cert_info,
+            acl: Arc::new(acl),
+            honeypot,
+            resolver: Arc::new(resolver),
+            connect_slots: Arc::new(connect_slots),
+            channel_slots: Arc::new(channel_slots),
+            throttle: Arc::new(throttle),
+            metrics: Arc::new(ServerMetrics::default()),
+            fd_limit,
+            knock,
+            sessions: Arc::default(),
+            announcement,
+            smtp: Arc::new(smtp),
+            tasks: TaskGroup::new(),
         })
     }
 
-    /// Reload users from file
+    /// Replace the announcement sent to clients from their next handshake
+    pub fn set_announcement(&self, announcement: Option<String>) -> anyhow::Result<()> {
+        check_announcement(announcement.as_deref())?;
+        let mut current = self.announcement.write().unwrap();
+        if *current != announcement {
+            match &announcement {
+                Some(text) => info!("Announcement set: {}", text),
+                None => info!("Announcement cleared"),
+            }
+            *current = announcement;
+        }
+        Ok(())
+    }
+
+    /// Reload users from the store
     pub async fn reload_users(&self) -> anyhow::Result<()> {
-        let users = UsersConfig::from_file(&self.config.users_file)?;
-        let mut guard = self.users.write().await;
-        *guard = users;
+        self.load_users().await?;
         info!("Reloaded users configuration");
         Ok(())
     }
 
+    /// Replace the users with the store's, returning how many there are
+    async fn load_users(&self) -> anyhow::Result<usize> {
+        let store = Arc::clone(&self.store);
+        let users = tokio::task::spawn_blocking(move || store.load()).await??;
+        let count = users.users.len();
+        let mut guard = self.users.write().await;
+        let old = std::mem::replace(&mut *guard, users);
+        apply_user_changes(&self.sessions, self.config.log_users, &old, &guard);
+        Ok(count)
+    }
+
     /// Run the server
+    ///
+    /// Binds the listener, logs a startup summary and runs a loopback
+    /// self-test alongside the accept loop; a failed self-test stops the
+    /// server so misconfigurations surface at startup, not on first client.
+    /// The certificate's expiry is checked at startup and periodically.
     pub async fn run(&self) -> anyhow::Result<()> {
         let addr = self.config.bind_addr()?;
         let listener = TcpListener::bind(&addr).await?;
-        info!("SMTP Tunnel Server listening on {}", addr);
-        info!("Hostname: {}", self.config.hostname);
+        let local_addr = listener.local_addr()?;
+        info!("SMTP Tunnel Server listening on {}", local_addr);
+        // Implicit TLS runs the same session logic, minus the plaintext
+        // EHLO/STARTTLS exchange
+        let smtps = match self.config.smtps_bind_addr()? {
+            Some(addr) => {
+                let listener = TcpListener::bind(&addr).await?;
+                info!(
+                    "Implicit TLS (SMTPS) listening on {}",
+                    listener.local_addr()?
+                );
+                Some(listener)
+            }
+            None => None,
+        };
+        #[cfg(all(unix, not(feature = "minimal")))]
+        if let Some(admin) = &self.config.admin {
+            let listener = bind_admin_socket(&admin.socket)?;
+            info!("Admin socket on {}", admin.socket);
+            let server = self.clone();
+            self.tasks
+                .spawn("admin socket", server.listen_admin(listener));
+        }
+        #[cfg(feature = "minimal")]
+        if self.config.admin.is_some() {
+            warn!("The admin socket isn't available in minimal builds");
+        }
+        if let Some(interval) = self.store.refresh_interval() {
+            let server = self.clone();
+            self.tasks
+                .spawn("users refresh", server.refresh_users(interval));
+        }
+        if let (Some(gate), Some(addr)) = (&self.knock, self.config.knock_bind_addr()?) {
+            let socket = UdpSocket::bind(&addr).await?;
+            info!("Knock listener on {} (udp)", socket.local_addr()?);
+            let server = self.clone();
+            let gate = Arc::clone(gate);
+            self.tasks
+                .spawn("knock listener", server.listen_knocks(socket, gate));
+        }
+        // Connections on rotating ports join the accept loop below
+        let (rotated_tx, mut rotated) = mpsc::channel(16);
+        if let Some(rotation) = &self.config.port_rotation {
+            let schedule = PortSchedule::from_config(rotation)?;
+            info!(
+                "Rotating ports {}-{} every {}s",
+                rotation.min_port, rotation.max_port, rotation.period
+            );
+            self.tasks.spawn(
+                "port rotation",
+                self.clone().rotate_ports(schedule, rotated_tx),
+            );
+        }
+        self.log_summary(local_addr).await;
+
+        let self_test = self.self_test(local_addr);
+        tokio::pin!(self_test);
+        let mut self_test_done = false;
+        let mut cert_check = tokio::time::interval(CERT_CHECK_INTERVAL);
+        let mut metrics_log = tokio::time::interval(METRICS_LOG_INTERVAL);
+        metrics_log.tick().await;
+        let mut fds = self.fd_limit.map(|limit| FdCount::new(limit.soft));
 
         loop {
-            let (stream, addr) = listener.accept().await?;
+            let ((stream, addr), implicit_tls) = tokio::select! {
+                _ = cert_check.tick(), if self.cert_info.is_some() => {
+                    if let Some(cert) = &self.cert_info {
+                        cert.check_expiry("Server certificate", self.config.cert_warn_days);
+                    }
+                    continue;
+                }
+                _ = metrics_log.tick(), if !cfg!(feature = "minimal") => {
+                    info!("Metrics: {}", self.metrics.snapshot());
+                    let frames = self.metrics.frames().snapshot();
+                    info!("Frames sent: {}", frames.sent);
+                    info!("Frames received: {}", frames.received);
+                    continue;
+                }
+                result = &mut self_test, if !self_test_done => {
+                    self_test_done = true;
+                    result.map_err(|e| anyhow::anyhow!("Startup self-test failed: {e:#}"))?;
+                    info!("Startup self-test passed (EHLO + STARTTLS over loopback)");
+                    continue;
+                }
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => (accepted, false),
+                    Err(e) => {
+                        debug!("Accept error: {}", e);
+                        tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                        continue;
+                    }
+                },
+                accepted = accept_optional(smtps.as_ref()) => match accepted {
+                    Ok(accepted) => (accepted, true),
+                    Err(e) => {
+                        debug!("Accept error on the SMTPS port: {}", e);
+                        tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                        continue;
+                    }
+                },
+                Some(accepted) = rotated.recv() => (accepted, false),
+            };
             trace!("Connection from {}", addr);
+            if let Some(gate) = &self.knock
+                && !gate.is_open(addr.ip())
+            {
+                trace!("Resetting {}: no knock", addr);
+                crate::platform::reset(stream);
+                continue;
+            }
+            if let Some(open) = fds.as_mut().and_then(FdCount::accepted) {
+                self.metrics.session_refused();
+                debug!("Refusing {}: {} file descriptors open", addr, open);
+                let response = self.smtp().service_unavailable(&self.config.hostname);
+                let _ = crate::platform::send_now(&stream, response.as_bytes());
+                continue;
+            }
+            crate::platform::configure_stream(&stream);
 
             let server = Arc::new(self.clone());
+            // Each connection gets its own group, so a panic in one cancels
+            // nothing else
+            self.tasks
+                .child()
+                .spawn(format!("connection from {addr}"), async move {
+                    let _active = server.metrics.session_started();
+                    let result = if implicit_tls {
+                        server.handle_implicit_tls(stream, addr).await
+                    } else {
+                        server.handle_client(stream, addr).await
+                    };
+                    if let Err(e) = result {
+                        debug!("Client error from {}: {}", addr, e);
+                    }
+                });
+        }
+    }
+
+    /// Open the gate for addresses sending valid knocks. Knocks get no
+    /// reply either way.
+    async fn listen_knocks(self, socket: UdpSocket, gate: Arc<KnockGate>) {
+        let mut buf = [0u8; 512];
+        loop {
+            let (n, from) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    debug!("Knock receive error: {}", e);
+                    continue;
+                }
+            };
+            let users = self.users.read().await;
+            match gate.knock(&buf[..n], from.ip(), &users) {
+                Some(username) => debug!("Knock from {} for {}", from, username),
+                None => trace!("Ignored knock from {}", from),
+            }
+        }
+    }
+
+    /// Reload users from a store that changes on its own, keeping the
+    /// current ones when it can't be reached
+    async fn refresh_users(self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        // The first tick is immediate, and the users were just loaded
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            match self.load_users().await {
+                Ok(count) => debug!("Refreshed users ({} users)", count),
+                Err(e) => warn!("Failed to refresh users: {}", e),
+            }
+        }
+    }
+
+    /// Answer user-management requests on the admin socket
+    #[cfg(all(unix, not(feature = "minimal")))]
+    async fn listen_admin(self, listener: tokio::net::UnixListener) {
+        loop {
+            let mut stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    debug!("Admin socket accept error: {}", e);
+                    tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                    continue;
+                }
+            };
+            let server = self.clone();
             tokio::spawn(async move {
-                if let Err(e) = server.handle_client(stream, addr).await {
-                    debug!("Client error from {}: {}", addr, e);
+                let mut request = String::new();
+                let response = match (&mut stream)
+                    .take(admin::MAX_REQUEST)
+                    .read_to_string(&mut request)
+                    .await
+                {
+                    Ok(_) => match serde_yaml::from_str(&request) {
+                        Ok(request) => server.admin_request(request).await,
+                        Err(e) => admin::Response::error(format!("Invalid request: {e}")),
+                    },
+                    Err(e) => admin::Response::error(format!("Invalid request: {e}")),
+                };
+                let response = serde_yaml::to_string(&response).unwrap_or_default();
+                if let Err(e) = stream.write_all(response.as_bytes()).await {
+                    debug!("Admin socket write error: {}", e);
+                }
+            });
+        }
+    }
+
+    /// Carry out an admin request. Changes are saved to the store while
+    /// the users lock is held, so a reload can't interleave.
+    #[cfg(not(feature = "minimal"))]
+    pub async fn admin_request(&self, request: admin::Request) -> admin::Response {
+        let read_only = self.config.admin.as_ref().is_some_and(|a| a.read_only);
+        if read_only && request.is_write() {
+            return admin::Response::error("The admin socket is read-only");
+        }
+        let mut users = self.users.write().await;
+        let previous = users.clone();
+        match request {
+            admin::Request::ListUsers => {
+                return admin::Response::Users {
+                    users: users.clone(),
+                };
+            }
+            admin::Request::Metrics => {
+                return admin::Response::Metrics {
+                    summary: self.metrics.snapshot().to_string(),
+                    frames: self.metrics.frames().snapshot(),
+                };
+            }
+            admin::Request::AddUser { username, entry } => {
+                if users.get_user(&username).is_some() {
+                    return admin::Response::error(format!("User '{username}' already exists"));
+                }
+                info!("Adding user {} (admin socket)", username);
+                users.set_user(username, entry);
+            }
+            admin::Request::RemoveUser { username } => {
+                if users.remove_user(&username).is_none() {
+                    return admin::Response::error(format!("User '{username}' not found"));
+                }
+                info!("Removing user {} (admin socket)", username);
+            }
+        }
+        let store = Arc::clone(&self.store);
+        let saved = users.clone();
+        match tokio::task::spawn_blocking(move || store.save(&saved)).await {
+            Ok(Ok(())) => {
+                apply_user_changes(&self.sessions, self.config.log_users, &previous, &users);
+                admin::Response::Done
+            }
+            Ok(Err(e)) => {
+                *users = previous;
+                admin::Response::error(format!("Failed to save {}: {e}", self.store.describe()))
+            }
+            Err(e) => {
+                *users = previous;
+                admin::Response::error(format!("Failed to save users: {e}"))
+            }
+        }
+    }
+
+    /// Keep the schedule's current ports listening, handing their
+    /// connections to the accept loop
+    async fn rotate_ports(
+        self,
+        schedule: PortSchedule,
+        accepted: mpsc::Sender<(TcpStream, SocketAddr)>,
+    ) {
+        let mut listening: HashMap<u16, JoinHandle<()>> = HashMap::new();
+        loop {
+            let now = crate::rotation::unix_now();
+            let wanted: HashSet<u16> = schedule
+                .open_slots(now)
+                .map(|slot| schedule.port(slot))
+                .collect();
+            listening.retain(|port, task| {
+                let keep = wanted.contains(port);
+                if !keep {
+                    task.abort();
+                    info!("Rotating port {} closed", port);
                 }
+                keep
             });
+            for port in wanted {
+                if listening.contains_key(&port) {
+                    continue;
+                }
+                let listener = match TcpListener::bind((self.config.host.as_str(), port)).await {
+                    Ok(listener) => listener,
+                    Err(e) => {
+                        warn!("Could not listen on rotating port {}: {}", port, e);
+                        continue;
+                    }
+                };
+                info!("Rotating port {} listening", port);
+                let accepted = accepted.clone();
+                let task = self
+                    .tasks
+                    .spawn(format!("rotating port {port}"), async move {
+                        loop {
+                            match listener.accept().await {
+                                Ok(connection) => {
+                                    if accepted.send(connection).await.is_err() {
+                                        return;
+                                    }
+                                }
+                                Err(e) => {
+                                    debug!("Accept error on rotating port {}: {}", port, e);
+                                    tokio::time::sleep(ACCEPT_ERROR_DELAY).await;
+                                }
+                            }
+                        }
+                    });
+                listening.insert(port, task);
+            }
+            tokio::time::sleep(schedule.until_next_change(now)).await;
+        }
+    }
+
+    /// Stop all connections and sessions, waiting briefly for their tasks
+    pub async fn shutdown(&self) {
+        if !self.tasks.shutdown(SHUTDOWN_TIMEOUT).await {
+            warn!(
+                "Tasks still running after {}s, exiting anyway",
+                SHUTDOWN_TIMEOUT.as_secs()
+            );
+        }
+    }
+
+    /// Log a structured summary of the running configuration
+    async fn log_summary(&self, local_addr: SocketAddr) {
+        let users = self.users.read().await.users.len();
+        info!("Server summary:");
+        info!(
+            "  Bind address: {} (configured {}:{})",
+            local_addr, self.config.host, self.config.port
+        );
+        info!("  Hostname:     {}", self.config.hostname);
+        match &self.cert_info {
+            Some(cert) => info!(
+                "  Certificate:  {} (expires {}, {} days)",
+                cert.subject,
+                cert.expiry_date(),
+                cert.days_remaining()
+            ),
+            None => info!(
+                "  Certificate:  {} (not inspectable)",
+                self.config.cert_file
+            ),
+        }
+        info!("  Users:        {} ({})", users, self.store.describe());
+        if let Some(limit) = self.fd_limit {
+            info!("  Open files:   limit {} (hard {})", limit.soft, limit.hard);
+            // Each in-flight connect needs the outbound socket plus the
+            // session carrying it; leave room for that and the safety margin
+            let wanted = (self.config.max_concurrent_connects as u64)
+                .saturating_mul(2)
+                .saturating_add(FD_SAFETY_MARGIN * 2);
+            if limit.soft < wanted {
+                warn!(
+                    "Open-file limit {} is low for max_concurrent_connects {}; \
+                     raise it (ulimit -n / LimitNOFILE) to at least {}",
+                    limit.soft, self.config.max_concurrent_connects, wanted
+                );
+            }
+        }
+        if !self.acl.is_empty() {
+            info!(
+                "  Blocked:      {} destination rules{}",
+                self.config.blocked_destinations.len(),
+                if self.honeypot.is_some() {
+                    " (honeypot logging)"
+                } else {
+                    ""
+                }
+            );
         }
+        info!("  Resolver:     {}", self.resolver.describe());
+        info!("  Features:     {}", crate::enabled_features().join(", "));
+    }
+
+    /// Connect to our own listener and run greeting, EHLO, STARTTLS and a
+    /// TLS handshake, catching e.g. a key that doesn't match the certificate
+    async fn self_test(&self, local_addr: SocketAddr) -> anyhow::Result<()> {
+        let target = match local_addr.ip() {
+            ip if !ip.is_unspecified() => local_addr,
+            IpAddr::V4(_) => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), local_addr.port()),
+            IpAddr::V6(_) => SocketAddr::new(Ipv6Addr::LOCALHOST.into(), local_addr.port()),
+        };
+        let connector = crate::tls::self_test_connector()?;
+        let server_name = ServerName::try_from(self.config.hostname.clone())
+            .map_err(|e| anyhow::anyhow!("Invalid hostname {}: {e}", self.config.hostname))?;
+        // Let the self-test in without a knock, for as long as it runs
+        if let Some(gate) = &self.knock {
+            gate.open(target.ip(), std::time::Instant::now());
+        }
+
+        let test = async {
+            let mut stream = TcpStream::connect(target).await?;
+            let mut buf = BytesMut::with_capacity(1024);
+
+            let greeting = read_line(&mut stream, &mut buf).await?.unwrap_or_default();
+            if !greeting.starts_with("220") {
+                anyhow::bail!("unexpected greeting: {greeting:?}");
+            }
+
+            stream.write_all(b"EHLO self-test.localhost\r\n").await?;
+            loop {
+                let line = read_line(&mut stream, &mut buf).await?.unwrap_or_default();
+                if line.starts_with("250 ") {
+                    break;
+                }
+                if !line.starts_with("250-") {
+                    anyhow::bail!("unexpected EHLO response: {line:?}");
+                }
+            }
+
+            stream.write_all(b"STARTTLS\r\n").await?;
+            let line = read_line(&mut stream, &mut buf).await?.unwrap_or_default();
+            if !line.starts_with("220") {
+                anyhow::bail!("unexpected STARTTLS response: {line:?}");
+            }
+
+            let mut tls = connector.connect(server_name, stream).await.map_err(|e| {
+                anyhow::anyhow!("TLS handshake failed (does the key match the certificate?): {e}")
+            })?;
+            tls.write_all(b"QUIT\r\n").await?;
+            tls.shutdown().await?;
+            Ok(())
+        };
+
+        // The gate is closed again however the test ends
+        let result = match tokio::time::timeout(SELF_TEST_TIMEOUT, test).await {
+            Ok(result) => result,
+            Err(_) => Err(anyhow::anyhow!("timed out connecting to {target}")),
+        };
+        if let Some(gate) = &self.knock {
+            gate.close(target.ip());
+        }
+        result
     }
 
     /// Handle a client connection
@@ -117,22 +895,35 @@ impl Server {
             username: None,
             state: smtp::State::Initial,
             binary_mode: false,
-            channels: HashMap::new(),
             client_addr: addr,
         };
 
         // Send greeting
+        camouflage::before_greeting(&self.config.camouflage.response_delays).await;
         stream
-            .write_all(smtp::Response::greeting(&self.config.hostname).as_bytes())
+            .write_all(self.smtp().greeting(&self.config.hostname).as_bytes())
             .await?;
-        session.state = smtp::State::Greeted;
 
-        // Handle SMTP commands until binary mode or disconnect
+        // Scanners often open with a TLS ClientHello or an HTTP request;
+        // drop those rather than waiting on a line that never comes
         let mut buf = BytesMut::with_capacity(1024);
+        if stream.read_buf(&mut buf).await? == 0 {
+            debug!("Client {} disconnected", addr);
+            return Ok(());
+        }
+        if let Some(protocol) = smtp::ForeignProtocol::sniff(&buf) {
+            self.metrics.non_smtp();
+            debug!("Closing {}: {} instead of SMTP", addr, protocol);
+            return Ok(());
+        }
 
+        // Handle SMTP commands until binary mode or disconnect
+        let buf = &mut buf;
+        let violations = &Violations::new(Arc::clone(&self.metrics), self.config.max_violations);
+        let mut envelope = None;
         loop {
             // Read line
-            let line = match read_line(&mut stream, &mut buf).await? {
+            let line = match read_line(&mut stream, buf).await? {
                 Some(line) => line,
                 None => {
                     debug!("Client {} disconnected", addr);
@@ -143,162 +934,69 @@ impl Server {
             trace!("Client {}: {}", addr, line);
 
             // Parse command
-            let (cmd, arg) = match smtp::parse_line(&line) {
+            let (cmd, arg) = match smtp::parse_line(&line, &self.config.binary_verb) {
                 Some(c) => c,
                 None => continue,
             };
+            camouflage::before_reply(&self.config.camouflage.response_delays, cmd).await;
 
-            // Handle command
+            let Some(next) = self
+                .check_sequence(&mut stream, violations, session.state, cmd)
+                .await?
+            else {
+                continue;
+            };
+
+            // Handle command; AUTH and BINARY never get this far in
+            // plaintext
             match cmd {
                 smtp::Command::Ehlo | smtp::Command::Helo => {
-                    if session.state == smtp::State::Initial
-                        || session.state == smtp::State::Greeted
-                    {
-                        let starttls = !matches!(
-                            session.state,
-                            smtp::State::TlsStarted | smtp::State::Authenticated
-                        );
-                        stream
-                            .write_all(
-                                smtp::Response::ehlo(&self.config.hostname, starttls).as_bytes(),
-                            )
-                            .await?;
-                        session.state = smtp::State::Greeted;
-                    } else {
-                        stream
-                            .write_all(smtp::Response::bad_sequence().as_bytes())
-                            .await?;
-                    }
+                    stream
+                        .write_all(
+                            self.smtp()
+                                .ehlo(&self.config.hostname, &arg, addr.ip(), false)
+                                .as_bytes(),
+                        )
+                        .await?;
+                    session.state = next;
                 }
 
                 smtp::Command::StartTls => {
-                    if session.state == smtp::State::Greeted {
-                        stream
-                            .write_all(smtp::Response::starttls().as_bytes())
-                            .await?;
-
-                        // Upgrade to TLS
-                        let tls_stream = self.tls_acceptor.accept(stream).await?;
-
-                        // Handle TLS session
-                        self.handle_tls_session(tls_stream, &mut session, addr, &mut buf)
-                            .await?;
-                        return Ok(());
-                    } else {
-                        stream
-                            .write_all(smtp::Response::bad_sequence().as_bytes())
-                            .await?;
-                    }
-                }
-
-                smtp::Command::Auth => {
-                    if session.state == smtp::State::Greeted {
-                        // Parse AUTH PLAIN token
-                        let parts: Vec<&str> = arg.split_whitespace().collect();
-                        if parts.len() < 2 || parts[0].to_uppercase() != "PLAIN" {
-                            stream
-                                .write_all(smtp::Response::auth_failed().as_bytes())
-                                .await?;
-                            continue;
-                        }
-
-                        let token = parts[1];
-                        let users_guard = self.users.read().await;
-
-                        // Create user secrets map
-                        let user_secrets: HashMap<String, crate::crypto::UserSecret> = users_guard
-                            .users
-                            .iter()
-                            .map(|(k, v)| (k.clone(), crate::crypto::UserSecret::new(&v.secret)))
-                            .collect();
-
-                        // Check whitelist
-                        let whitelist: HashMap<String, Vec<String>> = users_guard
-                            .users
-                            .iter()
-                            .map(|(k, v)| (k.clone(), v.whitelist.clone()))
-                            .collect();
-
-                        drop(users_guard);
-
-                        let (valid, username) = AuthToken::verify_multi_user(
-                            token,
-                            &user_secrets,
-                            300, // 5 minute max age
-                        );
+                    stream.write_all(self.smtp().starttls().as_bytes()).await?;
 
-                        if valid {
-                            let username = username.unwrap();
-
-                            // Check IP whitelist
-                            let user_whitelist = whitelist.get(&username);
-                            let whitelisted = user_whitelist
-                                .map(|w| {
-                                    if w.is_empty() {
-                                        true
-                                    } else {
-                                        let client_ip = addr.ip().to_string();
-                                        w.contains(&client_ip)
-                                    }
-                                })
-                                .unwrap_or(true);
-
-                            if !whitelisted {
-                                warn!("User {} not whitelisted from IP {}", username, addr.ip());
-                                stream
-                                    .write_all(smtp::Response::auth_failed().as_bytes())
-                                    .await?;
-                                continue;
-                            }
+                    // Upgrade to TLS
+                    let tls_stream = self.tls_acceptor.accept(stream).await?;
 
-                            session.username = Some(username.clone());
-                            session.state = smtp::State::Authenticated;
-                            stream
-                                .write_all(smtp::Response::auth_success().as_bytes())
-                                .await?;
-                            info!("User {} authenticated from {}", username, addr);
-                        } else {
-                            warn!("Authentication failed from {}", addr);
-                            stream
-                                .write_all(smtp::Response::auth_failed().as_bytes())
-                                .await?;
-                        }
-                    } else {
-                        stream
-                            .write_all(smtp::Response::bad_sequence().as_bytes())
-                            .await?;
-                    }
+                    // Handle TLS session
+                    self.handle_tls_session(tls_stream, &mut session, addr, buf, violations)
+                        .await?;
+                    return Ok(());
                 }
 
-                smtp::Command::Binary => {
-                    if session.state == smtp::State::Authenticated {
-                        stream
-                            .write_all(smtp::Response::binary_mode().as_bytes())
-                            .await?;
-                        session.state = smtp::State::BinaryMode;
-                        session.binary_mode = true;
-
-                        // For non-TLS, we still handle binary mode
-                        // In this simplified version, we just end the session
-                        info!("Binary mode requested but not fully implemented for non-TLS");
-                        break;
-                    } else {
-                        stream
-                            .write_all(smtp::Response::auth_failed().as_bytes())
-                            .await?;
+                smtp::Command::Mail | smtp::Command::Rcpt | smtp::Command::Data
+                    if self.config.camouflage.inbound_mail.is_some() =>
+                {
+                    let violation = self
+                        .inbound_command(&mut stream, buf, cmd, &arg, &mut envelope, addr)
+                        .await?;
+                    if let Some(kind) = violation {
+                        self.smtp_violation(&mut stream, violations, kind).await?;
                     }
                 }
 
                 smtp::Command::Quit => {
                     stream
-                        .write_all(smtp::Response::goodbye().as_bytes())
+                        .write_all(self.smtp().goodbye(&self.config.hostname).as_bytes())
                         .await?;
+                    close_gracefully(&mut stream).await;
                     break;
                 }
 
                 _ => {
                     stream
-                        .write_all(smtp::Response::command_unrecognized().as_bytes())
+                        .write_all(self.smtp().command_unrecognized().as_bytes())
+                        .await?;
+                    self.smtp_violation(&mut stream, violations, Violation::SmtpUnknown)
                         .await?;
                 }
             }
@@ -307,6 +1005,30 @@ impl Server {
         Ok(())
     }
 
+    /// Handle a client connection on the SMTPS listener: TLS first, then
+    /// the greeting and the same commands as after STARTTLS
+    async fn handle_implicit_tls(
+        self: Arc<Self>,
+        stream: TcpStream,
+        addr: SocketAddr,
+    ) -> anyhow::Result<()> {
+        let mut stream = self.tls_acceptor.accept(stream).await?;
+        camouflage::before_greeting(&self.config.camouflage.response_delays).await;
+        stream
+            .write_all(self.smtp().greeting(&self.config.hostname).as_bytes())
+            .await?;
+        let mut session = Session {
+            username: None,
+            state: smtp::State::TlsStarted,
+            binary_mode: false,
+            client_addr: addr,
+        };
+        let mut buf = BytesMut::with_capacity(1024);
+        let violations = Violations::new(Arc::clone(&self.metrics), self.config.max_violations);
+        self.handle_tls_session(stream, &mut session, addr, &mut buf, &violations)
+            .await
+    }
+
     /// Handle TLS session
     async fn handle_tls_session(
         self: &Arc<Self>,
@@ -314,10 +1036,19 @@ impl Server {
         session: &mut Session,
         addr: SocketAddr,
         buf: &mut BytesMut,
+        violations: &Violations,
     ) -> anyhow::Result<()> {
         session.state = smtp::State::TlsStarted;
         debug!("TLS established with {}", addr);
+        let binding = crate::tls::channel_binding(stream.get_ref().1);
+        if binding.is_none() {
+            warn!("No TLS channel binding available for {}", addr);
+        }
 
+        let mut envelope = None;
+        // The tunnel's own transaction: Some once MAIL is in, true after RCPT
+        let mut recipient = None;
+        let mut auth_failures = 0;
         loop {
             // Read line
             let line = match read_line(&mut stream, buf).await? {
@@ -331,25 +1062,37 @@ impl Server {
             trace!("TLS Client {}: {}", addr, line);
 
             // Parse command
-            let (cmd, arg) = match smtp::parse_line(&line) {
+            let (cmd, arg) = match smtp::parse_line(&line, &self.config.binary_verb) {
                 Some(c) => c,
                 None => continue,
             };
+            camouflage::before_reply(&self.config.camouflage.response_delays, cmd).await;
+
+            let Some(next) = self
+                .check_sequence(&mut stream, violations, session.state, cmd)
+                .await?
+            else {
+                continue;
+            };
 
             // Handle command
             match cmd {
                 smtp::Command::Ehlo | smtp::Command::Helo => {
                     stream
-                        .write_all(smtp::Response::ehlo(&self.config.hostname, false).as_bytes())
+                        .write_all(
+                            self.smtp()
+                                .ehlo(&self.config.hostname, &arg, addr.ip(), true)
+                                .as_bytes(),
+                        )
                         .await?;
+                    session.state = next;
                 }
 
                 smtp::Command::Auth => {
                     // Parse AUTH PLAIN token
                     let parts: Vec<&str> = arg.split_whitespace().collect();
                     if parts.len() < 2 || parts[0].to_uppercase() != "PLAIN" {
-                        stream
-                            .write_all(smtp::Response::auth_failed().as_bytes())
+                        self.auth_failure(&mut stream, &mut auth_failures, violations)
                             .await?;
                         continue;
                     }
@@ -373,10 +1116,12 @@ impl Server {
 
                     drop(users_guard);
 
-                    let (valid, username) = AuthToken::verify_multi_user(
+                    // Tokens must be bound to this TLS connection
+                    let (valid, username) = AuthToken::verify_multi_user_bound(
                         token,
                         &user_secrets,
                         300, // 5 minute max age
+                        binding.as_ref().map(|b| &b[..]),
                     );
 
                     if valid {
@@ -397,54 +1142,119 @@ impl Server {
 
                         if !whitelisted {
                             warn!("User {} not whitelisted from IP {}", username, addr.ip());
-                            stream
-                                .write_all(smtp::Response::auth_failed().as_bytes())
+                            self.auth_failure(&mut stream, &mut auth_failures, violations)
                                 .await?;
                             continue;
                         }
 
+                        let reply = match self.affinity_token(&username) {
+                            Some(token) => self.smtp().auth_success_affinity(&token),
+                            None => self.smtp().auth_success(),
+                        };
                         session.username = Some(username.clone());
-                        session.state = smtp::State::Authenticated;
-                        stream
-                            .write_all(smtp::Response::auth_success().as_bytes())
-                            .await?;
+                        session.state = next;
+                        stream.write_all(reply.as_bytes()).await?;
                         info!("User {} authenticated from {} (TLS)", username, addr);
                     } else {
                         warn!("Authentication failed from {}", addr);
-                        stream
-                            .write_all(smtp::Response::auth_failed().as_bytes())
+                        self.auth_failure(&mut stream, &mut auth_failures, violations)
                             .await?;
                     }
                 }
 
-                smtp::Command::Binary => {
-                    if session.state == smtp::State::Authenticated {
+                smtp::Command::Binary
+                    if self.config.camouflage.data_channel == DataChannel::Binary =>
+                {
+                    let Some((resumable, peer_received)) = self
+                        .open_session(&mut stream, &arg, session, violations)
+                        .await?
+                    else {
+                        continue;
+                    };
+                    session.state = next;
+                    session.binary_mode = true;
+
+                    // Enter binary mode; anything pipelined after BINARY
+                    // is already frame data
+                    let attachment = resumable.link.attach().await;
+                    let leftover = std::mem::take(buf);
+                    self.carry_session(resumable, attachment, stream, leftover, peer_received)
+                        .await;
+                    break;
+                }
+
+                // With the bdat data channel the tunnel opens a mail
+                // transaction of its own and sends everything after it
+                // as message data
+                smtp::Command::Mail | smtp::Command::Rcpt if self.chunked(session) => {
+                    let reply = match cmd {
+                        smtp::Command::Mail => {
+                            recipient = Some(false);
+                            self.smtp().sender_ok()
+                        }
+                        _ if recipient.is_some() => {
+                            recipient = Some(true);
+                            self.smtp().recipient_ok()
+                        }
+                        _ => {
+                            stream
+                                .write_all(self.smtp().bad_sequence().as_bytes())
+                                .await?;
+                            self.smtp_violation(&mut stream, violations, Violation::SmtpSequence)
+                                .await?;
+                            continue;
+                        }
+                    };
+                    stream.write_all(reply.as_bytes()).await?;
+                }
+
+                smtp::Command::Bdat => {
+                    if !self.chunked(session) || recipient != Some(true) {
                         stream
-                            .write_all(smtp::Response::binary_mode().as_bytes())
+                            .write_all(self.smtp().bad_sequence().as_bytes())
                             .await?;
-                        session.state = smtp::State::BinaryMode;
-                        session.binary_mode = true;
-
-                        // Enter binary mode
-                        self.handle_binary_mode_tls(stream, session.clone()).await?;
-                        break;
-                    } else {
+                        self.smtp_violation(&mut stream, violations, Violation::SmtpSequence)
+                            .await?;
+                        continue;
+                    }
+                    let Ok(header) = chunking::parse_header(line.as_bytes()) else {
                         stream
-                            .write_all(smtp::Response::auth_failed().as_bytes())
+                            .write_all(self.smtp().syntax_error().as_bytes())
+                            .await?;
+                        self.smtp_violation(&mut stream, violations, Violation::SmtpSyntax)
                             .await?;
+                        continue;
+                    };
+                    session.state = next;
+                    let stream = Chunked::after_header(stream, std::mem::take(buf), header);
+                    self.carry_chunked(stream, session, violations).await?;
+                    break;
+                }
+
+                smtp::Command::Mail | smtp::Command::Rcpt | smtp::Command::Data
+                    if self.config.camouflage.inbound_mail.is_some() =>
+                {
+                    let violation = self
+                        .inbound_command(&mut stream, buf, cmd, &arg, &mut envelope, addr)
+                        .await?;
+                    if let Some(kind) = violation {
+                        self.smtp_violation(&mut stream, violations, kind).await?;
                     }
                 }
 
                 smtp::Command::Quit => {
                     stream
-                        .write_all(smtp::Response::goodbye().as_bytes())
+                        .write_all(self.smtp().goodbye(&self.config.hostname).as_bytes())
                         .await?;
+                    close_gracefully(&mut stream).await;
                     break;
                 }
 
                 _ => {
                     stream
-                        .write_all(smtp::Response::command_unrecognized().as_bytes())
+                        .write_all(self.smtp().command_unrecognized().as_bytes())
+                        .await?;
+                    self.smtp_violation(&mut stream, violations, Violation::SmtpUnknown)
                         .await?;
                 }
             }
@@ -453,27 +1263,1239 @@ impl Server {
         Ok(())
     }
 
-    /// Handle binary streaming mode (TLS)
-    async fn handle_binary_mode_tls(
+    /// The session sends tunnel data in BDAT chunks, and is ready to
+    fn chunked(&self, session: &Session) -> bool {
+        self.config.camouflage.data_channel == DataChannel::Bdat
+            && session.state == smtp::State::Authenticated
+    }
+
+    /// Start or resume the session BINARY (`arg`: "RESUME <session>
+    /// <received> [affinity]") asks for. If there is none to take up the
+    /// client is told so, and this gives None.
+    async fn open_session<S: AsyncWrite + Unpin>(
         &self,
-        _stream: tokio_rustls::server::TlsStream<TcpStream>,
-        mut session: Session,
+        stream: &mut S,
+        arg: &str,
+        session: &Session,
+        violations: &Violations,
+    ) -> anyhow::Result<Option<(Resumable, u64)>> {
+        if arg.is_empty() {
+            return Ok(Some((self.start_session(session).await, 0)));
+        }
+        let Some((id, received, affinity)) = parse_resume(arg) else {
+            stream
+                .write_all(self.smtp().syntax_error().as_bytes())
+                .await?;
+            self.smtp_violation(stream, violations, Violation::SmtpSyntax)
+                .await?;
+            return Ok(None);
+        };
+        match self.resumable(id, session) {
+            Some(resumable) => {
+                *resumable.client_ip.lock().unwrap() = session.client_addr.ip();
+                Ok(Some((resumable, received)))
+            }
+            None => {
+                let reply = match self.affinity_redirect(affinity, session) {
+                    Some(node) => {
+                        debug!(
+                            "Sending {} to {} for session {}",
+                            session.client_addr, node, id
+                        );
+                        smtp::Response::session_elsewhere(node)
+                    }
+                    None => {
+                        debug!("No session {} to resume for {}", id, session.client_addr);
+                        smtp::Response::session_not_found()
+                    }
+                };
+                stream.write_all(reply.as_bytes()).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Carry a session inside BDAT chunks. The chunks start with the
+    /// BINARY line, answered as in binary mode; only that is accepted.
+    async fn carry_chunked<S>(
+        &self,
+        mut stream: Chunked<S>,
+        session: &mut Session,
+        violations: &Violations,
+    ) -> anyhow::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut buf = BytesMut::new();
+        while let Some(line) = read_line(&mut stream, &mut buf).await? {
+            let (cmd, arg) = smtp::Command::parse_with(&line, &self.config.binary_verb);
+            if cmd != smtp::Command::Binary {
+                stream
+                    .write_all(self.smtp().command_unrecognized().as_bytes())
+                    .await?;
+                self.smtp_violation(&mut stream, violations, Violation::SmtpUnknown)
+                    .await?;
+                continue;
+            }
+            let Some((resumable, peer_received)) = self
+                .open_session(&mut stream, arg, session, violations)
+                .await?
+            else {
+                continue;
+            };
+            session.binary_mode = true;
+            let attachment = resumable.link.attach().await;
+            self.carry_session(resumable, attachment, stream, buf, peer_received)
+                .await;
+            break;
+        }
+        Ok(())
+    }
+
+    /// Where `cmd` takes a session in `state`. A command the state
+    /// machine refuses is answered and counted here, and gives None.
+    async fn check_sequence<S: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        violations: &Violations,
+        state: smtp::State,
+        cmd: smtp::Command,
+    ) -> anyhow::Result<Option<smtp::State>> {
+        let (reply, kind) = match state.on(cmd) {
+            smtp::Transition::To(next) => return Ok(Some(next)),
+            smtp::Transition::BadSequence => (self.smtp().bad_sequence(), Violation::SmtpSequence),
+            smtp::Transition::Unrecognized => {
+                (self.smtp().command_unrecognized(), Violation::SmtpUnknown)
+            }
+        };
+        stream.write_all(reply.as_bytes()).await?;
+        self.smtp_violation(stream, violations, kind).await?;
+        Ok(None)
+    }
+
+    /// Replies of the MTA the server imitates
+    fn smtp(&self) -> &Profile {
+        &self.smtp
+    }
+
+    /// Count an SMTP client's protocol violation. Past the strict-mode
+    /// limit the client is told so, as Postfix would, and the returned
+    /// error ends the connection.
+    async fn smtp_violation<S: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        violations: &Violations,
+        kind: Violation,
     ) -> anyhow::Result<()> {
-        // Simplified for compilation
-        info!("Binary mode started for {:?}", session.username);
+        if let Err(e) = violations.record(kind) {
+            let reply = self.smtp().too_many_errors(&self.config.hostname);
+            stream.write_all(reply.as_bytes()).await?;
+            return Err(e);
+        }
+        Ok(())
+    }
 
-        // Cleanup
-        for (_channel_id, channel) in session.channels.drain() {
-            drop(channel);
+    /// Answer a failed AUTH, counting it as a violation. Past
+    /// `max_auth_attempts` the client is told it made too many errors, as
+    /// Postfix would, and the returned error ends the connection.
+    async fn auth_failure<S: AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        failures: &mut u32,
+        violations: &Violations,
+    ) -> anyhow::Result<()> {
+        stream
+            .write_all(self.smtp().auth_failed().as_bytes())
+            .await?;
+        *failures += 1;
+        let limit = self.config.camouflage.max_auth_attempts;
+        if limit == 0 || *failures < limit {
+            return self
+                .smtp_violation(stream, violations, Violation::SmtpAuth)
+                .await;
         }
+        // Counted, though the connection ends either way
+        let _ = violations.record(Violation::SmtpAuth);
+        let reply = self.smtp().too_many_errors(&self.config.hostname);
+        stream.write_all(reply.as_bytes()).await?;
+        anyhow::bail!("Too many failed AUTH attempts ({})", failures);
+    }
 
-        info!(
-            "Session ended for {:?} from {}",
-            session.username, session.client_addr
+    /// Handle MAIL, RCPT or DATA for genuine inbound mail, returning the
+    /// violation if the command was out of order or malformed
+    async fn inbound_command<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        stream: &mut S,
+        buf: &mut BytesMut,
+        cmd: smtp::Command,
+        arg: &str,
+        envelope: &mut Option<Envelope>,
+        addr: SocketAddr,
+    ) -> anyhow::Result<Option<Violation>> {
+        let Some(config) = &self.config.camouflage.inbound_mail else {
+            return Ok(None);
+        };
+        let mut violation = None;
+        let reply = match cmd {
+            smtp::Command::Mail => match inbound::parse_path(arg, "FROM") {
+                Some(from) => {
+                    *envelope = Some(Envelope {
+                        from: from.to_string(),
+                        recipients: Vec::new(),
+                    });
+                    self.smtp().sender_ok()
+                }
+                None => {
+                    violation = Some(Violation::SmtpSyntax);
+                    self.smtp().syntax_error()
+                }
+            },
+            smtp::Command::Rcpt => match (envelope.as_mut(), inbound::parse_path(arg, "TO")) {
+                (None, _) => {
+                    violation = Some(Violation::SmtpSequence);
+                    self.smtp().bad_sequence()
+                }
+                (Some(_), None) => {
+                    violation = Some(Violation::SmtpSyntax);
+                    self.smtp().syntax_error()
+                }
+                (Some(envelope), Some(to)) => {
+                    if !inbound::accepts(config, to) {
+                        debug!("Rejecting mail from {} for {}", addr, to);
+                        self.smtp().recipient_unknown(to)
+                    } else if envelope.recipients.len() >= inbound::MAX_RECIPIENTS {
+                        self.smtp().too_many_recipients()
+                    } else {
+                        envelope.recipients.push(to.to_string());
+                        self.smtp().recipient_ok()
+                    }
+                }
+            },
+            _ => match envelope.take() {
+                None => {
+                    violation = Some(Violation::SmtpSequence);
+                    self.smtp().bad_sequence()
+                }
+                Some(envelope) if envelope.recipients.is_empty() => {
+                    self.smtp().no_valid_recipients()
+                }
+                Some(envelope) => {
+                    stream
+                        .write_all(self.smtp().start_mail_input().as_bytes())
+                        .await?;
+                    match inbound::read_data(stream, buf, config.max_message_size).await? {
+                        None => self.smtp().message_too_large(),
+                        Some(message) => {
+                            match inbound::deliver(
+                                config,
+                                &self.config.hostname,
+                                addr.ip(),
+                                &envelope,
+                                &message,
+                            )
+                            .await
+                            {
+                                Ok(()) => {
+                                    info!(
+                                        "Accepted mail from {} <{}> for {} recipient(s)",
+                                        addr,
+                                        envelope.from,
+                                        envelope.recipients.len()
+                                    );
+                                    self.smtp().queued()
+                                }
+                                Err(e) => {
+                                    warn!("Failed to deliver mail from {}: {:#}", addr, e);
+                                    self.smtp().local_error()
+                                }
+                            }
+                        }
+                    }
+                }
+            },
+        };
+        stream.write_all(reply.as_bytes()).await?;
+        Ok(violation)
+    }
+
+    /// Start the frame loop for a new binary-mode session. It runs until
+    /// the session's link is closed, whichever connection carries it.
+    async fn start_session(&self, session: &Session) -> Resumable {
+        let username = session.username.as_deref().unwrap_or("unknown");
+        let log_connects = self.config.log_users
+            && self
+                .users
+                .read()
+                .await
+                .get_user(username)
+                .is_none_or(|user| user.logging);
+        info!("Binary mode started for {}", username);
+
+        let log_connects = Arc::new(AtomicBool::new(log_connects));
+        let ctx = Arc::new(SessionContext {
+            username: username.to_string(),
+            client_addr: session.client_addr,
+            log_connects: Arc::clone(&log_connects),
+            acl: Arc::clone(&self.acl),
+            honeypot: self.honeypot.clone(),
+            resolver: Arc::clone(&self.resolver),
+            connect_slots: Arc::clone(&self.connect_slots),
+            channel_slots: Arc::clone(&self.channel_slots),
+            throttle: Arc::clone(&self.throttle),
+            channel_idle_timeout: channel_idle_timeout(&self.config),
+            violations: Violations::new(Arc::clone(&self.metrics), self.config.max_violations),
+            tasks: self.tasks.child(),
+        });
+        let (link, inbound, outbound) = Link::new(SessionId::random());
+        let resumable = Resumable {
+            username: username.to_string(),
+            link,
+            log_connects,
+            client_ip: Arc::new(std::sync::Mutex::new(session.client_addr.ip())),
+            tasks: ctx.tasks.clone(),
+        };
+        self.sessions
+            .lock()
+            .unwrap()
+            .insert(resumable.link.id(), resumable.clone());
+
+        // Anything the session started goes with it, even if the frame
+        // loop panics
+        let guard = SessionGuard::new(&self.sessions, resumable.clone());
+        let name = format!(
+            "session {} for {} from {}",
+            resumable.link.id(),
+            ctx.username,
+            ctx.client_addr
         );
+        ctx.tasks.clone().spawn(name, async move {
+            let _guard = guard;
+            if let Err(e) = serve_frames(inbound, outbound, Arc::clone(&ctx)).await {
+                debug!("Session error for {}: {:#}", ctx.username, e);
+            }
+            info!(
+                "Session ended for {} from {}",
+                ctx.username, ctx.client_addr
+            );
+        });
+        resumable
+    }
+
+    /// Affinity token naming this node, if affinity is configured
+    fn affinity_token(&self, username: &str) -> Option<String> {
+        let affinity = self.config.affinity.as_ref()?;
+        let token = AffinityToken {
+            node: affinity.node.clone(),
+            username: username.to_string(),
+            expires: crate::rotation::unix_now() + affinity.ttl,
+        };
+        Some(token.sign(&affinity.secret))
+    }
+
+    /// Address of the node an affinity token says holds the session, if
+    /// it's valid for this user and names another node we know
+    fn affinity_redirect(&self, token: Option<&str>, session: &Session) -> Option<&str> {
+        let affinity = self.config.affinity.as_ref()?;
+        let token = AffinityToken::verify(token?, &affinity.secret, crate::rotation::unix_now())?;
+        if token.node == affinity.node || session.username.as_deref() != Some(&token.username) {
+            return None;
+        }
+        affinity.nodes.get(&token.node).map(String::as_str)
+    }
 
+    /// Session `id` if it belongs to the authenticated user
+    fn resumable(&self, id: SessionId, session: &Session) -> Option<Resumable> {
+        let sessions = self.sessions.lock().unwrap();
+        let resumable = sessions.get(&id)?;
+        (session.username.as_deref() == Some(resumable.username.as_str()))
+            .then(|| resumable.clone())
+    }
+
+    /// Carry a binary-mode session over this connection. If the connection
+    /// is lost the session waits [`RESUME_GRACE`] for the client to resume
+    /// it before closing. It is closed at once if this handler panics.
+    async fn carry_session<S>(
+        &self,
+        session: Resumable,
+        attachment: Attachment,
+        mut stream: S,
+        mut leftover: BytesMut,
+        peer_received: u64,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let link = Arc::clone(&session.link);
+        let username = session.username.clone();
+        let guard = SessionGuard::new(&self.sessions, session);
+        let generation = attachment.generation();
+        let mut options = link_options(&self.config);
+        // Router builds skip counting frames
+        options.frame_stats = (!cfg!(feature = "minimal")).then(|| self.metrics.frames());
+        let reply = smtp::Response::binary_session(&link.id().to_string(), link.received());
+        let result = async {
+            stream.write_all(reply.as_bytes()).await?;
+            // The user's secret authenticates the key exchange
+            let secret = self
+                .users
+                .read()
+                .await
+                .get_user(&username)
+                .map(|user| user.secret.clone());
+            let kex = match secret {
+                Some(secret) => Some((KeyExchange::new(Role::Server)?, secret)),
+                None => None,
+            };
+            let message = kex
+                .as_ref()
+                .map(|(kex, secret)| kex.message(secret, &username));
+            let announcement = self.announcement.read().unwrap().clone();
+            let (hello, offered) =
+                hello_server(&mut stream, &mut leftover, message, announcement).await?;
+            debug!(
+                "Session {} speaks protocol version {} (features: {})",
+                link.id(),
+                hello.version,
+                hello.features
+            );
+            options.large_frames = hello.features.contains(Features::LARGE_FRAMES);
+            options.checksum = hello.features.contains(Features::CHECKSUM);
+            options.sequence = hello.features.contains(Features::SEQUENCE);
+            if hello.features.contains(Features::COMPRESSION) {
+                options.compression = Some(Compression {
+                    level: self.config.compression_level,
+                    min_size: self.config.compression_min_size,
+                });
+            }
+            if hello.features.contains(Features::PADDING) {
+                options.padding = self.config.padding.as_ref().map(Padding::from_config);
+            }
+            if hello.features.contains(Features::ENCRYPTION)
+                && let Some((kex, secret)) = kex
+                && let Some(peer) = hello.key_exchange
+            {
+                let transcript = Features::transcript(offered, hello.features);
+                let keys = kex
+                    .finish(&secret, &username, &peer, &transcript)
+                    .map_err(|e| anyhow::anyhow!("Key exchange failed: {e}"))?;
+                options.keys = Some(keys);
+            }
+            link.run(attachment, stream, leftover, peer_received, options)
+                .await
+        }
+        .await;
+        match result {
+            Ok(Detached::Closed) => return,
+            Ok(Detached::Replaced) => {
+                debug!("Session {} moved to a new connection", link.id());
+                guard.disarm();
+                return;
+            }
+            Err(e) => {
+                // Frames that don't decode end the connection at once
+                if e.downcast_ref::<FrameError>()
+                    .is_some_and(|e| !matches!(e, FrameError::Io(_)))
+                {
+                    self.metrics.violation(Violation::FrameMalformed);
+                }
+                info!("Session {} lost its connection: {:#}", link.id(), e)
+            }
+        }
+
+        if link.wait_for_resume(generation, RESUME_GRACE).await {
+            guard.disarm();
+        } else {
+            debug!("Session {} was not resumed", link.id());
+        }
+    }
+}
+
+/// Serve one session over `stream` with no SMTP handshake, TLS or
+/// authentication: the server end of the in-memory transport. The session
+/// ends, cancelling `tasks`, when the stream closes.
+#[cfg_attr(not(feature = "client"), allow(dead_code))]
+pub(crate) fn serve_stream<S>(
+    config: &ServerConfig,
+    stream: S,
+    tasks: TaskGroup,
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let ctx = Arc::new(SessionContext {
+        username: "in-memory".to_string(),
+        client_addr: SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0),
+        log_connects: Arc::default(),
+        acl: Arc::new(DestinationAcl::new(&config.blocked_destinations)?),
+        honeypot: None,
+        resolver: Arc::new(Resolver::new(&config.resolver)?),
+        connect_slots: Arc::new(connect_slots(config)),
+        channel_slots: Arc::new(channel_slots(config)),
+        throttle: Arc::new(DestinationThrottle::new(config.destination_limits.as_ref())),
+        channel_idle_timeout: channel_idle_timeout(config),
+        violations: Violations::new(Arc::default(), config.max_violations),
+        tasks: tasks.clone(),
+    });
+    let options = link_options(config);
+    let (link, inbound, outbound) = Link::new(SessionId::random());
+    tasks.spawn("in-memory link", async move {
+        let attachment = link.attach().await;
+        if let Err(e) = link
+            .run(attachment, stream, BytesMut::new(), 0, options)
+            .await
+        {
+            debug!("In-memory link failed: {:#}", e);
+        }
+        link.close();
+    });
+    tasks.spawn("in-memory session", async move {
+        if let Err(e) = serve_frames(inbound, outbound, Arc::clone(&ctx)).await {
+            debug!("In-memory session error: {:#}", e);
+        }
+        ctx.tasks.cancel();
+    });
+    Ok(())
+}
+
+/// Accept from `listener`, or wait forever if there is none
+async fn accept_optional(
+    listener: Option<&TcpListener>,
+) -> std::io::Result<(TcpStream, SocketAddr)> {
+    match listener {
+        Some(listener) => listener.accept().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Announcements have to fit in HELLO and on one status line
+fn check_announcement(announcement: Option<&str>) -> anyhow::Result<()> {
+    if let Some(text) = announcement
+        && text.len() > MAX_ANNOUNCEMENT_LEN
+    {
+        anyhow::bail!(
+            "Announcement is {} bytes; the limit is {}",
+            text.len(),
+            MAX_ANNOUNCEMENT_LEN
+        );
+    }
+    Ok(())
+}
+
+/// Outbound connect slots allowed by `max_concurrent_connects`
+fn connect_slots(config: &ServerConfig) -> Semaphore {
+    Semaphore::new(match config.max_concurrent_connects {
+        0 => Semaphore::MAX_PERMITS,
+        n => n,
+    })
+}
+
+/// Open channels allowed by `max_total_channels`
+fn channel_slots(config: &ServerConfig) -> Semaphore {
+    Semaphore::new(match config.max_total_channels {
+        0 => Semaphore::MAX_PERMITS,
+        n => n,
+    })
+}
+
+/// `channel_idle_timeout`, if any
+fn channel_idle_timeout(config: &ServerConfig) -> Option<Duration> {
+    match config.channel_idle_timeout {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    }
+}
+
+/// How a session's link is run, from the server config
+fn link_options(config: &ServerConfig) -> LinkOptions {
+    LinkOptions {
+        heartbeat: None,
+        idle_timeout: match config.idle_timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        },
+        batching: Batching {
+            max_delay: Duration::from_millis(config.write_batch_delay_ms),
+            max_bytes: config.write_batch_bytes,
+        },
+        channel_turn: config.channel_turn_bytes,
+        large_frames: false,
+        compression: None,
+        padding: None,
+        checksum: false,
+        sequence: false,
+        keys: None,
+        frame_stats: None,
+    }
+}
+
+/// Parse the arguments of "BINARY RESUME <session> <received> [<affinity>]"
+fn parse_resume(arg: &str) -> Option<(SessionId, u64, Option<&str>)> {
+    let mut parts = arg.split_whitespace();
+    if !parts.next()?.eq_ignore_ascii_case("RESUME") {
+        return None;
+    }
+    let id = parts.next()?.parse().ok()?;
+    let received = parts.next()?.parse().ok()?;
+    let affinity = parts.next();
+    parts.next().is_none().then_some((id, received, affinity))
+}
+
+/// Run the binary frame loop for one authenticated session.
+///
+/// Frames from the client are dispatched per channel: CONNECT spawns an
+/// egress task that dials the destination, DATA is queued to that task and
+/// CLOSE tears it down. Frames come in and go out through the session's
+/// [`Link`], so the loop carries on across client reconnects; it ends when
+/// the link is closed.
+async fn serve_frames(
+    mut inbound: mpsc::Receiver<Frame>,
+    out_tx: mpsc::Sender<Frame>,
+    ctx: Arc<SessionContext>,
+) -> anyhow::Result<()> {
+    let channels = ChannelRegistry::<Channel>::new();
+    let udp = ChannelRegistry::<UdpRelay>::new();
+    // Egress tasks report here when they finish, e.g. after both sides shut
+    // down, so their channels don't linger until the client reuses the ID
+    let (finished_tx, mut finished) = mpsc::unbounded_channel::<(u16, u64)>();
+    let mut serial = 0u64;
+    let result = loop {
+        let frame = tokio::select! {
+            frame = inbound.recv() => match frame {
+                Some(frame) => frame,
+                None => break Ok(()),
+            },
+            Some((id, done)) = finished.recv() => {
+                if channels.with(id, |ch| ch.serial == done) == Some(true) {
+                    channels.close(id);
+                }
+                continue;
+            }
+        };
+
+        match frame.frame_type {
+            FrameType::Connect => {
+                let id = frame.channel_id;
+                let Some((host, port, meta)) = frame.parse_connect_with_meta() else {
+                    debug!("Malformed CONNECT on channel {}", id);
+                    ctx.violations.record(Violation::FrameMalformed)?;
+                    out_tx
+                        .send(Frame::connect_fail(
+                            id,
+                            ConnectError::Protocol,
+                            "Malformed CONNECT",
+                        ))
+                        .await?;
+                    continue;
+                };
+                // The client only reuses an ID it has finished with, so an
+                // entry it has shut down (or whose egress task is done) is
+                // stale; anything else is a live channel
+                match channels.with(id, |ch| ch.tx.is_none() || ch.task.is_finished()) {
+                    Some(true) => {
+                        if let Some(stale) = channels.close(id) {
+                            stale.task.abort();
+                        }
+                    }
+                    Some(false) => {
+                        ctx.violations.record(Violation::FrameUnexpected)?;
+                        out_tx
+                            .send(Frame::connect_fail(
+                                id,
+                                ConnectError::Protocol,
+                                "Channel already open",
+                            ))
+                            .await?;
+                        continue;
+                    }
+                    None => {}
+                }
+                if ctx.log_connects.load(Ordering::Relaxed) {
+                    info!("{} -> {}:{} (channel {})", ctx.username, host, port, id);
+                } else {
+                    debug!("Channel {} -> {}:{}", id, host, port);
+                }
+
+                let (tx, rx) = mpsc::unbounded_channel();
+                let send_window = SendWindow::new();
+                let recv_window = RecvWindow::new();
+                serial += 1;
+                let egress = run_channel(
+                    Arc::clone(&ctx),
+                    id,
+                    ChannelTarget { host, port, meta },
+                    rx,
+                    (send_window.clone(), recv_window.clone()),
+                    out_tx.clone(),
+                );
+                let finished_tx = finished_tx.clone();
+                let task =
+                    ctx.tasks
+                        .spawn(format!("channel {} for {}", id, ctx.username), async move {
+                            egress.await;
+                            let _ = finished_tx.send((id, serial));
+                        });
+                let channel = Channel {
+                    tx: Some(tx),
+                    send_window,
+                    recv_window,
+                    task,
+                    serial,
+                };
+                if let Err(rejected) = channels.open(id, channel) {
+                    debug!("Channel {} rejected (reserved ID)", id);
+                    rejected.task.abort();
+                    ctx.violations.record(Violation::FrameUnexpected)?;
+                    out_tx
+                        .send(Frame::connect_fail(
+                            id,
+                            ConnectError::Protocol,
+                            "Invalid channel",
+                        ))
+                        .await?;
+                }
+            }
+
+            FrameType::Resolve => {
+                let id = frame.channel_id;
+                let Some((host, family)) = frame.parse_resolve() else {
+                    debug!("Malformed RESOLVE (request {})", id);
+                    ctx.violations.record(Violation::FrameMalformed)?;
+                    out_tx
+                        .send(Frame::resolve_result(id, Err("Malformed RESOLVE")))
+                        .await?;
+                    continue;
+                };
+                ctx.tasks.spawn(
+                    format!("resolve {} for {}", host, ctx.username),
+                    resolve(Arc::clone(&ctx), id, host, family, out_tx.clone()),
+                );
+            }
+
+            FrameType::Datagram => {
+                let id = frame.channel_id;
+                let Some(datagram) = frame.parse_datagram() else {
+                    debug!("Malformed DATAGRAM (association {})", id);
+                    ctx.violations.record(Violation::FrameMalformed)?;
+                    continue;
+                };
+                let tx = udp
+                    .with(id, |relay| relay.tx.clone())
+                    .filter(|tx| !tx.is_closed());
+                let tx = match tx {
+                    Some(tx) => tx,
+                    None => {
+                        // A new association, or one whose relay idled out
+                        if let Some(stale) = udp.close(id) {
+                            stale.task.abort();
+                        }
+                        let socket = match crate::platform::bind_udp_any() {
+                            Ok(socket) => socket,
+                            Err(e) => {
+                                warn!("Could not bind UDP relay socket: {}", e);
+                                continue;
+                            }
+                        };
+                        if ctx.log_connects.load(Ordering::Relaxed) {
+                            info!("{} opened UDP association {}", ctx.username, id);
+                        } else {
+                            debug!("UDP association {} opened", id);
+                        }
+                        let (tx, rx) = mpsc::channel(UDP_RELAY_QUEUE);
+                        let task = ctx.tasks.spawn(
+                            format!("UDP relay {} for {}", id, ctx.username),
+                            run_udp_relay(Arc::clone(&ctx), id, socket, rx, out_tx.clone()),
+                        );
+                        if let Err(rejected) = udp.open(
+                            id,
+                            UdpRelay {
+                                tx: tx.clone(),
+                                task,
+                            },
+                        ) {
+                            debug!("UDP association {} rejected (reserved ID)", id);
+                            rejected.task.abort();
+                            ctx.violations.record(Violation::FrameUnexpected)?;
+                            continue;
+                        }
+                        tx
+                    }
+                };
+                // Like any congested UDP path, an overloaded relay drops
+                if tx.try_send(datagram).is_err() {
+                    trace!("UDP association {} queue full, dropping datagram", id);
+                }
+            }
+
+            FrameType::Data => {
+                let id = frame.channel_id;
+                let len = frame.payload.len();
+                let tx = channels.with(id, |ch| {
+                    ch.tx.clone().map(|tx| (tx, ch.recv_window.receive(len)))
+                });
+                match tx.flatten() {
+                    Some((tx, true)) => {
+                        // A closed queue means the egress task has already
+                        // finished with the channel
+                        if tx.send(frame.payload).is_err() {
+                            channels.close(id);
+                        }
+                    }
+                    Some((_, false)) => {
+                        debug!("Channel {} overran its flow-control window", id);
+                        ctx.violations.record(Violation::FlowControl)?;
+                        if let Some(channel) = channels.close(id) {
+                            channel.task.abort();
+                        }
+                        out_tx.send(Frame::close(id)).await?;
+                    }
+                    None => trace!("DATA for unknown or shut down channel {}", id),
+                }
+            }
+
+            FrameType::WindowUpdate => {
+                let id = frame.channel_id;
+                let Some(increment) = frame.parse_window_update() else {
+                    debug!("Malformed WINDOW_UPDATE on channel {}", id);
+                    ctx.violations.record(Violation::FrameMalformed)?;
+                    continue;
+                };
+                if channels.with(id, |ch| ch.send_window.grant(increment)) == Some(false) {
+                    debug!("Channel {} granted more than its window", id);
+                    ctx.violations.record(Violation::FlowControl)?;
+                    if let Some(channel) = channels.close(id) {
+                        channel.task.abort();
+                    }
+                    out_tx.send(Frame::close(id)).await?;
+                }
+            }
+
+            FrameType::Shutdown => {
+                // Dropping the sender lets the egress task flush queued data
+                // before shutting down the destination's write side
+                channels.with(frame.channel_id, |ch| ch.tx = None);
+            }
+
+            FrameType::Close => {
+                if let Some(channel) = channels.close(frame.channel_id) {
+                    channel.task.abort();
+                }
+            }
+
+            // Handled by the link
+            FrameType::Keepalive
+            | FrameType::KeepaliveAck
+            | FrameType::Ack
+            | FrameType::Hello
+            | FrameType::Padding => {}
+
+            FrameType::ConnectOk | FrameType::ConnectFail | FrameType::ResolveResult => {
+                debug!(
+                    "Unexpected {:?} from client on channel {}",
+                    frame.frame_type, frame.channel_id
+                );
+                ctx.violations.record(Violation::FrameUnexpected)?;
+            }
+        }
+    };
+
+    for channel in channels.drain() {
+        channel.task.abort();
+    }
+    for relay in udp.drain() {
+        relay.task.abort();
+    }
+    result
+}
+
+/// Answer a RESOLVE under the same destination rules and connect slots as
+/// CONNECT. Blocked addresses are left out of the answer.
+async fn resolve(
+    ctx: Arc<SessionContext>,
+    id: u16,
+    host: String,
+    family: AddressFamily,
+    out: mpsc::Sender<Frame>,
+) {
+    if ctx.log_connects.load(Ordering::Relaxed) {
+        info!("{} resolving {}", ctx.username, host);
+    } else {
+        debug!("Resolving {} (request {})", host, id);
+    }
+    if let Some(rule) = ctx.acl.check_host(&host) {
+        warn!(
+            "Blocked {} resolving {} (rule {})",
+            ctx.username, host, rule
+        );
+        let _ = out
+            .send(Frame::resolve_result(id, Err("Name not allowed")))
+            .await;
+        return;
+    }
+
+    let slot = tokio::time::timeout(CONNECT_QUEUE_TIMEOUT, ctx.connect_slots.acquire()).await;
+    let Ok(Ok(_slot)) = slot else {
+        debug!("Resolve {} gave up waiting for a connect slot", id);
+        let _ = out
+            .send(Frame::resolve_result(id, Err("Server busy")))
+            .await;
+        return;
+    };
+    let lookup = ctx.resolver.lookup(&host, 0);
+    let result = match tokio::time::timeout(CONNECT_TIMEOUT, lookup).await {
+        Ok(Ok(addrs)) => {
+            let mut found: Vec<IpAddr> = Vec::new();
+            for ip in addrs.iter().map(|addr| addr.ip()) {
+                if family.matches(ip) && ctx.acl.check_addr(ip).is_none() && !found.contains(&ip) {
+                    found.push(ip);
+                }
+            }
+            if found.is_empty() {
+                Err("No addresses found".to_string())
+            } else {
+                Ok(found)
+            }
+        }
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("Lookup timed out".to_string()),
+    };
+    if let Err(e) = &result {
+        debug!("Resolving {} failed: {}", host, e);
+    }
+    let result = match &result {
+        Ok(found) => Ok(&found[..]),
+        Err(e) => Err(e.as_str()),
+    };
+    let _ = out.send(Frame::resolve_result(id, result)).await;
+}
+
+/// Relay task for one UDP association: send the client's datagrams under
+/// the destination rules and pass every reply back, until the association
+/// is idle for [`UDP_IDLE_TIMEOUT`]
+async fn run_udp_relay(
+    ctx: Arc<SessionContext>,
+    id: u16,
+    socket: UdpSocket,
+    mut rx: mpsc::Receiver<(String, u16, Bytes)>,
+    out: mpsc::Sender<Frame>,
+) {
+    let mut buf = vec![0u8; MAX_DATAGRAM_SIZE];
+    loop {
+        tokio::select! {
+            outgoing = rx.recv() => {
+                let Some((host, port, data)) = outgoing else {
+                    break;
+                };
+                if let Err(e) = send_datagram(&ctx, &socket, &host, port, &data).await {
+                    trace!("UDP association {} to {}:{}: {}", id, host, port, e);
+                }
+            }
+            received = socket.recv_from(&mut buf) => match received {
+                Ok((n, from)) => {
+                    let source = from.ip().to_canonical().to_string();
+                    let frame = Frame::datagram(id, &source, from.port(), &buf[..n]);
+                    if out.send(frame).await.is_err() {
+                        break;
+                    }
+                }
+                // e.g. ICMP port unreachable for an earlier datagram
+                Err(e) => trace!("UDP association {} receive error: {}", id, e),
+            },
+            _ = tokio::time::sleep(UDP_IDLE_TIMEOUT) => {
+                debug!("UDP association {} for {} idle, closing", id, ctx.username);
+                break;
+            }
+        }
+    }
+}
+
+/// Send one datagram for a UDP association. Blocked destinations are
+/// dropped silently, as a firewall would.
+async fn send_datagram(
+    ctx: &SessionContext,
+    socket: &UdpSocket,
+    host: &str,
+    port: u16,
+    data: &[u8],
+) -> std::io::Result<()> {
+    if let Some(rule) = ctx.acl.check_host(host) {
+        debug!(
+            "Dropping datagram from {} to {}:{} (rule {})",
+            ctx.username, host, port, rule
+        );
+        return Ok(());
+    }
+    let dual_stack = socket.local_addr()?.is_ipv6();
+    let lookup = tokio::time::timeout(CONNECT_TIMEOUT, ctx.resolver.lookup(host, port))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+    let addr = lookup
+        .into_iter()
+        .find(|addr| dual_stack || addr.is_ipv4())
+        .ok_or_else(|| std::io::Error::other("No usable address"))?;
+    if let Some(rule) = ctx.acl.check_addr(addr.ip()) {
+        debug!(
+            "Dropping datagram from {} to {}:{} ({}, rule {})",
+            ctx.username,
+            host,
+            port,
+            addr.ip(),
+            rule
+        );
+        return Ok(());
+    }
+    let target = match addr {
+        SocketAddr::V4(v4) if dual_stack => {
+            SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+        }
+        _ => addr,
+    };
+    socket.send_to(data, target).await?;
+    Ok(())
+}
+
+/// Destination requested by a CONNECT frame
+struct ChannelTarget {
+    host: String,
+    port: u16,
+    meta: ConnectMeta,
+}
+
+/// Egress task for one channel: check the ACL, dial the destination, then
+/// pump data both ways until either side closes.
+async fn run_channel(
+    ctx: Arc<SessionContext>,
+    id: u16,
+    target: ChannelTarget,
+    mut rx: mpsc::UnboundedReceiver<Bytes>,
+    (send_window, recv_window): (SendWindow, RecvWindow),
+    out: mpsc::Sender<Frame>,
+) {
+    let ChannelTarget { host, port, .. } = &target;
+    let (host, port) = (host.as_str(), *port);
+
+    if let Some(rule) = ctx.acl.check_host(host) {
+        deny_channel(&ctx, id, &target, None, rule, &out).await;
+        return;
+    }
+
+    // Held for the channel's whole life, so the server's sockets stay
+    // bounded however many sessions share it
+    let Ok(_channel_slot) = ctx.channel_slots.try_acquire() else {
+        debug!("Channel {} refused: max_total_channels reached", id);
+        let _ = out
+            .send(Frame::connect_fail(
+                id,
+                ConnectError::Busy,
+                "Server at its channel limit",
+            ))
+            .await;
+        return;
+    };
+
+    // Per-host caps keep tunneled crawlers from getting the server's
+    // address blacklisted by the sites they hit
+    let _host_permit = match ctx.throttle.acquire(host) {
+        Ok(permit) => permit,
+        Err(refusal) => {
+            let (limit, message) = match refusal {
+                Refusal::Concurrency => ("max_connections", "Destination connection limit reached"),
+                Refusal::Rate => ("connects_per_minute", "Destination rate limit reached"),
+            };
+            debug!("Channel {} to {} refused: {} reached", id, host, limit);
+            let _ = out
+                .send(Frame::connect_fail(id, ConnectError::Busy, message))
+                .await;
+            return;
+        }
+    };
+
+    // Bound concurrent DNS + connect work so a burst of CONNECTs can't
+    // exhaust ephemeral ports or file descriptors; the slot is held until
+    // the connect attempt finishes
+    let slot = tokio::time::timeout(CONNECT_QUEUE_TIMEOUT, ctx.connect_slots.acquire()).await;
+    let Ok(Ok(slot)) = slot else {
+        debug!("Channel {} gave up waiting for a connect slot", id);
+        let _ = out
+            .send(Frame::connect_fail(id, ConnectError::Busy, "Server busy"))
+            .await;
+        return;
+    };
+
+    let connect = async {
+        let addrs = match ctx.resolver.lookup(host, port).await {
+            Ok(addrs) => addrs,
+            Err(e) => return Err((ConnectError::HostNotFound, e)),
+        };
+        // Names resolving into blocked ranges are rejected like IP literals
+        if let Some((ip, rule)) = addrs
+            .iter()
+            .find_map(|addr| Some((addr.ip(), ctx.acl.check_addr(addr.ip())?)))
+        {
+            return Ok(Err((ip, rule)));
+        }
+        #[cfg(feature = "testing")]
+        crate::chaos::delay_connect().await;
+        TcpStream::connect(&addrs[..])
+            .await
+            .map(Ok)
+            .map_err(|e| (ConnectError::from_io(&e), e))
+    };
+    let result = tokio::time::timeout(CONNECT_TIMEOUT, connect).await;
+    drop(slot);
+    let stream = match result {
+        Ok(Ok(Ok(stream))) => stream,
+        Ok(Ok(Err((ip, rule)))) => {
+            deny_channel(&ctx, id, &target, Some(ip), rule, &out).await;
+            return;
+        }
+        Ok(Err((code, e))) => {
+            debug!("Channel {} connect to {}:{} failed: {}", id, host, port, e);
+            let _ = out
+                .send(Frame::connect_fail(id, code, &e.to_string()))
+                .await;
+            return;
+        }
+        Err(_) => {
+            debug!("Channel {} connect to {}:{} timed out", id, host, port);
+            let _ = out
+                .send(Frame::connect_fail(
+                    id,
+                    ConnectError::TimedOut,
+                    "Connection timed out",
+                ))
+                .await;
+            return;
+        }
+    };
+    crate::platform::configure_stream(&stream);
+    if out.send(Frame::connect_ok(id)).await.is_err() {
+        return;
+    }
+
+    // The directions run independently so one waiting for window credit
+    // never holds up the other. Each ends on its own with a half-close; an
+    // error in either aborts the channel.
+    let (mut upstream_read, mut upstream_write) = stream.into_split();
+    let activity = Activity::new();
+    let download = async {
+        // Frames share the read buffer rather than copying out of it; once
+        // they've been written the allocation is reclaimed by `reserve`.
+        // Reads grow while they keep filling the buffer, so a fast
+        // destination is sent in frames as large as the link allows.
+        let mut read_size = crate::IO_BUFFER_SIZE;
+        let mut buf = BytesMut::with_capacity(read_size);
+        loop {
+            buf.reserve(read_size);
+            match upstream_read.read_buf(&mut buf).await {
+                Ok(0) => break,
+                Ok(n) => {
+                    activity.touch();
+                    if n >= read_size {
+                        read_size = (read_size * 2).min(MAX_CHANNEL_READ);
+                    }
+                    while !buf.is_empty() {
+                        let wanted = buf.len().min(MAX_LARGE_PAYLOAD_SIZE);
+                        let Ok(granted) = send_window.reserve(wanted).await else {
+                            return Err(());
+                        };
+                        let chunk = buf.split_to(granted).freeze();
+                        out.send(Frame::data(id, chunk)).await.map_err(drop)?;
+                    }
+                }
+                Err(_) => {
+                    let _ = out.send(Frame::close(id)).await;
+                    return Err(());
+                }
+            }
+        }
+        // The destination is done sending; the client may not be
+        out.send(Frame::shutdown(id)).await.map_err(drop)
+    };
+    let upload = async {
+        // Ends when the client shuts down its side
+        while let Some(data) = rx.recv().await {
+            activity.touch();
+            if upstream_write.write_all(&data).await.is_err() {
+                let _ = out.send(Frame::close(id)).await;
+                return Err(());
+            }
+            if let Some(increment) = recv_window.consume(data.len()) {
+                out.send(Frame::window_update(id, increment))
+                    .await
+                    .map_err(drop)?;
+            }
+        }
+        let _ = upstream_write.shutdown().await;
         Ok(())
+    };
+    let idle = async {
+        match ctx.channel_idle_timeout {
+            Some(timeout) => activity.idle(timeout).await,
+            None => std::future::pending().await,
+        }
+    };
+    tokio::select! {
+        _ = async { tokio::try_join!(download, upload) } => {}
+        _ = idle => {
+            debug!("Channel {} to {}:{} idle, closing", id, host, port);
+            let _ = out.send(Frame::close(id)).await;
+        }
     }
+    trace!("Channel {} closed", id);
+}
+
+/// Reject a CONNECT blocked by the destination ACL.
+///
+/// In honeypot mode the request is recorded and the client gets a plain
+/// "connection refused" after a randomised delay, indistinguishable from a
+/// closed port, rather than an immediate policy error.
+async fn deny_channel(
+    ctx: &SessionContext,
+    id: u16,
+    target: &ChannelTarget,
+    resolved: Option<IpAddr>,
+    rule: &str,
+    out: &mpsc::Sender<Frame>,
+) {
+    warn!(
+        "Blocked {} -> {}:{} (rule {})",
+        ctx.username, target.host, target.port, rule
+    );
+    let Some(honeypot) = &ctx.honeypot else {
+        let _ = out
+            .send(Frame::connect_fail(
+                id,
+                ConnectError::NotAllowed,
+                "Destination not allowed",
+            ))
+            .await;
+        return;
+    };
+
+    let entry = HoneypotEntry {
+        username: &ctx.username,
+        client_addr: ctx.client_addr,
+        channel_id: id,
+        host: &target.host,
+        port: target.port,
+        resolved,
+        rule,
+        meta: &target.meta,
+    };
+    if let Err(e) = honeypot.record(&entry).await {
+        warn!("Failed to write honeypot log: {}", e);
+    }
+    let delay = {
+        use rand::Rng;
+        rand::thread_rng().gen_range(HONEYPOT_DELAY_MS)
+    };
+    tokio::time::sleep(Duration::from_millis(delay)).await;
+    let _ = out
+        .send(Frame::connect_fail(
+            id,
+            ConnectError::Refused,
+            "Connection refused",
+        ))
+        .await;
 }
 
 impl Clone for Server {
@@ -481,30 +2503,36 @@ impl Clone for Server {
         Self {
             config: self.config.clone(),
             users: Arc::clone(&self.users),
+            store: Arc::clone(&self.store),
             tls_acceptor: self.tls_acceptor.clone(),
+            cert_info: self.cert_info.clone(),
+            acl: Arc::clone(&self.acl),
+            honeypot: self.honeypot.clone(),
+            resolver: Arc::clone(&self.resolver),
+            connect_slots: Arc::clone(&self.connect_slots),
+            channel_slots: Arc::clone(&self.channel_slots),
+            throttle: Arc::clone(&self.throttle),
+            metrics: Arc::clone(&self.metrics),
+            fd_limit: self.fd_limit,
+            knock: self.knock.clone(),
+            sessions: Arc::clone(&self.sessions),
+            announcement: Arc::clone(&self.announcement),
+            smtp: Arc::clone(&self.smtp),
+            tasks: self.tasks.clone(),
         }
     }
 }
 
-/// Read a line from stream
-async fn read_line<S: AsyncReadExt + Unpin>(
-    stream: &mut S,
-    buf: &mut BytesMut,
-) -> anyhow::Result<Option<String>> {
-    loop {
-        if let Some(pos) = buf.windows(2).position(|w| w == b"\r\n") {
-            let line = buf.split_to(pos);
-            buf.advance(2); // Skip \r\n
-            return Ok(Some(String::from_utf8_lossy(&line).to_string()));
+/// Listen on the admin socket, replacing a stale one, accessible to its
+/// owner only
+#[cfg(all(unix, not(feature = "minimal")))]
+fn bind_admin_socket(path: &str) -> anyhow::Result<tokio::net::UnixListener> {
+    crate::platform::bind_private_socket(std::path::Path::new(path)).map_err(|e| match e.kind() {
+        std::io::ErrorKind::AddrInUse => {
+            anyhow::anyhow!("Admin socket {} is in use by another server", path)
         }
-
-        let mut temp = vec![0u8; 1024];
-        let n = stream.read(&mut temp).await?;
-        if n == 0 {
-            return Ok(None);
-        }
-        buf.extend_from_slice(&temp[..n]);
-    }
+        _ => anyhow::anyhow!("Can't bind admin socket {}: {}", path, e),
+    })
 }
 
 /// Run the server
@@ -512,3 +2540,380 @@ pub async fn run_server(config: ServerConfig, users: UsersConfig) -> anyhow::Res
     let server = Server::new(config, users).await?;
     server.run().await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::{SinkExt, Stream, StreamExt};
+    use tokio_util::codec::{FramedRead, FramedWrite};
+
+    fn test_context(blocked: &[&str]) -> Arc<SessionContext> {
+        let blocked: Vec<String> = blocked.iter().map(|b| b.to_string()).collect();
+        Arc::new(SessionContext {
+            username: "alice".to_string(),
+            client_addr: "127.0.0.1:40000".parse().unwrap(),
+            log_connects: Arc::default(),
+            acl: Arc::new(DestinationAcl::new(&blocked).unwrap()),
+            honeypot: None,
+            resolver: Arc::new(Resolver::new(&Default::default()).unwrap()),
+            connect_slots: Arc::new(Semaphore::new(4)),
+            channel_slots: Arc::new(Semaphore::new(Semaphore::MAX_PERMITS)),
+            throttle: Arc::default(),
+            channel_idle_timeout: None,
+            violations: Violations::new(Arc::default(), 0),
+            tasks: TaskGroup::new(),
+        })
+    }
+
+    /// Carry a new session over `stream` the way a connection handler
+    /// does, returning the frame loop's task
+    fn spawn_session<S>(
+        stream: S,
+        leftover: BytesMut,
+        ctx: Arc<SessionContext>,
+    ) -> JoinHandle<anyhow::Result<()>>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (link, inbound, outbound) = Link::new(SessionId::random());
+        tokio::spawn(async move {
+            let attachment = link.attach().await;
+            link.run(attachment, stream, leftover, 0, LinkOptions::default())
+                .await
+        });
+        tokio::spawn(serve_frames(inbound, outbound, ctx))
+    }
+
+    /// Frames from the session, without the link's ACKs
+    fn session_frames<R>(reader: R) -> impl Stream<Item = Result<Frame, FrameError>> + Unpin
+    where
+        R: AsyncRead + Unpin,
+    {
+        FramedRead::new(reader, FrameCodec::new()).filter(|frame| {
+            let ack = matches!(frame, Ok(frame) if frame.frame_type == FrameType::Ack);
+            std::future::ready(!ack)
+        })
+    }
+
+    #[test]
+    fn test_parse_resume() {
+        let id = SessionId::random();
+        assert_eq!(
+            parse_resume(&format!("RESUME {id} 17")),
+            Some((id, 17, None))
+        );
+        assert_eq!(parse_resume(&format!("resume {id} 0")), Some((id, 0, None)));
+        assert_eq!(
+            parse_resume(&format!("RESUME {id} 1 dG9rZW4=")),
+            Some((id, 1, Some("dG9rZW4=")))
+        );
+        assert_eq!(parse_resume(&format!("RESUME {id}")), None);
+        assert_eq!(parse_resume(&format!("RESUME {id} 1 token extra")), None);
+        assert_eq!(parse_resume("RESUME nothex 1"), None);
+    }
+
+    fn test_resumable(username: &str, link: &Arc<Link>, client_ip: IpAddr) -> Resumable {
+        Resumable {
+            username: username.to_string(),
+            link: Arc::clone(link),
+            log_connects: Arc::new(AtomicBool::new(true)),
+            client_ip: Arc::new(std::sync::Mutex::new(client_ip)),
+            tasks: TaskGroup::new(),
+        }
+    }
+
+    #[test]
+    fn test_fd_count_recounts_near_limit() {
+        let Some(open) = crate::platform::open_fds() else {
+            return;
+        };
+        let mut fds = FdCount::new(open + FD_SAFETY_MARGIN + 100);
+        assert_eq!(fds.accepted(), None);
+        // An estimate at the limit is recounted before anything is refused
+        fds.open = fds.limit;
+        assert_eq!(fds.accepted(), None);
+        assert!(fds.open < fds.limit - FD_SAFETY_MARGIN);
+
+        let mut fds = FdCount::new(0);
+        assert!(fds.accepted().is_some());
+    }
+
+    #[test]
+    fn test_user_changes_end_sessions() {
+        let entry = |secret: &str| crate::config::UserEntry {
+            secret: secret.to_string(),
+            whitelist: Vec::new(),
+            logging: true,
+        };
+        let mut old = UsersConfig::default();
+        for user in ["alice", "bob", "carol", "dave"] {
+            old.set_user(user, entry(user));
+        }
+        let sessions = Sessions::default();
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let mut started = HashMap::new();
+        for user in ["alice", "bob", "carol", "dave"] {
+            let (link, _inbound, _outbound) = Link::new(SessionId::random());
+            let session = test_resumable(user, &link, ip);
+            sessions.lock().unwrap().insert(link.id(), session.clone());
+            started.insert(user, session);
+        }
+
+        // bob is removed, carol's secret changes, dave stops logging and
+        // is limited to an address his session isn't from
+        let mut new = old.clone();
+        new.remove_user("bob");
+        new.set_user("carol", entry("new"));
+        let dave = new.users.get_mut("dave").unwrap();
+        dave.logging = false;
+        dave.whitelist = vec!["192.0.2.1".to_string()];
+        apply_user_changes(&sessions, true, &old, &new);
+
+        assert!(!started["alice"].tasks.is_cancelled());
+        assert!(started["bob"].tasks.is_cancelled());
+        assert!(started["carol"].tasks.is_cancelled());
+        assert!(!started["dave"].tasks.is_cancelled());
+        assert!(!started["dave"].log_connects.load(Ordering::Relaxed));
+        assert_eq!(sessions.lock().unwrap().len(), 2);
+
+        let mut newer = new.clone();
+        newer.users.get_mut("dave").unwrap().whitelist = vec!["192.0.2.2".to_string()];
+        apply_user_changes(&sessions, true, &new, &newer);
+        assert!(started["dave"].tasks.is_cancelled());
+        assert_eq!(sessions.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_session_ended_when_handler_panics() {
+        let sessions = Sessions::default();
+        let (link, mut inbound, _outbound) = Link::new(SessionId::random());
+        let session = test_resumable("alice", &link, Ipv4Addr::LOCALHOST.into());
+        sessions.lock().unwrap().insert(link.id(), session.clone());
+
+        let guard = SessionGuard::new(&sessions, session.clone());
+        let handler = tokio::spawn(async move {
+            let _guard = guard;
+            panic!("decoding bug");
+        });
+        assert!(handler.await.unwrap_err().is_panic());
+
+        assert!(sessions.lock().unwrap().is_empty());
+        assert!(session.tasks.is_cancelled());
+        assert!(inbound.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_frame_loop_round_trip() {
+        // Echo server standing in for the destination
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut sock, _) = echo.accept().await.unwrap();
+            let (mut r, mut w) = sock.split();
+            let _ = tokio::io::copy(&mut r, &mut w).await;
+        });
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        // CONNECT arrives pipelined with the BINARY command
+        let connect = Frame::connect(1, "127.0.0.1", echo_addr.port());
+        let leftover = BytesMut::from(&connect.serialize()[..]);
+        let session = spawn_session(server, leftover, test_context(&[]));
+
+        let (reader, writer) = tokio::io::split(client);
+        let mut frames = session_frames(reader);
+        let mut sink = FramedWrite::new(writer, FrameCodec::new());
+
+        let reply = frames.next().await.unwrap().unwrap();
+        assert_eq!(reply.frame_type, FrameType::ConnectOk);
+        assert_eq!(reply.channel_id, 1);
+
+        sink.send(Frame::data(1, &b"hello"[..])).await.unwrap();
+        let reply = frames.next().await.unwrap().unwrap();
+        assert_eq!(reply.frame_type, FrameType::Data);
+        assert_eq!(&reply.payload[..], b"hello");
+
+        sink.send(Frame::new(FrameType::Keepalive, 0, Bytes::new()))
+            .await
+            .unwrap();
+        let reply = frames.next().await.unwrap().unwrap();
+        assert_eq!(reply.frame_type, FrameType::KeepaliveAck);
+
+        sink.send(Frame::close(1)).await.unwrap();
+        drop(sink);
+        drop(frames);
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_frame_loop_half_close() {
+        // Destination that answers only once the request is complete
+        let dest = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dest_port = dest.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut sock, _) = dest.accept().await.unwrap();
+            let mut request = Vec::new();
+            sock.read_to_end(&mut request).await.unwrap();
+            let reply = format!("got {}", request.len());
+            sock.write_all(reply.as_bytes()).await.unwrap();
+        });
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        spawn_session(server, BytesMut::new(), test_context(&[]));
+        let (reader, writer) = tokio::io::split(client);
+        let mut frames = session_frames(reader);
+        let mut sink = FramedWrite::new(writer, FrameCodec::new());
+
+        sink.send(Frame::connect(5, "127.0.0.1", dest_port))
+            .await
+            .unwrap();
+        let reply = frames.next().await.unwrap().unwrap();
+        assert_eq!(reply.frame_type, FrameType::ConnectOk);
+
+        sink.send(Frame::data(5, &b"ping"[..])).await.unwrap();
+        sink.send(Frame::shutdown(5)).await.unwrap();
+
+        let reply = frames.next().await.unwrap().unwrap();
+        assert_eq!(reply.frame_type, FrameType::Data);
+        assert_eq!(&reply.payload[..], b"got 4");
+        let reply = frames.next().await.unwrap().unwrap();
+        assert_eq!(reply.frame_type, FrameType::Shutdown);
+        assert_eq!(reply.channel_id, 5);
+    }
+
+    #[tokio::test]
+    async fn test_frame_loop_flow_control() {
+        use crate::proto::flow::INITIAL_WINDOW;
+
+        // Destination that sends far more than one window
+        let source = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let source_port = source.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut sock, _) = source.accept().await.unwrap();
+            let _ = sock
+                .write_all(&vec![7u8; 4 * INITIAL_WINDOW as usize])
+                .await;
+        });
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        spawn_session(server, BytesMut::new(), test_context(&[]));
+        let (reader, writer) = tokio::io::split(client);
+        let mut frames = session_frames(reader);
+        let mut sink = FramedWrite::new(writer, FrameCodec::new());
+
+        sink.send(Frame::connect(3, "127.0.0.1", source_port))
+            .await
+            .unwrap();
+        let reply = frames.next().await.unwrap().unwrap();
+        assert_eq!(reply.frame_type, FrameType::ConnectOk);
+
+        // Exactly one window arrives, then the channel stalls
+        let mut received = 0;
+        while received < INITIAL_WINDOW as usize {
+            let frame = frames.next().await.unwrap().unwrap();
+            assert_eq!(frame.frame_type, FrameType::Data);
+            received += frame.payload.len();
+        }
+        assert_eq!(received, INITIAL_WINDOW as usize);
+        let stalled = tokio::time::timeout(Duration::from_millis(100), frames.next()).await;
+        assert!(stalled.is_err());
+
+        // Credit resumes the flow
+        sink.send(Frame::window_update(3, 1024)).await.unwrap();
+        let frame = frames.next().await.unwrap().unwrap();
+        assert_eq!(frame.frame_type, FrameType::Data);
+        assert!(frame.payload.len() <= 1024);
+    }
+
+    #[tokio::test]
+    async fn test_frame_loop_connect_fail() {
+        // Grab a free port, then close it so the connect is refused
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        spawn_session(server, BytesMut::new(), test_context(&[]));
+
+        let (reader, writer) = tokio::io::split(client);
+        let mut frames = session_frames(reader);
+        let mut sink = FramedWrite::new(writer, FrameCodec::new());
+
+        sink.send(Frame::connect(9, "127.0.0.1", port))
+            .await
+            .unwrap();
+        let reply = frames.next().await.unwrap().unwrap();
+        assert_eq!(reply.frame_type, FrameType::ConnectFail);
+        assert_eq!(reply.channel_id, 9);
+        let (code, _) = reply.parse_connect_fail().unwrap();
+        assert_eq!(code, ConnectError::Refused);
+    }
+
+    #[tokio::test]
+    async fn test_frame_loop_channel_limit() {
+        let ctx = Arc::new(SessionContext {
+            channel_slots: Arc::new(Semaphore::new(0)),
+            ..Arc::into_inner(test_context(&[])).unwrap()
+        });
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        spawn_session(server, BytesMut::new(), ctx);
+
+        let (reader, writer) = tokio::io::split(client);
+        let mut frames = session_frames(reader);
+        let mut sink = FramedWrite::new(writer, FrameCodec::new());
+
+        sink.send(Frame::connect(4, "127.0.0.1", 80)).await.unwrap();
+        let reply = frames.next().await.unwrap().unwrap();
+        assert_eq!(reply.frame_type, FrameType::ConnectFail);
+        assert_eq!(
+            reply.parse_connect_fail(),
+            Some((ConnectError::Busy, "Server at its channel limit".into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_frame_loop_strict_violations() {
+        let ctx = Arc::new(SessionContext {
+            violations: Violations::new(Arc::default(), 3),
+            ..Arc::into_inner(test_context(&[])).unwrap()
+        });
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let session = spawn_session(server, BytesMut::new(), ctx);
+
+        let (_reader, writer) = tokio::io::split(client);
+        let mut sink = FramedWrite::new(writer, FrameCodec::new());
+        // Frames only the server sends, and a WINDOW_UPDATE too short to parse
+        sink.send(Frame::connect_ok(1)).await.unwrap();
+        sink.send(Frame::new(FrameType::WindowUpdate, 1, Bytes::new()))
+            .await
+            .unwrap();
+        sink.send(Frame::connect_ok(2)).await.unwrap();
+
+        let err = session.await.unwrap().unwrap_err();
+        assert!(err.to_string().contains("Too many protocol violations"));
+    }
+
+    #[tokio::test]
+    async fn test_frame_loop_blocked_destination() {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let ctx = test_context(&["127.0.0.0/8", "localhost"]);
+        spawn_session(server, BytesMut::new(), ctx);
+
+        let (reader, writer) = tokio::io::split(client);
+        let mut frames = session_frames(reader);
+        let mut sink = FramedWrite::new(writer, FrameCodec::new());
+
+        for (id, host) in [(1, "127.0.0.1"), (2, "localhost")] {
+            sink.send(Frame::connect(id, host, 80)).await.unwrap();
+            let reply = frames.next().await.unwrap().unwrap();
+            assert_eq!(reply.frame_type, FrameType::ConnectFail);
+            assert_eq!(reply.channel_id, id);
+            assert_eq!(
+                reply.parse_connect_fail(),
+                Some((ConnectError::NotAllowed, "Destination not allowed".into()))
+            );
+        }
+    }
+}
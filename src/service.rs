@@ -0,0 +1,61 @@
+//! tower service for tunnel dialing
+//!
+//! [`TunnelService`] is a `Service<(String, u16)>` whose responses are
+//! channels opened with [`Client::open_stream`]. Being a plain tower
+//! service, it composes with tower middleware: timeouts, retries, load
+//! shedding and so on wrap it the way they wrap any other service.
+
+use crate::client::Client;
+use crate::socks5::TunnelIo;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_service::Service;
+
+/// Dials `(host, port)` as a channel through a [`Client`]'s session from
+/// [`Client::open_session`]
+#[derive(Clone)]
+pub struct TunnelService {
+    client: Arc<Client>,
+}
+
+impl TunnelService {
+    pub fn new(client: Arc<Client>) -> Self {
+        Self { client }
+    }
+}
+
+impl Service<(String, u16)> for TunnelService {
+    type Response = TunnelIo;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<TunnelIo>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, (host, port): (String, u16)) -> Self::Future {
+        let client = Arc::clone(&self.client);
+        Box::pin(async move { client.open_stream(&host, port).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ClientConfig;
+
+    #[tokio::test]
+    async fn test_tunnel_service() {
+        // Without a session there's nothing to dial through
+        let client = Arc::new(Client::new(ClientConfig::default()));
+        let mut service = TunnelService::new(client);
+        let err = service
+            .call(("example.com".to_string(), 443))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+    }
+}
@@ -1,12 +1,27 @@
 //! SOCKS5 Proxy Server
 //!
-//! Implements SOCKS5 protocol (RFC 1928) for local proxy interface.
-
-use bytes::{BufMut, BytesMut};
+//! Implements SOCKS5 protocol (RFC 1928) for local proxy interface. Legacy
+//! SOCKS4 and SOCKS4a clients are told apart by their first byte and get
+//! CONNECT too. A listener may require RFC 1929 username/password
+//! authentication, which SOCKS4 can't do.
+
+use crate::channel::Activity;
+use crate::proto::MAX_LARGE_PAYLOAD_SIZE;
+use crate::tasks::TaskGroup;
+use bytes::{BufMut, Bytes, BytesMut};
+use ipnet::IpNet;
+use std::borrow::Borrow;
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll, ready};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio_util::sync::PollSender;
 use tracing::{debug, info, trace, warn};
 
 /// SOCKS5 protocol constants
@@ -26,6 +41,36 @@ pub const ATYP_IPV4: u8 = 0x01;
 pub const ATYP_DOMAIN: u8 = 0x03;
 pub const ATYP_IPV6: u8 = 0x04;
 
+/// SOCKS4 version byte, and the one its replies carry
+pub const SOCKS4_VERSION: u8 = 0x04;
+pub const SOCKS4_REPLY_VERSION: u8 = 0x00;
+
+/// SOCKS4 reply codes
+pub const SOCKS4_GRANTED: u8 = 0x5a;
+pub const SOCKS4_REJECTED: u8 = 0x5b;
+
+/// Version of the username/password subnegotiation (RFC 1929)
+pub const PASSWORD_VERSION: u8 = 0x01;
+
+/// Longest SOCKS4 user ID or SOCKS4a hostname accepted
+const SOCKS4_MAX_STRING: usize = 255;
+
+/// Protocol a client spoke
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Protocol {
+    Socks4,
+    Socks5,
+}
+
+impl Protocol {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Socks4 => "SOCKS4",
+            Self::Socks5 => "SOCKS5",
+        }
+    }
+}
+
 /// SOCKS5 reply codes
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
@@ -41,6 +86,24 @@ pub enum Reply {
     AddressNotSupported = 0x08,
 }
 
+impl Reply {
+    /// Reply for a CONNECT that failed with `err`, as reported by the
+    /// server's CONNECT_FAIL code or a local connect. A lost tunnel, a busy
+    /// server and anything unrecognised are general failures, so clients
+    /// don't take the proxy's trouble for the destination's.
+    pub fn for_error(err: &io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::PermissionDenied => Self::NotAllowed,
+            io::ErrorKind::NetworkUnreachable => Self::NetworkUnreachable,
+            io::ErrorKind::HostUnreachable | io::ErrorKind::NotFound => Self::HostUnreachable,
+            io::ErrorKind::ConnectionRefused => Self::ConnectionRefused,
+            io::ErrorKind::TimedOut => Self::TtlExpired,
+            io::ErrorKind::InvalidInput => Self::AddressNotSupported,
+            _ => Self::GeneralFailure,
+        }
+    }
+}
+
 /// SOCKS5 request info
 #[derive(Debug, Clone)]
 pub struct ConnectRequest {
@@ -48,10 +111,42 @@ pub struct ConnectRequest {
     pub port: u16,
 }
 
+/// Byte counters for proxied traffic
+#[derive(Debug, Default)]
+pub struct TrafficStats {
+    sent: AtomicU64,
+    received: AtomicU64,
+}
+
+impl TrafficStats {
+    /// Add the totals of a finished connection
+    pub fn record(&self, sent: u64, received: u64) {
+        self.sent.fetch_add(sent, Ordering::Relaxed);
+        self.received.fetch_add(received, Ordering::Relaxed);
+    }
+
+    /// Total bytes sent by local clients
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// Total bytes received by local clients
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+}
+
 /// SOCKS5 server
 pub struct Socks5Server<F> {
     bind_addr: SocketAddr,
     handler: F,
+    stats: Arc<TrafficStats>,
+    tasks: TaskGroup,
+    /// Networks clients may connect from; empty allows everyone
+    allowed_clients: Vec<IpNet>,
+    /// Username and password clients must give
+    credentials: Option<Arc<(String, String)>>,
+    idle_timeout: Option<Duration>,
 }
 
 impl<F, Fut> Socks5Server<F>
@@ -61,61 +156,202 @@ where
 {
     /// Create a new SOCKS5 server
     pub fn new(bind_addr: SocketAddr, handler: F) -> Self {
-        Self { bind_addr, handler }
+        Self {
+            bind_addr,
+            handler,
+            stats: Arc::default(),
+            tasks: TaskGroup::new(),
+            allowed_clients: Vec::new(),
+            credentials: None,
+            idle_timeout: None,
+        }
+    }
+
+    /// Close connections that carry no data for `timeout`
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Require SOCKS5 username/password authentication
+    pub fn with_password(mut self, username: String, password: String) -> Self {
+        self.credentials = Some(Arc::new((username, password)));
+        self
+    }
+
+    /// Only serve clients connecting from these networks
+    pub fn with_allowed_clients(mut self, allowed_clients: Vec<IpNet>) -> Self {
+        self.allowed_clients = allowed_clients;
+        self
+    }
+
+    /// Accumulate transferred bytes into shared counters
+    pub fn with_stats(mut self, stats: Arc<TrafficStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Run connections in `tasks`, so cancelling it drops them
+    pub fn with_tasks(mut self, tasks: TaskGroup) -> Self {
+        self.tasks = tasks;
+        self
     }
 
     /// Start the server
     pub async fn run(self) -> io::Result<()> {
         let listener = TcpListener::bind(self.bind_addr).await?;
-        info!("SOCKS5 proxy listening on {}", self.bind_addr);
+        self.serve(listener).await
+    }
+
+    /// Serve connections on an already bound listener, which may be
+    /// borrowed so it outlives the server
+    pub async fn serve(self, listener: impl Borrow<TcpListener>) -> io::Result<()> {
+        let listener = listener.borrow();
+        info!("SOCKS5 proxy listening on {}", listener.local_addr()?);
 
         loop {
             let (stream, addr) = listener.accept().await?;
-            trace!("SOCKS5 connection from {}", addr);
-
-            let handler = self.handler.clone();
-            tokio::spawn(async move {
-                if let Err(e) = handle_client(stream, handler).await {
-                    debug!("SOCKS5 client error: {}", e);
-                }
-            });
+            self.handle(stream, addr);
+        }
+    }
+
+    /// Serve one connection accepted from `addr`
+    pub fn handle(&self, stream: TcpStream, addr: SocketAddr) {
+        trace!("SOCKS5 connection from {}", addr);
+        let ip = addr.ip().to_canonical();
+        if !self.allowed_clients.is_empty()
+            && !self.allowed_clients.iter().any(|net| net.contains(&ip))
+        {
+            debug!(
+                "Refusing SOCKS connection from {}: not in allowed_clients",
+                addr
+            );
+            return;
         }
+
+        let handler = self.handler.clone();
+        let stats = Arc::clone(&self.stats);
+        let credentials = self.credentials.clone();
+        let idle_timeout = self.idle_timeout;
+        self.tasks.spawn("socks5", async move {
+            let credentials = credentials.as_deref();
+            let result = handle_client(stream, handler, &stats, credentials, idle_timeout).await;
+            if let Err(e) = result {
+                debug!("SOCKS5 client error: {}", e);
+            }
+        });
     }
 }
 
-/// Handle a SOCKS5 client connection
-async fn handle_client<F, Fut>(mut stream: TcpStream, handler: F) -> io::Result<()>
+/// Handle a SOCKS4 or SOCKS5 client connection
+async fn handle_client<F, Fut>(
+    mut stream: TcpStream,
+    handler: F,
+    stats: &TrafficStats,
+    credentials: Option<&(String, String)>,
+    idle_timeout: Option<Duration>,
+) -> io::Result<()>
 where
     F: FnOnce(ConnectRequest) -> Fut + Send,
     Fut: std::future::Future<Output = io::Result<ProxyStream>> + Send,
 {
-    // 1. Greeting
-    let mut buf = [0u8; 2];
-    stream.read_exact(&mut buf).await?;
+    let (protocol, host, port) = match stream.read_u8().await? {
+        VERSION => {
+            let (host, port) = socks5_request(&mut stream, credentials).await?;
+            (Protocol::Socks5, host, port)
+        }
+        SOCKS4_VERSION => {
+            let (host, port) = socks4_request(&mut stream).await?;
+            if credentials.is_some() {
+                send_reply(&mut stream, Protocol::Socks4, Reply::NotAllowed, None).await?;
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "SOCKS4 can't authenticate",
+                ));
+            }
+            (Protocol::Socks4, host, port)
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid SOCKS version",
+            ));
+        }
+    };
 
-    if buf[0] != VERSION {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidData,
-            "Invalid SOCKS version",
-        ));
+    info!("{} CONNECT {}:{}", protocol.name(), host, port);
+
+    // Call handler to establish connection
+    let request = ConnectRequest {
+        host: host.clone(),
+        port,
+    };
+    match handler(request).await {
+        Ok(proxy_stream) => {
+            // Send success reply
+            send_reply(
+                &mut stream,
+                protocol,
+                Reply::Success,
+                Some(proxy_stream.local_addr),
+            )
+            .await?;
+
+            // Start proxying
+            let (sent, received) = proxy_stream
+                .with_idle_timeout(idle_timeout)
+                .proxy(stream)
+                .await?;
+            stats.record(sent, received);
+            debug!(
+                "{} {}:{} closed ({} bytes sent, {} bytes received)",
+                protocol.name(),
+                host,
+                port,
+                sent,
+                received
+            );
+            Ok(())
+        }
+        Err(e) => {
+            warn!("Failed to establish tunnel: {}", e);
+            send_reply(&mut stream, protocol, Reply::for_error(&e), None).await?;
+            Err(e)
+        }
     }
+}
 
-    let nmethods = buf[1] as usize;
+/// Negotiate a SOCKS5 CONNECT, after the version byte, returning its
+/// destination. With `credentials`, the client must authenticate with them.
+async fn socks5_request(
+    stream: &mut TcpStream,
+    credentials: Option<&(String, String)>,
+) -> io::Result<(String, u16)> {
+    // 1. Greeting
+    let nmethods = stream.read_u8().await? as usize;
     let mut methods = vec![0u8; nmethods];
     stream.read_exact(&mut methods).await?;
 
-    // We only support no authentication
-    if !methods.contains(&AUTH_NONE) {
+    if let Some(credentials) = credentials {
+        if !methods.contains(&AUTH_PASSWORD) {
+            stream.write_all(&[VERSION, AUTH_NO_ACCEPTABLE]).await?;
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "Client doesn't offer username/password auth",
+            ));
+        }
+        stream.write_all(&[VERSION, AUTH_PASSWORD]).await?;
+        authenticate(stream, credentials).await?;
+    } else if !methods.contains(&AUTH_NONE) {
         stream.write_all(&[VERSION, AUTH_NO_ACCEPTABLE]).await?;
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
             "No acceptable auth method",
         ));
+    } else {
+        stream.write_all(&[VERSION, AUTH_NONE]).await?;
     }
 
-    // Select no authentication
-    stream.write_all(&[VERSION, AUTH_NONE]).await?;
-
     // 2. Request
     let mut buf = [0u8; 4];
     stream.read_exact(&mut buf).await?;
@@ -131,7 +367,7 @@ where
     let atyp = buf[3];
 
     if cmd != CMD_CONNECT {
-        send_reply(&mut stream, Reply::CommandNotSupported, None).await?;
+        send_reply(stream, Protocol::Socks5, Reply::CommandNotSupported, None).await?;
         return Err(io::Error::new(
             io::ErrorKind::InvalidData,
             "Unsupported command",
@@ -139,13 +375,13 @@ where
     }
 
     // Parse destination address
-    let (host, port) = match atyp {
+    match atyp {
         ATYP_IPV4 => {
             let mut addr = [0u8; 4];
             stream.read_exact(&mut addr).await?;
             let port = stream.read_u16().await?;
             let ip = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
-            (ip.to_string(), port)
+            Ok((ip.to_string(), port))
         }
         ATYP_DOMAIN => {
             let len = stream.read_u8().await?;
@@ -153,7 +389,7 @@ where
             stream.read_exact(&mut domain).await?;
             let port = stream.read_u16().await?;
             let host = String::from_utf8_lossy(&domain).to_string();
-            (host, port)
+            Ok((host, port))
         }
         ATYP_IPV6 => {
             let mut addr = [0u8; 16];
@@ -169,44 +405,110 @@ where
                 u16::from_be_bytes([addr[12], addr[13]]),
                 u16::from_be_bytes([addr[14], addr[15]]),
             );
-            (ip.to_string(), port)
+            Ok((ip.to_string(), port))
         }
         _ => {
-            send_reply(&mut stream, Reply::AddressNotSupported, None).await?;
-            return Err(io::Error::new(
+            send_reply(stream, Protocol::Socks5, Reply::AddressNotSupported, None).await?;
+            Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "Unsupported address type",
-            ));
+            ))
         }
-    };
+    }
+}
 
-    info!("SOCKS5 CONNECT {}:{}", host, port);
+/// Check a username/password subnegotiation against `credentials`
+async fn authenticate(stream: &mut TcpStream, credentials: &(String, String)) -> io::Result<()> {
+    let version = stream.read_u8().await?;
+    if version != PASSWORD_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Invalid auth version",
+        ));
+    }
+    let mut username = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut username).await?;
+    let mut password = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut password).await?;
+
+    let (expected_user, expected_password) = credentials;
+    if username == expected_user.as_bytes() && password == expected_password.as_bytes() {
+        stream.write_all(&[PASSWORD_VERSION, 0]).await?;
+        Ok(())
+    } else {
+        stream.write_all(&[PASSWORD_VERSION, 1]).await?;
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "Wrong SOCKS password for {:?}",
+                String::from_utf8_lossy(&username)
+            ),
+        ))
+    }
+}
 
-    // Call handler to establish connection
-    let request = ConnectRequest { host, port };
-    match handler(request).await {
-        Ok(proxy_stream) => {
-            // Send success reply
-            send_reply(&mut stream, Reply::Success, Some(proxy_stream.local_addr)).await?;
+/// Read a SOCKS4 CONNECT, after the version byte, returning its
+/// destination. A 0.0.0.x address (x != 0) is SOCKS4a: the hostname
+/// follows the user ID, and the proxy resolves it.
+async fn socks4_request(stream: &mut TcpStream) -> io::Result<(String, u16)> {
+    let cmd = stream.read_u8().await?;
+    let port = stream.read_u16().await?;
+    let mut addr = [0u8; 4];
+    stream.read_exact(&mut addr).await?;
+    // The user ID identifies nobody here; it's read and ignored
+    read_socks4_string(stream).await?;
 
-            // Start proxying
-            proxy_stream.proxy(stream).await?;
-            Ok(())
-        }
-        Err(e) => {
-            warn!("Failed to establish tunnel: {}", e);
-            send_reply(&mut stream, Reply::HostUnreachable, None).await?;
-            Err(e)
+    if cmd != CMD_CONNECT {
+        send_reply(stream, Protocol::Socks4, Reply::CommandNotSupported, None).await?;
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unsupported command",
+        ));
+    }
+
+    if addr[..3] == [0, 0, 0] && addr[3] != 0 {
+        let host = read_socks4_string(stream).await?;
+        Ok((host, port))
+    } else {
+        Ok((Ipv4Addr::from(addr).to_string(), port))
+    }
+}
+
+/// A NUL-terminated SOCKS4 string
+async fn read_socks4_string(stream: &mut TcpStream) -> io::Result<String> {
+    let mut bytes = Vec::new();
+    loop {
+        match stream.read_u8().await? {
+            0 => return Ok(String::from_utf8_lossy(&bytes).into_owned()),
+            _ if bytes.len() == SOCKS4_MAX_STRING => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "SOCKS4 string too long",
+                ));
+            }
+            byte => bytes.push(byte),
         }
     }
 }
 
-/// Send SOCKS5 reply
+/// Send a reply in the client's protocol
 async fn send_reply(
     stream: &mut TcpStream,
+    protocol: Protocol,
     reply: Reply,
     bound_addr: Option<SocketAddr>,
 ) -> io::Result<()> {
+    let buf = match protocol {
+        Protocol::Socks5 => socks5_reply(reply, bound_addr),
+        Protocol::Socks4 => socks4_reply(reply, bound_addr),
+    };
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// SOCKS5 reply
+fn socks5_reply(reply: Reply, bound_addr: Option<SocketAddr>) -> BytesMut {
     let mut buf = BytesMut::with_capacity(10);
     buf.put_u8(VERSION);
     buf.put_u8(reply as u8);
@@ -230,22 +532,65 @@ async fn send_reply(
         buf.put_u32(0);
         buf.put_u16(0);
     }
+    buf
+}
 
-    stream.write_all(&buf).await?;
-    stream.flush().await?;
-    Ok(())
+/// SOCKS4 reply: granted or rejected, with the bound address if it's IPv4
+fn socks4_reply(reply: Reply, bound_addr: Option<SocketAddr>) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(8);
+    buf.put_u8(SOCKS4_REPLY_VERSION);
+    buf.put_u8(match reply {
+        Reply::Success => SOCKS4_GRANTED,
+        _ => SOCKS4_REJECTED,
+    });
+    match bound_addr {
+        Some(SocketAddr::V4(addr)) => {
+            buf.put_u16(addr.port());
+            buf.extend_from_slice(&addr.ip().octets());
+        }
+        _ => {
+            buf.put_u16(0);
+            buf.put_u32(0);
+        }
+    }
+    buf
 }
 
+/// Byte stream a [`ProxyStream`] can carry: a direct socket or a tunnel channel
+trait ProxyIo: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> ProxyIo for T {}
+
 /// A stream that can be used for proxying
 pub struct ProxyStream {
     local_addr: SocketAddr,
-    stream: TcpStream,
+    stream: Box<dyn ProxyIo>,
+    /// Close both sides after this long without data either way
+    idle_timeout: Option<Duration>,
 }
 
 impl ProxyStream {
     /// Create a new proxy stream
     pub fn new(local_addr: SocketAddr, stream: TcpStream) -> Self {
-        Self { local_addr, stream }
+        Self::from_io(local_addr, stream)
+    }
+
+    /// Create a proxy stream over any async byte stream (e.g. [`TunnelIo`])
+    pub fn from_io<S>(local_addr: SocketAddr, stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        Self {
+            local_addr,
+            stream: Box::new(stream),
+            idle_timeout: None,
+        }
+    }
+
+    /// Close both sides after `timeout` without data either way
+    pub fn with_idle_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.idle_timeout = timeout;
+        self
     }
 
     /// Get the local address
@@ -253,25 +598,99 @@ impl ProxyStream {
         self.local_addr
     }
 
-    /// Start bidirectional proxying between the SOCKS5 client and the tunneled connection
-    pub async fn proxy(mut self, mut client: TcpStream) -> io::Result<()> {
-        let (mut client_read, mut client_write) = client.split();
-        let (mut stream_read, mut stream_write) = self.stream.split();
-
-        // Bidirectional copy
-        let client_to_stream = tokio::io::copy(&mut client_read, &mut stream_write);
-        let stream_to_client = tokio::io::copy(&mut stream_read, &mut client_write);
-
-        tokio::select! {
-            result = client_to_stream => {
-                debug!("Client to stream finished: {:?}", result);
+    /// Start bidirectional proxying between the SOCKS5 client and the tunneled connection.
+    ///
+    /// Each direction runs until EOF, and EOF on one side is propagated as a
+    /// write shutdown to the other, so half-closed connections keep flowing.
+    /// With an idle timeout, both sides are dropped once it passes without
+    /// data. Returns `(bytes_sent, bytes_received)` from the client's
+    /// perspective.
+    pub async fn proxy<C>(self, client: C) -> io::Result<(u64, u64)>
+    where
+        C: AsyncRead + AsyncWrite + Unpin,
+    {
+        let activity = Activity::new();
+        let mut client = Counted::new(client, activity.clone());
+        let mut stream = Counted::new(self.stream, activity.clone());
+        let copy = tokio::io::copy_bidirectional_with_sizes(
+            &mut client,
+            &mut stream,
+            crate::IO_BUFFER_SIZE,
+            crate::IO_BUFFER_SIZE,
+        );
+        let idle = async {
+            match self.idle_timeout {
+                Some(timeout) => activity.idle(timeout).await,
+                None => std::future::pending().await,
             }
-            result = stream_to_client => {
-                debug!("Stream to client finished: {:?}", result);
+        };
+        tokio::select! {
+            result = copy => {
+                result?;
             }
+            _ = idle => debug!("Proxy idle, closing"),
         }
+        Ok((client.read, stream.read))
+    }
+}
 
-        Ok(())
+impl std::fmt::Debug for ProxyStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ProxyStream")
+            .field("local_addr", &self.local_addr)
+            .finish()
+    }
+}
+
+/// A stream that counts what's read from it and notes it in an [`Activity`]
+struct Counted<S> {
+    inner: S,
+    activity: Activity,
+    read: u64,
+}
+
+impl<S> Counted<S> {
+    fn new(inner: S, activity: Activity) -> Self {
+        Self {
+            inner,
+            activity,
+            read: 0,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let n = (buf.filled().len() - before) as u64;
+        if n > 0 {
+            self.read += n;
+            self.activity.touch();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
     }
 }
 
@@ -285,8 +704,25 @@ pub struct TunnelRequest {
 
 /// A stream through the tunnel
 pub struct TunnelStream {
-    pub reader: tokio::sync::mpsc::Receiver<Vec<u8>>,
-    pub writer: tokio::sync::mpsc::Sender<Vec<u8>>,
+    pub reader: mpsc::Receiver<Bytes>,
+    pub writer: mpsc::Sender<Bytes>,
+}
+
+impl TunnelStream {
+    /// Create a tunnel stream from its channel halves
+    pub fn new(reader: mpsc::Receiver<Bytes>, writer: mpsc::Sender<Bytes>) -> Self {
+        Self { reader, writer }
+    }
+
+    /// Convert into an `AsyncRead + AsyncWrite` adapter
+    pub fn into_io(self) -> TunnelIo {
+        TunnelIo {
+            reader: self.reader,
+            pending: Bytes::new(),
+            writer: PollSender::new(self.writer),
+            scratch: BytesMut::with_capacity(crate::IO_BUFFER_SIZE),
+        }
+    }
 }
 
 impl std::fmt::Debug for TunnelStream {
@@ -294,3 +730,275 @@ impl std::fmt::Debug for TunnelStream {
         f.debug_struct("TunnelStream").finish()
     }
 }
+
+/// `AsyncRead + AsyncWrite` view of a [`TunnelStream`]
+///
+/// Writes are chunked to at most [`MAX_LARGE_PAYLOAD_SIZE`] bytes, the
+/// largest DATA frame the link sends, and only complete once the channel has
+/// capacity, so a slow tunnel pushes back on the writer. Shutting down the
+/// write side closes the sender; the read side reports EOF once the peer
+/// drops its sender.
+pub struct TunnelIo {
+    reader: mpsc::Receiver<Bytes>,
+    pending: Bytes,
+    writer: PollSender<Bytes>,
+    /// Written data is split off this buffer, whose allocation is reused
+    /// once the frames holding it have gone out
+    scratch: BytesMut,
+}
+
+impl std::fmt::Debug for TunnelIo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TunnelIo")
+            .field("pending", &self.pending.len())
+            .finish()
+    }
+}
+
+impl AsyncRead for TunnelIo {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.pending.is_empty() {
+            match ready!(self.reader.poll_recv(cx)) {
+                Some(data) => self.pending = data,
+                None => return Poll::Ready(Ok(())), // EOF
+            }
+        }
+        let n = self.pending.len().min(buf.remaining());
+        buf.put_slice(&self.pending.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for TunnelIo {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if ready!(self.writer.poll_reserve(cx)).is_err() {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let n = buf.len().min(MAX_LARGE_PAYLOAD_SIZE);
+        self.scratch.extend_from_slice(&buf[..n]);
+        let data = self.scratch.split().freeze();
+        self.writer
+            .send_item(data)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.writer.close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tunnel_io_roundtrip() {
+        let (to_io_tx, to_io_rx) = mpsc::channel(4);
+        let (from_io_tx, mut from_io_rx) = mpsc::channel(4);
+        let mut io = TunnelStream::new(to_io_rx, from_io_tx).into_io();
+
+        io.write_all(b"hello").await.unwrap();
+        assert_eq!(&from_io_rx.recv().await.unwrap()[..], b"hello");
+
+        to_io_tx.send(Bytes::from_static(b"world!")).await.unwrap();
+        drop(to_io_tx);
+        let mut small = [0u8; 4];
+        io.read_exact(&mut small).await.unwrap();
+        assert_eq!(&small, b"worl");
+        let mut rest = Vec::new();
+        io.read_to_end(&mut rest).await.unwrap();
+        assert_eq!(rest, b"d!");
+
+        io.shutdown().await.unwrap();
+        assert!(from_io_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tunnel_io_chunks_large_writes() {
+        let (_to_io_tx, to_io_rx) = mpsc::channel(1);
+        let (from_io_tx, mut from_io_rx) = mpsc::channel(4);
+        let mut io = TunnelStream::new(to_io_rx, from_io_tx).into_io();
+
+        let data = vec![7u8; MAX_LARGE_PAYLOAD_SIZE + 10];
+        let n = io.write(&data).await.unwrap();
+        assert_eq!(n, MAX_LARGE_PAYLOAD_SIZE);
+        assert_eq!(
+            from_io_rx.recv().await.unwrap().len(),
+            MAX_LARGE_PAYLOAD_SIZE
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proxy_idle_timeout() {
+        let (io, mut remote) = tokio::io::duplex(64);
+        let (client, mut app) = tokio::io::duplex(64);
+        let proxy = ProxyStream::from_io("10.1.2.3:4567".parse().unwrap(), io)
+            .with_idle_timeout(Some(Duration::from_millis(100)));
+        let proxy = tokio::spawn(proxy.proxy(client));
+
+        app.write_all(b"ping").await.unwrap();
+        let mut data = [0u8; 4];
+        remote.read_exact(&mut data).await.unwrap();
+        remote.write_all(b"pong!").await.unwrap();
+        let mut data = [0u8; 5];
+        app.read_exact(&mut data).await.unwrap();
+
+        // Both sides silent: both are closed, neither having sent EOF
+        assert_eq!(proxy.await.unwrap().unwrap(), (4, 5));
+        assert_eq!(remote.read(&mut data).await.unwrap(), 0);
+        assert_eq!(app.read(&mut data).await.unwrap(), 0);
+    }
+
+    #[test]
+    fn test_reply_for_connect_fail() {
+        use crate::proto::ConnectError;
+
+        let reply = |code: ConnectError| Reply::for_error(&code.io_kind().into()) as u8;
+        assert_eq!(reply(ConnectError::NotAllowed), Reply::NotAllowed as u8);
+        assert_eq!(
+            reply(ConnectError::NetworkUnreachable),
+            Reply::NetworkUnreachable as u8
+        );
+        assert_eq!(
+            reply(ConnectError::HostNotFound),
+            Reply::HostUnreachable as u8
+        );
+        assert_eq!(reply(ConnectError::Refused), Reply::ConnectionRefused as u8);
+        assert_eq!(reply(ConnectError::TimedOut), Reply::TtlExpired as u8);
+        assert_eq!(reply(ConnectError::Busy), Reply::GeneralFailure as u8);
+        let closed = io::Error::new(io::ErrorKind::NotConnected, "Tunnel closed");
+        assert_eq!(Reply::for_error(&closed) as u8, Reply::GeneralFailure as u8);
+    }
+
+    #[tokio::test]
+    async fn test_allowed_clients() {
+        let handler =
+            |_req| async { Err::<ProxyStream, _>(io::ErrorKind::ConnectionRefused.into()) };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Socks5Server::new(addr, handler)
+            .with_allowed_clients(vec!["192.168.1.0/24".parse().unwrap()]);
+        tokio::spawn(server.serve(listener));
+
+        // Dropped on accept, before it sends anything
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut reply = Vec::new();
+        client.read_to_end(&mut reply).await.unwrap();
+        assert!(reply.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_password_auth() {
+        let handler =
+            |_req| async { Err::<ProxyStream, _>(io::ErrorKind::ConnectionRefused.into()) };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Socks5Server::new(addr, handler).with_password("bob".into(), "pw".into());
+        tokio::spawn(server.serve(listener));
+
+        // No auth offered: nothing acceptable
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&[VERSION, 1, AUTH_NONE]).await.unwrap();
+        let mut reply = [0u8; 2];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [VERSION, AUTH_NO_ACCEPTABLE]);
+
+        // Wrong password
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(&[VERSION, 2, AUTH_NONE, AUTH_PASSWORD])
+            .await
+            .unwrap();
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [VERSION, AUTH_PASSWORD]);
+        client.write_all(b"\x01\x03bob\x02no").await.unwrap();
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [PASSWORD_VERSION, 1]);
+
+        // Right password, then the CONNECT goes to the handler
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(&[VERSION, 1, AUTH_PASSWORD])
+            .await
+            .unwrap();
+        client.read_exact(&mut reply).await.unwrap();
+        client.write_all(b"\x01\x03bob\x02pw").await.unwrap();
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [PASSWORD_VERSION, 0]);
+        client
+            .write_all(&[VERSION, CMD_CONNECT, 0, ATYP_IPV4, 10, 0, 0, 1, 0, 80])
+            .await
+            .unwrap();
+        let mut reply = [0u8; 10];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], Reply::ConnectionRefused as u8);
+
+        // SOCKS4 can't authenticate
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"\x04\x01\x00\x50\x0a\x00\x00\x01\x00")
+            .await
+            .unwrap();
+        let mut reply = [0u8; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[1], SOCKS4_REJECTED);
+    }
+
+    #[tokio::test]
+    async fn test_socks4a_connect() {
+        let (requests_tx, mut requests) = mpsc::channel(1);
+        let server = Socks5Server::new("127.0.0.1:0".parse().unwrap(), move |req| {
+            let requests_tx = requests_tx.clone();
+            async move {
+                requests_tx.send(req).await.unwrap();
+                let (io, mut peer) = tokio::io::duplex(64);
+                tokio::spawn(async move { peer.write_all(b"pong").await });
+                Ok(ProxyStream::from_io("10.1.2.3:4567".parse().unwrap(), io))
+            }
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(server.serve(listener));
+
+        // CONNECT example.com:80 as user "me", the host left to the proxy
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"\x04\x01\x00\x50\x00\x00\x00\x01me\x00example.com\x00")
+            .await
+            .unwrap();
+        let mut reply = [0u8; 8];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, [0, SOCKS4_GRANTED, 0x11, 0xd7, 10, 1, 2, 3]);
+        let request = requests.recv().await.unwrap();
+        assert_eq!((request.host.as_str(), request.port), ("example.com", 80));
+        let mut data = [0u8; 4];
+        client.read_exact(&mut data).await.unwrap();
+        assert_eq!(&data, b"pong");
+
+        // Plain SOCKS4 names an address; BIND isn't supported
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"\x04\x02\x00\x50\x7f\x00\x00\x01\x00")
+            .await
+            .unwrap();
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply[..2], [0, SOCKS4_REJECTED]);
+    }
+}
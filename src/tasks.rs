@@ -0,0 +1,126 @@
+//! Task supervision
+//!
+//! Long-lived tasks are spawned into a [`TaskGroup`] rather than detached:
+//! a group is cancelled as a unit, shutdown can wait for every task to
+//! finish, and a task that panics is logged by name instead of vanishing.
+//! A panic also cancels the rest of its group, so a session hit by a bug
+//! is torn down whole rather than left half-working.
+
+use futures_util::FutureExt;
+use std::any::Any;
+use std::fmt;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+use tracing::error;
+
+/// Tasks that are cancelled together and waited for on shutdown
+#[derive(Debug, Clone, Default)]
+pub struct TaskGroup {
+    tracker: TaskTracker,
+    cancel: CancellationToken,
+}
+
+impl TaskGroup {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A group for one part of this one, such as a session. It can be
+    /// cancelled on its own, is cancelled along with this group, and its
+    /// tasks count towards this group's [`shutdown`](Self::shutdown).
+    pub fn child(&self) -> Self {
+        Self {
+            tracker: self.tracker.clone(),
+            cancel: self.cancel.child_token(),
+        }
+    }
+
+    /// Spawn `task`, dropping it if the group is cancelled first. If it
+    /// panics, the panic is logged under `name` and the group cancelled.
+    pub fn spawn<F, N>(&self, name: N, task: F) -> JoinHandle<()>
+    where
+        F: Future<Output = ()> + Send + 'static,
+        N: fmt::Display + Send + 'static,
+    {
+        let cancel = self.cancel.clone();
+        self.tracker.spawn(async move {
+            tokio::select! {
+                _ = cancel.cancelled() => {}
+                result = AssertUnwindSafe(task).catch_unwind() => {
+                    if let Err(panic) = result {
+                        error!("Task {} panicked: {}", name, panic_message(&*panic));
+                        cancel.cancel();
+                    }
+                }
+            }
+        })
+    }
+
+    /// Cancel every task in the group
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// Cancel the group and wait up to `timeout` for its tasks, including
+    /// those of child groups, to finish. False if some were still running.
+    pub async fn shutdown(&self, timeout: Duration) -> bool {
+        self.cancel.cancel();
+        self.tracker.close();
+        tokio::time::timeout(timeout, self.tracker.wait())
+            .await
+            .is_ok()
+    }
+}
+
+/// Text of a panic payload, as the default panic hook prints it
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_child_group_cancelled_with_parent() {
+        let root = TaskGroup::new();
+        let session = root.child();
+        let (_tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let task = session.spawn("waiting", async move {
+            let _ = rx.await;
+        });
+
+        assert!(root.shutdown(Duration::from_secs(1)).await);
+        assert!(session.is_cancelled());
+        task.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_panic_cancels_only_its_group() {
+        let root = TaskGroup::new();
+        let session = root.child();
+        let (_tx, rx) = tokio::sync::oneshot::channel::<()>();
+        let sibling = session.spawn("waiting", async move {
+            let _ = rx.await;
+        });
+
+        let broken = session.spawn("broken", async { panic!("boom") });
+        // The panic is logged, not propagated to whoever awaits the task
+        broken.await.unwrap();
+        sibling.await.unwrap();
+        assert!(session.is_cancelled());
+        assert!(!root.is_cancelled());
+    }
+}
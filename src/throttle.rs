@@ -0,0 +1,167 @@
+//! Per-destination outbound limits
+//!
+//! A crawler pointed through the tunnel can open hundreds of connections
+//! to one site, which then rate-limits or blacklists the server's address
+//! for everyone. With `destination_limits` each destination host gets a cap
+//! on connections open at once and on new connections per minute; channels
+//! over either are refused as busy. Hosts are counted by the name (or IP
+//! literal) clients ask for.
+
+use crate::config::DestinationLimitsConfig;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Hosts tracked before idle ones are forgotten
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// State per host, shared with the permits
+type Hosts = Arc<Mutex<HashMap<String, HostState>>>;
+
+/// Why a connection was refused
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    /// `max_connections` already open to the host
+    Concurrency,
+    /// `connects_per_minute` used up
+    Rate,
+}
+
+/// Connection counts per destination host
+#[derive(Debug, Default)]
+pub struct DestinationThrottle {
+    max_connections: usize,
+    connects_per_minute: u32,
+    hosts: Hosts,
+}
+
+#[derive(Debug)]
+struct HostState {
+    open: usize,
+    /// Connects left in the bucket as of `refilled`
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl HostState {
+    /// Top up the bucket for the time since it was last filled
+    fn refill(&mut self, capacity: u32, now: Instant) {
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * f64::from(capacity) / 60.0).min(f64::from(capacity));
+        self.refilled = now;
+    }
+
+    /// Nothing open and the bucket full: the entry says nothing
+    fn idle(&self, capacity: u32) -> bool {
+        self.open == 0 && (capacity == 0 || self.tokens >= f64::from(capacity))
+    }
+}
+
+impl DestinationThrottle {
+    /// Limits from `config`; without it nothing is limited
+    pub fn new(config: Option<&DestinationLimitsConfig>) -> Self {
+        Self {
+            max_connections: config.map_or(0, |c| c.max_connections),
+            connects_per_minute: config.map_or(0, |c| c.connects_per_minute),
+            hosts: Arc::default(),
+        }
+    }
+
+    fn unlimited(&self) -> bool {
+        self.max_connections == 0 && self.connects_per_minute == 0
+    }
+
+    /// Count a new connection to `host`, if its limits allow one. The
+    /// connection is counted as open until the permit is dropped.
+    pub fn acquire(&self, host: &str) -> Result<HostPermit, Refusal> {
+        if self.unlimited() {
+            return Ok(HostPermit { host: None });
+        }
+        let host = host.to_ascii_lowercase();
+        let capacity = self.connects_per_minute;
+        let now = Instant::now();
+        let mut hosts = self.hosts.lock().unwrap();
+        if hosts.len() >= PRUNE_THRESHOLD {
+            hosts.retain(|_, state| {
+                state.refill(capacity, now);
+                !state.idle(capacity)
+            });
+        }
+        let state = hosts.entry(host.clone()).or_insert(HostState {
+            open: 0,
+            tokens: f64::from(capacity),
+            refilled: now,
+        });
+        if self.max_connections > 0 && state.open >= self.max_connections {
+            return Err(Refusal::Concurrency);
+        }
+        if capacity > 0 {
+            state.refill(capacity, now);
+            if state.tokens < 1.0 {
+                return Err(Refusal::Rate);
+            }
+            state.tokens -= 1.0;
+        }
+        state.open += 1;
+        Ok(HostPermit {
+            host: Some((host, Arc::clone(&self.hosts), capacity)),
+        })
+    }
+}
+
+/// A connection counted against its host's limits
+#[derive(Debug)]
+pub struct HostPermit {
+    host: Option<(String, Hosts, u32)>,
+}
+
+impl Drop for HostPermit {
+    fn drop(&mut self) {
+        let Some((host, hosts, capacity)) = self.host.take() else {
+            return;
+        };
+        let mut hosts = hosts.lock().unwrap();
+        if let Some(state) = hosts.get_mut(&host) {
+            state.open -= 1;
+            state.refill(capacity, Instant::now());
+            if state.idle(capacity) {
+                hosts.remove(&host);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_destination_limits() {
+        let throttle = DestinationThrottle::new(Some(&DestinationLimitsConfig {
+            max_connections: 2,
+            connects_per_minute: 3,
+        }));
+        let a = throttle.acquire("example.com").unwrap();
+        let b = throttle.acquire("EXAMPLE.com").unwrap();
+        assert_eq!(
+            throttle.acquire("example.com").unwrap_err(),
+            Refusal::Concurrency
+        );
+        // Other hosts have limits of their own
+        let other = throttle.acquire("example.org").unwrap();
+
+        drop(a);
+        let c = throttle.acquire("example.com").unwrap();
+        drop((b, c));
+        // Three connects in the minute, however short-lived
+        assert_eq!(throttle.acquire("example.com").unwrap_err(), Refusal::Rate);
+        drop(other);
+
+        let unlimited = DestinationThrottle::new(None);
+        let permits: Vec<_> = (0..100)
+            .map(|_| unlimited.acquire("example.com").unwrap())
+            .collect();
+        assert_eq!(permits.len(), 100);
+        assert!(unlimited.hosts.lock().unwrap().is_empty());
+    }
+}
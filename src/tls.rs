@@ -0,0 +1,284 @@
+//! TLS helpers shared by client and server
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{
+    CryptoProvider, WebPkiSupportedAlgorithms, verify_tls12_signature, verify_tls13_signature,
+};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio_rustls::TlsConnector;
+use tracing::{debug, error, warn};
+
+#[cfg(not(any(feature = "tls-ring", feature = "tls-aws-lc")))]
+compile_error!("enable one TLS backend feature: `tls-ring` or `tls-aws-lc`");
+
+/// Crypto provider selected at build time
+#[cfg(feature = "tls-aws-lc")]
+pub fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::aws_lc_rs::default_provider())
+}
+
+/// Crypto provider selected at build time
+#[cfg(all(feature = "tls-ring", not(feature = "tls-aws-lc")))]
+pub fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// TLS exporter label for binding AUTH tokens to a connection
+const CHANNEL_BINDING_LABEL: &[u8] = b"EXPORTER-smtp-tunnel-auth";
+
+/// Length of the exported channel binding value
+pub const CHANNEL_BINDING_LEN: usize = 32;
+
+/// Export the channel binding value for an established TLS connection.
+///
+/// Both ends of the same connection get the same value; a MITM terminating
+/// TLS ends up with two connections and two different values.
+pub fn channel_binding<D>(conn: &rustls::ConnectionCommon<D>) -> Option<[u8; CHANNEL_BINDING_LEN]> {
+    conn.export_keying_material([0u8; CHANNEL_BINDING_LEN], CHANNEL_BINDING_LABEL, None)
+        .ok()
+}
+
+/// Summary of an X.509 certificate
+#[derive(Debug, Clone)]
+pub struct CertInfo {
+    /// Subject distinguished name
+    pub subject: String,
+    /// Issuer distinguished name
+    pub issuer: String,
+    /// Expiry as a Unix timestamp
+    pub not_after: i64,
+    /// SHA-256 of the public key, as published in TLSA 3 1 1 records
+    pub spki_sha256: [u8; 32],
+}
+
+impl CertInfo {
+    /// Parse a DER-encoded certificate
+    pub fn from_der(der: &[u8]) -> anyhow::Result<Self> {
+        let (_, cert) = x509_parser::parse_x509_certificate(der)
+            .map_err(|e| anyhow::anyhow!("Invalid certificate: {e}"))?;
+        Ok(Self {
+            subject: cert.subject().to_string(),
+            issuer: cert.issuer().to_string(),
+            not_after: cert.validity().not_after.timestamp(),
+            spki_sha256: Sha256::digest(cert.public_key().raw).into(),
+        })
+    }
+
+    /// Parse the first certificate of a PEM file
+    pub fn from_pem(pem: &[u8]) -> anyhow::Result<Self> {
+        let der = rustls_pemfile::certs(&mut &pem[..])
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("No certificate found"))??;
+        Self::from_der(&der)
+    }
+
+    /// Whole days until expiry (negative once expired)
+    pub fn days_remaining(&self) -> i64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or_default();
+        (self.not_after - now).div_euclid(86400)
+    }
+
+    /// Classify the certificate against a warning window
+    pub fn expiry_status(&self, warn_days: u32) -> ExpiryStatus {
+        let days = self.days_remaining();
+        if days < 0 {
+            ExpiryStatus::Expired
+        } else if days < i64::from(warn_days) {
+            ExpiryStatus::ExpiringSoon(days)
+        } else {
+            ExpiryStatus::Valid(days)
+        }
+    }
+
+    /// Log a warning if the certificate is expired or about to expire.
+    ///
+    /// `what` names the certificate in the message ("Server certificate",
+    /// "CA certificate", ...). Returns the computed status.
+    pub fn check_expiry(&self, what: &str, warn_days: u32) -> ExpiryStatus {
+        let status = self.expiry_status(warn_days);
+        match status {
+            ExpiryStatus::Expired => error!(
+                "{} {} expired on {}",
+                what,
+                self.subject,
+                self.expiry_date()
+            ),
+            ExpiryStatus::ExpiringSoon(days) => warn!(
+                "{} {} expires in {} days ({})",
+                what,
+                self.subject,
+                days,
+                self.expiry_date()
+            ),
+            ExpiryStatus::Valid(days) => {
+                debug!("{} {} valid for {} more days", what, self.subject, days)
+            }
+        }
+        status
+    }
+
+    /// Expiry formatted as a UTC date
+    pub fn expiry_date(&self) -> String {
+        time::OffsetDateTime::from_unix_timestamp(self.not_after)
+            .map(|t| t.date().to_string())
+            .unwrap_or_else(|_| self.not_after.to_string())
+    }
+}
+
+/// Result of a certificate expiry check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpiryStatus {
+    /// Valid for the given number of days, outside the warning window
+    Valid(i64),
+    /// Valid, but expiring within the warning window
+    ExpiringSoon(i64),
+    /// Already expired
+    Expired,
+}
+
+/// Certificate verifier that accepts any certificate but still checks the
+/// handshake signatures against it.
+///
+/// Only used by the server's loopback self-test: a key that doesn't belong to
+/// the certificate makes the handshake fail, which is exactly what we want to
+/// catch before real clients do.
+#[derive(Debug)]
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub(crate) struct SelfTestVerifier {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl SelfTestVerifier {
+    pub(crate) fn new() -> Self {
+        Self {
+            algorithms: provider().signature_verification_algorithms,
+        }
+    }
+}
+
+impl ServerCertVerifier for SelfTestVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// TLS connector for the tunnel client.
+///
+/// With `ca_cert` set, only certificates issued by that CA are trusted (the
+/// normal self-signed deployment); otherwise the bundled web PKI roots are
+/// used, for servers with a publicly issued certificate.
+pub fn client_connector(ca_cert: Option<&str>) -> anyhow::Result<TlsConnector> {
+    if ca_cert.is_none() {
+        warn!("No ca_cert configured, verifying the server against public CAs");
+    }
+    Ok(TlsConnector::from(client_config(ca_cert)?))
+}
+
+/// Client TLS settings trusting the CA in `ca_cert`, or the web PKI roots
+pub fn client_config(ca_cert: Option<&str>) -> anyhow::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    match ca_cert {
+        Some(path) => {
+            let pem = std::fs::read(path)
+                .map_err(|e| anyhow::anyhow!("Failed to read CA certificate {path}: {e}"))?;
+            for cert in rustls_pemfile::certs(&mut &pem[..]) {
+                roots.add(cert?)?;
+            }
+            if roots.is_empty() {
+                anyhow::bail!("No certificates found in {path}");
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// TLS connector for the server's loopback self-test
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub(crate) fn self_test_connector() -> anyhow::Result<TlsConnector> {
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(SelfTestVerifier::new()))
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cert_expiring_in(days: i64) -> CertInfo {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        CertInfo {
+            subject: "CN=test".to_string(),
+            issuer: "CN=test".to_string(),
+            not_after: now + days * 86400 + 60,
+            spki_sha256: [0; 32],
+        }
+    }
+
+    #[test]
+    fn test_expiry_status() {
+        assert_eq!(
+            cert_expiring_in(365).expiry_status(30),
+            ExpiryStatus::Valid(365)
+        );
+        assert_eq!(
+            cert_expiring_in(10).expiry_status(30),
+            ExpiryStatus::ExpiringSoon(10)
+        );
+        assert_eq!(
+            cert_expiring_in(0).expiry_status(30),
+            ExpiryStatus::ExpiringSoon(0)
+        );
+        assert_eq!(
+            cert_expiring_in(-2).expiry_status(30),
+            ExpiryStatus::Expired
+        );
+    }
+}
@@ -0,0 +1,283 @@
+//! Transparent mode: redirect selected processes into the tunnel
+//!
+//! For applications that can't be pointed at a SOCKS proxy, the client can
+//! intercept the outbound TCP connections of chosen processes and carry them
+//! over the tunnel like SOCKS requests. Interception needs the WinDivert
+//! driver, so it's only available in Windows builds with the `windivert`
+//! feature; the packet rewriting itself is plain Rust and lives here.
+//!
+//! Redirection reflects packets: a segment from `local:p` to `remote:r` is
+//! turned around into `remote:p -> local:listen` and re-injected inbound, so
+//! the local listener sees the original remote address as its peer. Replies
+//! from the listener are rewritten back the same way. Only IPv4 is
+//! redirected.
+//!
+//! Tun mode on Linux and macOS reuses the reflection for every TCP
+//! connection routed into a tun device.
+
+// Process classification is only reachable with WinDivert
+#![cfg_attr(not(all(windows, feature = "windivert")), allow(dead_code))]
+
+mod packet;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod tun;
+#[cfg(all(windows, feature = "windivert"))]
+mod windivert;
+
+use crate::config::{TransparentConfig, TunConfig};
+use crate::routes::RouteGuard;
+use packet::TcpPacket;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a SYN waits for the socket watcher to classify its connection
+const SYN_CLASSIFY_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// How a local connection is routed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Route {
+    /// Left alone (not from a selected process)
+    Direct,
+    /// Redirected into the tunnel; holds the original destination
+    Redirect(SocketAddrV4),
+}
+
+/// Routes of connections, keyed by their local port.
+///
+/// Entries outlive their sockets so the closing FIN/ACK exchange is still
+/// rewritten; a closed entry only stops matching new connections and is
+/// replaced when the port is reused.
+#[derive(Debug, Default)]
+struct NatTable {
+    routes: Mutex<HashMap<u16, (Route, bool)>>,
+    changed: Condvar,
+}
+
+impl NatTable {
+    /// Record a newly opened connection
+    fn insert(&self, local_port: u16, route: Route) {
+        self.routes
+            .lock()
+            .unwrap()
+            .insert(local_port, (route, true));
+        self.changed.notify_all();
+    }
+
+    /// Mark a connection's socket as closed
+    fn close(&self, local_port: u16) {
+        if let Some((_, open)) = self.routes.lock().unwrap().get_mut(&local_port) {
+            *open = false;
+        }
+    }
+
+    fn get(&self, local_port: u16) -> Option<Route> {
+        self.routes
+            .lock()
+            .unwrap()
+            .get(&local_port)
+            .map(|(route, _)| *route)
+    }
+
+    /// Route of a connection being opened, waiting up to `timeout` for the
+    /// socket watcher to classify it
+    fn wait_for(&self, local_port: u16, timeout: Duration) -> Option<Route> {
+        let deadline = Instant::now() + timeout;
+        let mut routes = self.routes.lock().unwrap();
+        loop {
+            if let Some((route, true)) = routes.get(&local_port) {
+                return Some(*route);
+            }
+            let remaining = deadline.checked_duration_since(Instant::now())?;
+            routes = self.changed.wait_timeout(routes, remaining).unwrap().0;
+        }
+    }
+}
+
+/// Rewrite an intercepted outbound packet if it belongs to a redirected
+/// connection. Returns true when the packet must be re-injected inbound.
+fn divert(packet: &mut TcpPacket<'_>, table: &NatTable, listen_port: u16) -> bool {
+    // Listener -> application: restore the original source port
+    if packet.src_port() == listen_port {
+        let Some(Route::Redirect(dst)) = table.get(packet.dst_port()) else {
+            return false;
+        };
+        if *dst.ip() != packet.dst_ip() {
+            return false;
+        }
+        packet.set_src_port(dst.port());
+        packet.swap_addrs();
+        packet.update_checksums();
+        return true;
+    }
+
+    // Application -> original destination: reflect into the listener
+    let route = if packet.is_syn() {
+        table.wait_for(packet.src_port(), SYN_CLASSIFY_TIMEOUT)
+    } else {
+        table.get(packet.src_port())
+    };
+    let Some(Route::Redirect(dst)) = route else {
+        return false;
+    };
+    if dst != SocketAddrV4::new(packet.dst_ip(), packet.dst_port()) {
+        return false;
+    }
+    packet.set_dst_port(listen_port);
+    packet.swap_addrs();
+    packet.update_checksums();
+    true
+}
+
+/// Intercepts connections of the configured processes, or of everything
+/// routed into the tun device
+#[derive(Debug)]
+pub struct Redirector {
+    table: Arc<NatTable>,
+    port: u16,
+    /// Routes into the tun device, removed when the redirector goes
+    routes: Option<Mutex<RouteGuard>>,
+}
+
+impl Redirector {
+    /// Install the packet filters and start redirecting
+    pub fn start(config: &TransparentConfig) -> anyhow::Result<Self> {
+        #[cfg(all(windows, feature = "windivert"))]
+        {
+            let table = Arc::new(NatTable::default());
+            windivert::start(config, Arc::clone(&table))?;
+            Ok(Self {
+                table,
+                port: config.port,
+                routes: None,
+            })
+        }
+        #[cfg(not(all(windows, feature = "windivert")))]
+        {
+            let _ = config;
+            anyhow::bail!("transparent mode needs a Windows build with the `windivert` feature")
+        }
+    }
+
+    /// Create the tun device and route traffic into it. The `servers` are
+    /// kept on their current routes so the tunnel doesn't loop into itself.
+    pub fn start_tun(config: &TunConfig, servers: &[IpAddr]) -> anyhow::Result<Self> {
+        #[cfg(any(target_os = "linux", target_os = "macos"))]
+        {
+            let table = Arc::new(NatTable::default());
+            let routes = tun::start(config, servers, Arc::clone(&table))?;
+            Ok(Self {
+                table,
+                port: config.port,
+                routes: Some(Mutex::new(routes)),
+            })
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+        {
+            let _ = (config, servers);
+            anyhow::bail!("tun mode is only available on Linux and macOS")
+        }
+    }
+
+    /// Keep `server` off the tun device as well, for servers the client
+    /// dials after tun mode started. Does nothing in transparent mode.
+    pub fn pin(&self, server: IpAddr) -> anyhow::Result<()> {
+        match &self.routes {
+            // Only IPv4 is routed into the device
+            Some(routes) if server.is_ipv4() => routes.lock().unwrap().pin_server(server),
+            _ => Ok(()),
+        }
+    }
+
+    /// Address the redirect listener must be bound to
+    pub fn listen_addr(&self) -> SocketAddr {
+        SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), self.port)
+    }
+
+    /// Original destination of a redirected connection, by the peer
+    /// address the listener accepted it from
+    pub fn original_destination(&self, peer: SocketAddr) -> Option<SocketAddrV4> {
+        let SocketAddr::V4(peer) = peer else {
+            return None;
+        };
+        match self.table.get(peer.port()) {
+            Some(Route::Redirect(dst)) if dst.ip() == peer.ip() => Some(dst),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::packet::tests::{checksums_valid, syn};
+    use super::*;
+
+    const LISTEN_PORT: u16 = 1081;
+
+    #[test]
+    fn test_divert_round_trip() {
+        let local = Ipv4Addr::new(192, 168, 1, 20);
+        let remote = SocketAddrV4::new(Ipv4Addr::new(93, 184, 216, 34), 443);
+        let table = NatTable::default();
+        table.insert(50000, Route::Redirect(remote));
+        table.insert(50001, Route::Direct);
+
+        // Application SYN is reflected into the listener
+        let mut buf = syn((local, 50000), (*remote.ip(), remote.port()));
+        let mut packet = TcpPacket::parse(&mut buf).unwrap();
+        assert!(divert(&mut packet, &table, LISTEN_PORT));
+        assert_eq!(packet.src_ip(), *remote.ip());
+        assert_eq!((packet.dst_ip(), packet.dst_port()), (local, LISTEN_PORT));
+        assert!(checksums_valid(&buf));
+
+        let redirector = Redirector {
+            table: Arc::new(table),
+            port: LISTEN_PORT,
+            routes: None,
+        };
+        let peer = SocketAddr::new((*remote.ip()).into(), 50000);
+        assert_eq!(redirector.original_destination(peer), Some(remote));
+        let table = &redirector.table;
+
+        // Listener reply goes back looking like it came from the remote
+        let mut buf = syn((local, LISTEN_PORT), (*remote.ip(), 50000));
+        let mut packet = TcpPacket::parse(&mut buf).unwrap();
+        assert!(divert(&mut packet, table, LISTEN_PORT));
+        assert_eq!((packet.src_ip(), packet.src_port()), (*remote.ip(), 443));
+        assert_eq!((packet.dst_ip(), packet.dst_port()), (local, 50000));
+        assert!(checksums_valid(&buf));
+
+        // Other connections pass untouched
+        let mut buf = syn((local, 50001), (*remote.ip(), 443));
+        assert!(!divert(
+            &mut TcpPacket::parse(&mut buf).unwrap(),
+            table,
+            LISTEN_PORT
+        ));
+    }
+
+    #[test]
+    fn test_unclassified_syn_waits_then_passes() {
+        let table = Arc::new(NatTable::default());
+        let remote = SocketAddrV4::new(Ipv4Addr::new(10, 1, 2, 3), 80);
+
+        let watcher = Arc::clone(&table);
+        let classify = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(5));
+            watcher.insert(40000, Route::Redirect(remote));
+        });
+        assert_eq!(
+            table.wait_for(40000, Duration::from_secs(5)),
+            Some(Route::Redirect(remote))
+        );
+        classify.join().unwrap();
+
+        assert_eq!(table.wait_for(40001, Duration::from_millis(5)), None);
+
+        // A closed entry still routes its last packets but not a new SYN
+        table.close(40000);
+        assert_eq!(table.get(40000), Some(Route::Redirect(remote)));
+        assert_eq!(table.wait_for(40000, Duration::from_millis(5)), None);
+    }
+}
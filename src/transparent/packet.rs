@@ -0,0 +1,191 @@
+//! In-place IPv4/TCP header rewriting for redirected packets
+
+use std::net::Ipv4Addr;
+
+const IPV4_MIN_HEADER: usize = 20;
+const TCP_MIN_HEADER: usize = 20;
+const PROTO_TCP: u8 = 6;
+
+/// TCP SYN flag
+pub const TCP_SYN: u8 = 0x02;
+/// TCP ACK flag
+pub const TCP_ACK: u8 = 0x10;
+
+/// Mutable view of an IPv4 packet carrying a TCP segment
+pub struct TcpPacket<'a> {
+    buf: &'a mut [u8],
+    ip_len: usize,
+}
+
+impl<'a> TcpPacket<'a> {
+    /// Parse a packet; None for anything but unfragmented IPv4 TCP
+    pub fn parse(buf: &'a mut [u8]) -> Option<Self> {
+        if buf.len() < IPV4_MIN_HEADER || buf[0] >> 4 != 4 || buf[9] != PROTO_TCP {
+            return None;
+        }
+        let ip_len = usize::from(buf[0] & 0x0f) * 4;
+        let total = usize::from(u16::from_be_bytes([buf[2], buf[3]]));
+        let fragment_offset = u16::from_be_bytes([buf[6], buf[7]]) & 0x1fff;
+        if ip_len < IPV4_MIN_HEADER
+            || total > buf.len()
+            || total < ip_len + TCP_MIN_HEADER
+            || fragment_offset != 0
+        {
+            return None;
+        }
+        Some(Self {
+            buf: &mut buf[..total],
+            ip_len,
+        })
+    }
+
+    pub fn src_ip(&self) -> Ipv4Addr {
+        Ipv4Addr::new(self.buf[12], self.buf[13], self.buf[14], self.buf[15])
+    }
+
+    pub fn dst_ip(&self) -> Ipv4Addr {
+        Ipv4Addr::new(self.buf[16], self.buf[17], self.buf[18], self.buf[19])
+    }
+
+    pub fn src_port(&self) -> u16 {
+        self.read_u16(self.ip_len)
+    }
+
+    pub fn dst_port(&self) -> u16 {
+        self.read_u16(self.ip_len + 2)
+    }
+
+    pub fn set_src_port(&mut self, port: u16) {
+        self.write_u16(self.ip_len, port);
+    }
+
+    pub fn set_dst_port(&mut self, port: u16) {
+        self.write_u16(self.ip_len + 2, port);
+    }
+
+    /// TCP flags byte
+    pub fn flags(&self) -> u8 {
+        self.buf[self.ip_len + 13]
+    }
+
+    /// Whether this is the opening SYN of a connection
+    pub fn is_syn(&self) -> bool {
+        self.flags() & (TCP_SYN | TCP_ACK) == TCP_SYN
+    }
+
+    /// The whole packet, up to the IP total length
+    pub fn as_bytes(&self) -> &[u8] {
+        self.buf
+    }
+
+    /// Swap the source and destination addresses
+    pub fn swap_addrs(&mut self) {
+        for i in 12..16 {
+            self.buf.swap(i, i + 4);
+        }
+    }
+
+    /// Recompute the IP header and TCP checksums after rewriting
+    pub fn update_checksums(&mut self) {
+        self.write_u16(10, 0);
+        let ip_sum = checksum(&self.buf[..self.ip_len], 0);
+        self.write_u16(10, ip_sum);
+
+        let tcp_offset = self.ip_len + 16;
+        self.write_u16(tcp_offset, 0);
+        let segment_len = (self.buf.len() - self.ip_len) as u32;
+        // Pseudo header: addresses, protocol and segment length
+        let pseudo = sum_words(&self.buf[12..20], u32::from(PROTO_TCP) + segment_len);
+        let tcp_sum = checksum(&self.buf[self.ip_len..], pseudo);
+        self.write_u16(tcp_offset, tcp_sum);
+    }
+
+    fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.buf[offset], self.buf[offset + 1]])
+    }
+
+    fn write_u16(&mut self, offset: usize, value: u16) {
+        self.buf[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+    }
+}
+
+/// Add big-endian 16-bit words to a running one's-complement sum
+fn sum_words(data: &[u8], mut sum: u32) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for word in &mut chunks {
+        sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    if let [last] = chunks.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    sum
+}
+
+/// Internet checksum of `data`, seeded with a partial sum
+fn checksum(data: &[u8], seed: u32) -> u16 {
+    let mut sum = sum_words(data, seed);
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /// A SYN from `src` to `dst` with valid checksums
+    pub fn syn(src: (Ipv4Addr, u16), dst: (Ipv4Addr, u16)) -> Vec<u8> {
+        let mut buf = vec![0u8; 40];
+        buf[0] = 0x45;
+        buf[2..4].copy_from_slice(&40u16.to_be_bytes());
+        buf[8] = 64;
+        buf[9] = PROTO_TCP;
+        buf[12..16].copy_from_slice(&src.0.octets());
+        buf[16..20].copy_from_slice(&dst.0.octets());
+        buf[20..22].copy_from_slice(&src.1.to_be_bytes());
+        buf[22..24].copy_from_slice(&dst.1.to_be_bytes());
+        buf[32] = 5 << 4;
+        buf[33] = TCP_SYN;
+        TcpPacket::parse(&mut buf).unwrap().update_checksums();
+        buf
+    }
+
+    /// Whether the IP and TCP checksums of a packet verify
+    pub fn checksums_valid(buf: &[u8]) -> bool {
+        let ip_len = usize::from(buf[0] & 0x0f) * 4;
+        let segment_len = (buf.len() - ip_len) as u32;
+        let pseudo = sum_words(&buf[12..20], u32::from(PROTO_TCP) + segment_len);
+        checksum(&buf[..ip_len], 0) == 0 && checksum(&buf[ip_len..], pseudo) == 0
+    }
+
+    #[test]
+    fn test_rewrite_keeps_checksums_valid() {
+        let local = Ipv4Addr::new(192, 168, 1, 20);
+        let remote = Ipv4Addr::new(93, 184, 216, 34);
+        let mut buf = syn((local, 50000), (remote, 443));
+        assert!(checksums_valid(&buf));
+
+        let mut packet = TcpPacket::parse(&mut buf).unwrap();
+        assert!(packet.is_syn());
+        packet.swap_addrs();
+        packet.set_dst_port(1081);
+        packet.update_checksums();
+        assert_eq!(packet.src_ip(), remote);
+        assert_eq!(packet.dst_ip(), local);
+        assert_eq!((packet.src_port(), packet.dst_port()), (50000, 1081));
+        assert!(checksums_valid(&buf));
+    }
+
+    #[test]
+    fn test_parse_rejects_other_packets() {
+        let mut buf = syn((Ipv4Addr::LOCALHOST, 1), (Ipv4Addr::LOCALHOST, 2));
+        buf[9] = 17; // UDP
+        assert!(TcpPacket::parse(&mut buf).is_none());
+        buf[9] = PROTO_TCP;
+        buf[6] = 0x00;
+        buf[7] = 0x10; // Non-first fragment
+        assert!(TcpPacket::parse(&mut buf).is_none());
+        assert!(TcpPacket::parse(&mut buf[..30]).is_none());
+    }
+}
@@ -0,0 +1,336 @@
+//! Tun devices and the tun mode packet loop
+//!
+//! Tun mode routes IPv4 into a tun device instead of intercepting chosen
+//! processes. Every TCP connection arriving there is reflected into the
+//! redirect listener the same way transparent mode does it; anything else
+//! is dropped. On Linux the device comes from `/dev/net/tun`. On macOS it's
+//! a utun device from the `com.apple.net.utun_control` kernel control
+//! socket, which puts a 4-byte address family header in front of each
+//! packet.
+
+use super::packet::TcpPacket;
+use super::{NatTable, Route};
+use crate::config::TunConfig;
+use crate::routes::{NextHop, RouteGuard, run};
+use ipnet::IpNet;
+use std::io;
+use std::net::{IpAddr, SocketAddrV4};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::process::Command;
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+
+/// Largest packet read from the device
+const MAX_PACKET: usize = 0xffff;
+
+/// Length of the address family header utun puts before each packet
+const AF_HEADER_LEN: usize = 4;
+
+/// An open tun device
+struct Device {
+    fd: OwnedFd,
+    name: String,
+}
+
+impl Device {
+    /// Create a device named `name`, or one the kernel names
+    #[cfg(target_os = "linux")]
+    fn open(name: Option<&str>) -> io::Result<Self> {
+        /// `struct ifreq` with the flags member of its union
+        #[repr(C)]
+        struct IfReq {
+            name: [u8; libc::IFNAMSIZ],
+            flags: libc::c_short,
+            _pad: [u8; 22],
+        }
+
+        // SAFETY: a plain open of a NUL-terminated path
+        let fd = unsafe { libc::open(c"/dev/net/tun".as_ptr(), libc::O_RDWR | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: fd was just opened and is owned from here on
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let mut req = IfReq {
+            name: [0; libc::IFNAMSIZ],
+            flags: (libc::IFF_TUN | libc::IFF_NO_PI) as libc::c_short,
+            _pad: [0; 22],
+        };
+        let name = name.unwrap_or("smtptun%d").as_bytes();
+        if name.len() >= libc::IFNAMSIZ {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "tun device name too long",
+            ));
+        }
+        req.name[..name.len()].copy_from_slice(name);
+        // SAFETY: req is a valid ifreq for TUNSETIFF, which writes the name back
+        if unsafe { libc::ioctl(fd.as_raw_fd(), libc::TUNSETIFF, &mut req) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let len = req
+            .name
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(req.name.len());
+        Ok(Self {
+            fd,
+            name: String::from_utf8_lossy(&req.name[..len]).into_owned(),
+        })
+    }
+
+    /// Create the next free utun device; macOS doesn't let it be named
+    #[cfg(target_os = "macos")]
+    fn open(name: Option<&str>) -> io::Result<Self> {
+        if name.is_some() {
+            warn!("tun.name is ignored on macOS; the system picks the utun unit");
+        }
+        // SAFETY: plain socket call
+        let fd = unsafe { libc::socket(libc::PF_SYSTEM, libc::SOCK_DGRAM, libc::SYSPROTO_CONTROL) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: fd was just opened and is owned from here on
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // SAFETY: all-zero is a valid ctl_info
+        let mut info: libc::ctl_info = unsafe { std::mem::zeroed() };
+        for (dst, src) in info.ctl_name.iter_mut().zip(b"com.apple.net.utun_control") {
+            *dst = *src as libc::c_char;
+        }
+        // SAFETY: info is a valid ctl_info for CTLIOCGINFO
+        if unsafe { libc::ioctl(fd.as_raw_fd(), libc::CTLIOCGINFO, &mut info) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Unit 0 lets the kernel pick the next free utunN
+        let addr = libc::sockaddr_ctl {
+            sc_len: std::mem::size_of::<libc::sockaddr_ctl>() as u8,
+            sc_family: libc::AF_SYSTEM as u8,
+            ss_sysaddr: libc::AF_SYS_CONTROL as u16,
+            sc_id: info.ctl_id,
+            sc_unit: 0,
+            sc_reserved: [0; 5],
+        };
+        // SAFETY: addr is a sockaddr_ctl of the length passed
+        let connected = unsafe {
+            libc::connect(
+                fd.as_raw_fd(),
+                (&addr as *const libc::sockaddr_ctl).cast(),
+                std::mem::size_of::<libc::sockaddr_ctl>() as libc::socklen_t,
+            )
+        };
+        if connected < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut name = [0u8; libc::IFNAMSIZ];
+        let mut len = name.len() as libc::socklen_t;
+        // SAFETY: name is writable for len bytes
+        let got = unsafe {
+            libc::getsockopt(
+                fd.as_raw_fd(),
+                libc::SYSPROTO_CONTROL,
+                libc::UTUN_OPT_IFNAME,
+                name.as_mut_ptr().cast(),
+                &mut len,
+            )
+        };
+        if got < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+        Ok(Self {
+            fd,
+            name: String::from_utf8_lossy(&name[..len]).into_owned(),
+        })
+    }
+
+    /// Give the device its address and bring it up
+    fn configure(&self, address: std::net::Ipv4Addr) -> anyhow::Result<()> {
+        let address = address.to_string();
+        if cfg!(target_os = "linux") {
+            run(Command::new("ip").args([
+                "addr",
+                "add",
+                &format!("{address}/32"),
+                "dev",
+                &self.name,
+            ]))?;
+            run(Command::new("ip").args(["link", "set", &self.name, "up"]))?;
+        } else {
+            // Point-to-point: utun needs a destination, so it's the same address
+            run(Command::new("ifconfig").args([&self.name, "inet", &address, &address, "up"]))?;
+        }
+        Ok(())
+    }
+
+    /// Read one packet, without the utun header
+    fn recv<'a>(&self, buf: &'a mut [u8]) -> io::Result<&'a mut [u8]> {
+        // SAFETY: buf is writable for its length
+        let n = unsafe { libc::read(self.fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let packet = &mut buf[..n as usize];
+        if cfg!(target_os = "macos") {
+            return Ok(strip_af_header(packet).unwrap_or_default());
+        }
+        Ok(packet)
+    }
+
+    /// Write one IPv4 packet, adding the utun header where needed
+    fn send(&self, packet: &[u8], scratch: &mut Vec<u8>) -> io::Result<()> {
+        let packet = if cfg!(target_os = "macos") {
+            scratch.clear();
+            scratch.extend_from_slice(&af_inet_header());
+            scratch.extend_from_slice(packet);
+            scratch.as_slice()
+        } else {
+            packet
+        };
+        // SAFETY: packet is readable for its length
+        let n = unsafe { libc::write(self.fd.as_raw_fd(), packet.as_ptr().cast(), packet.len()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// utun's address family header for IPv4: `AF_INET` as a big-endian u32
+fn af_inet_header() -> [u8; AF_HEADER_LEN] {
+    (libc::AF_INET as u32).to_be_bytes()
+}
+
+/// The IPv4 packet behind a utun header; None for other families
+fn strip_af_header(packet: &mut [u8]) -> Option<&mut [u8]> {
+    if packet.len() < AF_HEADER_LEN || packet[..AF_HEADER_LEN] != af_inet_header() {
+        return None;
+    }
+    Some(&mut packet[AF_HEADER_LEN..])
+}
+
+/// Open and configure the device, route traffic into it and start its
+/// packet loop. `servers` keep their routes through the current gateway so
+/// the tunnel itself doesn't loop back into the device. The returned guard
+/// removes the routes again.
+pub(super) fn start(
+    config: &TunConfig,
+    servers: &[IpAddr],
+    table: Arc<NatTable>,
+) -> anyhow::Result<RouteGuard> {
+    let networks = config
+        .routes
+        .iter()
+        .map(|net| {
+            net.parse::<IpNet>()
+                .map_err(|_| anyhow::anyhow!("Invalid tun route {net:?}"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let device = Device::open(config.name.as_deref())
+        .map_err(|e| anyhow::anyhow!("Can't create the tun device (are we root?): {e}"))?;
+    device.configure(config.address)?;
+
+    let mut routes = RouteGuard::new(crate::routes::default_state_file()?)?;
+    // Only IPv4 is routed into the device
+    for server in servers.iter().filter(|server| server.is_ipv4()) {
+        routes.pin_server(*server)?;
+    }
+    let next_hop = NextHop::Interface(device.name.clone());
+    if networks.is_empty() {
+        routes.override_default(next_hop)?;
+    } else {
+        for destination in networks {
+            routes.add(crate::routes::Route {
+                destination,
+                next_hop: next_hop.clone(),
+            })?;
+        }
+    }
+
+    info!(
+        "Tun mode: routing into {} ({}), redirecting TCP into port {}",
+        device.name, config.address, config.port
+    );
+    let listen_port = config.port;
+    std::thread::Builder::new()
+        .name("tun-packets".into())
+        .spawn(move || tun_packets(device, &table, listen_port))?;
+    Ok(routes)
+}
+
+/// Reflect TCP packets read from the device back into it, dropping the rest
+fn tun_packets(device: Device, table: &NatTable, listen_port: u16) {
+    let mut buf = vec![0u8; MAX_PACKET];
+    let mut scratch = Vec::with_capacity(MAX_PACKET + AF_HEADER_LEN);
+    loop {
+        let packet = match device.recv(&mut buf) {
+            Ok(packet) => packet,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                warn!("Tun mode packet loop stopped: {}", e);
+                return;
+            }
+        };
+        let Some(mut tcp) = TcpPacket::parse(packet) else {
+            continue;
+        };
+        if !reflect(&mut tcp, table, listen_port) {
+            continue;
+        }
+        if let Err(e) = device.send(tcp.as_bytes(), &mut scratch) {
+            debug!("Failed to write packet to {}: {}", device.name, e);
+        }
+    }
+}
+
+/// Redirect a packet read from the device. Every new connection in the
+/// device is redirected, so its SYN is what classifies it.
+fn reflect(packet: &mut TcpPacket<'_>, table: &NatTable, listen_port: u16) -> bool {
+    if packet.is_syn() && packet.src_port() != listen_port {
+        let dst = SocketAddrV4::new(packet.dst_ip(), packet.dst_port());
+        table.insert(packet.src_port(), Route::Redirect(dst));
+    }
+    super::divert(packet, table, listen_port)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::packet::tests::{checksums_valid, syn};
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_reflect_redirects_new_connections() {
+        let local = Ipv4Addr::new(198, 18, 0, 1);
+        let remote = Ipv4Addr::new(93, 184, 216, 34);
+        let table = NatTable::default();
+
+        // The SYN alone is enough to classify the connection
+        let mut buf = syn((local, 50000), (remote, 443));
+        let mut packet = TcpPacket::parse(&mut buf).unwrap();
+        assert!(reflect(&mut packet, &table, 1082));
+        assert_eq!((packet.src_ip(), packet.src_port()), (remote, 50000));
+        assert_eq!((packet.dst_ip(), packet.dst_port()), (local, 1082));
+        assert!(checksums_valid(&buf));
+
+        // The listener's answer goes back as if from the destination
+        let mut buf = syn((local, 1082), (remote, 50000));
+        let mut packet = TcpPacket::parse(&mut buf).unwrap();
+        assert!(reflect(&mut packet, &table, 1082));
+        assert_eq!((packet.src_ip(), packet.src_port()), (remote, 443));
+        assert_eq!((packet.dst_ip(), packet.dst_port()), (local, 50000));
+        assert!(checksums_valid(&buf));
+    }
+
+    #[test]
+    fn test_af_header() {
+        let mut packet = af_inet_header().to_vec();
+        packet.extend_from_slice(&[0x45, 0, 0, 20]);
+        assert_eq!(strip_af_header(&mut packet).unwrap(), [0x45, 0, 0, 20]);
+
+        // AF_INET6 packets and runts are dropped
+        let mut v6 = [0, 0, 0, 30, 0x60];
+        assert!(strip_af_header(&mut v6).is_none());
+        assert!(strip_af_header(&mut [0, 0]).is_none());
+    }
+}
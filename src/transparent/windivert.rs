@@ -0,0 +1,296 @@
+//! WinDivert bindings and the interception threads
+//!
+//! Two handles are opened: a sniffing SOCKET-layer handle that classifies
+//! new connections by owning process, and a NETWORK-layer handle that
+//! captures outbound IPv4 TCP and rewrites packets of redirected
+//! connections. Both block in the driver, so each runs on its own thread.
+
+use super::packet::TcpPacket;
+use super::{NatTable, Route};
+use crate::config::TransparentConfig;
+use std::ffi::{CString, OsString, c_char, c_void};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::os::windows::ffi::OsStringExt;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{debug, info, warn};
+
+type RawHandle = *mut c_void;
+
+const INVALID_HANDLE_VALUE: RawHandle = -1isize as RawHandle;
+
+const LAYER_NETWORK: u32 = 0;
+const LAYER_SOCKET: u32 = 3;
+
+const EVENT_SOCKET_CONNECT: u32 = 4;
+const EVENT_SOCKET_CLOSE: u32 = 7;
+
+const FLAG_SNIFF: u64 = 0x0001;
+const FLAG_RECV_ONLY: u64 = 0x0004;
+
+/// Bits of the `WINDIVERT_ADDRESS` bitfield word
+const ADDR_OUTBOUND: u32 = 1 << 17;
+const ADDR_IP_CHECKSUM: u32 = 1 << 21;
+const ADDR_TCP_CHECKSUM: u32 = 1 << 22;
+
+const PROCESS_QUERY_LIMITED_INFORMATION: u32 = 0x1000;
+
+/// Largest packet the driver hands us
+const MAX_PACKET: usize = 0xffff;
+
+/// `WINDIVERT_ADDRESS` (WinDivert 2.x)
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Address {
+    timestamp: i64,
+    /// Layer:8, Event:8, Sniffed, Outbound, Loopback, Impostor, IPv6,
+    /// IPChecksum, TCPChecksum, UDPChecksum, reserved
+    bits: u32,
+    reserved: u32,
+    /// Layer-specific data union
+    data: [u8; 64],
+}
+
+impl Address {
+    fn zeroed() -> Self {
+        Self {
+            timestamp: 0,
+            bits: 0,
+            reserved: 0,
+            data: [0; 64],
+        }
+    }
+
+    fn event(&self) -> u32 {
+        (self.bits >> 8) & 0xff
+    }
+
+    /// `WINDIVERT_DATA_SOCKET` fields: (process ID, local port, remote address)
+    fn socket(&self) -> (u32, u16, Option<SocketAddrV4>) {
+        let u32_at = |o: usize| u32::from_ne_bytes(self.data[o..o + 4].try_into().unwrap());
+        let u16_at = |o: usize| u16::from_ne_bytes(self.data[o..o + 2].try_into().unwrap());
+        let process_id = u32_at(16);
+        // Addresses are IPv4-mapped IPv6 in host order, lowest word first
+        let remote = [u32_at(36), u32_at(40), u32_at(44), u32_at(48)];
+        let remote = (remote[1] == 0xffff && remote[2] == 0 && remote[3] == 0)
+            .then(|| SocketAddrV4::new(Ipv4Addr::from(remote[0]), u16_at(54)));
+        (process_id, u16_at(52), remote)
+    }
+}
+
+#[link(name = "WinDivert")]
+unsafe extern "C" {
+    fn WinDivertOpen(filter: *const c_char, layer: u32, priority: i16, flags: u64) -> RawHandle;
+    fn WinDivertRecv(
+        handle: RawHandle,
+        packet: *mut c_void,
+        packet_len: u32,
+        recv_len: *mut u32,
+        addr: *mut Address,
+    ) -> i32;
+    fn WinDivertSend(
+        handle: RawHandle,
+        packet: *const c_void,
+        packet_len: u32,
+        send_len: *mut u32,
+        addr: *const Address,
+    ) -> i32;
+    fn WinDivertClose(handle: RawHandle) -> i32;
+}
+
+#[link(name = "kernel32")]
+unsafe extern "system" {
+    fn OpenProcess(access: u32, inherit: i32, process_id: u32) -> RawHandle;
+    fn QueryFullProcessImageNameW(
+        process: RawHandle,
+        flags: u32,
+        name: *mut u16,
+        size: *mut u32,
+    ) -> i32;
+    fn CloseHandle(handle: RawHandle) -> i32;
+}
+
+/// An open WinDivert handle
+struct Divert(RawHandle);
+
+// SAFETY: WinDivert handles are plain kernel handles, usable from any thread
+unsafe impl Send for Divert {}
+
+impl Divert {
+    fn open(filter: &str, layer: u32, flags: u64) -> anyhow::Result<Self> {
+        let filter = CString::new(filter)?;
+        // SAFETY: the filter is a valid NUL-terminated string
+        let handle = unsafe { WinDivertOpen(filter.as_ptr(), layer, 0, flags) };
+        if handle == INVALID_HANDLE_VALUE {
+            anyhow::bail!(
+                "WinDivertOpen failed (is WinDivert installed and are we elevated?): {}",
+                io::Error::last_os_error()
+            );
+        }
+        Ok(Self(handle))
+    }
+
+    fn recv(&self, buf: &mut [u8], addr: &mut Address) -> io::Result<usize> {
+        let mut len = 0u32;
+        // SAFETY: buf and addr are valid for the lengths passed
+        let ok = unsafe {
+            WinDivertRecv(
+                self.0,
+                buf.as_mut_ptr().cast(),
+                buf.len() as u32,
+                &mut len,
+                addr,
+            )
+        };
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(len as usize)
+    }
+
+    fn send(&self, packet: &[u8], addr: &Address) -> io::Result<()> {
+        // SAFETY: packet and addr are valid for the lengths passed
+        let ok = unsafe {
+            WinDivertSend(
+                self.0,
+                packet.as_ptr().cast(),
+                packet.len() as u32,
+                std::ptr::null_mut(),
+                addr,
+            )
+        };
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Divert {
+    fn drop(&mut self) {
+        // SAFETY: the handle came from WinDivertOpen and is closed once
+        unsafe { WinDivertClose(self.0) };
+    }
+}
+
+/// Open both handles and start the interception threads
+pub(super) fn start(config: &TransparentConfig, table: Arc<NatTable>) -> anyhow::Result<()> {
+    if config.processes.is_empty() {
+        anyhow::bail!("transparent.processes is empty");
+    }
+    let processes: Vec<String> = config
+        .processes
+        .iter()
+        .map(|p| p.to_ascii_lowercase())
+        .collect();
+    let own_pid = std::process::id();
+
+    let sockets = Divert::open(
+        &format!(
+            "tcp and !loopback and !ipv6 and processId != {own_pid} and (event == CONNECT or event == CLOSE)"
+        ),
+        LAYER_SOCKET,
+        FLAG_SNIFF | FLAG_RECV_ONLY,
+    )?;
+    let packets = Divert::open("outbound and !loopback and ip and tcp", LAYER_NETWORK, 0)?;
+
+    let watcher_table = Arc::clone(&table);
+    std::thread::Builder::new()
+        .name("windivert-sockets".into())
+        .spawn(move || watch_sockets(sockets, &watcher_table, &processes))?;
+    let listen_port = config.port;
+    std::thread::Builder::new()
+        .name("windivert-packets".into())
+        .spawn(move || divert_packets(packets, &table, listen_port))?;
+
+    info!(
+        "Transparent mode: redirecting {} into port {}",
+        config.processes.join(", "),
+        config.port
+    );
+    Ok(())
+}
+
+/// Classify connections as they're opened and mark them when closed
+fn watch_sockets(handle: Divert, table: &NatTable, processes: &[String]) {
+    let mut addr = Address::zeroed();
+    loop {
+        if let Err(e) = handle.recv(&mut [], &mut addr) {
+            warn!("Transparent mode socket watcher stopped: {}", e);
+            return;
+        }
+        let (process_id, local_port, remote) = addr.socket();
+        match addr.event() {
+            EVENT_SOCKET_CONNECT => {
+                let route = match remote {
+                    Some(dst) if is_selected(process_id, processes) => {
+                        debug!(
+                            "Redirecting pid {} port {} -> {}",
+                            process_id, local_port, dst
+                        );
+                        Route::Redirect(dst)
+                    }
+                    _ => Route::Direct,
+                };
+                table.insert(local_port, route);
+            }
+            EVENT_SOCKET_CLOSE => table.close(local_port),
+            _ => {}
+        }
+    }
+}
+
+/// Rewrite packets of redirected connections, passing everything else
+fn divert_packets(handle: Divert, table: &NatTable, listen_port: u16) {
+    let mut buf = vec![0u8; MAX_PACKET];
+    let mut addr = Address::zeroed();
+    loop {
+        let len = match handle.recv(&mut buf, &mut addr) {
+            Ok(len) => len,
+            Err(e) => {
+                warn!("Transparent mode packet filter stopped: {}", e);
+                return;
+            }
+        };
+        let packet = &mut buf[..len];
+        if let Some(mut tcp) = TcpPacket::parse(packet)
+            && super::divert(&mut tcp, table, listen_port)
+        {
+            addr.bits &= !ADDR_OUTBOUND;
+            addr.bits |= ADDR_IP_CHECKSUM | ADDR_TCP_CHECKSUM;
+        }
+        if let Err(e) = handle.send(packet, &addr) {
+            debug!("Failed to re-inject packet: {}", e);
+        }
+    }
+}
+
+/// Whether the process's executable name is in the configured list
+fn is_selected(process_id: u32, processes: &[String]) -> bool {
+    image_name(process_id).is_some_and(|name| processes.iter().any(|p| *p == name))
+}
+
+/// Lowercased executable file name of a process
+fn image_name(process_id: u32) -> Option<String> {
+    // SAFETY: plain Win32 calls; the handle is closed before returning
+    unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, 0, process_id);
+        if process.is_null() {
+            return None;
+        }
+        let mut buf = [0u16; 1024];
+        let mut size = buf.len() as u32;
+        let ok = QueryFullProcessImageNameW(process, 0, buf.as_mut_ptr(), &mut size);
+        CloseHandle(process);
+        if ok == 0 {
+            return None;
+        }
+        let path = OsString::from_wide(&buf[..size as usize]);
+        let name = Path::new(&path)
+            .file_name()?
+            .to_string_lossy()
+            .to_ascii_lowercase();
+        Some(name)
+    }
+}
@@ -0,0 +1,197 @@
+//! In-memory transport
+//!
+//! [`InMemory`] connects a tunnel client and server through a
+//! [`tokio::io::duplex`] pipe instead of SMTP over TLS, so applications
+//! embedding the library can integration-test tunneled connections without
+//! certificates or a listening socket. Past the handshake it is the real
+//! thing: both ends run the same links and frame loops as a live session,
+//! and the server end dials channel destinations as usual.
+
+use crate::client::{SessionEvents, TunnelHandle, UdpAssociation};
+use crate::config::ServerConfig;
+use crate::proto::AddressFamily;
+use crate::socks5::{ConnectRequest, TunnelIo};
+use crate::tasks::TaskGroup;
+use std::io;
+use std::net::IpAddr;
+
+/// Buffer size of the pipe between the two ends, in each direction
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// A tunnel client and server connected in memory
+#[derive(Debug)]
+pub struct InMemory {
+    pub client: ClientEndpoint,
+    pub server: ServerEndpoint,
+}
+
+impl InMemory {
+    /// Connect a client to a server with the default configuration. Must be
+    /// called within a tokio runtime.
+    pub fn new() -> Self {
+        Self::with_server_config(&ServerConfig::default()).expect("default server config is valid")
+    }
+
+    /// Connect a client to a server using `config`'s destination rules,
+    /// connect limit, idle timeout and write batching
+    pub fn with_server_config(config: &ServerConfig) -> anyhow::Result<Self> {
+        let (client, server) = tokio::io::duplex(PIPE_SIZE);
+        let server_tasks = TaskGroup::new();
+        crate::server::serve_stream(config, server, server_tasks.clone())?;
+        let client_tasks = TaskGroup::new();
+        Ok(Self {
+            client: ClientEndpoint {
+                tunnel: TunnelHandle::over_stream(
+                    client,
+                    client_tasks.clone(),
+                    SessionEvents::none(),
+                ),
+                tasks: client_tasks,
+            },
+            server: ServerEndpoint {
+                tasks: server_tasks,
+            },
+        })
+    }
+}
+
+impl Default for InMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Client end of an [`InMemory`] tunnel. Dropping it hangs up.
+pub struct ClientEndpoint {
+    tunnel: TunnelHandle,
+    tasks: TaskGroup,
+}
+
+impl ClientEndpoint {
+    /// Open a channel to `host:port` through the server end, as a SOCKS5
+    /// CONNECT would
+    pub async fn connect(&self, host: &str, port: u16) -> io::Result<TunnelIo> {
+        let req = ConnectRequest {
+            host: host.to_string(),
+            port,
+        };
+        Ok(self.tunnel.open_stream(req).await?.into_io())
+    }
+
+    /// Open a UDP association through the server end
+    pub fn udp(&self) -> io::Result<UdpAssociation> {
+        self.tunnel.udp()
+    }
+
+    /// Resolve `host` through the server end
+    pub async fn resolve(&self, host: &str, family: AddressFamily) -> io::Result<Vec<IpAddr>> {
+        self.tunnel.resolve(host, family).await
+    }
+}
+
+impl std::fmt::Debug for ClientEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ClientEndpoint").finish()
+    }
+}
+
+impl Drop for ClientEndpoint {
+    fn drop(&mut self) {
+        self.tasks.cancel();
+    }
+}
+
+/// Server end of an [`InMemory`] tunnel. Dropping it hangs up, closing the
+/// connections it opened.
+#[derive(Debug)]
+pub struct ServerEndpoint {
+    tasks: TaskGroup,
+}
+
+impl Drop for ServerEndpoint {
+    fn drop(&mut self) {
+        self.tasks.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_in_memory_round_trip() {
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut socket, _) = echo.accept().await.unwrap();
+            let (mut reader, mut writer) = socket.split();
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+        });
+
+        let tunnel = InMemory::new();
+        let mut stream = tunnel.client.connect("127.0.0.1", port).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"hello");
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_blocked_destination() {
+        let config = ServerConfig {
+            blocked_destinations: vec!["127.0.0.0/8".to_string()],
+            ..ServerConfig::default()
+        };
+        let tunnel = InMemory::with_server_config(&config).unwrap();
+        let err = tunnel.client.connect("127.0.0.1", 80).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_udp() {
+        let echo = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let port = echo.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let (n, from) = echo.recv_from(&mut buf).await.unwrap();
+            echo.send_to(&buf[..n], from).await.unwrap();
+        });
+
+        let tunnel = InMemory::new();
+        let mut udp = tunnel.client.udp().unwrap();
+        udp.send_to("127.0.0.1", port, b"ping").await.unwrap();
+        let (from, reply) = udp.recv_from().await.unwrap();
+        assert_eq!(from, std::net::SocketAddr::from(([127, 0, 0, 1], port)));
+        assert_eq!(&reply[..], b"ping");
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_resolve() {
+        let config = ServerConfig {
+            blocked_destinations: vec!["blocked.localhost".to_string()],
+            ..ServerConfig::default()
+        };
+        let tunnel = InMemory::with_server_config(&config).unwrap();
+        let addrs = tunnel
+            .client
+            .resolve("127.0.0.1", AddressFamily::Any)
+            .await
+            .unwrap();
+        assert_eq!(addrs, vec![IpAddr::from([127, 0, 0, 1])]);
+        let err = tunnel
+            .client
+            .resolve("127.0.0.1", AddressFamily::Ipv6)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let err = tunnel
+            .client
+            .resolve("blocked.localhost", AddressFamily::Any)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
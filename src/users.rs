@@ -0,0 +1,421 @@
+//! Where users are kept
+//!
+//! The server reads and writes its users through a [`UserStore`], picked
+//! by `users_backend`: the YAML users file, a SQLite database, or a
+//! document pulled over HTTP(S) for deployments whose accounts live in
+//! another system. Stores are blocking; the server calls them off the
+//! async runtime.
+
+use crate::config::{ServerConfig, UsersBackend, UsersConfig};
+use crate::http::parse_http_response;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Timeout for connecting to and reading from an HTTP users source
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest users document accepted over HTTP
+const MAX_HTTP_BODY: u64 = 16 * 1024 * 1024;
+
+/// One user's difference between two sets of users
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UserEvent {
+    Added(String),
+    Removed(String),
+    SecretChanged(String),
+    /// Whitelist or logging changed
+    PolicyChanged(String),
+}
+
+impl std::fmt::Display for UserEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Added(user) => write!(f, "user {user} added"),
+            Self::Removed(user) => write!(f, "user {user} removed"),
+            Self::SecretChanged(user) => write!(f, "secret of {user} changed"),
+            Self::PolicyChanged(user) => write!(f, "settings of {user} changed"),
+        }
+    }
+}
+
+/// What changed from `old` to `new`, by username
+pub fn diff(old: &UsersConfig, new: &UsersConfig) -> Vec<UserEvent> {
+    let mut events = Vec::new();
+    for (username, entry) in &new.users {
+        let event = match old.get_user(username) {
+            None => UserEvent::Added(username.clone()),
+            Some(previous) if previous.secret != entry.secret => {
+                UserEvent::SecretChanged(username.clone())
+            }
+            Some(previous)
+                if previous.whitelist != entry.whitelist || previous.logging != entry.logging =>
+            {
+                UserEvent::PolicyChanged(username.clone())
+            }
+            Some(_) => continue,
+        };
+        events.push(event);
+    }
+    for username in old.users.keys() {
+        if new.get_user(username).is_none() {
+            events.push(UserEvent::Removed(username.clone()));
+        }
+    }
+    events.sort_by(|a, b| event_user(a).cmp(event_user(b)));
+    events
+}
+
+fn event_user(event: &UserEvent) -> &str {
+    match event {
+        UserEvent::Added(user)
+        | UserEvent::Removed(user)
+        | UserEvent::SecretChanged(user)
+        | UserEvent::PolicyChanged(user) => user,
+    }
+}
+
+/// Storage for the users table
+pub trait UserStore: Send + Sync {
+    /// The current users
+    fn load(&self) -> anyhow::Result<UsersConfig>;
+
+    /// Replace the stored users
+    fn save(&self, users: &UsersConfig) -> anyhow::Result<()>;
+
+    /// Where the users are kept, for logs and messages
+    fn describe(&self) -> String;
+
+    /// How often to reload, for stores that change behind the server's back
+    fn refresh_interval(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Open the store `config` names
+pub fn open(config: &ServerConfig) -> anyhow::Result<Box<dyn UserStore>> {
+    match &config.users_backend {
+        UsersBackend::File => Ok(Box::new(FileStore {
+            path: config.users_file.clone(),
+        })),
+        #[cfg(feature = "sqlite")]
+        UsersBackend::Sqlite { path } => Ok(Box::new(SqliteStore::open(path)?)),
+        #[cfg(not(feature = "sqlite"))]
+        UsersBackend::Sqlite { .. } => {
+            anyhow::bail!("users_backend sqlite needs a build with the sqlite feature")
+        }
+        UsersBackend::Http {
+            url,
+            refresh,
+            ca_cert,
+        } => Ok(Box::new(HttpStore::new(
+            url,
+            Duration::from_secs(*refresh),
+            ca_cert.as_deref(),
+        )?)),
+    }
+}
+
+/// The YAML users file
+pub struct FileStore {
+    path: String,
+}
+
+impl UserStore for FileStore {
+    fn load(&self) -> anyhow::Result<UsersConfig> {
+        UsersConfig::from_file(&self.path)
+    }
+
+    fn save(&self, users: &UsersConfig) -> anyhow::Result<()> {
+        users.save_to_file(&self.path)
+    }
+
+    fn describe(&self) -> String {
+        self.path.clone()
+    }
+}
+
+/// A SQLite database with one row per user
+#[cfg(feature = "sqlite")]
+pub struct SqliteStore {
+    path: String,
+    conn: Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStore {
+    /// Open the database, creating the users table if it's missing
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let conn = rusqlite::Connection::open(path)
+            .map_err(|e| anyhow::anyhow!("Can't open {}: {}", path, e))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS users (
+                username TEXT PRIMARY KEY,
+                secret TEXT NOT NULL,
+                whitelist TEXT NOT NULL DEFAULT '',
+                logging INTEGER NOT NULL DEFAULT 1
+            )",
+        )?;
+        Ok(Self {
+            path: path.to_string(),
+            conn: Mutex::new(conn),
+        })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl UserStore for SqliteStore {
+    fn load(&self) -> anyhow::Result<UsersConfig> {
+        let conn = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        let mut stmt = conn.prepare("SELECT username, secret, whitelist, logging FROM users")?;
+        let rows = stmt.query_map([], |row| {
+            let whitelist: String = row.get(2)?;
+            Ok((
+                row.get::<_, String>(0)?,
+                crate::config::UserEntry {
+                    secret: row.get(1)?,
+                    // Comma-separated addresses and networks
+                    whitelist: whitelist
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|s| !s.is_empty())
+                        .map(String::from)
+                        .collect(),
+                    logging: row.get(3)?,
+                },
+            ))
+        })?;
+        let mut users = UsersConfig::default();
+        for row in rows {
+            let (username, entry) = row?;
+            users.set_user(username, entry);
+        }
+        Ok(users)
+    }
+
+    fn save(&self, users: &UsersConfig) -> anyhow::Result<()> {
+        let mut conn = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM users", [])?;
+        for (username, entry) in &users.users {
+            tx.execute(
+                "INSERT INTO users (username, secret, whitelist, logging) VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![
+                    username,
+                    entry.secret,
+                    entry.whitelist.join(","),
+                    entry.logging
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        format!("sqlite:{}", self.path)
+    }
+}
+
+/// Users pulled from an HTTP(S) URL, revalidated with the ETag of the last
+/// copy so an unchanged document isn't transferred again
+pub struct HttpStore {
+    url: String,
+    host: String,
+    port: u16,
+    path: String,
+    refresh: Duration,
+    tls: Option<std::sync::Arc<rustls::ClientConfig>>,
+    /// ETag and users of the last document fetched
+    cached: Mutex<Option<(String, UsersConfig)>>,
+}
+
+impl HttpStore {
+    pub fn new(url: &str, refresh: Duration, ca_cert: Option<&str>) -> anyhow::Result<Self> {
+        let invalid = || anyhow::anyhow!("Invalid users URL: {}", url);
+        let (https, rest) = if let Some(rest) = url.strip_prefix("https://") {
+            (true, rest)
+        } else if let Some(rest) = url.strip_prefix("http://") {
+            (false, rest)
+        } else {
+            return Err(invalid());
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let default_port = if https { 443 } else { 80 };
+        // "[v6]:port", "host:port" or just the host
+        let (host, port) = if let Some(v6) = authority.strip_prefix('[') {
+            let (host, after) = v6.split_once(']').ok_or_else(invalid)?;
+            match after.strip_prefix(':') {
+                Some(port) => (host, port.parse().map_err(|_| invalid())?),
+                None => (host, default_port),
+            }
+        } else {
+            match authority.rsplit_once(':') {
+                Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+                None => (authority, default_port),
+            }
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let tls = if https {
+            Some(crate::tls::client_config(ca_cert)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            url: url.to_string(),
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            refresh,
+            tls,
+            cached: Mutex::new(None),
+        })
+    }
+
+    /// Send a GET, revalidating `etag`, and return the raw response
+    fn get(&self, etag: Option<&str>) -> anyhow::Result<Vec<u8>> {
+        let addrs = (self.host.as_str(), self.port).to_socket_addrs()?;
+        let mut last_error = None;
+        let mut tcp = None;
+        for addr in addrs {
+            match TcpStream::connect_timeout(&addr, HTTP_TIMEOUT) {
+                Ok(stream) => {
+                    tcp = Some(stream);
+                    break;
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        let tcp = match (tcp, last_error) {
+            (Some(tcp), _) => tcp,
+            (None, Some(e)) => return Err(e.into()),
+            (None, None) => anyhow::bail!("{} has no addresses", self.host),
+        };
+        tcp.set_read_timeout(Some(HTTP_TIMEOUT))?;
+        tcp.set_write_timeout(Some(HTTP_TIMEOUT))?;
+
+        // HTTP/1.0: the body ends when the connection does, never chunked
+        let mut request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: application/yaml, application/json\r\nUser-Agent: smtp-tunnel/{}\r\n",
+            self.path,
+            self.host,
+            crate::VERSION
+        );
+        if let Some(etag) = etag {
+            request.push_str(&format!("If-None-Match: {etag}\r\n"));
+        }
+        request.push_str("\r\n");
+
+        let mut response = Vec::new();
+        match &self.tls {
+            Some(tls) => {
+                let name = rustls::pki_types::ServerName::try_from(self.host.clone())?;
+                let conn = rustls::ClientConnection::new(tls.clone(), name)?;
+                let mut stream = rustls::StreamOwned::new(conn, tcp);
+                stream.write_all(request.as_bytes())?;
+                match (&mut stream).take(MAX_HTTP_BODY).read_to_end(&mut response) {
+                    // Servers often close without close_notify; a short
+                    // body is caught by the Content-Length check
+                    Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {}
+                    result => {
+                        result?;
+                    }
+                }
+            }
+            None => {
+                let mut stream = tcp;
+                stream.write_all(request.as_bytes())?;
+                (&mut stream)
+                    .take(MAX_HTTP_BODY)
+                    .read_to_end(&mut response)?;
+            }
+        }
+        Ok(response)
+    }
+}
+
+impl UserStore for HttpStore {
+    fn load(&self) -> anyhow::Result<UsersConfig> {
+        let mut cached = self.cached.lock().unwrap_or_else(|e| e.into_inner());
+        let etag = cached.as_ref().map(|(etag, _)| etag.as_str());
+        let raw = self
+            .get(etag)
+            .map_err(|e| anyhow::anyhow!("Fetching {}: {}", self.url, e))?;
+        let response = parse_http_response(&raw)?;
+        match response.status {
+            304 => match cached.as_ref() {
+                Some((_, users)) => Ok(users.clone()),
+                None => anyhow::bail!("{} answered 304 to an unconditional GET", self.url),
+            },
+            200 => {
+                let users: UsersConfig = serde_yaml::from_slice(response.body)?;
+                *cached = response.etag.map(|etag| (etag, users.clone()));
+                Ok(users)
+            }
+            status => anyhow::bail!("{} answered HTTP {}", self.url, status),
+        }
+    }
+
+    fn save(&self, _users: &UsersConfig) -> anyhow::Result<()> {
+        anyhow::bail!("Users from {} are read-only", self.url)
+    }
+
+    fn describe(&self) -> String {
+        self.url.clone()
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        Some(self.refresh)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_diff() {
+        let entry = |secret: &str| crate::config::UserEntry {
+            secret: secret.to_string(),
+            whitelist: Vec::new(),
+            logging: true,
+        };
+        let mut old = UsersConfig::default();
+        old.set_user("alice", entry("a"));
+        old.set_user("bob", entry("b"));
+        old.set_user("carol", entry("c"));
+        old.set_user("dave", entry("d"));
+        let mut new = old.clone();
+        new.remove_user("bob");
+        new.set_user("carol", entry("c2"));
+        new.users.get_mut("dave").unwrap().logging = false;
+        new.set_user("erin", entry("e"));
+
+        assert_eq!(
+            diff(&old, &new),
+            [
+                UserEvent::Removed("bob".into()),
+                UserEvent::SecretChanged("carol".into()),
+                UserEvent::PolicyChanged("dave".into()),
+                UserEvent::Added("erin".into()),
+            ]
+        );
+        assert!(diff(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn test_http_store() {
+        let store = HttpStore::new("http://[::1]:8080/users.yaml", Duration::ZERO, None).unwrap();
+        assert_eq!((store.host.as_str(), store.port), ("::1", 8080));
+        assert_eq!(store.path, "/users.yaml");
+        let store = HttpStore::new("http://accounts.example", Duration::ZERO, None).unwrap();
+        assert_eq!((store.port, store.path.as_str()), (80, "/"));
+        assert!(HttpStore::new("ftp://example.com/", Duration::ZERO, None).is_err());
+    }
+}
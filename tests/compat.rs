@@ -0,0 +1,200 @@
+//! Cross-version compatibility
+//!
+//! HELLO lets a client and server of different releases agree on a
+//! protocol version and the features both have, so upgrading one end
+//! shouldn't break the other. These tests hold the binaries to that: each
+//! runs a server and a client, and passes data through the client's SOCKS
+//! proxy, which needs a completed handshake.
+//!
+//! The current pair always runs. To check an older release, point
+//! `SMTP_TUNNEL_OLD_SERVER` and/or `SMTP_TUNNEL_OLD_CLIENT` at its
+//! downloaded binaries:
+//!
+//! ```bash
+//! SMTP_TUNNEL_OLD_SERVER=./old/smtp-tunnel-server \
+//! SMTP_TUNNEL_OLD_CLIENT=./old/smtp-tunnel-client \
+//!     cargo test --test compat
+//! ```
+//!
+//! A test whose variable isn't set is skipped.
+
+#![cfg(all(feature = "client", feature = "server", feature = "tools"))]
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+const SERVER: &str = env!("CARGO_BIN_EXE_smtp-tunnel-server");
+const CLIENT: &str = env!("CARGO_BIN_EXE_smtp-tunnel-client");
+const GEN_CERTS: &str = env!("CARGO_BIN_EXE_smtp-tunnel-gen-certs");
+
+/// How long the pair gets to come up and connect
+const STARTUP: Duration = Duration::from_secs(30);
+
+/// A running binary, killed when dropped
+struct Process {
+    child: Child,
+    log: PathBuf,
+}
+
+impl Process {
+    fn spawn(binary: &Path, dir: &Path, name: &str) -> Self {
+        let log = dir.join(format!("{name}.log"));
+        let file = std::fs::File::create(&log).unwrap();
+        let child = Command::new(binary)
+            .args(["-c", "config.yaml"])
+            .current_dir(dir)
+            .stdin(Stdio::null())
+            .stdout(file.try_clone().unwrap())
+            .stderr(file)
+            .spawn()
+            .unwrap_or_else(|e| panic!("Failed to start {}: {}", binary.display(), e));
+        Self { child, log }
+    }
+
+    fn output(&self) -> String {
+        std::fs::read_to_string(&self.log).unwrap_or_default()
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A port free when asked; good enough for tests
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Certificates, users and a config both binaries read, sticking to
+/// settings every release understands
+fn setup() -> (TempDir, u16) {
+    let dir = tempfile::tempdir().unwrap();
+    let status = Command::new(GEN_CERTS)
+        .args(["-h", "localhost", "-o"])
+        .arg(dir.path())
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success(), "gen-certs failed");
+
+    std::fs::write(
+        dir.path().join("users.yaml"),
+        "users:\n  compat:\n    secret: \"compat-secret\"\n",
+    )
+    .unwrap();
+
+    let server_port = free_port();
+    let socks_port = free_port();
+    let config = format!(
+        r#"server:
+  host: "127.0.0.1"
+  port: {server_port}
+  hostname: "localhost"
+  cert_file: "server.crt"
+  key_file: "server.key"
+  users_file: "users.yaml"
+
+client:
+  server_host: "localhost"
+  server_port: {server_port}
+  socks_port: {socks_port}
+  username: "compat"
+  secret: "compat-secret"
+  ca_cert: "ca.crt"
+"#
+    );
+    std::fs::write(dir.path().join("config.yaml"), config).unwrap();
+    (dir, socks_port)
+}
+
+/// Echo one message through the SOCKS proxy on `socks_port`
+fn echo_through(socks_port: u16, echo_port: u16) -> std::io::Result<bool> {
+    let mut socks = TcpStream::connect(("127.0.0.1", socks_port))?;
+    socks.set_read_timeout(Some(Duration::from_secs(10)))?;
+    socks.write_all(&[5, 1, 0])?;
+    let mut method = [0u8; 2];
+    socks.read_exact(&mut method)?;
+    let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+    request.extend_from_slice(&echo_port.to_be_bytes());
+    socks.write_all(&request)?;
+    let mut reply = [0u8; 10];
+    socks.read_exact(&mut reply)?;
+    if reply[1] != 0 {
+        return Ok(false);
+    }
+    socks.write_all(b"compat")?;
+    let mut echoed = [0u8; 6];
+    socks.read_exact(&mut echoed)?;
+    Ok(&echoed == b"compat")
+}
+
+/// Run `server` and `client` together and pass data through them
+fn check(server: &Path, client: &Path) {
+    let echo = TcpListener::bind("127.0.0.1:0").unwrap();
+    let echo_port = echo.local_addr().unwrap().port();
+    std::thread::spawn(move || {
+        for stream in echo.incoming() {
+            let Ok(mut stream) = stream else { continue };
+            std::thread::spawn(move || {
+                let mut reader = stream.try_clone().unwrap();
+                let _ = std::io::copy(&mut reader, &mut stream);
+            });
+        }
+    });
+
+    let (dir, socks_port) = setup();
+    let server = Process::spawn(server, dir.path(), "server");
+    let client = Process::spawn(client, dir.path(), "client");
+
+    let deadline = Instant::now() + STARTUP;
+    // Until the proxy is listening and the tunnel is up
+    while !matches!(echo_through(socks_port, echo_port), Ok(true)) {
+        assert!(
+            Instant::now() < deadline,
+            "No data through the tunnel after {:?}\n--- server ---\n{}\n--- client ---\n{}",
+            STARTUP,
+            server.output(),
+            client.output()
+        );
+        std::thread::sleep(Duration::from_millis(250));
+    }
+}
+
+/// An older release's binary, if one was supplied
+fn old(var: &str) -> Option<PathBuf> {
+    let path = std::env::var_os(var).map(PathBuf::from);
+    if path.is_none() {
+        eprintln!("{var} not set, skipping");
+    }
+    path
+}
+
+#[test]
+fn test_current_client_current_server() {
+    check(Path::new(SERVER), Path::new(CLIENT));
+}
+
+#[test]
+fn test_current_client_old_server() {
+    if let Some(server) = old("SMTP_TUNNEL_OLD_SERVER") {
+        check(&server, Path::new(CLIENT));
+    }
+}
+
+#[test]
+fn test_old_client_current_server() {
+    if let Some(client) = old("SMTP_TUNNEL_OLD_CLIENT") {
+        check(Path::new(SERVER), &client);
+    }
+}